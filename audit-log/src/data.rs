@@ -23,6 +23,7 @@ pub struct AuditLog {
     pub event_id: String,
     pub service: String,
     pub user: String,
+    pub trace_id: Option<String>,
     pub action: Action,
 }
 
@@ -30,6 +31,13 @@ pub struct AuditLog {
 pub enum Action {
     Encryption(EncryptionAction),
     Decryption(DecryptionAction),
+    Rewrap(RewrapAction),
+    DataKeyGeneration(DataKeyGenerationAction),
+    DataKeyDecryption(DataKeyDecryptionAction),
+    KeyringReload(KeyringReloadAction),
+    Unseal(UnsealAction),
+    KeyStateChange(KeyStateChangeAction),
+    KeyRotation(KeyRotationAction),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -45,3 +53,81 @@ pub struct DecryptionAction {
     pub data_key: Option<String>,
     pub algorithm: String,
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RewrapAction {
+    pub data_key: Option<String>,
+    /// Key which wrapped the DEK before
+    pub old_key_id: String,
+    /// Key which wraps the DEK now
+    pub new_key_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DataKeyGenerationAction {
+    pub algorithm: String,
+    pub key_id: String,
+    /// Whether the plaintext DEK was returned to the caller
+    pub with_plaintext: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DataKeyDecryptionAction {
+    pub algorithm: String,
+    pub key_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyringReloadAction {
+    /// What triggered the reload (e.g. `SIGHUP` or `file-watch`)
+    pub trigger: String,
+    pub succeeded: bool,
+    pub default_key_id: Option<String>,
+    pub added_key_ids: Vec<String>,
+    pub removed_key_ids: Vec<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnsealAction {
+    /// Index of the submitted share, if it could be parsed
+    pub share_index: Option<u8>,
+    pub accepted: bool,
+    pub progress: u32,
+    pub threshold: u32,
+    /// Whether the keyring is unsealed after this submission
+    pub unsealed: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyStateChangeAction {
+    pub key_id: String,
+    /// State before the change, if the key was found
+    pub old_state: Option<String>,
+    pub new_state: String,
+    /// When the key is destroyed, if it is pending destruction
+    pub destroy_at: Option<DateTime<Utc>>,
+    pub succeeded: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyRotationAction {
+    /// Name of the rotated key, which is `default` for the default key
+    pub key_name: String,
+    /// Why the key is rotated (e.g. `max-age` or `max-encryptions`)
+    pub reason: String,
+    pub old_key_id: String,
+    /// ID of the new primary version, if it is generated
+    pub new_key_id: Option<String>,
+    pub succeeded: bool,
+    pub error: Option<String>,
+}
@@ -0,0 +1,37 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{AuditLog, AuditLogger};
+use async_trait::async_trait;
+
+/// Writes each audit log to all sinks.
+pub struct FanOutAuditLogger {
+    sinks: Vec<Box<dyn AuditLogger>>,
+}
+
+impl FanOutAuditLogger {
+    pub fn new(sinks: Vec<Box<dyn AuditLogger>>) -> Self {
+        Self { sinks }
+    }
+}
+
+#[async_trait]
+impl AuditLogger for FanOutAuditLogger {
+    async fn log(&self, log: AuditLog) {
+        for sink in &self.sinks {
+            sink.log(log.clone()).await;
+        }
+    }
+}
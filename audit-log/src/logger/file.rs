@@ -0,0 +1,67 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{AuditLog, AuditLogger};
+use async_trait::async_trait;
+use std::path::Path;
+use telemetry::metrics::metrics;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// Appends audit logs to a file as JSON lines.
+#[derive(Debug)]
+pub struct FileAuditLogger {
+    file: Mutex<File>,
+}
+
+impl FileAuditLogger {
+    pub async fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+#[async_trait]
+impl AuditLogger for FileAuditLogger {
+    async fn log(&self, log: AuditLog) {
+        let mut line = match serde_json::to_vec(&log) {
+            Ok(line) => line,
+            Err(e) => {
+                metrics().record_audit_sink_failure();
+                tracing::error!("Failed to serialize audit log: {e}");
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        let mut file = self.file.lock().await;
+        if let Err(e) = async {
+            file.write_all(&line).await?;
+            file.flush().await
+        }
+        .await
+        {
+            metrics().record_audit_sink_failure();
+            tracing::error!("Failed to write audit log: {e}");
+        }
+    }
+}
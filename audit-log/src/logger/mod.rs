@@ -1 +1,4 @@
+pub mod fanout;
+pub mod file;
+pub mod queued;
 pub mod tracing;
@@ -0,0 +1,86 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{AuditLog, AuditLogger};
+use async_trait::async_trait;
+use telemetry::metrics::metrics;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+/// Writes audit logs to the inner logger on a background task.
+#[derive(Debug, Clone)]
+pub struct QueuedAuditLogger {
+    sender: mpsc::Sender<AuditLog>,
+}
+
+/// The background task of a [`QueuedAuditLogger`], to be closed on shutdown
+/// so that no queued audit log is lost.
+#[derive(Debug)]
+pub struct AuditLogWriter {
+    close: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl QueuedAuditLogger {
+    pub fn new<L>(inner: L, capacity: usize) -> (Self, AuditLogWriter)
+    where
+        L: 'static + AuditLogger,
+    {
+        let (sender, mut receiver) = mpsc::channel::<AuditLog>(capacity);
+        let (close, mut closed) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    log = receiver.recv() => match log {
+                        Some(log) => {
+                            inner.log(log).await;
+                            metrics().audit_log_dequeued();
+                        }
+                        None => return,
+                    },
+                    Ok(()) = &mut closed => break,
+                }
+            }
+            receiver.close();
+            while let Some(log) = receiver.recv().await {
+                inner.log(log).await;
+                metrics().audit_log_dequeued();
+            }
+        });
+        (Self { sender }, AuditLogWriter { close, task })
+    }
+}
+
+impl AuditLogWriter {
+    /// Stops accepting audit logs and waits until the queued ones are written.
+    pub async fn close(self) {
+        let _ = self.close.send(());
+        if let Err(e) = self.task.await {
+            tracing::error!("Audit log writer failed: {e}");
+        }
+    }
+}
+
+#[async_trait]
+impl AuditLogger for QueuedAuditLogger {
+    async fn log(&self, log: AuditLog) {
+        metrics().audit_log_enqueued();
+        if let Err(e) = self.sender.send(log).await {
+            metrics().audit_log_dequeued();
+            metrics().record_audit_sink_failure();
+            tracing::error!("Failed to enqueue audit log: {:?}", e.0);
+        }
+    }
+}
@@ -18,7 +18,6 @@ use aes_siv::aead::generic_array::typenum::Unsigned;
 use aes_siv::aead::{Aead, OsRng};
 use aes_siv::{AeadCore, Aes256SivAead, Key, KeyInit, KeySizeUser};
 use async_trait::async_trait;
-use chacha20poly1305::ChaCha20Poly1305;
 
 #[derive(Clone)]
 pub struct AesGcmSivCipher {
@@ -26,6 +25,8 @@ pub struct AesGcmSivCipher {
 }
 
 impl AesGcmSivCipher {
+    pub const KEY_SIZE: usize = <Aes256SivAead as KeySizeUser>::KeySize::USIZE;
+
     pub fn new(key: Key<Aes256SivAead>) -> Self {
         Self { key }
     }
@@ -41,7 +42,7 @@ impl TryFrom<&[u8]> for AesGcmSivCipher {
     type Error = Error;
 
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        if value.len() != <ChaCha20Poly1305 as KeySizeUser>::KeySize::USIZE {
+        if value.len() != Self::KEY_SIZE {
             Err(Error::InvalidKeyLength)
         } else {
             Ok(Self::new(Key::<Aes256SivAead>::clone_from_slice(value)))
@@ -102,4 +103,13 @@ mod tests {
     }
 
     predefined_tests!(create_sut);
+
+    #[test]
+    fn test_try_from_key_length() {
+        assert!(AesGcmSivCipher::try_from(&[0u8; 64][..]).is_ok());
+        assert!(matches!(
+            AesGcmSivCipher::try_from(&[0u8; 32][..]),
+            Err(Error::InvalidKeyLength)
+        ));
+    }
 }
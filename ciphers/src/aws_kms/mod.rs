@@ -0,0 +1,407 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+//! A [`Cipher`] that wraps data with AWS KMS, or a service compatible with its API.
+
+mod sigv4;
+
+use crate::Cipher;
+use crate::error::Error;
+use crate::http::{self, Failure};
+use async_trait::async_trait;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use chrono::Utc;
+use reqwest::{Certificate, Client, Url};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::debug;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const SERVICE: &str = "kms";
+
+#[derive(Debug, Clone)]
+pub struct AwsKmsConfig {
+    pub region: String,
+    /// Endpoint of KMS. `https://kms.{region}.amazonaws.com` is used if omitted.
+    pub endpoint: Option<String>,
+    /// Key ID, key ARN, alias name or alias ARN of the KMS key.
+    pub key_id: String,
+    /// Encryption context bound to ciphertexts as additional authenticated data.
+    pub encryption_context: BTreeMap<String, String>,
+    pub credentials: AwsCredentials,
+    /// PEM encoded CA certificate to verify the endpoint with, in addition to the system roots.
+    pub ca_certificate: Option<Vec<u8>>,
+    /// Number of retries of requests failed by a connection error, throttling or 5xx.
+    pub retries: u32,
+}
+
+#[derive(Clone)]
+pub struct AwsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl Debug for AwsCredentials {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AwsCredentials")
+            .field("access_key_id", &self.access_key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Encrypts data with the `Encrypt` action of KMS.
+///
+/// Ciphertexts are the ciphertext blobs returned by KMS.
+#[derive(Clone)]
+pub struct AwsKmsCipher {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    client: Client,
+    url: Url,
+    config: AwsKmsConfig,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct EncryptRequest<'a> {
+    key_id: &'a str,
+    plaintext: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    encryption_context: &'a BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct EncryptResponse {
+    ciphertext_blob: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct DecryptRequest<'a> {
+    key_id: &'a str,
+    ciphertext_blob: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    encryption_context: &'a BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DecryptResponse {
+    plaintext: String,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    #[serde(rename = "__type", default)]
+    kind: String,
+    #[serde(alias = "Message", default)]
+    message: String,
+}
+
+impl AwsKmsCipher {
+    pub fn new(config: AwsKmsConfig) -> Result<Self, Error> {
+        let endpoint = match &config.endpoint {
+            Some(endpoint) => endpoint.clone(),
+            None => format!("https://kms.{}.amazonaws.com/", config.region),
+        };
+        let url = Url::parse(&endpoint).map_err(|e| Error::AwsKms(format!("{endpoint}: {e}")))?;
+
+        let mut builder = Client::builder().timeout(REQUEST_TIMEOUT);
+        if let Some(pem) = &config.ca_certificate {
+            let certificate =
+                Certificate::from_pem(pem).map_err(|e| Error::AwsKms(e.to_string()))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+        let client = builder.build().map_err(|e| Error::AwsKms(e.to_string()))?;
+        debug!("Using AWS KMS key {} at {url}", config.key_id);
+        Ok(Self {
+            inner: Arc::new(Inner {
+                client,
+                url,
+                config,
+            }),
+        })
+    }
+
+    async fn call<Req, Res>(&self, action: &str, request: &Req) -> Result<Res, Error>
+    where
+        Req: Serialize,
+        Res: DeserializeOwned,
+    {
+        let Inner {
+            client,
+            url,
+            config,
+        } = self.inner.as_ref();
+        let payload = serde_json::to_vec(request).map_err(|e| Error::AwsKms(e.to_string()))?;
+        let target = format!("TrentService.{action}");
+        let headers = [
+            ("content-type", "application/x-amz-json-1.1"),
+            ("x-amz-target", target.as_str()),
+        ];
+        // Signed for each attempt, since signatures expire.
+        let build = || {
+            let signature = sigv4::sign(
+                &config.credentials,
+                &config.region,
+                SERVICE,
+                url,
+                &headers,
+                &payload,
+                Utc::now(),
+            );
+            let mut request = client.post(url.clone()).body(payload.clone());
+            for (name, value) in headers.iter().copied() {
+                request = request.header(name, value);
+            }
+            for (name, value) in signature {
+                request = request.header(name, value);
+            }
+            request
+        };
+        http::send(
+            "AWS KMS",
+            config.retries,
+            build,
+            |response| match serde_json::from_str::<ErrorBody>(response.body) {
+                Ok(body) => (
+                    format!("{}: {}", body.kind, body.message),
+                    response.is_transient() || body.kind.ends_with("ThrottlingException"),
+                ),
+                Err(_) => (String::new(), response.is_transient()),
+            },
+        )
+        .await
+        .map_err(|Failure { message, .. }| Error::AwsKms(message))
+    }
+}
+
+#[async_trait]
+impl Cipher for AwsKmsCipher {
+    fn name(&self) -> &'static str {
+        "AWS KMS"
+    }
+
+    fn key(&self) -> &[u8] {
+        // The key never leaves KMS.
+        &[]
+    }
+
+    async fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let config = &self.inner.config;
+        let response: EncryptResponse = self
+            .call(
+                "Encrypt",
+                &EncryptRequest {
+                    key_id: &config.key_id,
+                    plaintext: BASE64_STANDARD.encode(data),
+                    encryption_context: &config.encryption_context,
+                },
+            )
+            .await?;
+        BASE64_STANDARD
+            .decode(response.ciphertext_blob)
+            .map_err(|e| Error::AwsKms(format!("ciphertext is not valid base64: {e}")))
+    }
+
+    async fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let config = &self.inner.config;
+        let response: DecryptResponse = self
+            .call(
+                "Decrypt",
+                &DecryptRequest {
+                    key_id: &config.key_id,
+                    ciphertext_blob: BASE64_STANDARD.encode(data),
+                    encryption_context: &config.encryption_context,
+                },
+            )
+            .await?;
+        BASE64_STANDARD
+            .decode(response.plaintext)
+            .map_err(|e| Error::AwsKms(format!("plaintext is not valid base64: {e}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::{HttpStandIn, Request};
+    use chrono::NaiveDateTime;
+    use serde_json::{Value, json};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    const KEY_ID: &str = "alias/kagimori";
+
+    fn credentials() -> AwsCredentials {
+        AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "secret".to_string(),
+            session_token: Some("session".to_string()),
+        }
+    }
+
+    /// Stands in for KMS, checking signatures. Ciphertext blobs record the key ID, the
+    /// encryption context and the plaintext.
+    async fn stand_in(throttled: u32) -> HttpStandIn {
+        let throttled = AtomicU32::new(throttled);
+        let address = Arc::new(std::sync::OnceLock::<Url>::new());
+        let url = address.clone();
+        let kms = HttpStandIn::start(move |request: &Request| {
+            let header = |name: &str| request.headers[name].as_str();
+            let time = NaiveDateTime::parse_from_str(header("x-amz-date"), "%Y%m%dT%H%M%SZ")
+                .unwrap()
+                .and_utc();
+            let signature = sigv4::sign(
+                &credentials(),
+                "us-east-1",
+                SERVICE,
+                url.get().unwrap(),
+                &[
+                    ("content-type", header("content-type")),
+                    ("x-amz-target", header("x-amz-target")),
+                ],
+                request.body.as_bytes(),
+                time,
+            );
+            if signature.last().unwrap().1 != header("authorization") {
+                let body = json!({"__type": "InvalidSignatureException", "message": "bad signature"});
+                return (400, body.to_string());
+            }
+            if throttled
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                let body = json!({"__type": "ThrottlingException", "message": "Rate exceeded"});
+                return (400, body.to_string());
+            }
+
+            let body: Value = serde_json::from_str(&request.body).unwrap();
+            let context = body.get("EncryptionContext").cloned().unwrap_or(json!({}));
+            match header("x-amz-target") {
+                "TrentService.Encrypt" => {
+                    let blob = json!({"key": body["KeyId"], "context": context, "plaintext": body["Plaintext"]});
+                    let blob = BASE64_STANDARD.encode(blob.to_string());
+                    (200, json!({"CiphertextBlob": blob, "KeyId": KEY_ID}).to_string())
+                }
+                "TrentService.Decrypt" => {
+                    let blob = body["CiphertextBlob"].as_str().unwrap();
+                    let blob: Value = BASE64_STANDARD
+                        .decode(blob)
+                        .ok()
+                        .and_then(|blob| serde_json::from_slice(&blob).ok())
+                        .unwrap_or_default();
+                    if blob["key"] != body["KeyId"] || blob["context"] != context {
+                        let body = json!({"__type": "InvalidCiphertextException", "message": ""});
+                        return (400, body.to_string());
+                    }
+                    (200, json!({"Plaintext": blob["plaintext"], "KeyId": KEY_ID}).to_string())
+                }
+                _ => (400, json!({"__type": "UnknownOperationException"}).to_string()),
+            }
+        })
+        .await;
+        address.set(Url::parse(&kms.address).unwrap()).unwrap();
+        kms
+    }
+
+    fn config(endpoint: &str) -> AwsKmsConfig {
+        AwsKmsConfig {
+            region: "us-east-1".to_string(),
+            endpoint: Some(endpoint.to_string()),
+            key_id: KEY_ID.to_string(),
+            encryption_context: BTreeMap::from([("cluster".to_string(), "test".to_string())]),
+            credentials: credentials(),
+            ca_certificate: None,
+            retries: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_decrypt() {
+        let kms = stand_in(0).await;
+        let sut = AwsKmsCipher::new(config(&kms.address)).unwrap();
+        let ciphertext = sut.encrypt(b"test data").await.unwrap();
+        assert_eq!(sut.decrypt(&ciphertext).await.unwrap(), b"test data");
+
+        let request = kms.requests.lock().unwrap()[0].clone();
+        assert_eq!(request.headers["x-amz-security-token"], "session");
+        assert!(
+            request.headers["authorization"]
+                .starts_with("AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/")
+        );
+
+        // ciphertexts are bound to the encryption context
+        let mut other = config(&kms.address);
+        other.encryption_context.clear();
+        let sut = AwsKmsCipher::new(other).unwrap();
+        assert!(matches!(
+            sut.decrypt(&ciphertext).await,
+            Err(Error::AwsKms(e)) if e.ends_with("InvalidCiphertextException: ")
+        ));
+
+        let mut other = config(&kms.address);
+        other.credentials.secret_access_key = "wrong".to_string();
+        let sut = AwsKmsCipher::new(other).unwrap();
+        assert!(sut.encrypt(b"test data").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retries() {
+        let kms = stand_in(2).await;
+        let sut = AwsKmsCipher::new(AwsKmsConfig {
+            retries: 2,
+            ..config(&kms.address)
+        })
+        .unwrap();
+        sut.encrypt(b"test data").await.unwrap();
+        assert_eq!(kms.paths().len(), 3);
+
+        let kms = stand_in(1).await;
+        let sut = AwsKmsCipher::new(config(&kms.address)).unwrap();
+        assert!(matches!(
+            sut.encrypt(b"test data").await,
+            Err(Error::AwsKms(e)) if e.ends_with("ThrottlingException: Rate exceeded")
+        ));
+    }
+
+    /// Runs against local-kms (https://github.com/nsmithuk/local-kms) when
+    /// `KAGIMORI_TEST_LOCAL_KMS_ENDPOINT` and `KAGIMORI_TEST_LOCAL_KMS_KEY_ID` are set.
+    #[tokio::test]
+    async fn test_local_kms() {
+        let (Ok(endpoint), Ok(key_id)) = (
+            std::env::var("KAGIMORI_TEST_LOCAL_KMS_ENDPOINT"),
+            std::env::var("KAGIMORI_TEST_LOCAL_KMS_KEY_ID"),
+        ) else {
+            return;
+        };
+        let sut = AwsKmsCipher::new(AwsKmsConfig {
+            key_id,
+            region: "eu-west-2".to_string(),
+            ..config(&endpoint)
+        })
+        .unwrap();
+        let ciphertext = sut.encrypt(b"test data").await.unwrap();
+        assert_eq!(sut.decrypt(&ciphertext).await.unwrap(), b"test data");
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+//! AWS Signature Version 4 of POST requests without a query string.
+
+use super::AwsCredentials;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+
+/// Returns the headers to add to a POST request to `url` with `headers` and `payload`
+/// to sign it, namely `X-Amz-Date`, `X-Amz-Security-Token` and `Authorization`.
+/// `headers` are signed as well, and `Host` is derived from `url`.
+pub(crate) fn sign(
+    credentials: &AwsCredentials,
+    region: &str,
+    service: &str,
+    url: &Url,
+    headers: &[(&str, &str)],
+    payload: &[u8],
+    time: DateTime<Utc>,
+) -> Vec<(String, String)> {
+    let amz_date = time.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = time.format("%Y%m%d").to_string();
+    let mut added = vec![("x-amz-date".to_string(), amz_date.clone())];
+    if let Some(token) = &credentials.session_token {
+        added.push(("x-amz-security-token".to_string(), token.clone()));
+    }
+
+    let host = match url.port() {
+        Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+    let mut signed: Vec<(String, String)> = headers
+        .iter()
+        .map(|(name, value)| (name.to_lowercase(), value.trim().to_string()))
+        .chain([("host".to_string(), host)])
+        .chain(added.iter().cloned())
+        .collect();
+    signed.sort();
+    let canonical_headers: String = signed
+        .iter()
+        .map(|(name, value)| format!("{name}:{value}\n"))
+        .collect();
+    let signed_headers = signed
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "POST\n{}\n\n{canonical_headers}\n{signed_headers}\n{}",
+        url.path(),
+        hex(&Sha256::digest(payload))
+    );
+
+    let scope = format!("{date}/{region}/{service}/aws4_request");
+    let string_to_sign = format!(
+        "{ALGORITHM}\n{amz_date}\n{scope}\n{}",
+        hex(&Sha256::digest(canonical_request))
+    );
+    let key = [date.as_str(), region, service, "aws4_request"]
+        .iter()
+        .fold(
+            format!("AWS4{}", credentials.secret_access_key).into_bytes(),
+            |key, data| hmac(&key, data.as_bytes()),
+        );
+    let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+
+    added.push((
+        "authorization".to_string(),
+        format!(
+            "{ALGORITHM} Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+            credentials.access_key_id
+        ),
+    ));
+    added
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        // post-vanilla of the AWS Signature Version 4 test suite
+        let credentials = AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        };
+        let headers = sign(
+            &credentials,
+            "us-east-1",
+            "service",
+            &Url::parse("https://example.amazonaws.com/").unwrap(),
+            &[],
+            b"",
+            "2015-08-30T12:36:00Z".parse().unwrap(),
+        );
+        assert_eq!(
+            headers,
+            [
+                ("x-amz-date".to_string(), "20150830T123600Z".to_string()),
+                (
+                    "authorization".to_string(),
+                    "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, SignedHeaders=host;x-amz-date, Signature=5da7c1a2acd57cee7505fc6676e4e544621c30862966e37dddb68e92efbe5d6b".to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -28,6 +28,8 @@ pub struct ChaCha20Poly1305Cipher {
 type ChaCha20Poly1305Nonce = Nonce<ChaCha20Poly1305>;
 
 impl ChaCha20Poly1305Cipher {
+    pub const KEY_SIZE: usize = <ChaCha20Poly1305 as KeySizeUser>::KeySize::USIZE;
+
     pub fn new(key: Key) -> Self {
         Self { key }
     }
@@ -43,7 +45,7 @@ impl TryFrom<&[u8]> for ChaCha20Poly1305Cipher {
     type Error = Error;
 
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        if value.len() != <ChaCha20Poly1305 as KeySizeUser>::KeySize::USIZE {
+        if value.len() != Self::KEY_SIZE {
             Err(Error::InvalidKeyLength)
         } else {
             Ok(Self::new(Key::clone_from_slice(value)))
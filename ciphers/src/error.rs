@@ -13,6 +13,7 @@
 // You should have received a copy of the GNU General Public License along with Kagimori.
 // If not, see <https://www.gnu.org/licenses/>.
 
+use crate::rotatable::KeyState;
 pub use uuid::Uuid;
 
 #[derive(Debug)]
@@ -22,4 +23,13 @@ pub enum Error {
     InvalidKeyLength,
     InvalidKeyId,
     KeyNotFound(Uuid),
+    /// A named key is reserved, lacks its primary version or shares a version.
+    InvalidKeyName(String),
+    /// A key cannot be in the state, such as a primary version which is not enabled.
+    InvalidKeyState(Uuid, KeyState),
+    InvalidShares(&'static str),
+    Pkcs11(String),
+    Vault(String),
+    AwsKms(String),
+    Stream(&'static str),
 }
@@ -0,0 +1,121 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+//! Requests to key providers behind an HTTP API.
+
+use reqwest::{RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+use std::time::Duration;
+use tracing::warn;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+pub(crate) struct Failure {
+    pub status: Option<StatusCode>,
+    pub message: String,
+}
+
+/// A response other than 2xx.
+pub(crate) struct ErrorResponse<'a> {
+    pub status: StatusCode,
+    pub body: &'a str,
+}
+
+impl ErrorResponse<'_> {
+    /// Whether the request may succeed if sent again.
+    pub fn is_transient(&self) -> bool {
+        self.status.is_server_error() || self.status == StatusCode::TOO_MANY_REQUESTS
+    }
+}
+
+/// Sends a request built by `build` until it succeeds, retrying up to `retries` times with
+/// exponential backoff on connection errors and on error responses `describe` marks as
+/// retryable. `describe` returns the error message of an error response and whether it is
+/// retryable.
+pub(crate) async fn send<Res, B, D>(
+    provider: &str,
+    retries: u32,
+    mut build: B,
+    describe: D,
+) -> Result<Res, Failure>
+where
+    Res: DeserializeOwned,
+    B: FnMut() -> RequestBuilder,
+    D: Fn(&ErrorResponse) -> (String, bool),
+{
+    let mut attempt = 0;
+    loop {
+        let failure = match build().send().await {
+            Ok(response) if response.status().is_success() => {
+                let url = response.url().clone();
+                return response.json().await.map_err(|e| Failure {
+                    status: None,
+                    message: format!("{url}: {e}"),
+                });
+            }
+            Ok(response) => {
+                let (status, url) = (response.status(), response.url().clone());
+                let body = response.text().await.unwrap_or_default();
+                let (message, retryable) = describe(&ErrorResponse {
+                    status,
+                    body: &body,
+                });
+                let failure = Failure {
+                    status: Some(status),
+                    message: format!("{url}: {status}: {message}"),
+                };
+                if !retryable {
+                    return Err(failure);
+                }
+                failure
+            }
+            Err(e) => Failure {
+                status: None,
+                message: e.to_string(),
+            },
+        };
+        if attempt >= retries {
+            return Err(failure);
+        }
+        let backoff = backoff(attempt);
+        warn!(
+            "{provider} request failed, retrying in {backoff:?}: {}",
+            failure.message
+        );
+        tokio::time::sleep(backoff).await;
+        attempt += 1;
+    }
+}
+
+/// Delay before the retry following `attempt`, doubling from [`INITIAL_BACKOFF`] up to [`MAX_BACKOFF`].
+fn backoff(attempt: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(0), Duration::from_millis(100));
+        assert_eq!(backoff(3), Duration::from_millis(800));
+        assert_eq!(backoff(7), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+}
@@ -15,12 +15,18 @@
 
 pub mod chacha20poly1305;
 mod error;
+mod http;
 
 pub mod aesgcmsiv;
+pub mod aws_kms;
 pub mod oneof;
+pub mod pkcs11;
 pub mod rotatable;
+pub mod shamir;
+pub mod stream;
 #[cfg(test)]
 mod test;
+pub mod vault;
 
 pub use crate::error::Error;
 use async_trait::async_trait;
@@ -14,7 +14,10 @@
 // If not, see <https://www.gnu.org/licenses/>.
 
 use crate::aesgcmsiv::AesGcmSivCipher;
+use crate::aws_kms::AwsKmsCipher;
 use crate::chacha20poly1305::ChaCha20Poly1305Cipher;
+use crate::pkcs11::Pkcs11Cipher;
+use crate::vault::VaultTransitCipher;
 use crate::{Cipher, Error, Unencrypted};
 use async_trait::async_trait;
 
@@ -23,6 +26,9 @@ pub enum OneOfCipher {
     Unencrypted(Unencrypted),
     AesGcmSiv(AesGcmSivCipher),
     ChaCha20Poly1305(ChaCha20Poly1305Cipher),
+    Pkcs11(Pkcs11Cipher),
+    VaultTransit(VaultTransitCipher),
+    AwsKms(AwsKmsCipher),
 }
 
 #[async_trait]
@@ -32,6 +38,9 @@ impl Cipher for OneOfCipher {
             OneOfCipher::Unencrypted(c) => c.name(),
             OneOfCipher::AesGcmSiv(c) => c.name(),
             OneOfCipher::ChaCha20Poly1305(c) => c.name(),
+            OneOfCipher::Pkcs11(c) => c.name(),
+            OneOfCipher::VaultTransit(c) => c.name(),
+            OneOfCipher::AwsKms(c) => c.name(),
         }
     }
 
@@ -40,6 +49,9 @@ impl Cipher for OneOfCipher {
             OneOfCipher::Unencrypted(c) => c.key(),
             OneOfCipher::AesGcmSiv(c) => c.key(),
             OneOfCipher::ChaCha20Poly1305(c) => c.key(),
+            OneOfCipher::Pkcs11(c) => c.key(),
+            OneOfCipher::VaultTransit(c) => c.key(),
+            OneOfCipher::AwsKms(c) => c.key(),
         }
     }
 
@@ -48,6 +60,9 @@ impl Cipher for OneOfCipher {
             OneOfCipher::Unencrypted(c) => c.encrypt(data).await,
             OneOfCipher::AesGcmSiv(c) => c.encrypt(data).await,
             OneOfCipher::ChaCha20Poly1305(c) => c.encrypt(data).await,
+            OneOfCipher::Pkcs11(c) => c.encrypt(data).await,
+            OneOfCipher::VaultTransit(c) => c.encrypt(data).await,
+            OneOfCipher::AwsKms(c) => c.encrypt(data).await,
         }
     }
 
@@ -56,6 +71,9 @@ impl Cipher for OneOfCipher {
             OneOfCipher::Unencrypted(c) => c.decrypt(data).await,
             OneOfCipher::AesGcmSiv(c) => c.decrypt(data).await,
             OneOfCipher::ChaCha20Poly1305(c) => c.decrypt(data).await,
+            OneOfCipher::Pkcs11(c) => c.decrypt(data).await,
+            OneOfCipher::VaultTransit(c) => c.decrypt(data).await,
+            OneOfCipher::AwsKms(c) => c.decrypt(data).await,
         }
     }
 }
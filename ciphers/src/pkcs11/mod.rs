@@ -0,0 +1,576 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+//! A [`Cipher`] backed by an AES key on a PKCS#11 token, so that the key never leaves the HSM.
+
+mod sys;
+
+use crate::Cipher;
+use crate::error::Error;
+use async_trait::async_trait;
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::rand_core::RngCore;
+use libloading::Library;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::ptr::null_mut;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use sys::*;
+use tracing::debug;
+
+const IV_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+/// Calls a function of the module's function list and returns its return value.
+macro_rules! call {
+    ($module:expr, $function:ident($($arg:expr),* $(,)?)) => {{
+        let function = $module.functions().$function.ok_or_else(|| {
+            Error::Pkcs11(concat!(stringify!($function), " is not supported").to_string())
+        })?;
+        unsafe { function($($arg),*) }
+    }};
+}
+
+/// Calls a function of the module's function list and checks its return value.
+macro_rules! ck {
+    ($module:expr, $function:ident($($arg:expr),* $(,)?)) => {
+        check(call!($module, $function($($arg),*)), stringify!($function))
+    };
+}
+
+fn check(rv: CK_RV, function: &str) -> Result<(), Error> {
+    if rv == CKR_OK {
+        Ok(())
+    } else {
+        Err(failed(rv, function))
+    }
+}
+
+fn failed(rv: CK_RV, function: &str) -> Error {
+    Error::Pkcs11(format!("{function} failed: {} ({rv:#x})", rv_name(rv)))
+}
+
+/// Location of an AES key on a PKCS#11 token.
+#[derive(Debug, Clone)]
+pub struct Pkcs11Key {
+    /// Path to the PKCS#11 module (shared library).
+    pub module: PathBuf,
+    /// Slot ID of the token. The first slot with a matching token is used if omitted.
+    pub slot: Option<u64>,
+    /// Label of the token.
+    pub token_label: Option<String>,
+    /// Label (`CKA_LABEL`) of the AES key.
+    pub key_label: String,
+}
+
+/// Encrypts data with `CKM_AES_GCM` on a PKCS#11 token.
+///
+/// Ciphertexts are the 12 bytes IV followed by the encrypted data and the 16 bytes tag.
+#[derive(Clone)]
+pub struct Pkcs11Cipher {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    slot: CK_SLOT_ID,
+    key: CK_OBJECT_HANDLE,
+    // Keeps the token logged in, which lasts as long as a session is open.
+    login_session: Session,
+}
+
+impl Pkcs11Cipher {
+    /// Loads the module, logs in to the token with `pin` if given and looks up the key.
+    pub fn open(key: &Pkcs11Key, pin: Option<&str>) -> Result<Self, Error> {
+        let module = Module::open(&key.module)?;
+        let slot = module.find_slot(key.slot, key.token_label.as_deref())?;
+        let session = Session::open(module, slot)?;
+        if let Some(pin) = pin {
+            session.login(pin)?;
+        }
+        let handle = session.find_key(&key.key_label)?;
+        debug!(
+            "Using PKCS#11 key {} on slot {slot} of {}",
+            key.key_label,
+            key.module.display()
+        );
+        Ok(Self {
+            inner: Arc::new(Inner {
+                slot,
+                key: handle,
+                login_session: session,
+            }),
+        })
+    }
+
+    fn session(&self) -> Result<Session, Error> {
+        Session::open(self.inner.login_session.module.clone(), self.inner.slot)
+    }
+
+    fn encrypt_blocking(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut iv = [0u8; IV_SIZE];
+        OsRng.fill_bytes(&mut iv);
+
+        let session = self.session()?;
+        let mut params = gcm_params(&mut iv);
+        let mut mechanism = gcm_mechanism(&mut params);
+        ck!(
+            session.module,
+            C_EncryptInit(session.handle, &mut mechanism, self.inner.key)
+        )?;
+
+        let mut encrypted = vec![0u8; IV_SIZE + data.len() + TAG_SIZE];
+        encrypted[..IV_SIZE].copy_from_slice(&iv);
+        let mut len = (data.len() + TAG_SIZE) as CK_ULONG;
+        ck!(
+            session.module,
+            C_Encrypt(
+                session.handle,
+                data.as_ptr() as *mut _,
+                data.len() as CK_ULONG,
+                encrypted[IV_SIZE..].as_mut_ptr(),
+                &mut len,
+            )
+        )?;
+        encrypted.truncate(IV_SIZE + len as usize);
+        Ok(encrypted)
+    }
+
+    fn decrypt_blocking(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        if data.len() < IV_SIZE + TAG_SIZE {
+            return Err(Error::Pkcs11("ciphertext is too short".to_string()));
+        }
+        let (iv, ciphertext) = data.split_at(IV_SIZE);
+        let mut iv: [u8; IV_SIZE] = iv.try_into().unwrap();
+
+        let session = self.session()?;
+        let mut params = gcm_params(&mut iv);
+        let mut mechanism = gcm_mechanism(&mut params);
+        ck!(
+            session.module,
+            C_DecryptInit(session.handle, &mut mechanism, self.inner.key)
+        )?;
+
+        let mut decrypted = vec![0u8; ciphertext.len()];
+        let mut len = decrypted.len() as CK_ULONG;
+        ck!(
+            session.module,
+            C_Decrypt(
+                session.handle,
+                ciphertext.as_ptr() as *mut _,
+                ciphertext.len() as CK_ULONG,
+                decrypted.as_mut_ptr(),
+                &mut len,
+            )
+        )?;
+        decrypted.truncate(len as usize);
+        Ok(decrypted)
+    }
+}
+
+#[async_trait]
+impl Cipher for Pkcs11Cipher {
+    fn name(&self) -> &'static str {
+        "PKCS#11 AES-GCM"
+    }
+
+    fn key(&self) -> &[u8] {
+        // The key cannot be extracted from the token.
+        &[]
+    }
+
+    async fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let (cipher, data) = (self.clone(), data.to_vec());
+        blocking(move || cipher.encrypt_blocking(&data)).await
+    }
+
+    async fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let (cipher, data) = (self.clone(), data.to_vec());
+        blocking(move || cipher.decrypt_blocking(&data)).await
+    }
+}
+
+/// Runs `f` on a blocking thread, as PKCS#11 calls may wait for the token.
+async fn blocking<F>(f: F) -> Result<Vec<u8>, Error>
+where
+    F: FnOnce() -> Result<Vec<u8>, Error> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Error::Pkcs11(e.to_string()))?
+}
+
+fn gcm_params(iv: &mut [u8; IV_SIZE]) -> CK_GCM_PARAMS {
+    CK_GCM_PARAMS {
+        pIv: iv.as_mut_ptr(),
+        ulIvLen: IV_SIZE as CK_ULONG,
+        ulIvBits: (IV_SIZE * 8) as CK_ULONG,
+        pAAD: null_mut(),
+        ulAADLen: 0,
+        ulTagBits: (TAG_SIZE * 8) as CK_ULONG,
+    }
+}
+
+fn gcm_mechanism(params: &mut CK_GCM_PARAMS) -> CK_MECHANISM {
+    CK_MECHANISM {
+        mechanism: CKM_AES_GCM,
+        pParameter: params as *mut CK_GCM_PARAMS as *mut _,
+        ulParameterLen: size_of::<CK_GCM_PARAMS>() as CK_ULONG,
+    }
+}
+
+/// A loaded and initialized PKCS#11 module.
+///
+/// A module can only be initialized once per process, so modules are shared by path
+/// and finalized when the last key using them is dropped.
+struct Module {
+    functions: *const CK_FUNCTION_LIST,
+    finalize: bool,
+    _library: Library,
+}
+
+// Modules are initialized with CKF_OS_LOCKING_OK, so they may be called from any thread.
+unsafe impl Send for Module {}
+unsafe impl Sync for Module {}
+
+impl Module {
+    fn open(path: &Path) -> Result<Arc<Self>, Error> {
+        static MODULES: OnceLock<Mutex<HashMap<PathBuf, Weak<Module>>>> = OnceLock::new();
+        let mut modules = MODULES.get_or_init(Default::default).lock().unwrap();
+        if let Some(module) = modules.get(path).and_then(Weak::upgrade) {
+            return Ok(module);
+        }
+
+        let error = |e: String| Error::Pkcs11(format!("{}: {e}", path.display()));
+        let library = unsafe { Library::new(path) }.map_err(|e| error(e.to_string()))?;
+        let mut functions = null_mut();
+        unsafe {
+            let get_function_list = library
+                .get::<C_GetFunctionList>(b"C_GetFunctionList")
+                .map_err(|e| error(e.to_string()))?;
+            check(get_function_list(&mut functions), "C_GetFunctionList")?;
+        }
+        if functions.is_null() {
+            return Err(error("C_GetFunctionList returned no functions".to_string()));
+        }
+
+        let mut module = Self {
+            functions,
+            finalize: false,
+            _library: library,
+        };
+        let mut args = CK_C_INITIALIZE_ARGS {
+            CreateMutex: null_mut(),
+            DestroyMutex: null_mut(),
+            LockMutex: null_mut(),
+            UnlockMutex: null_mut(),
+            flags: CKF_OS_LOCKING_OK,
+            pReserved: null_mut(),
+        };
+        // Another library in this process may have initialized the module already.
+        module.finalize = match call!(module, C_Initialize(&mut args as *mut _ as *mut _)) {
+            CKR_OK => true,
+            CKR_CRYPTOKI_ALREADY_INITIALIZED => false,
+            rv => return Err(failed(rv, "C_Initialize")),
+        };
+
+        let module = Arc::new(module);
+        modules.insert(path.to_path_buf(), Arc::downgrade(&module));
+        Ok(module)
+    }
+
+    fn functions(&self) -> &CK_FUNCTION_LIST {
+        unsafe { &*self.functions }
+    }
+
+    fn find_slot(&self, slot: Option<u64>, token_label: Option<&str>) -> Result<CK_SLOT_ID, Error> {
+        let mut count = 0;
+        ck!(self, C_GetSlotList(CK_TRUE, null_mut(), &mut count))?;
+        let mut slots = vec![0; count as usize];
+        ck!(self, C_GetSlotList(CK_TRUE, slots.as_mut_ptr(), &mut count))?;
+        slots.truncate(count as usize);
+
+        for id in slots {
+            // CK_ULONG is 32 bits on Windows.
+            #[allow(clippy::unnecessary_cast)]
+            if slot.is_some_and(|slot| slot != id as u64) {
+                continue;
+            }
+            if let Some(token_label) = token_label {
+                let mut info = unsafe { std::mem::zeroed::<CK_TOKEN_INFO>() };
+                ck!(self, C_GetTokenInfo(id, &mut info))?;
+                // Labels are padded with spaces.
+                if String::from_utf8_lossy(&info.label).trim_end() != token_label {
+                    continue;
+                }
+            }
+            return Ok(id);
+        }
+        Err(Error::Pkcs11(match (slot, token_label) {
+            (Some(slot), Some(label)) => format!("token {label} is not found in slot {slot}"),
+            (Some(slot), None) => format!("no token is present in slot {slot}"),
+            (None, Some(label)) => format!("token {label} is not found"),
+            (None, None) => "no token is present".to_string(),
+        }))
+    }
+}
+
+impl Drop for Module {
+    fn drop(&mut self) {
+        if self.finalize
+            && let Some(finalize) = self.functions().C_Finalize
+        {
+            unsafe { finalize(null_mut()) };
+        }
+    }
+}
+
+/// A session, closed when dropped.
+struct Session {
+    module: Arc<Module>,
+    handle: CK_SESSION_HANDLE,
+}
+
+impl Session {
+    fn open(module: Arc<Module>, slot: CK_SLOT_ID) -> Result<Self, Error> {
+        let mut handle = 0;
+        ck!(
+            module,
+            C_OpenSession(
+                slot,
+                CKF_SERIAL_SESSION,
+                null_mut(),
+                null_mut(),
+                &mut handle
+            )
+        )?;
+        Ok(Self { module, handle })
+    }
+
+    fn login(&self, pin: &str) -> Result<(), Error> {
+        let rv = call!(
+            self.module,
+            C_Login(
+                self.handle,
+                CKU_USER,
+                pin.as_ptr() as *mut _,
+                pin.len() as CK_ULONG
+            )
+        );
+        // Login state is shared by all sessions of the token, e.g. with another key on it.
+        match rv {
+            CKR_OK | CKR_USER_ALREADY_LOGGED_IN => Ok(()),
+            rv => Err(failed(rv, "C_Login")),
+        }
+    }
+
+    fn find_key(&self, label: &str) -> Result<CK_OBJECT_HANDLE, Error> {
+        let class: CK_ULONG = CKO_SECRET_KEY;
+        let mut template = [
+            CK_ATTRIBUTE::new(CKA_CLASS, &class),
+            CK_ATTRIBUTE::new(CKA_LABEL, label.as_bytes()),
+        ];
+        ck!(
+            self.module,
+            C_FindObjectsInit(
+                self.handle,
+                template.as_mut_ptr(),
+                template.len() as CK_ULONG
+            )
+        )?;
+        let mut handles = [0; 2];
+        let mut count = 0;
+        let found = ck!(
+            self.module,
+            C_FindObjects(
+                self.handle,
+                handles.as_mut_ptr(),
+                handles.len() as CK_ULONG,
+                &mut count
+            )
+        );
+        ck!(self.module, C_FindObjectsFinal(self.handle))?;
+        found?;
+        match count {
+            0 => Err(Error::Pkcs11(format!("key {label} is not found"))),
+            1 => Ok(handles[0]),
+            _ => Err(Error::Pkcs11(format!("multiple keys are labelled {label}"))),
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Some(close) = self.module.functions().C_CloseSession {
+            unsafe { close(self.handle) };
+        }
+    }
+}
+
+/// Runs against SoftHSM2, so ignored by default. Run with
+/// `KAGIMORI_TEST_SOFTHSM2_MODULE` set to the path of `libsofthsm2.so`, `SOFTHSM2_CONF` set to
+/// a configuration with a writable token directory and `softhsm2-util` in `PATH`:
+///
+/// ```shell
+/// cargo test -p ciphers pkcs11 -- --ignored
+/// ```
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use std::sync::{MutexGuard, PoisonError};
+
+    const KEY_LABEL: &str = "kagimori-kek";
+    const PIN: &str = "1234";
+
+    /// A token initialized for a test and deleted when dropped.
+    struct SoftHsm2 {
+        key: Pkcs11Key,
+        // Tests run one at a time, so that the module is finalized and sees the new token.
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    impl SoftHsm2 {
+        fn new(test: &str) -> Self {
+            static LOCK: Mutex<()> = Mutex::new(());
+            let lock = LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+
+            let module = std::env::var_os("KAGIMORI_TEST_SOFTHSM2_MODULE")
+                .expect("KAGIMORI_TEST_SOFTHSM2_MODULE is not set");
+            let token_label = format!("kagimori-{test}-{}", std::process::id());
+            let status = Command::new("softhsm2-util")
+                .args(["--init-token", "--free", "--label", &token_label])
+                .args(["--pin", PIN, "--so-pin", "5678"])
+                .status()
+                .expect("softhsm2-util is not found");
+            assert!(status.success());
+
+            let softhsm2 = Self {
+                key: Pkcs11Key {
+                    module: PathBuf::from(module),
+                    slot: None,
+                    token_label: Some(token_label),
+                    key_label: KEY_LABEL.to_string(),
+                },
+                _lock: lock,
+            };
+            generate_key(&softhsm2.key, PIN).unwrap();
+            softhsm2
+        }
+    }
+
+    impl Drop for SoftHsm2 {
+        fn drop(&mut self) {
+            let _ = Command::new("softhsm2-util")
+                .args(["--delete-token", "--token"])
+                .args(self.key.token_label.as_deref())
+                .status();
+        }
+    }
+
+    fn generate_key(key: &Pkcs11Key, pin: &str) -> Result<(), Error> {
+        let module = Module::open(&key.module)?;
+        let slot = module.find_slot(None, key.token_label.as_deref())?;
+        let mut handle = 0;
+        ck!(
+            module,
+            C_OpenSession(
+                slot,
+                CKF_SERIAL_SESSION | CKF_RW_SESSION,
+                null_mut(),
+                null_mut(),
+                &mut handle
+            )
+        )?;
+        let session = Session { module, handle };
+        session.login(pin)?;
+
+        let (class, key_type, len): (CK_ULONG, CK_ULONG, CK_ULONG) = (CKO_SECRET_KEY, CKK_AES, 32);
+        let mut template = [
+            CK_ATTRIBUTE::new(CKA_CLASS, &class),
+            CK_ATTRIBUTE::new(CKA_KEY_TYPE, &key_type),
+            CK_ATTRIBUTE::new(CKA_VALUE_LEN, &len),
+            CK_ATTRIBUTE::new(CKA_LABEL, key.key_label.as_bytes()),
+            CK_ATTRIBUTE::new(CKA_TOKEN, &CK_TRUE),
+            CK_ATTRIBUTE::new(CKA_SENSITIVE, &CK_TRUE),
+            CK_ATTRIBUTE::new(CKA_EXTRACTABLE, &CK_FALSE),
+            CK_ATTRIBUTE::new(CKA_ENCRYPT, &CK_TRUE),
+            CK_ATTRIBUTE::new(CKA_DECRYPT, &CK_TRUE),
+        ];
+        let mut mechanism = CK_MECHANISM {
+            mechanism: CKM_AES_KEY_GEN,
+            pParameter: null_mut(),
+            ulParameterLen: 0,
+        };
+        let mut key = 0;
+        ck!(
+            session.module,
+            C_GenerateKey(
+                session.handle,
+                &mut mechanism,
+                template.as_mut_ptr(),
+                template.len() as CK_ULONG,
+                &mut key
+            )
+        )
+    }
+
+    #[tokio::test]
+    #[ignore = "requires SoftHSM2"]
+    async fn test_encrypt_decrypt() {
+        let softhsm2 = SoftHsm2::new("crypt");
+        let key = &softhsm2.key;
+        let sut = Pkcs11Cipher::open(key, Some(PIN)).unwrap();
+
+        let ciphertext = sut.encrypt(b"test data").await.unwrap();
+        assert_eq!(ciphertext.len(), IV_SIZE + 9 + TAG_SIZE);
+        assert_ne!(sut.encrypt(b"test data").await.unwrap(), ciphertext);
+        assert_eq!(sut.decrypt(&ciphertext).await.unwrap(), b"test data");
+
+        // another handle to the same key, sharing the module and the login
+        let other = Pkcs11Cipher::open(key, Some(PIN)).unwrap();
+        drop(sut);
+        assert_eq!(other.decrypt(&ciphertext).await.unwrap(), b"test data");
+
+        let mut tampered = ciphertext.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(other.decrypt(&tampered).await.is_err());
+        assert!(other.decrypt(&ciphertext[..IV_SIZE]).await.is_err());
+    }
+
+    #[test]
+    #[ignore = "requires SoftHSM2"]
+    fn test_open_errors() {
+        let softhsm2 = SoftHsm2::new("open");
+        let key = &softhsm2.key;
+
+        let unknown_key = Pkcs11Key {
+            key_label: "unknown".to_string(),
+            ..key.clone()
+        };
+        assert!(matches!(
+            Pkcs11Cipher::open(&unknown_key, Some(PIN)),
+            Err(Error::Pkcs11(e)) if e == "key unknown is not found"
+        ));
+
+        let unknown_token = Pkcs11Key {
+            token_label: Some("unknown".to_string()),
+            ..key.clone()
+        };
+        assert!(Pkcs11Cipher::open(&unknown_token, Some(PIN)).is_err());
+
+        // the key is private, so it cannot be found without logging in
+        assert!(Pkcs11Cipher::open(key, None).is_err());
+    }
+}
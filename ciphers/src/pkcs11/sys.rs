@@ -0,0 +1,315 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+//! The subset of the PKCS#11 v2.40 C API used by [`Pkcs11Cipher`](super::Pkcs11Cipher).
+
+#![allow(non_camel_case_types, non_snake_case, dead_code)]
+
+use std::os::raw::{c_uchar, c_ulong, c_void};
+
+pub type CK_ULONG = c_ulong;
+pub type CK_RV = CK_ULONG;
+pub type CK_FLAGS = CK_ULONG;
+pub type CK_SLOT_ID = CK_ULONG;
+pub type CK_SESSION_HANDLE = CK_ULONG;
+pub type CK_OBJECT_HANDLE = CK_ULONG;
+pub type CK_ATTRIBUTE_TYPE = CK_ULONG;
+pub type CK_MECHANISM_TYPE = CK_ULONG;
+pub type CK_BBOOL = c_uchar;
+
+pub const CK_TRUE: CK_BBOOL = 1;
+pub const CK_FALSE: CK_BBOOL = 0;
+
+pub const CKR_OK: CK_RV = 0x0;
+pub const CKR_USER_ALREADY_LOGGED_IN: CK_RV = 0x100;
+pub const CKR_CRYPTOKI_ALREADY_INITIALIZED: CK_RV = 0x191;
+
+pub const CKF_OS_LOCKING_OK: CK_FLAGS = 0x2;
+pub const CKF_RW_SESSION: CK_FLAGS = 0x2;
+pub const CKF_SERIAL_SESSION: CK_FLAGS = 0x4;
+
+pub const CKU_USER: CK_ULONG = 1;
+
+pub const CKA_CLASS: CK_ATTRIBUTE_TYPE = 0x0;
+pub const CKA_TOKEN: CK_ATTRIBUTE_TYPE = 0x1;
+pub const CKA_LABEL: CK_ATTRIBUTE_TYPE = 0x3;
+pub const CKA_KEY_TYPE: CK_ATTRIBUTE_TYPE = 0x100;
+pub const CKA_SENSITIVE: CK_ATTRIBUTE_TYPE = 0x103;
+pub const CKA_ENCRYPT: CK_ATTRIBUTE_TYPE = 0x104;
+pub const CKA_DECRYPT: CK_ATTRIBUTE_TYPE = 0x105;
+pub const CKA_VALUE_LEN: CK_ATTRIBUTE_TYPE = 0x161;
+pub const CKA_EXTRACTABLE: CK_ATTRIBUTE_TYPE = 0x162;
+
+pub const CKO_SECRET_KEY: CK_ULONG = 0x4;
+pub const CKK_AES: CK_ULONG = 0x1f;
+
+pub const CKM_AES_KEY_GEN: CK_MECHANISM_TYPE = 0x1080;
+pub const CKM_AES_GCM: CK_MECHANISM_TYPE = 0x1087;
+
+#[repr(C)]
+pub struct CK_VERSION {
+    pub major: c_uchar,
+    pub minor: c_uchar,
+}
+
+#[repr(C)]
+pub struct CK_C_INITIALIZE_ARGS {
+    pub CreateMutex: *mut c_void,
+    pub DestroyMutex: *mut c_void,
+    pub LockMutex: *mut c_void,
+    pub UnlockMutex: *mut c_void,
+    pub flags: CK_FLAGS,
+    pub pReserved: *mut c_void,
+}
+
+#[repr(C)]
+pub struct CK_TOKEN_INFO {
+    pub label: [c_uchar; 32],
+    pub manufacturerID: [c_uchar; 32],
+    pub model: [c_uchar; 16],
+    pub serialNumber: [c_uchar; 16],
+    pub flags: CK_FLAGS,
+    pub ulMaxSessionCount: CK_ULONG,
+    pub ulSessionCount: CK_ULONG,
+    pub ulMaxRwSessionCount: CK_ULONG,
+    pub ulRwSessionCount: CK_ULONG,
+    pub ulMaxPinLen: CK_ULONG,
+    pub ulMinPinLen: CK_ULONG,
+    pub ulTotalPublicMemory: CK_ULONG,
+    pub ulFreePublicMemory: CK_ULONG,
+    pub ulTotalPrivateMemory: CK_ULONG,
+    pub ulFreePrivateMemory: CK_ULONG,
+    pub hardwareVersion: CK_VERSION,
+    pub firmwareVersion: CK_VERSION,
+    pub utcTime: [c_uchar; 16],
+}
+
+#[repr(C)]
+pub struct CK_ATTRIBUTE {
+    pub type_: CK_ATTRIBUTE_TYPE,
+    pub pValue: *mut c_void,
+    pub ulValueLen: CK_ULONG,
+}
+
+impl CK_ATTRIBUTE {
+    pub fn new<T: ?Sized>(type_: CK_ATTRIBUTE_TYPE, value: &T) -> Self {
+        Self {
+            type_,
+            pValue: value as *const T as *mut c_void,
+            ulValueLen: size_of_val(value) as CK_ULONG,
+        }
+    }
+}
+
+#[repr(C)]
+pub struct CK_MECHANISM {
+    pub mechanism: CK_MECHANISM_TYPE,
+    pub pParameter: *mut c_void,
+    pub ulParameterLen: CK_ULONG,
+}
+
+#[repr(C)]
+pub struct CK_GCM_PARAMS {
+    pub pIv: *mut c_uchar,
+    pub ulIvLen: CK_ULONG,
+    pub ulIvBits: CK_ULONG,
+    pub pAAD: *mut c_uchar,
+    pub ulAADLen: CK_ULONG,
+    pub ulTagBits: CK_ULONG,
+}
+
+type Unused = Option<unsafe extern "C" fn()>;
+
+/// `CK_FUNCTION_LIST` up to `C_GenerateKey`. It is only read through the pointer
+/// returned by `C_GetFunctionList`, so the functions after it can be left out.
+#[repr(C)]
+pub struct CK_FUNCTION_LIST {
+    pub version: CK_VERSION,
+    pub C_Initialize: Option<unsafe extern "C" fn(pInitArgs: *mut c_void) -> CK_RV>,
+    pub C_Finalize: Option<unsafe extern "C" fn(pReserved: *mut c_void) -> CK_RV>,
+    pub C_GetInfo: Unused,
+    pub C_GetFunctionList: Unused,
+    pub C_GetSlotList: Option<
+        unsafe extern "C" fn(
+            tokenPresent: CK_BBOOL,
+            pSlotList: *mut CK_SLOT_ID,
+            pulCount: *mut CK_ULONG,
+        ) -> CK_RV,
+    >,
+    pub C_GetSlotInfo: Unused,
+    pub C_GetTokenInfo:
+        Option<unsafe extern "C" fn(slotID: CK_SLOT_ID, pInfo: *mut CK_TOKEN_INFO) -> CK_RV>,
+    pub C_GetMechanismList: Unused,
+    pub C_GetMechanismInfo: Unused,
+    pub C_InitToken: Unused,
+    pub C_InitPIN: Unused,
+    pub C_SetPIN: Unused,
+    pub C_OpenSession: Option<
+        unsafe extern "C" fn(
+            slotID: CK_SLOT_ID,
+            flags: CK_FLAGS,
+            pApplication: *mut c_void,
+            Notify: *mut c_void,
+            phSession: *mut CK_SESSION_HANDLE,
+        ) -> CK_RV,
+    >,
+    pub C_CloseSession: Option<unsafe extern "C" fn(hSession: CK_SESSION_HANDLE) -> CK_RV>,
+    pub C_CloseAllSessions: Unused,
+    pub C_GetSessionInfo: Unused,
+    pub C_GetOperationState: Unused,
+    pub C_SetOperationState: Unused,
+    pub C_Login: Option<
+        unsafe extern "C" fn(
+            hSession: CK_SESSION_HANDLE,
+            userType: CK_ULONG,
+            pPin: *mut c_uchar,
+            ulPinLen: CK_ULONG,
+        ) -> CK_RV,
+    >,
+    pub C_Logout: Unused,
+    pub C_CreateObject: Unused,
+    pub C_CopyObject: Unused,
+    pub C_DestroyObject: Unused,
+    pub C_GetObjectSize: Unused,
+    pub C_GetAttributeValue: Unused,
+    pub C_SetAttributeValue: Unused,
+    pub C_FindObjectsInit: Option<
+        unsafe extern "C" fn(
+            hSession: CK_SESSION_HANDLE,
+            pTemplate: *mut CK_ATTRIBUTE,
+            ulCount: CK_ULONG,
+        ) -> CK_RV,
+    >,
+    pub C_FindObjects: Option<
+        unsafe extern "C" fn(
+            hSession: CK_SESSION_HANDLE,
+            phObject: *mut CK_OBJECT_HANDLE,
+            ulMaxObjectCount: CK_ULONG,
+            pulObjectCount: *mut CK_ULONG,
+        ) -> CK_RV,
+    >,
+    pub C_FindObjectsFinal: Option<unsafe extern "C" fn(hSession: CK_SESSION_HANDLE) -> CK_RV>,
+    pub C_EncryptInit: Option<
+        unsafe extern "C" fn(
+            hSession: CK_SESSION_HANDLE,
+            pMechanism: *mut CK_MECHANISM,
+            hKey: CK_OBJECT_HANDLE,
+        ) -> CK_RV,
+    >,
+    pub C_Encrypt: Option<
+        unsafe extern "C" fn(
+            hSession: CK_SESSION_HANDLE,
+            pData: *mut c_uchar,
+            ulDataLen: CK_ULONG,
+            pEncryptedData: *mut c_uchar,
+            pulEncryptedDataLen: *mut CK_ULONG,
+        ) -> CK_RV,
+    >,
+    pub C_EncryptUpdate: Unused,
+    pub C_EncryptFinal: Unused,
+    pub C_DecryptInit: Option<
+        unsafe extern "C" fn(
+            hSession: CK_SESSION_HANDLE,
+            pMechanism: *mut CK_MECHANISM,
+            hKey: CK_OBJECT_HANDLE,
+        ) -> CK_RV,
+    >,
+    pub C_Decrypt: Option<
+        unsafe extern "C" fn(
+            hSession: CK_SESSION_HANDLE,
+            pEncryptedData: *mut c_uchar,
+            ulEncryptedDataLen: CK_ULONG,
+            pData: *mut c_uchar,
+            pulDataLen: *mut CK_ULONG,
+        ) -> CK_RV,
+    >,
+    pub C_DecryptUpdate: Unused,
+    pub C_DecryptFinal: Unused,
+    pub C_DigestInit: Unused,
+    pub C_Digest: Unused,
+    pub C_DigestUpdate: Unused,
+    pub C_DigestKey: Unused,
+    pub C_DigestFinal: Unused,
+    pub C_SignInit: Unused,
+    pub C_Sign: Unused,
+    pub C_SignUpdate: Unused,
+    pub C_SignFinal: Unused,
+    pub C_SignRecoverInit: Unused,
+    pub C_SignRecover: Unused,
+    pub C_VerifyInit: Unused,
+    pub C_Verify: Unused,
+    pub C_VerifyUpdate: Unused,
+    pub C_VerifyFinal: Unused,
+    pub C_VerifyRecoverInit: Unused,
+    pub C_VerifyRecover: Unused,
+    pub C_DigestEncryptUpdate: Unused,
+    pub C_DecryptDigestUpdate: Unused,
+    pub C_SignEncryptUpdate: Unused,
+    pub C_DecryptVerifyUpdate: Unused,
+    pub C_GenerateKey: Option<
+        unsafe extern "C" fn(
+            hSession: CK_SESSION_HANDLE,
+            pMechanism: *mut CK_MECHANISM,
+            pTemplate: *mut CK_ATTRIBUTE,
+            ulCount: CK_ULONG,
+            phKey: *mut CK_OBJECT_HANDLE,
+        ) -> CK_RV,
+    >,
+}
+
+pub type C_GetFunctionList =
+    unsafe extern "C" fn(ppFunctionList: *mut *mut CK_FUNCTION_LIST) -> CK_RV;
+
+/// Returns the name of a PKCS#11 return value for error messages.
+pub fn rv_name(rv: CK_RV) -> &'static str {
+    match rv {
+        0x0 => "CKR_OK",
+        0x1 => "CKR_CANCEL",
+        0x2 => "CKR_HOST_MEMORY",
+        0x3 => "CKR_SLOT_ID_INVALID",
+        0x5 => "CKR_GENERAL_ERROR",
+        0x6 => "CKR_FUNCTION_FAILED",
+        0x7 => "CKR_ARGUMENTS_BAD",
+        0x30 => "CKR_DEVICE_ERROR",
+        0x31 => "CKR_DEVICE_MEMORY",
+        0x32 => "CKR_DEVICE_REMOVED",
+        0x40 => "CKR_ENCRYPTED_DATA_INVALID",
+        0x41 => "CKR_ENCRYPTED_DATA_LEN_RANGE",
+        0x54 => "CKR_FUNCTION_NOT_SUPPORTED",
+        0x60 => "CKR_KEY_HANDLE_INVALID",
+        0x63 => "CKR_KEY_TYPE_INCONSISTENT",
+        0x68 => "CKR_KEY_FUNCTION_NOT_PERMITTED",
+        0x70 => "CKR_MECHANISM_INVALID",
+        0x71 => "CKR_MECHANISM_PARAM_INVALID",
+        0x90 => "CKR_OPERATION_ACTIVE",
+        0x91 => "CKR_OPERATION_NOT_INITIALIZED",
+        0xa0 => "CKR_PIN_INCORRECT",
+        0xa2 => "CKR_PIN_LEN_RANGE",
+        0xa4 => "CKR_PIN_LOCKED",
+        0xb0 => "CKR_SESSION_CLOSED",
+        0xb1 => "CKR_SESSION_COUNT",
+        0xb3 => "CKR_SESSION_HANDLE_INVALID",
+        0xe0 => "CKR_TOKEN_NOT_PRESENT",
+        0xe1 => "CKR_TOKEN_NOT_RECOGNIZED",
+        0xe2 => "CKR_TOKEN_WRITE_PROTECTED",
+        0x100 => "CKR_USER_ALREADY_LOGGED_IN",
+        0x101 => "CKR_USER_NOT_LOGGED_IN",
+        0x103 => "CKR_USER_TYPE_INVALID",
+        0x150 => "CKR_BUFFER_TOO_SMALL",
+        0x190 => "CKR_CRYPTOKI_NOT_INITIALIZED",
+        0x191 => "CKR_CRYPTOKI_ALREADY_INITIALIZED",
+        _ => "unknown error",
+    }
+}
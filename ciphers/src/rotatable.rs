@@ -16,34 +16,298 @@
 use crate::oneof::OneOfCipher;
 use crate::{Cipher, Error};
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
 use tracing::debug;
 use uuid::Uuid;
 
+/// Name of the key whose versions are the keys not belonging to any other named key,
+/// with the default key as its primary version.
+pub const DEFAULT_KEY_NAME: &str = "default";
+
+/// A logical key whose versions are keys of the keyring.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamedKey {
+    /// ID of the version used for new encryptions.
+    pub primary: Uuid,
+    /// IDs of all versions, oldest first.
+    pub versions: Vec<Uuid>,
+}
+
+/// Lifecycle state of a key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyState {
+    /// Used for encryption and decryption.
+    #[default]
+    Enabled,
+    /// Used for decryption only.
+    DecryptOnly,
+    /// Not used at all until enabled again.
+    Disabled,
+    /// Not used at all, and destroyed when the grace period ends unless enabled again.
+    PendingDestruction,
+    /// Deleted from the keyring, so data encrypted with it cannot be decrypted.
+    Destroyed,
+}
+
+impl KeyState {
+    pub fn allows_encryption(self) -> bool {
+        self == KeyState::Enabled
+    }
+
+    pub fn allows_decryption(self) -> bool {
+        matches!(self, KeyState::Enabled | KeyState::DecryptOnly)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            KeyState::Enabled => "enabled",
+            KeyState::DecryptOnly => "decrypt-only",
+            KeyState::Disabled => "disabled",
+            KeyState::PendingDestruction => "pending-destruction",
+            KeyState::Destroyed => "destroyed",
+        }
+    }
+}
+
+impl Display for KeyState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Lifecycle state of a key with its last change.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyStatus {
+    pub state: KeyState,
+    /// When the state was changed, or `None` if it has never been.
+    pub changed_at: Option<DateTime<Utc>>,
+    /// Who changed the state.
+    pub changed_by: Option<String>,
+    /// When the key is destroyed, if it is pending destruction.
+    pub destroy_at: Option<DateTime<Utc>>,
+}
+
+/// When the primary version of a named key is replaced by a newly generated one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RotationPolicy {
+    /// Age of the primary version after which it is rotated.
+    pub max_age: Option<Duration>,
+    /// Number of encryptions with the primary version after which it is rotated.
+    pub max_encryptions: Option<u64>,
+    /// When the primary version became primary, or `None` if not recorded yet.
+    pub rotated_at: Option<DateTime<Utc>>,
+    /// Number of encryptions with the primary version saved with the keyring,
+    /// which includes those before the keyring was loaded.
+    pub saved_encryptions: u64,
+}
+
+/// Why a primary version is rotated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RotationReason {
+    MaxAge,
+    MaxEncryptions,
+}
+
+impl RotationPolicy {
+    /// Returns why the primary version, used for `encryptions` encryptions,
+    /// is due for rotation at `now`, or `None` if it is not.
+    pub fn due(&self, now: DateTime<Utc>, encryptions: u64) -> Option<RotationReason> {
+        if self.max_encryptions.is_some_and(|max| encryptions >= max) {
+            return Some(RotationReason::MaxEncryptions);
+        }
+        let rotated_at = self.rotated_at?;
+        let max_age = chrono::Duration::from_std(self.max_age?).ok()?;
+        rotated_at
+            .checked_add_signed(max_age)
+            .is_some_and(|at| at <= now)
+            .then_some(RotationReason::MaxAge)
+    }
+}
+
+impl RotationReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RotationReason::MaxAge => "max-age",
+            RotationReason::MaxEncryptions => "max-encryptions",
+        }
+    }
+}
+
+impl Display for RotationReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 #[derive(Clone)]
 pub struct RotatableCipher {
     default_key_id: Uuid,
     default_cipher: OneOfCipher,
     ciphers: HashMap<Uuid, OneOfCipher>,
+    named_keys: HashMap<String, NamedKey>,
+    key_names: HashMap<Uuid, String>,
+    statuses: HashMap<Uuid, KeyStatus>,
+    rotation_policies: HashMap<String, RotationPolicy>,
 }
 
 impl RotatableCipher {
-    pub fn new(default_key_id: Uuid, ciphers: HashMap<Uuid, OneOfCipher>) -> Self {
+    pub fn new(default_key_id: Uuid, ciphers: HashMap<Uuid, OneOfCipher>) -> Result<Self, Error> {
         debug!("Using encryption key: {default_key_id}");
-        Self {
+        let default_cipher = ciphers
+            .get(&default_key_id)
+            .ok_or(Error::KeyNotFound(default_key_id))?
+            .clone();
+        let mut versions: Vec<_> = ciphers.keys().copied().collect();
+        versions.sort();
+        let named_keys = HashMap::from([(
+            DEFAULT_KEY_NAME.to_string(),
+            NamedKey {
+                primary: default_key_id,
+                versions,
+            },
+        )]);
+        Ok(Self {
             default_key_id,
-            default_cipher: ciphers.get(&default_key_id).unwrap().clone(),
+            default_cipher,
             ciphers,
+            named_keys,
+            key_names: HashMap::new(),
+            statuses: HashMap::new(),
+            rotation_policies: HashMap::new(),
+        })
+    }
+
+    /// Adds named keys, whose versions are taken out of the default named key.
+    ///
+    /// Each key belongs to at most one named key, and the default key belongs to the default one.
+    pub fn with_named_keys(mut self, named_keys: HashMap<String, NamedKey>) -> Result<Self, Error> {
+        for (name, named_key) in named_keys {
+            if name == DEFAULT_KEY_NAME || !named_key.versions.contains(&named_key.primary) {
+                return Err(Error::InvalidKeyName(name));
+            }
+            for id in &named_key.versions {
+                if !self.ciphers.contains_key(id) {
+                    return Err(Error::KeyNotFound(*id));
+                }
+                if *id == self.default_key_id || self.key_names.contains_key(id) {
+                    return Err(Error::InvalidKeyName(name));
+                }
+                self.key_names.insert(*id, name.clone());
+            }
+            self.named_keys.insert(name, named_key);
+        }
+        let key_names = &self.key_names;
+        if let Some(default) = self.named_keys.get_mut(DEFAULT_KEY_NAME) {
+            default.versions.retain(|id| !key_names.contains_key(id));
         }
+        Ok(self)
     }
 
     pub fn default_key_id(&self) -> String {
         self.default_key_id.to_string()
     }
 
+    /// Returns the default key with its ID.
+    pub fn default_key(&self) -> (Uuid, &OneOfCipher) {
+        (self.default_key_id, &self.default_cipher)
+    }
+
+    /// Sets lifecycle states of keys, which are enabled unless given.
+    ///
+    /// Only destroyed keys may be missing from the keyring, and the primary versions of
+    /// named keys, given by [`RotatableCipher::with_named_keys`] beforehand, must be enabled.
+    pub fn with_key_statuses(mut self, statuses: HashMap<Uuid, KeyStatus>) -> Result<Self, Error> {
+        for (id, status) in &statuses {
+            let destroyed = status.state == KeyState::Destroyed;
+            if destroyed == self.ciphers.contains_key(id) {
+                return Err(Error::InvalidKeyState(*id, status.state));
+            }
+        }
+        self.statuses = statuses;
+        for named_key in self.named_keys.values() {
+            let state = self.status(&named_key.primary).state;
+            if !state.allows_encryption() {
+                return Err(Error::InvalidKeyState(named_key.primary, state));
+            }
+        }
+        Ok(self)
+    }
+
+    /// Sets rotation policies of named keys, given by [`RotatableCipher::with_named_keys`]
+    /// beforehand, including the default one.
+    pub fn with_rotation_policies(
+        mut self,
+        policies: HashMap<String, RotationPolicy>,
+    ) -> Result<Self, Error> {
+        if let Some(name) = policies.keys().find(|n| !self.named_keys.contains_key(*n)) {
+            return Err(Error::InvalidKeyName(name.clone()));
+        }
+        self.rotation_policies = policies;
+        Ok(self)
+    }
+
+    pub fn rotation_policies(&self) -> &HashMap<String, RotationPolicy> {
+        &self.rotation_policies
+    }
+
+    /// Returns the lifecycle state of the key.
+    pub fn status(&self, key_id: &Uuid) -> KeyStatus {
+        self.statuses.get(key_id).cloned().unwrap_or_default()
+    }
+
+    /// Returns the lifecycle states of all keys, including destroyed ones.
+    pub fn key_statuses(&self) -> HashMap<Uuid, KeyStatus> {
+        let mut statuses = self.statuses.clone();
+        for id in self.ciphers.keys() {
+            statuses.entry(*id).or_default();
+        }
+        statuses
+    }
+
+    /// Returns the primary version of the named key with its ID.
+    pub fn primary_key(&self, name: &str) -> Option<(Uuid, &OneOfCipher)> {
+        let id = self.named_keys.get(name)?.primary;
+        self.ciphers.get(&id).map(|cipher| (id, cipher))
+    }
+
+    /// Returns the name of the named key which `key_id` is a version of.
+    pub fn key_name_of(&self, key_id: &Uuid) -> &str {
+        self.key_names
+            .get(key_id)
+            .map_or(DEFAULT_KEY_NAME, String::as_str)
+    }
+
+    pub fn named_keys(&self) -> &HashMap<String, NamedKey> {
+        &self.named_keys
+    }
+
+    pub fn get(&self, key_id: &Uuid) -> Option<&OneOfCipher> {
+        self.ciphers.get(key_id)
+    }
+
     pub fn contains_key(&self, key_id: &Uuid) -> bool {
         self.ciphers.contains_key(key_id)
     }
+
+    pub fn key_count(&self) -> usize {
+        self.ciphers.len()
+    }
+
+    /// Returns the ID of the key which encrypted `data`.
+    pub fn key_id_of(data: &[u8]) -> Result<Uuid, Error> {
+        data.get(..16)
+            .and_then(|id| Uuid::from_slice_le(id).ok())
+            .ok_or(Error::InvalidKeyId)
+    }
+
+    pub fn key_ids(&self) -> impl Iterator<Item = &Uuid> {
+        self.ciphers.keys()
+    }
 }
 
 #[async_trait]
@@ -68,7 +332,7 @@ impl Cipher for RotatableCipher {
     }
 
     async fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
-        let key_id = Uuid::from_slice_le(&data[..16]).map_err(|_| Error::InvalidKeyId)?;
+        let key_id = Self::key_id_of(data)?;
         debug!("Decrypt using key ID: {key_id}");
         let ciphertext = &data[16..];
         let cipher = self
@@ -82,9 +346,14 @@ impl Cipher for RotatableCipher {
 #[cfg(test)]
 mod test {
     use crate::oneof::OneOfCipher;
-    use crate::rotatable::RotatableCipher;
+    use crate::rotatable::{
+        DEFAULT_KEY_NAME, KeyState, KeyStatus, NamedKey, RotatableCipher, RotationPolicy,
+        RotationReason,
+    };
     use crate::{Cipher, Unencrypted, predefined_tests};
+    use chrono::{TimeZone, Utc};
     use std::collections::HashMap;
+    use std::time::Duration;
     use uuid::Uuid;
 
     fn create_sut() -> RotatableCipher {
@@ -98,17 +367,154 @@ mod test {
             id,
             HashMap::from([(id, OneOfCipher::Unencrypted(Unencrypted))]),
         )
+        .unwrap()
     }
 
     predefined_tests!(create_sut);
 
+    #[test]
+    fn test_new_without_default_key() {
+        let id = Uuid::from_u128(1);
+        let result = RotatableCipher::new(
+            Uuid::from_u128(2),
+            HashMap::from([(id, OneOfCipher::Unencrypted(Unencrypted))]),
+        );
+        assert!(matches!(result, Err(crate::Error::KeyNotFound(_))));
+    }
+
     #[tokio::test]
     async fn test_encrypt_and_decrypt() {
         let sut = create_sut();
 
         let data = b"Hello, world!";
         let ciphertext = sut.encrypt(data).await.unwrap();
+        assert_eq!(
+            RotatableCipher::key_id_of(&ciphertext).unwrap().to_string(),
+            sut.default_key_id()
+        );
         let decrypted = sut.decrypt(&ciphertext).await.unwrap();
         assert_eq!(data, decrypted.as_slice());
+        assert!(RotatableCipher::key_id_of(&ciphertext[..15]).is_err());
+    }
+
+    #[test]
+    fn test_named_keys() {
+        let ids = [1, 2, 3, 4].map(Uuid::from_u128);
+        let ciphers = ids
+            .iter()
+            .map(|id| (*id, OneOfCipher::Unencrypted(Unencrypted)))
+            .collect::<HashMap<_, _>>();
+        let named_key = NamedKey {
+            primary: ids[2],
+            versions: vec![ids[1], ids[2]],
+        };
+        let sut = RotatableCipher::new(ids[0], ciphers.clone())
+            .unwrap()
+            .with_named_keys(HashMap::from([(
+                "payments/pii".to_string(),
+                named_key.clone(),
+            )]))
+            .unwrap();
+
+        assert_eq!(sut.primary_key("payments/pii").unwrap().0, ids[2]);
+        assert_eq!(sut.primary_key(DEFAULT_KEY_NAME).unwrap().0, ids[0]);
+        assert!(sut.primary_key("missing").is_none());
+        assert_eq!(sut.key_name_of(&ids[1]), "payments/pii");
+        assert_eq!(sut.key_name_of(&ids[3]), DEFAULT_KEY_NAME);
+        assert_eq!(
+            sut.named_keys()[DEFAULT_KEY_NAME].versions,
+            vec![ids[0], ids[3]]
+        );
+
+        for (name, versions) in [
+            (DEFAULT_KEY_NAME, vec![ids[2]]),
+            ("without-primary", vec![ids[1]]),
+            ("with-default-key", vec![ids[0], ids[2]]),
+        ] {
+            let result = RotatableCipher::new(ids[0], ciphers.clone())
+                .unwrap()
+                .with_named_keys(HashMap::from([(
+                    name.to_string(),
+                    NamedKey {
+                        primary: ids[2],
+                        versions,
+                    },
+                )]));
+            assert!(
+                matches!(result, Err(crate::Error::InvalidKeyName(n)) if n == name),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_key_statuses() {
+        let ids = [1, 2, 3].map(Uuid::from_u128);
+        let keyring = || {
+            RotatableCipher::new(
+                ids[0],
+                HashMap::from([
+                    (ids[0], OneOfCipher::Unencrypted(Unencrypted)),
+                    (ids[1], OneOfCipher::Unencrypted(Unencrypted)),
+                ]),
+            )
+            .unwrap()
+        };
+        let status = |state| KeyStatus {
+            state,
+            ..Default::default()
+        };
+
+        let sut = keyring()
+            .with_key_statuses(HashMap::from([
+                (ids[1], status(KeyState::DecryptOnly)),
+                (ids[2], status(KeyState::Destroyed)),
+            ]))
+            .unwrap();
+        assert_eq!(sut.status(&ids[0]).state, KeyState::Enabled);
+        assert_eq!(sut.status(&ids[1]).state, KeyState::DecryptOnly);
+        assert_eq!(sut.key_statuses().len(), 3);
+
+        for (id, state) in [
+            (ids[0], KeyState::Disabled),
+            (ids[1], KeyState::Destroyed),
+            (ids[2], KeyState::Disabled),
+        ] {
+            let result = keyring().with_key_statuses(HashMap::from([(id, status(state))]));
+            assert!(
+                matches!(result, Err(crate::Error::InvalidKeyState(i, s)) if i == id && s == state),
+                "{id} {state}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_rotation_policies() {
+        let at = Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap();
+        let policy = RotationPolicy {
+            max_age: Some(Duration::from_secs(3600)),
+            max_encryptions: Some(10),
+            rotated_at: Some(at),
+            saved_encryptions: 0,
+        };
+        assert_eq!(policy.due(at, 9), None);
+        assert_eq!(policy.due(at, 10), Some(RotationReason::MaxEncryptions));
+        assert_eq!(
+            policy.due(at + chrono::Duration::hours(1), 0),
+            Some(RotationReason::MaxAge)
+        );
+        let unrecorded = RotationPolicy {
+            rotated_at: None,
+            ..policy
+        };
+        assert_eq!(unrecorded.due(at + chrono::Duration::days(1), 0), None);
+
+        let sut = create_sut()
+            .with_rotation_policies(HashMap::from([(DEFAULT_KEY_NAME.to_string(), policy)]))
+            .unwrap();
+        assert_eq!(sut.rotation_policies()[DEFAULT_KEY_NAME], policy);
+        let result =
+            create_sut().with_rotation_policies(HashMap::from([("missing".to_string(), policy)]));
+        assert!(matches!(result, Err(crate::Error::InvalidKeyName(_))));
     }
 }
@@ -0,0 +1,163 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+//! Shamir's secret sharing over GF(2^8).
+//!
+//! Each byte of the secret is the constant term of a random polynomial of degree
+//! `threshold - 1`, and share `x` holds the values of the polynomials at `x`.
+
+use crate::Error;
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::rand_core::RngCore;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Share {
+    /// Evaluation point, never zero
+    pub x: u8,
+    pub y: Vec<u8>,
+}
+
+/// Splits `secret` into `shares` shares, any `threshold` of which recover it.
+pub fn split(secret: &[u8], threshold: u8, shares: u8) -> Result<Vec<Share>, Error> {
+    if threshold == 0 {
+        return Err(Error::InvalidShares("threshold must be at least 1"));
+    }
+    if shares < threshold {
+        return Err(Error::InvalidShares(
+            "number of shares must not be less than threshold",
+        ));
+    }
+
+    let mut result: Vec<Share> = (1..=shares)
+        .map(|x| Share {
+            x,
+            y: Vec::with_capacity(secret.len()),
+        })
+        .collect();
+    let mut coefficients = vec![0u8; threshold as usize];
+    for &byte in secret {
+        coefficients[0] = byte;
+        OsRng.fill_bytes(&mut coefficients[1..]);
+        for share in &mut result {
+            share.y.push(evaluate(&coefficients, share.x));
+        }
+    }
+    coefficients.fill(0);
+    Ok(result)
+}
+
+/// Recovers the secret from shares. Giving fewer shares than the threshold
+/// yields a wrong secret, which cannot be detected here.
+pub fn combine(shares: &[Share]) -> Result<Vec<u8>, Error> {
+    let Some(first) = shares.first() else {
+        return Err(Error::InvalidShares("no shares are given"));
+    };
+    for (i, share) in shares.iter().enumerate() {
+        if share.x == 0 {
+            return Err(Error::InvalidShares("share index must not be zero"));
+        }
+        if share.y.len() != first.y.len() {
+            return Err(Error::InvalidShares("shares have different lengths"));
+        }
+        if shares[..i].iter().any(|s| s.x == share.x) {
+            return Err(Error::InvalidShares("duplicate share"));
+        }
+    }
+
+    // Lagrange interpolation at x = 0
+    let mut secret = vec![0u8; first.y.len()];
+    for (i, share) in shares.iter().enumerate() {
+        let mut numerator = 1u8;
+        let mut denominator = 1u8;
+        for (j, other) in shares.iter().enumerate() {
+            if i != j {
+                numerator = mul(numerator, other.x);
+                denominator = mul(denominator, share.x ^ other.x);
+            }
+        }
+        let basis = mul(numerator, inverse(denominator));
+        for (s, &y) in secret.iter_mut().zip(&share.y) {
+            *s ^= mul(y, basis);
+        }
+    }
+    Ok(secret)
+}
+
+fn evaluate(coefficients: &[u8], x: u8) -> u8 {
+    coefficients.iter().rev().fold(0, |acc, &c| mul(acc, x) ^ c)
+}
+
+/// Multiplication in GF(2^8) modulo x^8 + x^4 + x^3 + x + 1, without data dependent branches.
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0u8;
+    for _ in 0..8 {
+        product ^= a & (b & 1).wrapping_neg();
+        let carry = (a >> 7).wrapping_neg();
+        a = (a << 1) ^ (0x1b & carry);
+        b >>= 1;
+    }
+    product
+}
+
+/// Multiplicative inverse as a^254.
+fn inverse(a: u8) -> u8 {
+    let mut result = 1u8;
+    let mut base = a;
+    let mut exponent = 254u8;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = mul(result, base);
+        }
+        base = mul(base, base);
+        exponent >>= 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_field_inverse() {
+        for a in 1..=255u8 {
+            assert_eq!(mul(a, inverse(a)), 1, "{a}");
+        }
+    }
+
+    #[test]
+    fn test_split_and_combine() {
+        let secret = b"0123456789abcdef0123456789abcdef";
+        let shares = split(secret, 3, 5).unwrap();
+        assert_eq!(shares.len(), 5);
+
+        for subset in [[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+            let subset: Vec<_> = subset.iter().map(|&i| shares[i].clone()).collect();
+            assert_eq!(combine(&subset).unwrap(), secret);
+        }
+        assert_eq!(combine(&shares).unwrap(), secret);
+        assert_ne!(combine(&shares[..2]).unwrap(), secret);
+    }
+
+    #[test]
+    fn test_invalid_shares() {
+        assert!(split(b"secret", 0, 1).is_err());
+        assert!(split(b"secret", 3, 2).is_err());
+
+        let shares = split(b"secret", 2, 3).unwrap();
+        assert!(combine(&[]).is_err());
+        assert!(combine(&[shares[0].clone(), shares[0].clone()]).is_err());
+    }
+}
@@ -0,0 +1,156 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+//! STREAM segmented AEAD (Hoang, Reyhanitabar, Rogaway and Vizár, 2015).
+//!
+//! Each segment is sealed with the nonce `prefix || counter || last`, where the counter
+//! is a 32-bit big endian segment index and `last` is `1` only for the final segment.
+//! Reordered segments fail to open with the wrong counter, and a truncated stream lacks
+//! a segment opened as the last one.
+
+use crate::Error;
+use crate::oneof::OneOfCipher;
+use aes_siv::Aes256SivAead;
+use chacha20poly1305::ChaCha20Poly1305;
+use chacha20poly1305::aead::generic_array::typenum::Unsigned;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+
+/// Size of the authentication tag appended to each segment.
+pub const TAG_SIZE: usize = 16;
+/// Size of the counter and the last segment flag at the end of nonces.
+const NONCE_SUFFIX_SIZE: usize = 5;
+
+enum SegmentAead {
+    ChaCha20Poly1305(ChaCha20Poly1305),
+    AesGcmSiv(Aes256SivAead),
+}
+
+pub struct StreamCipher {
+    aead: SegmentAead,
+    prefix: Vec<u8>,
+}
+
+impl StreamCipher {
+    /// Creates a cipher with a random nonce prefix for a DEK.
+    pub fn new(cipher: &OneOfCipher) -> Result<Self, Error> {
+        let aead = SegmentAead::new(cipher)?;
+        let mut prefix = vec![0; aead.nonce_size() - NONCE_SUFFIX_SIZE];
+        OsRng.fill_bytes(&mut prefix);
+        Ok(Self { aead, prefix })
+    }
+
+    /// Creates a cipher with the nonce prefix of an existing stream.
+    pub fn with_prefix(cipher: &OneOfCipher, prefix: &[u8]) -> Result<Self, Error> {
+        let aead = SegmentAead::new(cipher)?;
+        if prefix.len() != aead.nonce_size() - NONCE_SUFFIX_SIZE {
+            return Err(Error::Stream("invalid nonce prefix length"));
+        }
+        Ok(Self {
+            aead,
+            prefix: prefix.to_vec(),
+        })
+    }
+
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    pub fn seal(&self, counter: u32, last: bool, segment: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = self.nonce(counter, last);
+        match &self.aead {
+            SegmentAead::ChaCha20Poly1305(aead) => aead
+                .encrypt(nonce.as_slice().into(), segment)
+                .map_err(Error::ChaCha20Poly1305),
+            SegmentAead::AesGcmSiv(aead) => aead
+                .encrypt(nonce.as_slice().into(), segment)
+                .map_err(Error::AesGcmSiv),
+        }
+    }
+
+    pub fn open(&self, counter: u32, last: bool, segment: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = self.nonce(counter, last);
+        match &self.aead {
+            SegmentAead::ChaCha20Poly1305(aead) => aead
+                .decrypt(nonce.as_slice().into(), segment)
+                .map_err(Error::ChaCha20Poly1305),
+            SegmentAead::AesGcmSiv(aead) => aead
+                .decrypt(nonce.as_slice().into(), segment)
+                .map_err(Error::AesGcmSiv),
+        }
+    }
+
+    fn nonce(&self, counter: u32, last: bool) -> Vec<u8> {
+        let mut nonce = Vec::with_capacity(self.prefix.len() + NONCE_SUFFIX_SIZE);
+        nonce.extend_from_slice(&self.prefix);
+        nonce.extend_from_slice(&counter.to_be_bytes());
+        nonce.push(last as u8);
+        nonce
+    }
+}
+
+impl SegmentAead {
+    fn new(cipher: &OneOfCipher) -> Result<Self, Error> {
+        match cipher {
+            OneOfCipher::ChaCha20Poly1305(c) => Ok(Self::ChaCha20Poly1305(
+                ChaCha20Poly1305::new_from_slice(crate::Cipher::key(c))
+                    .map_err(|_| Error::InvalidKeyLength)?,
+            )),
+            OneOfCipher::AesGcmSiv(c) => Ok(Self::AesGcmSiv(
+                Aes256SivAead::new_from_slice(crate::Cipher::key(c))
+                    .map_err(|_| Error::InvalidKeyLength)?,
+            )),
+            _ => Err(Error::Stream("unsupported algorithm")),
+        }
+    }
+
+    fn nonce_size(&self) -> usize {
+        match self {
+            Self::ChaCha20Poly1305(_) => <ChaCha20Poly1305 as AeadCore>::NonceSize::USIZE,
+            Self::AesGcmSiv(_) => <Aes256SivAead as AeadCore>::NonceSize::USIZE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aesgcmsiv::AesGcmSivCipher;
+    use crate::chacha20poly1305::ChaCha20Poly1305Cipher;
+
+    #[test]
+    fn test_seal_open() {
+        for cipher in [
+            OneOfCipher::ChaCha20Poly1305(ChaCha20Poly1305Cipher::default()),
+            OneOfCipher::AesGcmSiv(AesGcmSivCipher::default()),
+        ] {
+            let sealer = StreamCipher::new(&cipher).unwrap();
+            let first = sealer.seal(0, false, b"first").unwrap();
+            let last = sealer.seal(1, true, b"last").unwrap();
+            assert_eq!(first.len(), 5 + TAG_SIZE);
+
+            let opener = StreamCipher::with_prefix(&cipher, sealer.prefix()).unwrap();
+            assert_eq!(opener.open(0, false, &first).unwrap(), b"first");
+            assert_eq!(opener.open(1, true, &last).unwrap(), b"last");
+            // reordered
+            assert!(opener.open(0, false, &last).is_err());
+            assert!(opener.open(1, false, &first).is_err());
+            // truncated after the first segment
+            assert!(opener.open(0, true, &first).is_err());
+
+            assert!(StreamCipher::with_prefix(&cipher, &[0; 3]).is_err());
+        }
+    }
+}
@@ -32,3 +32,83 @@ macro_rules! predefined_tests {
         }
     };
 }
+
+/// A request received by [`HttpStandIn`].
+#[derive(Debug, Clone)]
+pub(crate) struct Request {
+    pub path: String,
+    /// Headers with lowercase names.
+    pub headers: std::collections::HashMap<String, String>,
+    pub body: String,
+}
+
+/// A minimal HTTP/1.1 server standing in for a remote API, answering each request
+/// with `(status, JSON body)` returned by a handler.
+pub(crate) struct HttpStandIn {
+    pub address: String,
+    pub requests: std::sync::Arc<std::sync::Mutex<Vec<Request>>>,
+}
+
+impl HttpStandIn {
+    pub async fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&Request) -> (u16, String) + Send + Sync + 'static,
+    {
+        use std::sync::Arc;
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let handler = Arc::new(handler);
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (handler, recorded) = (handler.clone(), recorded.clone());
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    let mut line = String::new();
+                    stream.read_line(&mut line).await.unwrap();
+                    let path = line.split(' ').nth(1).unwrap_or_default().to_string();
+                    let mut headers = std::collections::HashMap::new();
+                    loop {
+                        line.clear();
+                        stream.read_line(&mut line).await.unwrap();
+                        let Some((name, value)) = line.trim_end().split_once(':') else {
+                            break;
+                        };
+                        headers.insert(name.to_lowercase(), value.trim().to_string());
+                    }
+                    let length = headers
+                        .get("content-length")
+                        .map_or(0, |length| length.parse().unwrap());
+                    let mut body = vec![0; length];
+                    stream.read_exact(&mut body).await.unwrap();
+
+                    let request = Request {
+                        path,
+                        headers,
+                        body: String::from_utf8(body).unwrap(),
+                    };
+                    let (status, body) = handler(&request);
+                    recorded.lock().unwrap().push(request);
+                    let response = format!(
+                        "HTTP/1.1 {status} Stand-in\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                        body.len()
+                    );
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                });
+            }
+        });
+        Self { address, requests }
+    }
+
+    pub fn paths(&self) -> Vec<String> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|r| r.path.clone())
+            .collect()
+    }
+}
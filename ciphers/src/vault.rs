@@ -0,0 +1,477 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+//! A [`Cipher`] that wraps data with the Transit secrets engine of HashiCorp Vault.
+
+use crate::Cipher;
+use crate::error::Error;
+use crate::http::{self, Failure};
+use async_trait::async_trait;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use reqwest::{Certificate, Client, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct VaultTransitConfig {
+    /// Address of Vault, e.g. `https://vault.example.com:8200`.
+    pub address: String,
+    /// Mount path of the Transit secrets engine.
+    pub mount: String,
+    pub key_name: String,
+    /// Version of the key to encrypt with. The latest version is used if omitted.
+    /// Decryption always uses the version recorded in the ciphertext.
+    pub key_version: Option<u32>,
+    pub namespace: Option<String>,
+    /// PEM encoded CA certificate to verify Vault with, in addition to the system roots.
+    pub ca_certificate: Option<Vec<u8>>,
+    pub auth: VaultAuth,
+    /// Number of retries of requests failed by a connection error, 429 or 5xx.
+    pub retries: u32,
+}
+
+#[derive(Clone)]
+pub enum VaultAuth {
+    Token(String),
+    AppRole {
+        mount: String,
+        role_id: String,
+        secret_id: String,
+    },
+}
+
+impl Debug for VaultAuth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VaultAuth::Token(_) => f.write_str("Token"),
+            VaultAuth::AppRole { mount, role_id, .. } => f
+                .debug_struct("AppRole")
+                .field("mount", mount)
+                .field("role_id", role_id)
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+/// Encrypts data with `POST /v1/{mount}/encrypt/{key}` of Vault.
+///
+/// Ciphertexts are the ciphertext strings returned by Vault (`vault:v1:...`).
+#[derive(Clone)]
+pub struct VaultTransitCipher {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    client: Client,
+    config: VaultTransitConfig,
+    token: Mutex<Option<Token>>,
+}
+
+#[derive(Clone)]
+struct Token {
+    value: String,
+    renew_at: Option<Instant>,
+}
+
+#[derive(Deserialize)]
+struct Data<T> {
+    data: T,
+}
+
+#[derive(Serialize)]
+struct EncryptRequest {
+    plaintext: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_version: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct EncryptResponse {
+    ciphertext: String,
+}
+
+#[derive(Serialize)]
+struct DecryptRequest<'a> {
+    ciphertext: &'a str,
+}
+
+#[derive(Deserialize)]
+struct DecryptResponse {
+    plaintext: String,
+}
+
+#[derive(Serialize)]
+struct LoginRequest<'a> {
+    role_id: &'a str,
+    secret_id: &'a str,
+}
+
+#[derive(Deserialize)]
+struct LoginResponse {
+    auth: LoginAuth,
+}
+
+#[derive(Deserialize)]
+struct LoginAuth {
+    client_token: String,
+    lease_duration: u64,
+}
+
+#[derive(Deserialize)]
+struct Errors {
+    errors: Vec<String>,
+}
+
+impl VaultTransitCipher {
+    pub fn new(config: VaultTransitConfig) -> Result<Self, Error> {
+        let mut builder = Client::builder().timeout(REQUEST_TIMEOUT);
+        if let Some(pem) = &config.ca_certificate {
+            let certificate =
+                Certificate::from_pem(pem).map_err(|e| Error::Vault(e.to_string()))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+        let client = builder.build().map_err(|e| Error::Vault(e.to_string()))?;
+        debug!(
+            "Using Vault Transit key {} at {}",
+            config.key_name, config.address
+        );
+        Ok(Self {
+            inner: Arc::new(Inner {
+                client,
+                config,
+                token: Mutex::new(None),
+            }),
+        })
+    }
+
+    async fn token(&self) -> Result<String, Error> {
+        let (mount, role_id, secret_id) = match &self.inner.config.auth {
+            VaultAuth::Token(token) => return Ok(token.clone()),
+            VaultAuth::AppRole {
+                mount,
+                role_id,
+                secret_id,
+            } => (mount, role_id, secret_id),
+        };
+        if let Some(token) = self.inner.token.lock().unwrap().clone()
+            && token.renew_at.is_none_or(|at| Instant::now() < at)
+        {
+            return Ok(token.value);
+        }
+
+        debug!("Logging in to Vault with AppRole");
+        let response: LoginResponse = self
+            .request(
+                &format!("auth/{mount}/login"),
+                &LoginRequest { role_id, secret_id },
+                None,
+            )
+            .await
+            .map_err(into_error)?;
+        // Log in again a little before the token expires.
+        let renew_at = (response.auth.lease_duration > 0)
+            .then(|| Instant::now() + Duration::from_secs(response.auth.lease_duration) * 9 / 10);
+        let value = response.auth.client_token;
+        *self.inner.token.lock().unwrap() = Some(Token {
+            value: value.clone(),
+            renew_at,
+        });
+        Ok(value)
+    }
+
+    /// Sends an authenticated request.
+    async fn post<Req, Res>(&self, path: &str, body: &Req) -> Result<Res, Error>
+    where
+        Req: Serialize,
+        Res: DeserializeOwned,
+    {
+        let token = self.token().await?;
+        match self.request(path, body, Some(&token)).await {
+            // An AppRole token may have been revoked or expired early.
+            Err(Failure {
+                status: Some(StatusCode::FORBIDDEN),
+                ..
+            }) if matches!(self.inner.config.auth, VaultAuth::AppRole { .. }) => {
+                *self.inner.token.lock().unwrap() = None;
+                let token = self.token().await?;
+                self.request(path, body, Some(&token)).await
+            }
+            result => result,
+        }
+        .map_err(into_error)
+    }
+
+    /// Sends a request, retrying on connection errors, 429 and 5xx.
+    async fn request<Req, Res>(
+        &self,
+        path: &str,
+        body: &Req,
+        token: Option<&str>,
+    ) -> Result<Res, Failure>
+    where
+        Req: Serialize,
+        Res: DeserializeOwned,
+    {
+        let config = &self.inner.config;
+        let url = format!("{}/v1/{path}", config.address.trim_end_matches('/'));
+        let build = || {
+            let mut request = self.inner.client.post(&url).json(body);
+            if let Some(namespace) = &config.namespace {
+                request = request.header("X-Vault-Namespace", namespace);
+            }
+            if let Some(token) = token {
+                request = request.header("X-Vault-Token", token);
+            }
+            request
+        };
+        http::send("Vault", config.retries, build, |response| {
+            let message = serde_json::from_str::<Errors>(response.body)
+                .map(|body| body.errors.join(", "))
+                .unwrap_or_default();
+            (message, response.is_transient())
+        })
+        .await
+    }
+}
+
+fn into_error(failure: Failure) -> Error {
+    Error::Vault(failure.message)
+}
+
+#[async_trait]
+impl Cipher for VaultTransitCipher {
+    fn name(&self) -> &'static str {
+        "Vault Transit"
+    }
+
+    fn key(&self) -> &[u8] {
+        // The key never leaves Vault.
+        &[]
+    }
+
+    async fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let config = &self.inner.config;
+        let response: Data<EncryptResponse> = self
+            .post(
+                &format!("{}/encrypt/{}", config.mount, config.key_name),
+                &EncryptRequest {
+                    plaintext: BASE64_STANDARD.encode(data),
+                    key_version: config.key_version,
+                },
+            )
+            .await?;
+        Ok(response.data.ciphertext.into_bytes())
+    }
+
+    async fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let config = &self.inner.config;
+        let ciphertext = std::str::from_utf8(data)
+            .map_err(|_| Error::Vault("ciphertext is not a Vault ciphertext".to_string()))?;
+        let response: Data<DecryptResponse> = self
+            .post(
+                &format!("{}/decrypt/{}", config.mount, config.key_name),
+                &DecryptRequest { ciphertext },
+            )
+            .await?;
+        BASE64_STANDARD
+            .decode(response.data.plaintext)
+            .map_err(|e| Error::Vault(format!("plaintext is not valid base64: {e}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::{HttpStandIn, Request};
+    use serde_json::{Value, json};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Stands in for the Transit API of a key `kek` with two versions. AppRole logins
+    /// issue a new token each time, and only the latest one is accepted.
+    async fn transit(unavailable: u32) -> HttpStandIn {
+        let unavailable = AtomicU32::new(unavailable);
+        let logins = AtomicU32::new(0);
+        HttpStandIn::start(move |request: &Request| {
+            if unavailable
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                return (503, json!({"errors": ["Vault is sealed"]}).to_string());
+            }
+            let body: Value = serde_json::from_str(&request.body).unwrap();
+            if request.path == "/v1/auth/approle/login" {
+                if body["role_id"] != "role" || body["secret_id"] != "secret" {
+                    return (
+                        400,
+                        json!({"errors": ["invalid role or secret ID"]}).to_string(),
+                    );
+                }
+                let login = logins.fetch_add(1, Ordering::SeqCst) + 1;
+                let auth =
+                    json!({"client_token": format!("approle-{login}"), "lease_duration": 3600});
+                return (200, json!({"auth": auth}).to_string());
+            }
+
+            let token = request.headers.get("x-vault-token").map(String::as_str);
+            let latest = format!("approle-{}", logins.load(Ordering::SeqCst));
+            if token != Some("root") && token != Some(&latest) {
+                return (403, json!({"errors": ["permission denied"]}).to_string());
+            }
+            match request.path.as_str() {
+                "/v1/transit/encrypt/kek" => {
+                    let version = body["key_version"].as_u64().unwrap_or(2);
+                    let plaintext = body["plaintext"].as_str().unwrap();
+                    let data = json!({"ciphertext": format!("vault:v{version}:{plaintext}")});
+                    (200, json!({"data": data}).to_string())
+                }
+                "/v1/transit/decrypt/kek" => {
+                    let ciphertext = body["ciphertext"].as_str().unwrap();
+                    match ciphertext
+                        .split_once("vault:v1:")
+                        .or(ciphertext.split_once("vault:v2:"))
+                    {
+                        Some(("", plaintext)) => {
+                            (200, json!({"data": {"plaintext": plaintext}}).to_string())
+                        }
+                        _ => (400, json!({"errors": ["invalid ciphertext"]}).to_string()),
+                    }
+                }
+                _ => (404, json!({"errors": []}).to_string()),
+            }
+        })
+        .await
+    }
+
+    fn config(address: &str, auth: VaultAuth) -> VaultTransitConfig {
+        VaultTransitConfig {
+            address: address.to_string(),
+            mount: "transit".to_string(),
+            key_name: "kek".to_string(),
+            key_version: None,
+            namespace: None,
+            ca_certificate: None,
+            auth,
+            retries: 0,
+        }
+    }
+
+    fn app_role(secret_id: &str) -> VaultAuth {
+        VaultAuth::AppRole {
+            mount: "approle".to_string(),
+            role_id: "role".to_string(),
+            secret_id: secret_id.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_token_auth() {
+        let vault = transit(0).await;
+        let sut = VaultTransitCipher::new(VaultTransitConfig {
+            namespace: Some("team".to_string()),
+            ..config(&vault.address, VaultAuth::Token("root".to_string()))
+        })
+        .unwrap();
+
+        let ciphertext = sut.encrypt(b"test data").await.unwrap();
+        assert!(ciphertext.starts_with(b"vault:v2:"));
+        assert_eq!(sut.decrypt(&ciphertext).await.unwrap(), b"test data");
+        assert!(sut.decrypt(b"vault:v3:AAAA").await.is_err());
+
+        let request = vault.requests.lock().unwrap()[0].clone();
+        assert_eq!(request.headers["x-vault-namespace"], "team");
+
+        let sut = VaultTransitCipher::new(VaultTransitConfig {
+            key_version: Some(1),
+            ..config(&vault.address, VaultAuth::Token("root".to_string()))
+        })
+        .unwrap();
+        let ciphertext = sut.encrypt(b"test data").await.unwrap();
+        assert!(ciphertext.starts_with(b"vault:v1:"));
+
+        let sut =
+            VaultTransitCipher::new(config(&vault.address, VaultAuth::Token("bad".to_string())))
+                .unwrap();
+        assert!(sut.encrypt(b"test data").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_app_role() {
+        let vault = transit(0).await;
+        let sut = VaultTransitCipher::new(config(&vault.address, app_role("secret"))).unwrap();
+        let ciphertext = sut.encrypt(b"test data").await.unwrap();
+        assert_eq!(sut.decrypt(&ciphertext).await.unwrap(), b"test data");
+
+        // another login revokes the cached token, which makes the cipher log in again
+        let other = VaultTransitCipher::new(config(&vault.address, app_role("secret"))).unwrap();
+        other.encrypt(b"test data").await.unwrap();
+        assert_eq!(sut.decrypt(&ciphertext).await.unwrap(), b"test data");
+        assert_eq!(
+            vault.paths(),
+            [
+                "/v1/auth/approle/login",
+                "/v1/transit/encrypt/kek",
+                "/v1/transit/decrypt/kek",
+                "/v1/auth/approle/login",
+                "/v1/transit/encrypt/kek",
+                "/v1/transit/decrypt/kek",
+                "/v1/auth/approle/login",
+                "/v1/transit/decrypt/kek",
+            ]
+        );
+
+        let sut = VaultTransitCipher::new(config(&vault.address, app_role("wrong"))).unwrap();
+        assert!(matches!(
+            sut.encrypt(b"test data").await,
+            Err(Error::Vault(e)) if e.ends_with("400 Bad Request: invalid role or secret ID")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_retries() {
+        let vault = transit(2).await;
+        let sut = VaultTransitCipher::new(VaultTransitConfig {
+            retries: 2,
+            ..config(&vault.address, VaultAuth::Token("root".to_string()))
+        })
+        .unwrap();
+        sut.encrypt(b"test data").await.unwrap();
+        assert_eq!(vault.paths().len(), 3);
+
+        let vault = transit(2).await;
+        let sut = VaultTransitCipher::new(VaultTransitConfig {
+            retries: 1,
+            ..config(&vault.address, VaultAuth::Token("root".to_string()))
+        })
+        .unwrap();
+        assert!(sut.encrypt(b"test data").await.is_err());
+
+        // client errors are not retried
+        let sut = VaultTransitCipher::new(VaultTransitConfig {
+            retries: 3,
+            ..config(&vault.address, VaultAuth::Token("root".to_string()))
+        })
+        .unwrap();
+        let before = vault.paths().len();
+        assert!(sut.decrypt(b"invalid").await.is_err());
+        assert_eq!(vault.paths().len(), before + 1);
+    }
+}
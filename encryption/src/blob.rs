@@ -0,0 +1,92 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+//! Self-contained ciphertexts, specified in `docs/ciphertext-blob.md`.
+
+use crate::envelope::Envelope;
+use crate::{Ciphertext, Error};
+
+const MAGIC: [u8; 4] = *b"KGM\x01";
+
+impl Ciphertext {
+    /// Encodes the wrapped DEK and the ciphertext into one blob.
+    pub fn to_blob(&self) -> Result<Vec<u8>, Error> {
+        let length = u16::try_from(self.dek.len()).map_err(|_| Error::InvalidDek("too long"))?;
+        let mut blob = Vec::with_capacity(MAGIC.len() + 2 + self.dek.len() + self.ciphertext.len());
+        blob.extend_from_slice(&MAGIC);
+        blob.extend_from_slice(&length.to_be_bytes());
+        blob.extend_from_slice(&self.dek);
+        blob.extend_from_slice(&self.ciphertext);
+        Ok(blob)
+    }
+
+    /// Returns whether `data` looks like a blob made by [`Ciphertext::to_blob`].
+    pub fn is_blob(data: &[u8]) -> bool {
+        data.starts_with(&MAGIC)
+    }
+
+    pub fn from_blob(blob: &[u8]) -> Result<Self, Error> {
+        let rest = blob
+            .strip_prefix(&MAGIC)
+            .ok_or(Error::InvalidDek("not a ciphertext blob"))?;
+        let [high, low, rest @ ..] = rest else {
+            return Err(Error::InvalidDek("truncated blob"));
+        };
+        let length = u16::from_be_bytes([*high, *low]) as usize;
+        if rest.len() < length {
+            return Err(Error::InvalidDek("truncated blob"));
+        }
+        let (dek, ciphertext) = rest.split_at(length);
+        let envelope = Envelope::decode(dek)?;
+        Ok(Ciphertext {
+            ciphertext: ciphertext.to_vec(),
+            dek: dek.to_vec(),
+            key_id: envelope.kek_id.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blob() {
+        let dek = vec![
+            0x02, 0x01, 0x00, 0x02, 0x00, 0x01, 0x02, 0x00, 0x10, 0x00, 0x11, 0x22, 0x33, 0x44,
+            0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff, 0x06, 0x00, 0x01,
+            0x2a,
+        ];
+        let ciphertext = Ciphertext {
+            ciphertext: b"ciphertext".to_vec(),
+            dek: dek.clone(),
+            key_id: "00112233-4455-6677-8899-aabbccddeeff".to_string(),
+        };
+
+        let blob = ciphertext.to_blob().unwrap();
+        assert!(Ciphertext::is_blob(&blob));
+        assert_eq!(blob[..6], [b'K', b'G', b'M', 0x01, 0x00, 29]);
+        assert_eq!(blob[6..35], dek);
+        assert_eq!(&blob[35..], b"ciphertext");
+
+        let decoded = Ciphertext::from_blob(&blob).unwrap();
+        assert_eq!(decoded.dek, dek);
+        assert_eq!(decoded.ciphertext, b"ciphertext");
+        assert_eq!(decoded.key_id, ciphertext.key_id);
+
+        assert!(Ciphertext::from_blob(b"ciphertext").is_err());
+        assert!(Ciphertext::from_blob(&blob[..20]).is_err());
+    }
+}
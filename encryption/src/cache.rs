@@ -0,0 +1,134 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use crate::KeyAlgorithm;
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use telemetry::metrics::metrics;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/// Limits of caching unwrapped DEKs for decryption.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DekCache {
+    pub capacity: NonZeroUsize,
+    pub ttl: Duration,
+}
+
+/// Unwrapped DEKs keyed by the SHA-256 hash of their wrapped form.
+///
+/// Keys are zeroized when they are evicted, expire or are purged.
+pub(crate) struct UnwrappedDeks {
+    ttl: Duration,
+    entries: Mutex<LruCache<[u8; 32], Entry>>,
+}
+
+pub(crate) struct UnwrappedDek {
+    pub algorithm: KeyAlgorithm,
+    pub key: Zeroizing<Vec<u8>>,
+}
+
+struct Entry {
+    kek_id: Uuid,
+    dek: UnwrappedDek,
+    inserted_at: Instant,
+}
+
+pub(crate) fn hash(wrapped_dek: &[u8]) -> [u8; 32] {
+    Sha256::digest(wrapped_dek).into()
+}
+
+impl UnwrappedDeks {
+    pub(crate) fn new(limits: DekCache) -> Self {
+        Self {
+            ttl: limits.ttl,
+            entries: Mutex::new(LruCache::new(limits.capacity)),
+        }
+    }
+
+    /// Returns a cached DEK with the ID of the key which wrapped it.
+    pub(crate) fn get(&self, hash: &[u8; 32]) -> Option<(Uuid, UnwrappedDek)> {
+        let mut entries = self.entries.lock().unwrap();
+        let found = match entries.get(hash) {
+            Some(entry) if entry.inserted_at.elapsed() < self.ttl => Some((
+                entry.kek_id,
+                UnwrappedDek {
+                    algorithm: entry.dek.algorithm,
+                    key: entry.dek.key.clone(),
+                },
+            )),
+            Some(_) => {
+                entries.pop(hash);
+                metrics().record_dek_cache_eviction("expired", 1);
+                None
+            }
+            None => None,
+        };
+        metrics().record_dek_cache_lookup(found.is_some());
+        metrics().set_dek_cache_entries(entries.len());
+        found
+    }
+
+    /// Caches `dek` unless `is_current` returns false, which is checked under the lock
+    /// so that a [`UnwrappedDeks::purge`] after replacing the keyring cannot be missed.
+    pub(crate) fn insert(
+        &self,
+        hash: [u8; 32],
+        kek_id: Uuid,
+        dek: UnwrappedDek,
+        is_current: impl FnOnce() -> bool,
+    ) {
+        let mut entries = self.entries.lock().unwrap();
+        if !is_current() {
+            return;
+        }
+        let entry = Entry {
+            kek_id,
+            dek,
+            inserted_at: Instant::now(),
+        };
+        // `push` also returns the replaced entry of the same hash, which is not an eviction
+        if entries
+            .push(hash, entry)
+            .is_some_and(|(evicted, _)| evicted != hash)
+        {
+            metrics().record_dek_cache_eviction("capacity", 1);
+        }
+        metrics().set_dek_cache_entries(entries.len());
+    }
+
+    /// Removes DEKs wrapped with any of `kek_ids`, which can no longer be unwrapped.
+    pub(crate) fn purge(&self, kek_ids: &[Uuid]) {
+        let mut entries = self.entries.lock().unwrap();
+        let purged: Vec<_> = entries
+            .iter()
+            .filter(|(_, entry)| kek_ids.contains(&entry.kek_id))
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in &purged {
+            entries.pop(hash);
+        }
+        metrics().record_dek_cache_eviction("purged", purged.len() as u64);
+        metrics().set_dek_cache_entries(entries.len());
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
@@ -0,0 +1,318 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+//! Encoding of wrapped DEKs, specified in `docs/wrapped-dek.md`.
+//!
+//! Version 2 is a version byte followed by tag-length-value fields.
+//! Version 1 is the former layout without a version byte, which is only decoded.
+
+use crate::{Error, KeyAlgorithm};
+use chrono::{DateTime, Utc};
+use ciphers::oneof::OneOfCipher;
+use uuid::Uuid;
+
+const VERSION: u8 = 2;
+
+const TAG_DEK_ALGORITHM: u8 = 0x01;
+const TAG_KEK_ID: u8 = 0x02;
+const TAG_KEK_ALGORITHM: u8 = 0x03;
+const TAG_CREATED_AT: u8 = 0x04;
+const TAG_CONTEXT_HASH: u8 = 0x05;
+const TAG_WRAPPED_KEY: u8 = 0x06;
+/// Fields with tags from this one may be ignored by decoders which do not know them.
+const TAG_OPTIONAL: u8 = 0x80;
+
+/// A DEK wrapped with a KEK, with what is needed to unwrap it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Envelope {
+    pub version: u8,
+    pub dek_algorithm: KeyAlgorithm,
+    pub kek_id: Uuid,
+    /// Absent in version 1.
+    pub kek_algorithm: Option<u16>,
+    /// Absent in version 1.
+    pub created_at: Option<DateTime<Utc>>,
+    /// SHA-256 hash of the context the DEK is bound to, if any.
+    pub context_hash: Option<[u8; 32]>,
+    /// The DEK encrypted with the KEK.
+    pub wrapped_key: Vec<u8>,
+}
+
+/// Returns the ID of the algorithm of a KEK.
+pub(crate) fn kek_algorithm(cipher: &OneOfCipher) -> u16 {
+    match cipher {
+        OneOfCipher::Unencrypted(_) => 0x0000,
+        OneOfCipher::ChaCha20Poly1305(_) => 0x0001,
+        OneOfCipher::AesGcmSiv(_) => 0x0002,
+        OneOfCipher::Pkcs11(_) => 0x0101,
+        OneOfCipher::VaultTransit(_) => 0x0102,
+        OneOfCipher::AwsKms(_) => 0x0103,
+    }
+}
+
+impl Envelope {
+    pub(crate) fn new(
+        dek_algorithm: KeyAlgorithm,
+        kek_id: Uuid,
+        kek: &OneOfCipher,
+        wrapped_key: Vec<u8>,
+    ) -> Self {
+        Self {
+            version: VERSION,
+            dek_algorithm,
+            kek_id,
+            kek_algorithm: Some(kek_algorithm(kek)),
+            created_at: Some(Utc::now()),
+            context_hash: None,
+            wrapped_key,
+        }
+    }
+
+    /// Encodes this envelope in version 2.
+    pub(crate) fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut encoded = vec![VERSION];
+        put(&mut encoded, TAG_DEK_ALGORITHM, &self.dek_algorithm.id())?;
+        put(&mut encoded, TAG_KEK_ID, self.kek_id.as_bytes())?;
+        if let Some(kek_algorithm) = self.kek_algorithm {
+            put(
+                &mut encoded,
+                TAG_KEK_ALGORITHM,
+                &kek_algorithm.to_be_bytes(),
+            )?;
+        }
+        if let Some(created_at) = self.created_at {
+            let seconds = created_at.timestamp().to_be_bytes();
+            put(&mut encoded, TAG_CREATED_AT, &seconds)?;
+        }
+        if let Some(context_hash) = &self.context_hash {
+            put(&mut encoded, TAG_CONTEXT_HASH, context_hash)?;
+        }
+        put(&mut encoded, TAG_WRAPPED_KEY, &self.wrapped_key)?;
+        Ok(encoded)
+    }
+
+    pub(crate) fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        match bytes.first() {
+            Some(&VERSION) => Self::decode_v2(&bytes[1..]),
+            // the algorithm IDs of version 1 start with a zero byte
+            Some(0x00) => Self::decode_v1(bytes),
+            Some(_) => Err(Error::InvalidDek("unsupported version")),
+            None => Err(Error::InvalidDek("empty")),
+        }
+    }
+
+    fn decode_v1(bytes: &[u8]) -> Result<Self, Error> {
+        let dek_algorithm = KeyAlgorithm::try_from(bytes)?;
+        let kek_id = bytes
+            .get(2..18)
+            .and_then(|id| Uuid::from_slice_le(id).ok())
+            .ok_or(Error::InvalidDek("truncated KEK ID"))?;
+        Ok(Self {
+            version: 1,
+            dek_algorithm,
+            kek_id,
+            kek_algorithm: None,
+            created_at: None,
+            context_hash: None,
+            wrapped_key: bytes[18..].to_vec(),
+        })
+    }
+
+    fn decode_v2(mut bytes: &[u8]) -> Result<Self, Error> {
+        let mut dek_algorithm = None;
+        let mut kek_id = None;
+        let mut kek_algorithm = None;
+        let mut created_at = None;
+        let mut context_hash = None;
+        let mut wrapped_key = None;
+        let mut last_tag = None;
+
+        while !bytes.is_empty() {
+            let (tag, value, rest) = take(bytes)?;
+            if last_tag.is_some_and(|last| tag <= last) {
+                return Err(Error::InvalidDek("fields out of order"));
+            }
+            last_tag = Some(tag);
+            bytes = rest;
+
+            match tag {
+                TAG_DEK_ALGORITHM if value.len() == 2 => {
+                    dek_algorithm = Some(KeyAlgorithm::try_from(value)?)
+                }
+                TAG_DEK_ALGORITHM => return Err(Error::InvalidDek("invalid DEK algorithm")),
+                TAG_KEK_ID => {
+                    kek_id = Some(
+                        Uuid::from_slice(value).map_err(|_| Error::InvalidDek("invalid KEK ID"))?,
+                    )
+                }
+                TAG_KEK_ALGORITHM => {
+                    let value = <[u8; 2]>::try_from(value)
+                        .map_err(|_| Error::InvalidDek("invalid KEK algorithm"))?;
+                    kek_algorithm = Some(u16::from_be_bytes(value));
+                }
+                TAG_CREATED_AT => {
+                    let value = <[u8; 8]>::try_from(value)
+                        .map_err(|_| Error::InvalidDek("invalid creation time"))?;
+                    created_at = Some(
+                        DateTime::from_timestamp(i64::from_be_bytes(value), 0)
+                            .ok_or(Error::InvalidDek("invalid creation time"))?,
+                    );
+                }
+                TAG_CONTEXT_HASH => {
+                    context_hash = Some(
+                        <[u8; 32]>::try_from(value)
+                            .map_err(|_| Error::InvalidDek("invalid context hash"))?,
+                    );
+                }
+                TAG_WRAPPED_KEY => wrapped_key = Some(value.to_vec()),
+                TAG_OPTIONAL.. => {}
+                _ => return Err(Error::InvalidDek("unknown required field")),
+            }
+        }
+
+        Ok(Self {
+            version: VERSION,
+            dek_algorithm: dek_algorithm.ok_or(Error::InvalidDek("missing DEK algorithm"))?,
+            kek_id: kek_id.ok_or(Error::InvalidDek("missing KEK ID"))?,
+            kek_algorithm,
+            created_at,
+            context_hash,
+            wrapped_key: wrapped_key.ok_or(Error::InvalidDek("missing wrapped key"))?,
+        })
+    }
+}
+
+fn put(encoded: &mut Vec<u8>, tag: u8, value: &[u8]) -> Result<(), Error> {
+    let length = u16::try_from(value.len()).map_err(|_| Error::InvalidDek("field too long"))?;
+    encoded.push(tag);
+    encoded.extend_from_slice(&length.to_be_bytes());
+    encoded.extend_from_slice(value);
+    Ok(())
+}
+
+/// Splits a field off `bytes`, returning its tag, value and the remaining bytes.
+fn take(bytes: &[u8]) -> Result<(u8, &[u8], &[u8]), Error> {
+    let [tag, high, low, rest @ ..] = bytes else {
+        return Err(Error::InvalidDek("truncated field header"));
+    };
+    let length = u16::from_be_bytes([*high, *low]) as usize;
+    if rest.len() < length {
+        return Err(Error::InvalidDek("truncated field"));
+    }
+    let (value, rest) = rest.split_at(length);
+    Ok((*tag, value, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_hex(hex: &str) -> Vec<u8> {
+        let hex: String = hex.split_whitespace().collect();
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    const KEK_ID: Uuid = Uuid::from_u128(0x00112233_4455_6677_8899_aabbccddeeff);
+
+    // golden vectors listed in docs/wrapped-dek.md
+    const V2: &str = "02
+        01 0002 0001
+        02 0010 00112233445566778899aabbccddeeff
+        03 0002 0102
+        04 0008 0000000065920080
+        06 0004 deadbeef";
+    const V2_WITH_CONTEXT: &str = "02
+        01 0002 0002
+        02 0010 00112233445566778899aabbccddeeff
+        03 0002 0000
+        04 0008 0000000065920080
+        05 0020 e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855
+        06 0004 deadbeef
+        80 0003 010203";
+    const V1: &str = "0002 33221100554477668899aabbccddeeff deadbeef";
+
+    fn envelope() -> Envelope {
+        Envelope {
+            version: 2,
+            dek_algorithm: KeyAlgorithm::ChaCha20Poly1305,
+            kek_id: KEK_ID,
+            kek_algorithm: Some(0x0102),
+            created_at: DateTime::from_timestamp(1704067200, 0),
+            context_hash: None,
+            wrapped_key: vec![0xde, 0xad, 0xbe, 0xef],
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(envelope().encode().unwrap(), decode_hex(V2));
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(Envelope::decode(&decode_hex(V2)).unwrap(), envelope());
+
+        let decoded = Envelope::decode(&decode_hex(V2_WITH_CONTEXT)).unwrap();
+        assert!(matches!(decoded.dek_algorithm, KeyAlgorithm::AesGcmSiv));
+        assert_eq!(decoded.kek_algorithm, Some(0x0000));
+        assert_eq!(decoded.context_hash.unwrap()[..2], [0xe3, 0xb0]);
+        assert_eq!(decoded.wrapped_key, [0xde, 0xad, 0xbe, 0xef]);
+    }
+
+    #[test]
+    fn test_decode_v1() {
+        let decoded = Envelope::decode(&decode_hex(V1)).unwrap();
+        assert_eq!(
+            decoded,
+            Envelope {
+                version: 1,
+                dek_algorithm: KeyAlgorithm::AesGcmSiv,
+                kek_id: KEK_ID,
+                kek_algorithm: None,
+                created_at: None,
+                context_hash: None,
+                wrapped_key: vec![0xde, 0xad, 0xbe, 0xef],
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        for hex in [
+            "",
+            "03",
+            "0003 33221100554477668899aabbccddeeff",
+            "0001 ffeeddcc",
+            "02 01 0002 0001 02 0010 00112233",
+            // missing wrapped key
+            "02 01 0002 0001 02 0010 00112233445566778899aabbccddeeff",
+            // fields out of order
+            "02 02 0010 00112233445566778899aabbccddeeff 01 0002 0001 06 0000",
+            // unknown required field
+            "02 01 0002 0001 02 0010 00112233445566778899aabbccddeeff 06 0000 07 0000",
+        ] {
+            assert!(
+                matches!(
+                    Envelope::decode(&decode_hex(hex)),
+                    Err(Error::InvalidDek(_) | Error::UnsupportedAlgorithm)
+                ),
+                "{hex}"
+            );
+        }
+    }
+}
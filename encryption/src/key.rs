@@ -13,25 +13,43 @@
 // You should have received a copy of the GNU General Public License along with Kagimori.
 // If not, see <https://www.gnu.org/licenses/>.
 
+use crate::cache::UnwrappedDek;
+use crate::envelope::{Envelope, kek_algorithm};
 use crate::{Encryptor, Error, KeyAlgorithm};
 use ciphers::Cipher;
 use ciphers::aesgcmsiv::AesGcmSivCipher;
 use ciphers::chacha20poly1305::ChaCha20Poly1305Cipher;
 use ciphers::oneof::OneOfCipher;
+use ciphers::rotatable::{DEFAULT_KEY_NAME, RotatableCipher};
+use telemetry::metrics::metrics;
+use tracing::{Instrument, info_span};
+use uuid::Uuid;
+use zeroize::Zeroizing;
 
 impl KeyAlgorithm {
-    fn id(self) -> [u8; 2] {
+    pub(crate) fn id(self) -> [u8; 2] {
         match self {
             KeyAlgorithm::ChaCha20Poly1305 => [0x00, 0x01],
             KeyAlgorithm::AesGcmSiv => [0x00, 0x02],
         }
     }
+
+    pub(crate) fn cipher(self, key: &[u8]) -> Result<OneOfCipher, Error> {
+        match self {
+            KeyAlgorithm::ChaCha20Poly1305 => Ok(OneOfCipher::ChaCha20Poly1305(
+                ChaCha20Poly1305Cipher::try_from(key).map_err(|_| Error::UnsupportedAlgorithm)?,
+            )),
+            KeyAlgorithm::AesGcmSiv => Ok(OneOfCipher::AesGcmSiv(
+                AesGcmSivCipher::try_from(key).map_err(|_| Error::UnsupportedAlgorithm)?,
+            )),
+        }
+    }
 }
 
 impl TryFrom<&[u8]> for KeyAlgorithm {
     type Error = Error;
     fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
-        match bytes[..2] {
+        match bytes.get(..2).ok_or(Error::UnsupportedAlgorithm)? {
             [0x00, 0x01] => Ok(KeyAlgorithm::ChaCha20Poly1305),
             [0x00, 0x02] => Ok(KeyAlgorithm::AesGcmSiv),
             _ => Err(Error::UnsupportedAlgorithm),
@@ -40,34 +58,97 @@ impl TryFrom<&[u8]> for KeyAlgorithm {
 }
 
 impl<L> Encryptor<L> {
-    pub(crate) async fn create_cipher(&self) -> Result<(OneOfCipher, Vec<u8>), Error> {
+    pub(crate) async fn create_cipher(
+        &self,
+        kek_id: Uuid,
+        kek: &OneOfCipher,
+    ) -> Result<(OneOfCipher, Vec<u8>), Error> {
+        let span = info_span!("dek.generate", algorithm = ?self.algorithm);
+        self.create_cipher_impl(kek_id, kek).instrument(span).await
+    }
+
+    async fn create_cipher_impl(
+        &self,
+        kek_id: Uuid,
+        kek: &OneOfCipher,
+    ) -> Result<(OneOfCipher, Vec<u8>), Error> {
         let cipher = match self.algorithm {
             KeyAlgorithm::AesGcmSiv => OneOfCipher::AesGcmSiv(AesGcmSivCipher::default()),
             KeyAlgorithm::ChaCha20Poly1305 => {
                 OneOfCipher::ChaCha20Poly1305(ChaCha20Poly1305Cipher::default())
             }
         };
-        let mut dek = self.algorithm.id().to_vec();
-        dek.extend(
-            self.kek
-                .encrypt(cipher.key())
-                .await
-                .map_err(Error::Encryption)?,
-        );
+        let dek = wrap(kek_id, kek, self.algorithm, cipher.key())
+            .await?
+            .encode()?;
+        metrics().record_dek_generation(&kek_id.to_string());
+        self.usage.record(kek_id);
 
         Ok((cipher, dek))
     }
 
-    pub(crate) fn extract_cipher(&self, dek: &[u8]) -> Result<OneOfCipher, Error> {
-        let algorithm: KeyAlgorithm = dek[..2].try_into()?;
-        match algorithm {
-            KeyAlgorithm::ChaCha20Poly1305 => Ok(OneOfCipher::ChaCha20Poly1305(
-                ChaCha20Poly1305Cipher::try_from(&dek[2..])
-                    .map_err(|_| Error::UnsupportedAlgorithm)?,
-            )),
-            KeyAlgorithm::AesGcmSiv => Ok(OneOfCipher::AesGcmSiv(
-                AesGcmSivCipher::try_from(&dek[2..]).map_err(|_| Error::UnsupportedAlgorithm)?,
-            )),
+    /// Unwraps a DEK, returning it with its decoded envelope.
+    pub(crate) async fn unwrap_dek(&self, dek: &[u8]) -> Result<(Envelope, UnwrappedDek), Error> {
+        let envelope = Envelope::decode(dek)?;
+        let kek_id = envelope.kek_id;
+        let keyring = self.kek.load_full().ok_or(Error::Sealed)?;
+        let kek = decryption_key(&keyring, kek_id)?;
+        if envelope
+            .kek_algorithm
+            .is_some_and(|algorithm| algorithm != kek_algorithm(kek))
+        {
+            return Err(Error::InvalidDek("KEK algorithm mismatch"));
         }
+        let key = kek
+            .decrypt(&envelope.wrapped_key)
+            .instrument(info_span!("kek.unwrap", %kek_id))
+            .await
+            .map_err(Error::Decryption)?;
+        let unwrapped = UnwrappedDek {
+            algorithm: envelope.dek_algorithm,
+            key: Zeroizing::new(key),
+        };
+        Ok((envelope, unwrapped))
+    }
+}
+
+/// Returns the key `kek_id` if its lifecycle state allows decryption.
+pub(crate) fn decryption_key(kek: &RotatableCipher, kek_id: Uuid) -> Result<&OneOfCipher, Error> {
+    let state = kek.status(&kek_id).state;
+    if !state.allows_decryption() {
+        return Err(Error::KeyNotUsable(kek_id, state));
     }
+    kek.get(&kek_id)
+        .ok_or(Error::Decryption(ciphers::Error::KeyNotFound(kek_id)))
+}
+
+/// Returns the primary version of the named key, or of the default key if `name` is not given.
+pub(crate) fn primary_key<'a>(
+    kek: &'a RotatableCipher,
+    name: Option<&str>,
+) -> Result<(Uuid, &'a OneOfCipher), Error> {
+    let name = name.unwrap_or(DEFAULT_KEY_NAME);
+    let (id, cipher) = kek
+        .primary_key(name)
+        .ok_or_else(|| Error::KeyNameNotFound(name.to_string()))?;
+    let state = kek.status(&id).state;
+    if !state.allows_encryption() {
+        return Err(Error::KeyNotUsable(id, state));
+    }
+    Ok((id, cipher))
+}
+
+/// Wraps `key` with the KEK `cipher` of `kek_id`.
+pub(crate) async fn wrap(
+    kek_id: Uuid,
+    cipher: &OneOfCipher,
+    algorithm: KeyAlgorithm,
+    key: &[u8],
+) -> Result<Envelope, Error> {
+    let wrapped_key = cipher
+        .encrypt(key)
+        .instrument(info_span!("kek.wrap", %kek_id))
+        .await
+        .map_err(Error::Encryption)?;
+    Ok(Envelope::new(algorithm, kek_id, cipher, wrapped_key))
 }
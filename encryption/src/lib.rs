@@ -13,12 +13,35 @@
 // You should have received a copy of the GNU General Public License along with Kagimori.
 // If not, see <https://www.gnu.org/licenses/>.
 
+mod blob;
+mod cache;
+mod envelope;
 mod key;
+mod reuse;
+mod stream;
+mod usage;
 
-use audit_log::{Action, AuditLog, AuditLogger, DecryptionAction, EncryptionAction};
-use chrono::Utc;
+pub use crate::cache::DekCache;
+use crate::cache::UnwrappedDeks;
+use crate::envelope::Envelope;
+use crate::reuse::ReusedDek;
+pub use crate::reuse::{DekReuse, MAX_DEK_REUSE_ENCRYPTIONS};
+pub use crate::stream::{DecryptionStream, EncryptionStream};
+use crate::usage::KeyUsage;
+use arc_swap::ArcSwapOption;
+use audit_log::{
+    Action, AuditLog, AuditLogger, DataKeyDecryptionAction, DataKeyGenerationAction,
+    DecryptionAction, EncryptionAction, RewrapAction,
+};
+use chrono::{DateTime, Utc};
 use ciphers::Cipher;
-use ciphers::rotatable::RotatableCipher;
+use ciphers::oneof::OneOfCipher;
+pub use ciphers::rotatable::{KeyState, KeyStatus, RotationPolicy, RotationReason};
+use ciphers::rotatable::{NamedKey, RotatableCipher};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use telemetry::metrics::metrics;
+use tracing::{Instrument, info_span};
 use uuid::Uuid;
 
 #[derive(Debug)]
@@ -26,9 +49,21 @@ pub enum Error {
     UnsupportedAlgorithm,
     Encryption(ciphers::Error),
     Decryption(ciphers::Error),
+    /// A new keyring lacks keys of the current one.
+    KeysRemoved(Vec<Uuid>),
+    /// No keyring is loaded until unsealed.
+    Sealed,
+    /// The wrapped DEK is malformed.
+    InvalidDek(&'static str),
+    /// The header or the segments of a stream are malformed.
+    InvalidStream(&'static str),
+    /// No named key has the name.
+    KeyNameNotFound(String),
+    /// The lifecycle state of the key does not allow the use.
+    KeyNotUsable(Uuid, KeyState),
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum KeyAlgorithm {
     ChaCha20Poly1305,
     AesGcmSiv,
@@ -37,8 +72,10 @@ pub enum KeyAlgorithm {
 pub struct Encryptor<L> {
     audit_logger: L,
     algorithm: KeyAlgorithm,
-    kek_id: String,
-    kek: RotatableCipher,
+    kek: Arc<ArcSwapOption<RotatableCipher>>,
+    reused_dek: Option<Arc<ReusedDek>>,
+    unwrapped_deks: Option<Arc<UnwrappedDeks>>,
+    usage: Arc<KeyUsage>,
 }
 
 impl<L> Clone for Encryptor<L>
@@ -49,8 +86,10 @@ where
         Self {
             audit_logger: self.audit_logger.clone(),
             algorithm: self.algorithm,
-            kek_id: self.kek_id.clone(),
             kek: self.kek.clone(),
+            reused_dek: self.reused_dek.clone(),
+            unwrapped_deks: self.unwrapped_deks.clone(),
+            usage: self.usage.clone(),
         }
     }
 }
@@ -59,19 +98,47 @@ impl<L> Encryptor<L>
 where
     L: AuditLogger,
 {
-    pub fn new(
-        audit_logger: L,
-        algorithm: KeyAlgorithm,
-        kek_id: String,
-        kek: RotatableCipher,
-    ) -> Self {
+    pub fn new(audit_logger: L, algorithm: KeyAlgorithm, kek: RotatableCipher) -> Self {
+        metrics().set_loaded_keys(kek.key_count());
+        let usage = Arc::new(KeyUsage::default());
+        restore_usage(&usage, &kek);
+        Self {
+            audit_logger,
+            algorithm,
+            kek: Arc::new(ArcSwapOption::from_pointee(kek)),
+            reused_dek: None,
+            unwrapped_deks: None,
+            usage,
+        }
+    }
+
+    /// Creates an encryptor without a keyring, which refuses to encrypt and decrypt
+    /// until one is given by [`Encryptor::replace_kek`].
+    pub fn sealed(audit_logger: L, algorithm: KeyAlgorithm) -> Self {
+        metrics().set_loaded_keys(0);
         Self {
             audit_logger,
             algorithm,
-            kek_id,
-            kek,
+            kek: Arc::new(ArcSwapOption::empty()),
+            reused_dek: None,
+            unwrapped_deks: None,
+            usage: Arc::default(),
         }
     }
+
+    /// Reuses a DEK for encryptions within `limits` instead of generating and wrapping
+    /// one per encryption, as KMS v2 allows. Each ciphertext still carries the wrapped DEK.
+    pub fn with_dek_reuse(mut self, limits: DekReuse) -> Self {
+        self.reused_dek = Some(Arc::new(ReusedDek::new(limits)));
+        self
+    }
+
+    /// Caches unwrapped DEKs within `limits` so that decrypting data sharing a DEK
+    /// does not unwrap it with the master key every time.
+    pub fn with_dek_cache(mut self, limits: DekCache) -> Self {
+        self.unwrapped_deks = Some(Arc::new(UnwrappedDeks::new(limits)));
+        self
+    }
 }
 
 pub struct Ciphertext {
@@ -80,16 +147,175 @@ pub struct Ciphertext {
     pub key_id: String,
 }
 
+/// A DEK wrapped with the master key of `key_id`.
+pub struct WrappedDek {
+    pub dek: Vec<u8>,
+    pub key_id: String,
+}
+
+/// A DEK for encryption by the caller.
+pub struct DataKey {
+    /// The plaintext DEK, if requested.
+    pub plaintext: Option<Vec<u8>>,
+    pub dek: Vec<u8>,
+    pub key_id: String,
+    pub algorithm: &'static str,
+}
+
 pub struct RequestInfo {
     pub event_id: String,
     pub service: String,
     pub user: String,
     pub data_key: Option<String>,
+    pub trace_id: Option<String>,
+    /// Name of the key to encrypt with, or the default key if not given.
+    pub key_name: Option<String>,
+}
+
+/// Difference between the replaced keyring and the new one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyringChange {
+    pub default_key_id: String,
+    pub added: Vec<Uuid>,
+    pub removed: Vec<Uuid>,
 }
 
 impl<L> Encryptor<L> {
+    pub fn is_sealed(&self) -> bool {
+        self.kek.load().is_none()
+    }
+
     pub fn contains_key(&self, key_id: &Uuid) -> bool {
-        self.kek.contains_key(key_id)
+        self.kek
+            .load()
+            .as_ref()
+            .is_some_and(|kek| kek.contains_key(key_id))
+    }
+
+    pub fn key_ids(&self) -> Vec<Uuid> {
+        self.kek
+            .load()
+            .as_ref()
+            .map(|kek| kek.key_ids().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the named keys of the keyring, including the default one.
+    pub fn named_keys(&self) -> HashMap<String, NamedKey> {
+        self.kek
+            .load()
+            .as_ref()
+            .map(|kek| kek.named_keys().clone())
+            .unwrap_or_default()
+    }
+
+    /// Returns the lifecycle states of all keys, including destroyed ones.
+    pub fn key_statuses(&self) -> HashMap<Uuid, KeyStatus> {
+        self.kek
+            .load()
+            .as_ref()
+            .map(|kek| kek.key_statuses())
+            .unwrap_or_default()
+    }
+
+    /// Returns the number of encryptions with the key, which are the DEKs wrapped with it,
+    /// by this and all cloned encryptors.
+    pub fn encryption_count(&self, kek_id: &Uuid) -> u64 {
+        self.usage.encryptions(kek_id)
+    }
+
+    /// Returns the rotation policies of named keys, including the default one.
+    pub fn rotation_policies(&self) -> HashMap<String, RotationPolicy> {
+        self.kek
+            .load()
+            .as_ref()
+            .map(|kek| kek.rotation_policies().clone())
+            .unwrap_or_default()
+    }
+
+    /// Returns the names of keys whose primary version is due for rotation at `now`
+    /// by their policies, with the reasons.
+    pub fn rotations_due(&self, now: DateTime<Utc>) -> Vec<(String, RotationReason)> {
+        let Some(kek) = self.kek.load_full() else {
+            return Vec::new();
+        };
+        let mut due: Vec<_> = kek
+            .rotation_policies()
+            .iter()
+            .filter_map(|(name, policy)| {
+                let primary = kek.named_keys().get(name)?.primary;
+                let reason = policy.due(now, self.usage.encryptions(&primary))?;
+                Some((name.clone(), reason))
+            })
+            .collect();
+        due.sort_by(|a, b| a.0.cmp(&b.0));
+        due
+    }
+
+    /// Returns the name of the key which wrapped `dek`,
+    /// or `None` if sealed or the DEK is malformed.
+    pub fn key_name_of(&self, dek: &[u8]) -> Option<String> {
+        let envelope = Envelope::decode(dek).ok()?;
+        let kek = self.kek.load_full()?;
+        Some(kek.key_name_of(&envelope.kek_id).to_string())
+    }
+
+    /// Replaces the keyring used by this and all cloned encryptors.
+    ///
+    /// Data encrypted with a removed key can no longer be decrypted, so a keyring
+    /// lacking any current key is rejected unless `allow_key_removal` is set
+    /// or the key is recorded as destroyed.
+    /// Requests in flight keep using the keyring they started with.
+    /// Concurrent calls must be serialized by the caller.
+    pub fn replace_kek(
+        &self,
+        kek: RotatableCipher,
+        allow_key_removal: bool,
+    ) -> Result<KeyringChange, Error> {
+        let old: BTreeSet<_> = self.key_ids().into_iter().collect();
+        let new: BTreeSet<_> = kek.key_ids().copied().collect();
+        let change = KeyringChange {
+            default_key_id: kek.default_key_id(),
+            added: new.difference(&old).copied().collect(),
+            removed: old.difference(&new).copied().collect(),
+        };
+        let unrecorded: Vec<_> = change
+            .removed
+            .iter()
+            .copied()
+            .filter(|id| kek.status(id).state != KeyState::Destroyed)
+            .collect();
+        if !unrecorded.is_empty() && !allow_key_removal {
+            return Err(Error::KeysRemoved(unrecorded));
+        }
+
+        // DEKs unwrapped by keys which no longer decrypt must not be used either
+        let mut unusable = change.removed.clone();
+        unusable.extend(
+            kek.key_ids()
+                .filter(|id| !kek.status(id).state.allows_decryption()),
+        );
+
+        metrics().set_loaded_keys(kek.key_count());
+        restore_usage(&self.usage, &kek);
+        self.kek.store(Some(Arc::new(kek)));
+        if let Some(reused_dek) = &self.reused_dek {
+            reused_dek.clear();
+        }
+        if let Some(unwrapped_deks) = &self.unwrapped_deks {
+            unwrapped_deks.purge(&unusable);
+        }
+        self.usage.purge(&change.removed);
+        Ok(change)
+    }
+}
+
+/// Counts the encryptions with primary versions saved with the keyring.
+fn restore_usage(usage: &KeyUsage, kek: &RotatableCipher) {
+    for (name, policy) in kek.rotation_policies() {
+        if let Some(key) = kek.named_keys().get(name) {
+            usage.restore(key.primary, policy.saved_encryptions);
+        }
     }
 }
 
@@ -97,13 +323,31 @@ impl<L> Encryptor<L>
 where
     L: AuditLogger,
 {
-    pub fn get_key_id(&self) -> String {
-        self.kek_id.clone()
+    /// Returns the ID of the default key, or `None` if sealed.
+    pub fn get_key_id(&self) -> Option<String> {
+        self.kek.load().as_ref().map(|kek| kek.default_key_id())
     }
 
     pub async fn encrypt(&self, request: RequestInfo, data: &[u8]) -> Result<Ciphertext, Error> {
-        let (cipher, dek) = self.create_cipher().await?;
+        let kek = self.kek.load_full().ok_or(Error::Sealed)?;
+        let (primary_id, primary) = key::primary_key(&kek, request.key_name.as_deref())?;
+        let kek_id = primary_id.to_string();
+        let reused = self
+            .reused_dek
+            .as_ref()
+            .and_then(|reused_dek| reused_dek.get(&kek_id));
+        let (cipher, dek) = match reused {
+            Some(reused) => reused,
+            None => {
+                let (cipher, dek) = self.create_cipher(primary_id, primary).await?;
+                if let Some(reused_dek) = &self.reused_dek {
+                    reused_dek.set(kek_id.clone(), cipher.clone(), dek.clone());
+                }
+                (cipher, dek)
+            }
+        };
         let ciphertext = cipher.encrypt(data).await.map_err(Error::Encryption)?;
+        metrics().record_encryption(cipher.name(), &kek_id);
 
         self.audit_logger
             .log(AuditLog {
@@ -111,31 +355,62 @@ where
                 event_id: request.event_id,
                 service: request.service,
                 user: request.user,
+                trace_id: request.trace_id,
                 action: Action::Encryption(EncryptionAction {
                     data_key: request.data_key,
                     algorithm: cipher.name().to_string(),
                 }),
             })
+            .instrument(info_span!("audit.log"))
             .await;
 
         Ok(Ciphertext {
             ciphertext,
             dek,
-            key_id: self.get_key_id(),
+            key_id: kek_id,
         })
     }
 
+    /// Unwraps `dek`, returning the ID of the key which unwrapped it with the cipher.
+    async fn extract_cipher(&self, dek: &[u8]) -> Result<(Uuid, OneOfCipher), Error> {
+        let Some(unwrapped_deks) = &self.unwrapped_deks else {
+            let (envelope, unwrapped) = self.unwrap_dek(dek).await?;
+            return Ok((envelope.kek_id, unwrapped.algorithm.cipher(&unwrapped.key)?));
+        };
+
+        let hash = cache::hash(dek);
+        let keyring = self.kek.load_full().ok_or(Error::Sealed)?;
+        if let Some((kek_id, unwrapped)) = unwrapped_deks.get(&hash) {
+            // the key may have been disabled since the DEK was cached
+            key::decryption_key(&keyring, kek_id)?;
+            return Ok((kek_id, unwrapped.algorithm.cipher(&unwrapped.key)?));
+        }
+        let (envelope, unwrapped) = self.unwrap_dek(dek).await?;
+        let cipher = unwrapped.algorithm.cipher(&unwrapped.key)?;
+        let kek_id = envelope.kek_id;
+        // a keyring replaced while unwrapping may no longer allow the key to decrypt
+        unwrapped_deks.insert(hash, kek_id, unwrapped, || {
+            self.kek
+                .load()
+                .as_ref()
+                .is_some_and(|current| Arc::ptr_eq(current, &keyring))
+        });
+        Ok((kek_id, cipher))
+    }
+
     pub async fn decrypt(
         &self,
         request: RequestInfo,
         ciphertext: Ciphertext,
     ) -> Result<Vec<u8>, Error> {
-        let cipher = self.extract_cipher(&ciphertext.dek)?;
+        // the key ID of a ciphertext is given by the client, so not trusted
+        let (kek_id, cipher) = self.extract_cipher(&ciphertext.dek).await?;
 
         let plaintext = cipher
             .decrypt(&ciphertext.ciphertext)
             .await
             .map_err(Error::Decryption)?;
+        metrics().record_decryption(cipher.name(), &kek_id.to_string());
 
         self.audit_logger
             .log(AuditLog {
@@ -143,13 +418,693 @@ where
                 event_id: request.event_id,
                 service: request.service,
                 user: request.user,
+                trace_id: request.trace_id,
                 action: Action::Decryption(DecryptionAction {
                     data_key: request.data_key,
                     algorithm: cipher.name().to_string(),
                 }),
             })
+            .instrument(info_span!("audit.log"))
             .await;
 
         Ok(plaintext)
     }
+
+    /// Starts encrypting data given in chunks with a new DEK.
+    pub async fn encrypt_stream(
+        &self,
+        request: RequestInfo,
+    ) -> Result<(WrappedDek, EncryptionStream), Error> {
+        let kek = self.kek.load_full().ok_or(Error::Sealed)?;
+        let (primary_id, primary) = key::primary_key(&kek, request.key_name.as_deref())?;
+        let kek_id = primary_id.to_string();
+        let (cipher, dek) = self.create_cipher(primary_id, primary).await?;
+        let stream = EncryptionStream::new(&cipher)?;
+        metrics().record_encryption(cipher.name(), &kek_id);
+
+        self.audit_logger
+            .log(AuditLog {
+                timestamp: Utc::now(),
+                event_id: request.event_id,
+                service: request.service,
+                user: request.user,
+                trace_id: request.trace_id,
+                action: Action::Encryption(EncryptionAction {
+                    data_key: request.data_key,
+                    algorithm: cipher.name().to_string(),
+                }),
+            })
+            .instrument(info_span!("audit.log"))
+            .await;
+
+        Ok((
+            WrappedDek {
+                dek,
+                key_id: kek_id,
+            },
+            stream,
+        ))
+    }
+
+    /// Starts decrypting a ciphertext made by [`Encryptor::encrypt_stream`].
+    pub async fn decrypt_stream(
+        &self,
+        request: RequestInfo,
+        dek: &WrappedDek,
+        header: &[u8],
+    ) -> Result<DecryptionStream, Error> {
+        let (kek_id, cipher) = self.extract_cipher(&dek.dek).await?;
+        let stream = DecryptionStream::new(&cipher, header)?;
+        metrics().record_decryption(cipher.name(), &kek_id.to_string());
+
+        self.audit_logger
+            .log(AuditLog {
+                timestamp: Utc::now(),
+                event_id: request.event_id,
+                service: request.service,
+                user: request.user,
+                trace_id: request.trace_id,
+                action: Action::Decryption(DecryptionAction {
+                    data_key: request.data_key,
+                    algorithm: cipher.name().to_string(),
+                }),
+            })
+            .instrument(info_span!("audit.log"))
+            .await;
+
+        Ok(stream)
+    }
+
+    /// Generates a DEK for the caller to encrypt data with,
+    /// returning the plaintext DEK only if `with_plaintext` is set.
+    pub async fn generate_data_key(
+        &self,
+        request: RequestInfo,
+        with_plaintext: bool,
+    ) -> Result<DataKey, Error> {
+        let kek = self.kek.load_full().ok_or(Error::Sealed)?;
+        let (primary_id, primary) = key::primary_key(&kek, request.key_name.as_deref())?;
+        let kek_id = primary_id.to_string();
+        let (cipher, dek) = self.create_cipher(primary_id, primary).await?;
+
+        self.audit_logger
+            .log(AuditLog {
+                timestamp: Utc::now(),
+                event_id: request.event_id,
+                service: request.service,
+                user: request.user,
+                trace_id: request.trace_id,
+                action: Action::DataKeyGeneration(DataKeyGenerationAction {
+                    algorithm: cipher.name().to_string(),
+                    key_id: kek_id.clone(),
+                    with_plaintext,
+                }),
+            })
+            .instrument(info_span!("audit.log"))
+            .await;
+
+        Ok(DataKey {
+            plaintext: with_plaintext.then(|| cipher.key().to_vec()),
+            dek,
+            key_id: kek_id,
+            algorithm: cipher.name(),
+        })
+    }
+
+    /// Unwraps a DEK made by [`Encryptor::generate_data_key`].
+    pub async fn decrypt_data_key(
+        &self,
+        request: RequestInfo,
+        dek: &[u8],
+    ) -> Result<DataKey, Error> {
+        let (envelope, unwrapped) = self.unwrap_dek(dek).await?;
+        let algorithm = unwrapped.algorithm.cipher(&unwrapped.key)?.name();
+        let key_id = envelope.kek_id.to_string();
+
+        self.audit_logger
+            .log(AuditLog {
+                timestamp: Utc::now(),
+                event_id: request.event_id,
+                service: request.service,
+                user: request.user,
+                trace_id: request.trace_id,
+                action: Action::DataKeyDecryption(DataKeyDecryptionAction {
+                    algorithm: algorithm.to_string(),
+                    key_id: key_id.clone(),
+                }),
+            })
+            .instrument(info_span!("audit.log"))
+            .await;
+
+        Ok(DataKey {
+            plaintext: Some(unwrapped.key.to_vec()),
+            dek: dek.to_vec(),
+            key_id,
+            algorithm,
+        })
+    }
+
+    /// Wraps a DEK again with the primary version of the named key, or of the key
+    /// which wrapped it if not given, without touching the data encrypted with it.
+    pub async fn rewrap(&self, request: RequestInfo, dek: &[u8]) -> Result<WrappedDek, Error> {
+        let kek = self.kek.load_full().ok_or(Error::Sealed)?;
+        let (old, unwrapped) = self.unwrap_dek(dek).await?;
+        let key_name = request
+            .key_name
+            .as_deref()
+            .unwrap_or_else(|| kek.key_name_of(&old.kek_id));
+        let (primary_id, primary) = key::primary_key(&kek, Some(key_name))?;
+        let new = key::wrap(primary_id, primary, unwrapped.algorithm, &unwrapped.key).await?;
+        self.usage.record(primary_id);
+        let rewrapped = Envelope {
+            created_at: old.created_at.or(new.created_at),
+            context_hash: old.context_hash,
+            ..new
+        };
+        let dek = rewrapped.encode()?;
+
+        self.audit_logger
+            .log(AuditLog {
+                timestamp: Utc::now(),
+                event_id: request.event_id,
+                service: request.service,
+                user: request.user,
+                trace_id: request.trace_id,
+                action: Action::Rewrap(RewrapAction {
+                    data_key: request.data_key,
+                    old_key_id: old.kek_id.to_string(),
+                    new_key_id: rewrapped.kek_id.to_string(),
+                }),
+            })
+            .instrument(info_span!("audit.log"))
+            .await;
+
+        Ok(WrappedDek {
+            dek,
+            key_id: rewrapped.kek_id.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::UnwrappedDek;
+    use async_trait::async_trait;
+    use ciphers::Unencrypted;
+    use ciphers::chacha20poly1305::ChaCha20Poly1305Cipher;
+    use ciphers::oneof::OneOfCipher;
+    use std::collections::HashMap;
+    use std::num::NonZeroUsize;
+    use std::time::Duration;
+
+    #[derive(Clone)]
+    struct NopAuditLogger;
+
+    #[async_trait]
+    impl AuditLogger for NopAuditLogger {
+        async fn log(&self, _log: AuditLog) {}
+    }
+
+    fn request_info() -> RequestInfo {
+        RequestInfo {
+            event_id: "event".to_string(),
+            service: "test".to_string(),
+            user: "user".to_string(),
+            data_key: None,
+            trace_id: None,
+            key_name: None,
+        }
+    }
+
+    fn create_sut() -> Encryptor<NopAuditLogger> {
+        let id = Uuid::new_v4();
+        let kek = RotatableCipher::new(
+            id,
+            HashMap::from([(id, OneOfCipher::Unencrypted(Unencrypted))]),
+        )
+        .unwrap();
+        Encryptor::new(NopAuditLogger, KeyAlgorithm::ChaCha20Poly1305, kek)
+    }
+
+    fn keyring(default: Uuid, ids: &[Uuid]) -> RotatableCipher {
+        RotatableCipher::new(
+            default,
+            ids.iter()
+                .map(|id| (*id, OneOfCipher::Unencrypted(Unencrypted)))
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_decrypt() {
+        let sut = create_sut();
+        let plaintext = b"Hello, world!";
+
+        let ciphertext = sut.encrypt(request_info(), plaintext).await.unwrap();
+        let decrypted = sut.decrypt(request_info(), ciphertext).await.unwrap();
+
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[tokio::test]
+    async fn test_decryption_metric_ignores_claimed_key_id() {
+        let sut = create_sut();
+        let kek_id = sut.get_key_id().unwrap();
+        let mut ciphertext = sut.encrypt(request_info(), b"data").await.unwrap();
+        ciphertext.key_id = "claimed-key-id".to_string();
+
+        sut.decrypt(request_info(), ciphertext).await.unwrap();
+        let encoded = metrics().encode();
+        assert!(!encoded.contains("claimed-key-id"));
+        assert!(encoded.contains(&format!("kek_id=\"{kek_id}\"")));
+    }
+
+    #[tokio::test]
+    async fn test_replace_kek() {
+        let sut = create_sut();
+        let old_id: Uuid = sut.get_key_id().unwrap().parse().unwrap();
+        let clone = sut.clone();
+        let ciphertext = sut.encrypt(request_info(), b"old").await.unwrap();
+
+        let new_id = Uuid::new_v4();
+        let change = sut
+            .replace_kek(keyring(new_id, &[old_id, new_id]), false)
+            .unwrap();
+        assert_eq!(change.added, vec![new_id]);
+        assert!(change.removed.is_empty());
+        assert_eq!(clone.get_key_id(), Some(new_id.to_string()));
+
+        let decrypted = clone.decrypt(request_info(), ciphertext).await.unwrap();
+        assert_eq!(decrypted, b"old");
+        let ciphertext = clone.encrypt(request_info(), b"new").await.unwrap();
+        assert_eq!(ciphertext.key_id, new_id.to_string());
+    }
+
+    #[test]
+    fn test_replace_kek_rejects_key_removal() {
+        let sut = create_sut();
+        let old_id: Uuid = sut.get_key_id().unwrap().parse().unwrap();
+        let new_id = Uuid::new_v4();
+
+        let result = sut.replace_kek(keyring(new_id, &[new_id]), false);
+        assert!(matches!(result, Err(Error::KeysRemoved(ids)) if ids == vec![old_id]));
+        assert_eq!(sut.get_key_id(), Some(old_id.to_string()));
+
+        let change = sut.replace_kek(keyring(new_id, &[new_id]), true).unwrap();
+        assert_eq!(change.removed, vec![old_id]);
+        assert_eq!(sut.get_key_id(), Some(new_id.to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_named_keys() {
+        let sut = create_sut();
+        let default_id: Uuid = sut.get_key_id().unwrap().parse().unwrap();
+        let (old_id, new_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let named_key = |primary| NamedKey {
+            primary,
+            versions: vec![old_id, new_id],
+        };
+        let named_keyring = |primary| {
+            keyring(default_id, &[default_id, old_id, new_id])
+                .with_named_keys(HashMap::from([(
+                    "payments/pii".to_string(),
+                    named_key(primary),
+                )]))
+                .unwrap()
+        };
+        sut.replace_kek(named_keyring(old_id), false).unwrap();
+        assert_eq!(sut.named_keys()["payments/pii"], named_key(old_id));
+
+        let request = || RequestInfo {
+            key_name: Some("payments/pii".to_string()),
+            ..request_info()
+        };
+        let ciphertext = sut.encrypt(request(), b"data").await.unwrap();
+        assert_eq!(ciphertext.key_id, old_id.to_string());
+        assert_eq!(
+            sut.key_name_of(&ciphertext.dek).as_deref(),
+            Some("payments/pii")
+        );
+        let default = sut.encrypt(request_info(), b"data").await.unwrap();
+        assert_eq!(default.key_id, default_id.to_string());
+        assert_eq!(sut.key_name_of(&default.dek).as_deref(), Some("default"));
+
+        let result = sut
+            .encrypt(
+                RequestInfo {
+                    key_name: Some("missing".to_string()),
+                    ..request_info()
+                },
+                b"data",
+            )
+            .await;
+        assert!(matches!(result, Err(Error::KeyNameNotFound(name)) if name == "missing"));
+
+        // the primary version changes, and the old one still decrypts
+        sut.replace_kek(named_keyring(new_id), false).unwrap();
+        let rewrapped = sut.rewrap(request_info(), &ciphertext.dek).await.unwrap();
+        assert_eq!(rewrapped.key_id, new_id.to_string());
+        let decrypted = sut.decrypt(request_info(), ciphertext).await.unwrap();
+        assert_eq!(decrypted, b"data");
+    }
+
+    #[tokio::test]
+    async fn test_rewrap() {
+        let sut = create_sut();
+        let old_id: Uuid = sut.get_key_id().unwrap().parse().unwrap();
+        let ciphertext = sut.encrypt(request_info(), b"data").await.unwrap();
+        let new_id = Uuid::new_v4();
+        sut.replace_kek(keyring(new_id, &[old_id, new_id]), false)
+            .unwrap();
+
+        let rewrapped = sut.rewrap(request_info(), &ciphertext.dek).await.unwrap();
+        assert_eq!(rewrapped.key_id, new_id.to_string());
+        let old = Envelope::decode(&ciphertext.dek).unwrap();
+        let new = Envelope::decode(&rewrapped.dek).unwrap();
+        assert_eq!(new.kek_id, new_id);
+        assert_eq!(new.created_at, old.created_at);
+
+        // the data can be decrypted without the old key
+        sut.replace_kek(keyring(new_id, &[new_id]), true).unwrap();
+        let decrypted = sut
+            .decrypt(
+                request_info(),
+                Ciphertext {
+                    ciphertext: ciphertext.ciphertext,
+                    dek: rewrapped.dek,
+                    key_id: rewrapped.key_id,
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(decrypted, b"data");
+        assert!(matches!(
+            sut.rewrap(request_info(), &ciphertext.dek).await,
+            Err(Error::Decryption(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_data_key() {
+        let sut = create_sut();
+        let generated = sut.generate_data_key(request_info(), true).await.unwrap();
+        let plaintext = generated.plaintext.unwrap();
+        assert_eq!(plaintext.len(), ChaCha20Poly1305Cipher::KEY_SIZE);
+        assert_eq!(generated.algorithm, "ChaCha20-Poly1305");
+        assert_eq!(generated.key_id, sut.get_key_id().unwrap());
+
+        let decrypted = sut
+            .decrypt_data_key(request_info(), &generated.dek)
+            .await
+            .unwrap();
+        assert_eq!(decrypted.plaintext.unwrap(), plaintext);
+        assert_eq!(decrypted.key_id, generated.key_id);
+        assert_eq!(decrypted.algorithm, generated.algorithm);
+
+        let generated = sut.generate_data_key(request_info(), false).await.unwrap();
+        assert!(generated.plaintext.is_none());
+        let decrypted = sut
+            .decrypt_data_key(request_info(), &generated.dek)
+            .await
+            .unwrap();
+        assert_eq!(
+            decrypted.plaintext.unwrap().len(),
+            ChaCha20Poly1305Cipher::KEY_SIZE
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let sut = create_sut();
+        let data = vec![0x2a; 100_000];
+        let (dek, mut encryption) = sut.encrypt_stream(request_info()).await.unwrap();
+        let header = encryption.header();
+        let mut ciphertext = encryption.update(&data).unwrap();
+        ciphertext.extend(encryption.finish().unwrap());
+
+        let mut decryption = sut
+            .decrypt_stream(request_info(), &dek, &header)
+            .await
+            .unwrap();
+        let mut plaintext = decryption.update(&ciphertext).unwrap();
+        plaintext.extend(decryption.finish().unwrap());
+        assert_eq!(plaintext, data);
+    }
+
+    #[tokio::test]
+    async fn test_decrypt_v1_dek() {
+        let sut = create_sut();
+        let kek_id: Uuid = sut.get_key_id().unwrap().parse().unwrap();
+        let cipher = ChaCha20Poly1305Cipher::default();
+        let ciphertext = cipher.encrypt(b"legacy").await.unwrap();
+
+        // algorithm ID, little endian KEK ID and the key wrapped with the unencrypted KEK
+        let mut dek = vec![0x00, 0x01];
+        dek.extend(kek_id.to_bytes_le());
+        dek.extend(cipher.key());
+        let decrypted = sut
+            .decrypt(
+                request_info(),
+                Ciphertext {
+                    ciphertext,
+                    dek,
+                    key_id: kek_id.to_string(),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(decrypted, b"legacy");
+    }
+
+    #[tokio::test]
+    async fn test_dek_reuse() {
+        let sut = create_sut().with_dek_reuse(DekReuse {
+            max_encryptions: 2,
+            max_age: Duration::from_secs(3600),
+        });
+        let first = sut.encrypt(request_info(), b"first").await.unwrap();
+        let second = sut.encrypt(request_info(), b"second").await.unwrap();
+        let third = sut.encrypt(request_info(), b"third").await.unwrap();
+        assert_eq!(first.dek, second.dek);
+        assert_ne!(second.dek, third.dek);
+        assert_ne!(first.ciphertext, second.ciphertext);
+        for (ciphertext, plaintext) in [(first, "first"), (second, "second"), (third, "third")] {
+            let decrypted = sut.decrypt(request_info(), ciphertext).await.unwrap();
+            assert_eq!(decrypted, plaintext.as_bytes());
+        }
+
+        // a new keyring discards the DEK wrapped with the old one
+        let dek = sut.encrypt(request_info(), b"data").await.unwrap().dek;
+        let id = Uuid::new_v4();
+        sut.replace_kek(keyring(id, &[id]), true).unwrap();
+        let ciphertext = sut.encrypt(request_info(), b"data").await.unwrap();
+        assert_ne!(ciphertext.dek, dek);
+        assert_eq!(ciphertext.key_id, id.to_string());
+
+        let sut = create_sut().with_dek_reuse(DekReuse {
+            max_encryptions: 100,
+            max_age: Duration::ZERO,
+        });
+        let first = sut.encrypt(request_info(), b"data").await.unwrap();
+        let second = sut.encrypt(request_info(), b"data").await.unwrap();
+        assert_ne!(first.dek, second.dek);
+    }
+
+    #[tokio::test]
+    async fn test_key_states() {
+        let sut = create_sut().with_dek_cache(DekCache {
+            capacity: NonZeroUsize::new(2).unwrap(),
+            ttl: Duration::from_secs(3600),
+        });
+        let old_id: Uuid = sut.get_key_id().unwrap().parse().unwrap();
+        let new_id = Uuid::new_v4();
+        let ciphertext = sut.encrypt(request_info(), b"data").await.unwrap();
+        let decrypt = || {
+            sut.decrypt(
+                request_info(),
+                Ciphertext {
+                    ciphertext: ciphertext.ciphertext.clone(),
+                    dek: ciphertext.dek.clone(),
+                    key_id: ciphertext.key_id.clone(),
+                },
+            )
+        };
+        let with_state = |ids: &[Uuid], state| {
+            keyring(new_id, ids)
+                .with_key_statuses(HashMap::from([(
+                    old_id,
+                    KeyStatus {
+                        state,
+                        ..Default::default()
+                    },
+                )]))
+                .unwrap()
+        };
+
+        sut.replace_kek(with_state(&[old_id, new_id], KeyState::DecryptOnly), false)
+            .unwrap();
+        assert_eq!(decrypt().await.unwrap(), b"data");
+        assert_eq!(sut.key_statuses()[&old_id].state, KeyState::DecryptOnly);
+        let cached = sut.unwrapped_deks.clone().unwrap();
+        let hash = cache::hash(&ciphertext.dek);
+        let (_, unwrapped) = cached.get(&hash).unwrap();
+
+        // the cached DEK is not used once the key is disabled
+        sut.replace_kek(with_state(&[old_id, new_id], KeyState::Disabled), false)
+            .unwrap();
+        assert!(matches!(
+            decrypt().await,
+            Err(Error::KeyNotUsable(id, KeyState::Disabled)) if id == old_id
+        ));
+        // an unwrap finishing after the keyring was replaced does not cache the DEK
+        let dek = || UnwrappedDek {
+            algorithm: unwrapped.algorithm,
+            key: unwrapped.key.clone(),
+        };
+        cached.insert(hash, old_id, dek(), || false);
+        assert_eq!(cached.len(), 0);
+        // nor is a DEK cached before the key was disabled used
+        cached.insert(hash, old_id, dek(), || true);
+        assert!(matches!(
+            decrypt().await,
+            Err(Error::KeyNotUsable(id, KeyState::Disabled)) if id == old_id
+        ));
+
+        sut.replace_kek(with_state(&[new_id], KeyState::Destroyed), false)
+            .unwrap();
+        assert!(matches!(
+            decrypt().await,
+            Err(Error::KeyNotUsable(id, KeyState::Destroyed)) if id == old_id
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rotations_due() {
+        let sut = create_sut();
+        let old_id: Uuid = sut.get_key_id().unwrap().parse().unwrap();
+        let new_id = Uuid::new_v4();
+        let with_policy = |default, ids: &[Uuid]| {
+            keyring(default, ids)
+                .with_rotation_policies(HashMap::from([(
+                    "default".to_string(),
+                    RotationPolicy {
+                        max_age: None,
+                        max_encryptions: Some(2),
+                        rotated_at: Some(Utc::now()),
+                        saved_encryptions: 0,
+                    },
+                )]))
+                .unwrap()
+        };
+        sut.replace_kek(with_policy(old_id, &[old_id]), false)
+            .unwrap();
+
+        sut.encrypt(request_info(), b"data").await.unwrap();
+        assert!(sut.rotations_due(Utc::now()).is_empty());
+        sut.generate_data_key(request_info(), false).await.unwrap();
+        assert_eq!(sut.encryption_count(&old_id), 2);
+        assert_eq!(
+            sut.rotations_due(Utc::now()),
+            vec![("default".to_string(), RotationReason::MaxEncryptions)]
+        );
+
+        sut.replace_kek(with_policy(new_id, &[old_id, new_id]), false)
+            .unwrap();
+        assert!(sut.rotations_due(Utc::now()).is_empty());
+        assert_eq!(sut.rotation_policies()["default"].max_encryptions, Some(2));
+
+        // encryptions saved with the keyring count, once
+        let saved = |saved_encryptions| {
+            keyring(new_id, &[old_id, new_id])
+                .with_rotation_policies(HashMap::from([(
+                    "default".to_string(),
+                    RotationPolicy {
+                        max_age: None,
+                        max_encryptions: Some(2),
+                        rotated_at: Some(Utc::now()),
+                        saved_encryptions,
+                    },
+                )]))
+                .unwrap()
+        };
+        sut.encrypt(request_info(), b"data").await.unwrap();
+        sut.replace_kek(saved(1), false).unwrap();
+        assert_eq!(sut.encryption_count(&new_id), 1);
+        sut.replace_kek(saved(2), false).unwrap();
+        assert_eq!(sut.encryption_count(&new_id), 2);
+        assert_eq!(
+            sut.rotations_due(Utc::now()),
+            vec![("default".to_string(), RotationReason::MaxEncryptions)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dek_cache() {
+        let sut = create_sut().with_dek_cache(DekCache {
+            capacity: NonZeroUsize::new(2).unwrap(),
+            ttl: Duration::from_secs(3600),
+        });
+        let cached = sut.unwrapped_deks.clone().unwrap();
+        let first = sut.encrypt(request_info(), b"first").await.unwrap();
+        let decrypt = |ciphertext: &Ciphertext| {
+            sut.decrypt(
+                request_info(),
+                Ciphertext {
+                    ciphertext: ciphertext.ciphertext.clone(),
+                    dek: ciphertext.dek.clone(),
+                    key_id: ciphertext.key_id.clone(),
+                },
+            )
+        };
+        assert_eq!(decrypt(&first).await.unwrap(), b"first");
+        assert_eq!(decrypt(&first).await.unwrap(), b"first");
+        assert_eq!(cached.len(), 1);
+
+        // the least recently used DEK is evicted
+        for data in [b"second", b"third_"] {
+            let ciphertext = sut.encrypt(request_info(), data).await.unwrap();
+            assert_eq!(decrypt(&ciphertext).await.unwrap(), data);
+        }
+        assert_eq!(cached.len(), 2);
+        assert!(cached.get(&cache::hash(&first.dek)).is_none());
+
+        // DEKs wrapped with a removed key are purged
+        let id = Uuid::new_v4();
+        sut.replace_kek(keyring(id, &[id]), true).unwrap();
+        assert_eq!(cached.len(), 0);
+        assert!(matches!(decrypt(&first).await, Err(Error::Decryption(_))));
+
+        let sut = create_sut().with_dek_cache(DekCache {
+            capacity: NonZeroUsize::new(2).unwrap(),
+            ttl: Duration::ZERO,
+        });
+        let cached = sut.unwrapped_deks.clone().unwrap();
+        let ciphertext = sut.encrypt(request_info(), b"data").await.unwrap();
+        let hash = cache::hash(&ciphertext.dek);
+        assert_eq!(
+            sut.decrypt(request_info(), ciphertext).await.unwrap(),
+            b"data"
+        );
+        assert!(cached.get(&hash).is_none());
+        assert_eq!(cached.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_sealed() {
+        let sut = Encryptor::sealed(NopAuditLogger, KeyAlgorithm::ChaCha20Poly1305);
+        assert!(sut.is_sealed());
+        assert_eq!(sut.get_key_id(), None);
+        assert!(matches!(
+            sut.encrypt(request_info(), b"data").await,
+            Err(Error::Sealed)
+        ));
+
+        let id = Uuid::new_v4();
+        sut.replace_kek(keyring(id, &[id]), false).unwrap();
+        assert!(!sut.is_sealed());
+        let ciphertext = sut.encrypt(request_info(), b"data").await.unwrap();
+        assert_eq!(ciphertext.key_id, id.to_string());
+    }
 }
@@ -0,0 +1,81 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use ciphers::oneof::OneOfCipher;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Upper bound of [`DekReuse::max_encryptions`]. DEK ciphers use random 96-bit nonces,
+/// which must not be drawn more than 2^32 times under one key.
+pub const MAX_DEK_REUSE_ENCRYPTIONS: u64 = 1 << 32;
+
+/// Limits of reusing a DEK for encryption, after which a new DEK is generated and wrapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DekReuse {
+    pub max_encryptions: u64,
+    pub max_age: Duration,
+}
+
+/// The DEK currently reused for encryption.
+pub(crate) struct ReusedDek {
+    limits: DekReuse,
+    current: Mutex<Option<CachedDek>>,
+}
+
+struct CachedDek {
+    kek_id: String,
+    cipher: OneOfCipher,
+    dek: Vec<u8>,
+    created_at: Instant,
+    encryptions: u64,
+}
+
+impl ReusedDek {
+    pub(crate) fn new(mut limits: DekReuse) -> Self {
+        limits.max_encryptions = limits.max_encryptions.min(MAX_DEK_REUSE_ENCRYPTIONS);
+        Self {
+            limits,
+            current: Mutex::new(None),
+        }
+    }
+
+    /// Returns the cipher and the wrapped DEK if the DEK is wrapped with `kek_id`
+    /// and within the limits, counting an encryption with it.
+    pub(crate) fn get(&self, kek_id: &str) -> Option<(OneOfCipher, Vec<u8>)> {
+        let mut current = self.current.lock().unwrap();
+        let cached = current.as_mut().filter(|cached| {
+            cached.kek_id == kek_id
+                && cached.encryptions < self.limits.max_encryptions
+                && cached.created_at.elapsed() < self.limits.max_age
+        })?;
+        cached.encryptions += 1;
+        Some((cached.cipher.clone(), cached.dek.clone()))
+    }
+
+    /// Reuses a new DEK, which has been used for an encryption.
+    pub(crate) fn set(&self, kek_id: String, cipher: OneOfCipher, dek: Vec<u8>) {
+        *self.current.lock().unwrap() = Some(CachedDek {
+            kek_id,
+            cipher,
+            dek,
+            created_at: Instant::now(),
+            encryptions: 1,
+        });
+    }
+
+    pub(crate) fn clear(&self) {
+        *self.current.lock().unwrap() = None;
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+//! Encryption of data larger than a message in segments of a STREAM construction.
+//!
+//! The ciphertext is the concatenation of the sealed segments. Every segment except the last
+//! holds `segment_size` bytes of plaintext, so segment boundaries do not depend on how the
+//! ciphertext is chunked in transport. The header records the segment size and the nonce prefix.
+
+use crate::Error;
+use ciphers::oneof::OneOfCipher;
+use ciphers::stream::{StreamCipher, TAG_SIZE};
+
+const HEADER_VERSION: u8 = 1;
+/// Size of plaintext segments of new streams.
+const SEGMENT_SIZE: u32 = 64 * 1024;
+/// Largest segment size accepted from a header, bounding the memory of a stream.
+const MAX_SEGMENT_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Encrypts data given in chunks of any size.
+pub struct EncryptionStream {
+    cipher: StreamCipher,
+    segment_size: usize,
+    counter: u32,
+    buffer: Vec<u8>,
+}
+
+/// Decrypts a ciphertext given in chunks of any size, verifying each segment.
+pub struct DecryptionStream {
+    cipher: StreamCipher,
+    segment_size: usize,
+    counter: u32,
+    buffer: Vec<u8>,
+}
+
+impl EncryptionStream {
+    pub(crate) fn new(dek: &OneOfCipher) -> Result<Self, Error> {
+        Ok(Self {
+            cipher: StreamCipher::new(dek).map_err(Error::Encryption)?,
+            segment_size: SEGMENT_SIZE as usize,
+            counter: 0,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Returns the header needed to decrypt the stream.
+    pub fn header(&self) -> Vec<u8> {
+        let mut header = vec![HEADER_VERSION];
+        header.extend_from_slice(&(self.segment_size as u32).to_be_bytes());
+        header.extend_from_slice(self.cipher.prefix());
+        header
+    }
+
+    /// Encrypts the next chunk, returning the segments completed by it.
+    pub fn update(&mut self, chunk: &[u8]) -> Result<Vec<u8>, Error> {
+        self.buffer.extend_from_slice(chunk);
+        let mut sealed = Vec::new();
+        // a full segment is kept until more data arrives, since it may be the last one
+        while self.buffer.len() > self.segment_size {
+            let segment: Vec<_> = self.buffer.drain(..self.segment_size).collect();
+            sealed.extend(self.seal(false, &segment)?);
+        }
+        Ok(sealed)
+    }
+
+    /// Encrypts the rest as the last segment.
+    pub fn finish(mut self) -> Result<Vec<u8>, Error> {
+        let segment = std::mem::take(&mut self.buffer);
+        self.seal(true, &segment)
+    }
+
+    fn seal(&mut self, last: bool, segment: &[u8]) -> Result<Vec<u8>, Error> {
+        let sealed = self
+            .cipher
+            .seal(self.counter, last, segment)
+            .map_err(Error::Encryption)?;
+        self.counter = next(self.counter)?;
+        Ok(sealed)
+    }
+}
+
+impl DecryptionStream {
+    pub(crate) fn new(dek: &OneOfCipher, header: &[u8]) -> Result<Self, Error> {
+        let [HEADER_VERSION, a, b, c, d, prefix @ ..] = header else {
+            return Err(Error::InvalidStream("unsupported header"));
+        };
+        let segment_size = u32::from_be_bytes([*a, *b, *c, *d]);
+        if segment_size == 0 || segment_size > MAX_SEGMENT_SIZE {
+            return Err(Error::InvalidStream("invalid segment size"));
+        }
+        Ok(Self {
+            cipher: StreamCipher::with_prefix(dek, prefix).map_err(Error::Decryption)?,
+            segment_size: segment_size as usize,
+            counter: 0,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Decrypts the next chunk, returning the plaintext of the segments completed by it.
+    pub fn update(&mut self, chunk: &[u8]) -> Result<Vec<u8>, Error> {
+        self.buffer.extend_from_slice(chunk);
+        let sealed_size = self.segment_size + TAG_SIZE;
+        let mut plaintext = Vec::new();
+        while self.buffer.len() > sealed_size {
+            let segment: Vec<_> = self.buffer.drain(..sealed_size).collect();
+            plaintext.extend(self.open(false, &segment)?);
+        }
+        Ok(plaintext)
+    }
+
+    /// Decrypts the rest as the last segment, which fails if the ciphertext is truncated.
+    pub fn finish(mut self) -> Result<Vec<u8>, Error> {
+        let segment = std::mem::take(&mut self.buffer);
+        self.open(true, &segment)
+    }
+
+    fn open(&mut self, last: bool, segment: &[u8]) -> Result<Vec<u8>, Error> {
+        let plaintext = self
+            .cipher
+            .open(self.counter, last, segment)
+            .map_err(Error::Decryption)?;
+        self.counter = next(self.counter)?;
+        Ok(plaintext)
+    }
+}
+
+fn next(counter: u32) -> Result<u32, Error> {
+    counter
+        .checked_add(1)
+        .ok_or(Error::InvalidStream("too many segments"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ciphers::chacha20poly1305::ChaCha20Poly1305Cipher;
+
+    fn encrypt(dek: &OneOfCipher, data: &[u8], chunk_size: usize) -> (Vec<u8>, Vec<u8>) {
+        let mut stream = EncryptionStream::new(dek).unwrap();
+        let mut ciphertext = Vec::new();
+        for chunk in data.chunks(chunk_size) {
+            ciphertext.extend(stream.update(chunk).unwrap());
+        }
+        (
+            stream.header(),
+            [ciphertext, stream.finish().unwrap()].concat(),
+        )
+    }
+
+    fn decrypt(
+        dek: &OneOfCipher,
+        header: &[u8],
+        ciphertext: &[u8],
+        chunk_size: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let mut stream = DecryptionStream::new(dek, header)?;
+        let mut plaintext = Vec::new();
+        for chunk in ciphertext.chunks(chunk_size) {
+            plaintext.extend(stream.update(chunk)?);
+        }
+        plaintext.extend(stream.finish()?);
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn test_stream() {
+        let dek = OneOfCipher::ChaCha20Poly1305(ChaCha20Poly1305Cipher::default());
+        let segment_size = SEGMENT_SIZE as usize;
+        for size in [0, 1, segment_size, segment_size + 1, 3 * segment_size + 7] {
+            let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            let (header, ciphertext) = encrypt(&dek, &data, 1000);
+            let segments = size.div_ceil(segment_size).max(1);
+            assert_eq!(ciphertext.len(), size + segments * TAG_SIZE);
+            assert_eq!(decrypt(&dek, &header, &ciphertext, 4096).unwrap(), data);
+            assert_eq!(
+                decrypt(&dek, &header, &ciphertext, usize::MAX).unwrap(),
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn test_stream_tampered() {
+        let dek = OneOfCipher::ChaCha20Poly1305(ChaCha20Poly1305Cipher::default());
+        let sealed_size = SEGMENT_SIZE as usize + TAG_SIZE;
+        let data = vec![0x2a; 3 * SEGMENT_SIZE as usize];
+        let (header, ciphertext) = encrypt(&dek, &data, 1 << 20);
+
+        // truncated at a segment boundary
+        let truncated = &ciphertext[..2 * sealed_size];
+        assert!(decrypt(&dek, &header, truncated, 4096).is_err());
+        // reordered segments
+        let reordered = [
+            &ciphertext[sealed_size..2 * sealed_size],
+            &ciphertext[..sealed_size],
+            &ciphertext[2 * sealed_size..],
+        ]
+        .concat();
+        assert!(decrypt(&dek, &header, &reordered, 4096).is_err());
+        // extended after the last segment
+        let extended = [&ciphertext[..], &ciphertext[..sealed_size]].concat();
+        assert!(decrypt(&dek, &header, &extended, 4096).is_err());
+
+        assert!(DecryptionStream::new(&dek, &header[..5]).is_err());
+        let mut header = header;
+        header[1..5].copy_from_slice(&0u32.to_be_bytes());
+        assert!(DecryptionStream::new(&dek, &header).is_err());
+    }
+}
@@ -0,0 +1,55 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// Numbers of encryptions with each KEK, which are the DEKs wrapped with it.
+#[derive(Default)]
+pub(crate) struct KeyUsage {
+    encryptions: Mutex<HashMap<Uuid, u64>>,
+}
+
+impl KeyUsage {
+    pub(crate) fn record(&self, kek_id: Uuid) {
+        *self.encryptions.lock().unwrap().entry(kek_id).or_default() += 1;
+    }
+
+    pub(crate) fn encryptions(&self, kek_id: &Uuid) -> u64 {
+        self.encryptions
+            .lock()
+            .unwrap()
+            .get(kek_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Raises the number of encryptions with `kek_id` to `encryptions` saved earlier,
+    /// unless more have been counted since.
+    pub(crate) fn restore(&self, kek_id: Uuid, encryptions: u64) {
+        let mut counts = self.encryptions.lock().unwrap();
+        let count = counts.entry(kek_id).or_default();
+        *count = (*count).max(encryptions);
+    }
+
+    /// Forgets the keys which are no longer loaded.
+    pub(crate) fn purge(&self, kek_ids: &[Uuid]) {
+        let mut encryptions = self.encryptions.lock().unwrap();
+        for kek_id in kek_ids {
+            encryptions.remove(kek_id);
+        }
+    }
+}
@@ -19,7 +19,7 @@ use std::path::PathBuf;
 fn main() {
     let mut builder = tonic_prost_build::configure()
         .build_server(true)
-        .build_client(false);
+        .build_client(true);
     #[cfg(feature = "reflection")]
     {
         builder = builder.file_descriptor_set_path(
@@ -28,6 +28,13 @@ fn main() {
     }
 
     builder
-        .compile_protos(&["proto/kagimori.proto", "proto/api.proto"], &["proto"])
+        .compile_protos(
+            &[
+                "proto/kagimori.proto",
+                "proto/admin.proto",
+                "proto/api.proto",
+            ],
+            &["proto"],
+        )
         .unwrap();
 }
@@ -0,0 +1,288 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use crate::lifecycle::{KeyLifecycle, KeyStateChange, KeyStateError};
+use crate::peer::caller;
+use crate::proto::kinorca::kagimori::admin::v1::kagimori_admin_service_server::KagimoriAdminService;
+use crate::proto::kinorca::kagimori::admin::v1::{
+    GetKeyringRequest, GetKeyringResponse, GetSealStatusRequest, KeyState, KeyStatus, NamedKey,
+    RotationPolicy, SealStatus, SetKeyStateRequest, UnsealRequest,
+};
+use crate::status::ensure_unsealed;
+use crate::trace::rpc_span;
+use crate::unseal::{UnsealError, Unsealer};
+use audit_log::AuditLogger;
+use encryption::Encryptor;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::{Request, Response, Status, async_trait};
+use tracing::{Instrument, info, warn};
+use uuid::Uuid;
+
+pub(crate) struct AdminService<L> {
+    encryptor: Encryptor<L>,
+    unsealer: Option<Arc<dyn Unsealer>>,
+    lifecycle: Option<Arc<dyn KeyLifecycle>>,
+}
+
+impl<L> AdminService<L>
+where
+    L: 'static + AuditLogger,
+{
+    pub(crate) fn new(
+        encryptor: Encryptor<L>,
+        unsealer: Option<Arc<dyn Unsealer>>,
+        lifecycle: Option<Arc<dyn KeyLifecycle>>,
+    ) -> Self {
+        Self {
+            encryptor,
+            unsealer,
+            lifecycle,
+        }
+    }
+
+    fn seal_status(&self) -> crate::unseal::SealStatus {
+        match &self.unsealer {
+            Some(unsealer) => unsealer.status(),
+            None => crate::unseal::SealStatus {
+                sealed: self.encryptor.is_sealed(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl From<SealStatus> for crate::unseal::SealStatus {
+    fn from(value: SealStatus) -> Self {
+        Self {
+            sealed: value.sealed,
+            threshold: value.threshold,
+            progress: value.progress,
+        }
+    }
+}
+
+impl From<encryption::KeyState> for KeyState {
+    fn from(value: encryption::KeyState) -> Self {
+        match value {
+            encryption::KeyState::Enabled => KeyState::Enabled,
+            encryption::KeyState::DecryptOnly => KeyState::DecryptOnly,
+            encryption::KeyState::Disabled => KeyState::Disabled,
+            encryption::KeyState::PendingDestruction => KeyState::PendingDestruction,
+            encryption::KeyState::Destroyed => KeyState::Destroyed,
+        }
+    }
+}
+
+impl TryFrom<KeyState> for encryption::KeyState {
+    type Error = Status;
+
+    fn try_from(value: KeyState) -> Result<Self, Self::Error> {
+        match value {
+            KeyState::Enabled => Ok(encryption::KeyState::Enabled),
+            KeyState::DecryptOnly => Ok(encryption::KeyState::DecryptOnly),
+            KeyState::Disabled => Ok(encryption::KeyState::Disabled),
+            KeyState::PendingDestruction => Ok(encryption::KeyState::PendingDestruction),
+            KeyState::Destroyed => Err(Status::invalid_argument(
+                "keys are destroyed only when the grace period of pending destruction ends",
+            )),
+            KeyState::Unspecified => Err(Status::invalid_argument("state must be given")),
+        }
+    }
+}
+
+fn rotation_policy(policy: &encryption::RotationPolicy) -> RotationPolicy {
+    RotationPolicy {
+        max_age_seconds: policy.max_age.map_or(0, |age| age.as_secs()),
+        max_encryptions: policy.max_encryptions.unwrap_or_default(),
+        rotated_at: policy
+            .rotated_at
+            .map(|t| t.to_rfc3339())
+            .unwrap_or_default(),
+    }
+}
+
+fn key_status(key_id: Uuid, status: encryption::KeyStatus) -> KeyStatus {
+    KeyStatus {
+        kek_id: key_id.to_string(),
+        state: KeyState::from(status.state).into(),
+        changed_at: status
+            .changed_at
+            .map(|t| t.to_rfc3339())
+            .unwrap_or_default(),
+        changed_by: status.changed_by.unwrap_or_default(),
+        destroy_at: status
+            .destroy_at
+            .map(|t| t.to_rfc3339())
+            .unwrap_or_default(),
+    }
+}
+
+impl From<crate::unseal::SealStatus> for SealStatus {
+    fn from(value: crate::unseal::SealStatus) -> Self {
+        Self {
+            sealed: value.sealed,
+            threshold: value.threshold,
+            progress: value.progress,
+        }
+    }
+}
+
+#[async_trait]
+impl<L> KagimoriAdminService for AdminService<L>
+where
+    L: 'static + AuditLogger,
+{
+    async fn get_keyring(
+        &self,
+        request: Request<GetKeyringRequest>,
+    ) -> Result<Response<GetKeyringResponse>, Status> {
+        info!("KagimoriAdminService::GetKeyring");
+        let (span, _) = rpc_span(
+            &request,
+            "kinorca.kagimori.admin.v1.KagimoriAdminService/GetKeyring",
+        );
+        let _enter = span.enter();
+
+        let mut kek_ids: Vec<String> = self
+            .encryptor
+            .key_ids()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        kek_ids.sort();
+        let policies = self.encryptor.rotation_policies();
+        let mut named_keys: Vec<NamedKey> = self
+            .encryptor
+            .named_keys()
+            .into_iter()
+            .map(|(name, key)| NamedKey {
+                rotation_policy: policies.get(&name).map(rotation_policy),
+                primary_encryptions: self.encryptor.encryption_count(&key.primary),
+                name,
+                primary_kek_id: key.primary.to_string(),
+                kek_ids: key.versions.iter().map(ToString::to_string).collect(),
+            })
+            .collect();
+        named_keys.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut key_statuses: Vec<KeyStatus> = self
+            .encryptor
+            .key_statuses()
+            .into_iter()
+            .map(|(id, status)| key_status(id, status))
+            .collect();
+        key_statuses.sort_by(|a, b| a.kek_id.cmp(&b.kek_id));
+
+        Ok(GetKeyringResponse {
+            default_kek_id: self.encryptor.get_key_id().unwrap_or_default(),
+            kek_ids,
+            named_keys,
+            key_statuses,
+        }
+        .into())
+    }
+
+    async fn get_seal_status(
+        &self,
+        request: Request<GetSealStatusRequest>,
+    ) -> Result<Response<SealStatus>, Status> {
+        info!("KagimoriAdminService::GetSealStatus");
+        let (span, _) = rpc_span(
+            &request,
+            "kinorca.kagimori.admin.v1.KagimoriAdminService/GetSealStatus",
+        );
+        let _enter = span.enter();
+
+        Ok(SealStatus::from(self.seal_status()).into())
+    }
+
+    async fn unseal(
+        &self,
+        request: Request<UnsealRequest>,
+    ) -> Result<Response<SealStatus>, Status> {
+        info!("KagimoriAdminService::Unseal");
+        let (span, _) = rpc_span(
+            &request,
+            "kinorca.kagimori.admin.v1.KagimoriAdminService/Unseal",
+        );
+        let Some(unsealer) = &self.unsealer else {
+            return Err(Status::failed_precondition(
+                "Kagimori is not running in sealed mode",
+            ));
+        };
+
+        let caller = caller(&request);
+        let share = request.into_inner().share;
+        match unsealer
+            .unseal(share.trim(), &caller)
+            .instrument(span)
+            .await
+        {
+            Ok(status) => Ok(SealStatus::from(status).into()),
+            Err(UnsealError::InvalidShare(e)) => {
+                warn!("Rejected unseal share: {e}");
+                Err(Status::invalid_argument(e))
+            }
+            Err(UnsealError::Failed(e)) => {
+                warn!("Unseal failed: {e}");
+                Err(Status::failed_precondition(e))
+            }
+        }
+    }
+
+    async fn set_key_state(
+        &self,
+        request: Request<SetKeyStateRequest>,
+    ) -> Result<Response<KeyStatus>, Status> {
+        info!("KagimoriAdminService::SetKeyState");
+        let (span, _) = rpc_span(
+            &request,
+            "kinorca.kagimori.admin.v1.KagimoriAdminService/SetKeyState",
+        );
+        let Some(lifecycle) = &self.lifecycle else {
+            return Err(Status::unimplemented("key states cannot be changed"));
+        };
+        ensure_unsealed(&self.encryptor)?;
+
+        let caller = caller(&request);
+        let request = request.into_inner();
+        let key_id: Uuid = request
+            .kek_id
+            .parse()
+            .map_err(|_| Status::invalid_argument("kek_id must be a UUID"))?;
+        let state = request.state().try_into()?;
+        let changed_by = match request.changed_by.as_str() {
+            "" => caller,
+            claimed => format!("{caller} ({claimed})"),
+        };
+        let change = KeyStateChange {
+            key_id,
+            state,
+            changed_by,
+            grace_period: (request.grace_period_seconds > 0)
+                .then(|| Duration::from_secs(request.grace_period_seconds)),
+        };
+
+        match lifecycle.set_state(change).instrument(span).await {
+            Ok(status) => Ok(key_status(key_id, status).into()),
+            Err(KeyStateError::NotFound(e)) => Err(Status::not_found(e)),
+            Err(KeyStateError::Rejected(e)) => Err(Status::failed_precondition(e)),
+            Err(KeyStateError::Failed(e)) => {
+                warn!("Cannot change key state: {e}");
+                Err(Status::internal(e))
+            }
+        }
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use crate::proto::kinorca::kagimori::admin::v1::kagimori_admin_service_client::KagimoriAdminServiceClient;
+use crate::proto::kinorca::kagimori::admin::v1::{GetSealStatusRequest, UnsealRequest};
+use crate::unseal::SealStatus;
+use hyper_util::rt::TokioIo;
+use std::path::PathBuf;
+use tokio::net::UnixStream;
+use tonic::Status;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Error, Identity, Uri};
+use tower::service_fn;
+
+/// Client of the Kagimori admin API.
+pub struct AdminClient {
+    inner: KagimoriAdminServiceClient<Channel>,
+}
+
+/// TLS settings to connect to a TCP listener.
+pub struct ClientTls {
+    /// PEM of the CA certificate to verify the server with
+    pub ca_certificate: Vec<u8>,
+    /// PEM of the client certificate and its private key, for listeners verifying clients
+    pub identity: Option<(Vec<u8>, Vec<u8>)>,
+    /// Name to verify the server certificate against instead of the host
+    pub server_name: Option<String>,
+}
+
+impl AdminClient {
+    /// Connects to `http://HOST:PORT`, or `https://HOST:PORT` if `tls` is given.
+    pub async fn connect_tcp(endpoint: String, tls: Option<ClientTls>) -> Result<Self, Error> {
+        let mut endpoint = Endpoint::from_shared(endpoint)?;
+        if let Some(tls) = tls {
+            let mut config =
+                ClientTlsConfig::new().ca_certificate(Certificate::from_pem(tls.ca_certificate));
+            if let Some((certificate, private_key)) = tls.identity {
+                config = config.identity(Identity::from_pem(certificate, private_key));
+            }
+            if let Some(server_name) = tls.server_name {
+                config = config.domain_name(server_name);
+            }
+            endpoint = endpoint.tls_config(config)?;
+        }
+        let channel = endpoint.connect().await?;
+        Ok(Self {
+            inner: KagimoriAdminServiceClient::new(channel),
+        })
+    }
+
+    /// Connects to a Unix domain socket.
+    pub async fn connect_unix(path: PathBuf) -> Result<Self, Error> {
+        // The URI is ignored by the connector
+        let channel = Endpoint::from_static("http://localhost")
+            .connect_with_connector(service_fn(move |_: Uri| {
+                let path = path.clone();
+                async move { UnixStream::connect(path).await.map(TokioIo::new) }
+            }))
+            .await?;
+        Ok(Self {
+            inner: KagimoriAdminServiceClient::new(channel),
+        })
+    }
+
+    pub async fn seal_status(&mut self) -> Result<SealStatus, Status> {
+        Ok(self
+            .inner
+            .get_seal_status(GetSealStatusRequest {})
+            .await?
+            .into_inner()
+            .into())
+    }
+
+    pub async fn unseal(&mut self, share: String) -> Result<SealStatus, Status> {
+        Ok(self
+            .inner
+            .unseal(UnsealRequest { share })
+            .await?
+            .into_inner()
+            .into())
+    }
+}
@@ -13,33 +13,183 @@
 // You should have received a copy of the GNU General Public License along with Kagimori.
 // If not, see <https://www.gnu.org/licenses/>.
 
+mod stream;
+
 use crate::debug_log::DebugLog;
 use crate::kms::DEK_KEY;
 use crate::proto::kinorca::kagimori::v1::kagimori_key_management_service_server::KagimoriKeyManagementService;
 use crate::proto::kinorca::kagimori::v1::{
-    DecryptRequest, DecryptResponse, EncryptRequest, EncryptResponse, GetInformationRequest,
-    GetInformationResponse, MigrateRequest, MigrateResponse,
+    BatchDecryptRequest, BatchDecryptResponse, BatchEncryptRequest, BatchEncryptResponse,
+    DecryptDataKeyRequest, DecryptDataKeyResponse, DecryptRequest, DecryptResponse, DecryptResult,
+    DecryptStreamRequest, DecryptStreamResponse, EncryptRequest, EncryptResponse, EncryptResult,
+    EncryptStreamRequest, EncryptStreamResponse, GenerateDataKeyRequest, GenerateDataKeyResponse,
+    GenerateDataKeyWithoutPlaintextResponse, GetInformationRequest, GetInformationResponse,
+    ItemError, MigrateRequest, MigrateResponse, RewrapRequest, RewrapResponse, decrypt_result,
+    encrypt_result,
 };
+use crate::status::{ensure_unsealed, from_encryption_error};
+use crate::trace::rpc_span;
 use audit_log::AuditLogger;
-use encryption::{Ciphertext, Encryptor, RequestInfo};
+use encryption::{Ciphertext, DataKey, Encryptor, RequestInfo, WrappedDek};
+use futures_util::StreamExt;
 use std::collections::HashMap;
-use tonic::{Request, Response, Status, async_trait};
-use tracing::info;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming, async_trait};
+use tracing::{Instrument, info};
 use uuid::Uuid;
 
+fn encrypt_response(ciphertext: Ciphertext, single_blob: bool) -> Result<EncryptResponse, Status> {
+    if single_blob {
+        Ok(EncryptResponse {
+            ciphertext: ciphertext.to_blob().map_err(from_encryption_error)?,
+            kek_id: ciphertext.key_id,
+            annotations: HashMap::new(),
+        })
+    } else {
+        Ok(EncryptResponse {
+            ciphertext: ciphertext.ciphertext,
+            kek_id: ciphertext.key_id,
+            annotations: HashMap::from([(DEK_KEY.to_string(), ciphertext.dek)]),
+        })
+    }
+}
+
+/// Annotation holding the header of a stream ciphertext.
+const STREAM_KEY: &str = "stream.v1.kagimori.kinorca.com";
+
+/// Treats an empty key name as not given.
+fn key_name(name: String) -> Option<String> {
+    (!name.is_empty()).then_some(name)
+}
+
+fn annotated_dek(annotations: &HashMap<String, Vec<u8>>) -> Result<&[u8], Status> {
+    annotations
+        .get(DEK_KEY)
+        .map(Vec::as_slice)
+        .ok_or(Status::invalid_argument(
+            "annotations must contain dek.kagimori.kinorca.com",
+        ))
+}
+
+/// Takes the ciphertext out of `request`, from the annotations or a single blob.
+fn ciphertext_of(request: &mut DecryptRequest) -> Result<Ciphertext, Status> {
+    match request.annotations.remove(DEK_KEY) {
+        Some(dek) => Ok(Ciphertext {
+            key_id: std::mem::take(&mut request.kek_id),
+            ciphertext: std::mem::take(&mut request.ciphertext),
+            dek,
+        }),
+        None if Ciphertext::is_blob(&request.ciphertext) => {
+            Ciphertext::from_blob(&request.ciphertext).map_err(from_encryption_error)
+        }
+        None => Err(Status::invalid_argument(
+            "annotations must contain dek.kagimori.kinorca.com \
+             unless ciphertext is a single blob",
+        )),
+    }
+}
+
+/// Number of items of a batch processed concurrently unless configured.
+const DEFAULT_BATCH_CONCURRENCY: usize = 16;
+
+fn item_error(status: Status) -> ItemError {
+    ItemError {
+        code: status.code() as i32,
+        message: status.message().to_string(),
+    }
+}
+
+impl From<Result<EncryptResponse, Status>> for EncryptResult {
+    fn from(result: Result<EncryptResponse, Status>) -> Self {
+        EncryptResult {
+            result: Some(match result {
+                Ok(response) => encrypt_result::Result::Response(response),
+                Err(status) => encrypt_result::Result::Error(item_error(status)),
+            }),
+        }
+    }
+}
+
+impl From<Result<DecryptResponse, Status>> for DecryptResult {
+    fn from(result: Result<DecryptResponse, Status>) -> Self {
+        DecryptResult {
+            result: Some(match result {
+                Ok(response) => decrypt_result::Result::Response(response),
+                Err(status) => decrypt_result::Result::Error(item_error(status)),
+            }),
+        }
+    }
+}
+
 pub(crate) struct KagimoriService<L> {
     encryptor: Encryptor<L>,
+    batch_concurrency: usize,
 }
 
 impl<L> KagimoriService<L>
 where
     L: 'static + AuditLogger,
 {
-    pub(crate) fn new(encryptor: Encryptor<L>) -> Self {
-        Self { encryptor }
+    pub(crate) fn new(encryptor: Encryptor<L>, batch_concurrency: Option<usize>) -> Self {
+        Self {
+            encryptor,
+            batch_concurrency: batch_concurrency.unwrap_or(DEFAULT_BATCH_CONCURRENCY),
+        }
     }
 
-    async fn encrypt_impl(&self, request: EncryptRequest) -> Result<Ciphertext, Status> {
+    /// Processes `items` with at most `batch_concurrency` at a time, keeping their order.
+    async fn batch<T, R, F>(&self, items: Vec<T>, process: impl FnMut(T) -> F) -> Vec<R>
+    where
+        F: Future<Output = R>,
+    {
+        futures_util::stream::iter(items)
+            .map(process)
+            .buffered(self.batch_concurrency)
+            .collect()
+            .await
+    }
+
+    async fn migrate_impl(
+        &self,
+        mut request: DecryptRequest,
+        trace_id: Option<String>,
+    ) -> Result<EncryptResponse, Status> {
+        // keep the format of the ciphertext and the key it is encrypted with
+        let single_blob = !request.annotations.contains_key(DEK_KEY);
+        let ciphertext = ciphertext_of(&mut request)?;
+        let key_name = self
+            .encryptor
+            .key_name_of(&ciphertext.dek)
+            .unwrap_or_default();
+
+        let plaintext = self
+            .decrypt_ciphertext(
+                request.service.clone(),
+                request.uid.clone(),
+                ciphertext,
+                trace_id.clone(),
+            )
+            .await?;
+        let ciphertext = self
+            .encrypt_impl(
+                EncryptRequest {
+                    plaintext,
+                    service: request.service,
+                    uid: request.uid,
+                    single_blob,
+                    key_name,
+                },
+                trace_id,
+            )
+            .await?;
+        encrypt_response(ciphertext, single_blob)
+    }
+
+    async fn encrypt_impl(
+        &self,
+        request: EncryptRequest,
+        trace_id: Option<String>,
+    ) -> Result<Ciphertext, Status> {
         self.encryptor
             .encrypt(
                 RequestInfo {
@@ -47,40 +197,71 @@ where
                     service: request.service,
                     user: request.uid,
                     data_key: None,
+                    trace_id,
+                    key_name: key_name(request.key_name),
                 },
                 &request.plaintext,
             )
             .await
             .debug_log()
-            .map_err(|e| Status::internal(format!("Internal: {e:?}")))
+            .map_err(from_encryption_error)
     }
 
-    async fn decrypt_impl(&self, request: DecryptRequest) -> Result<Vec<u8>, Status> {
-        let dek = request
-            .annotations
-            .get(DEK_KEY)
-            .ok_or(Status::invalid_argument(
-                "annotations must contain dek.kagimori.kinorca.com",
-            ))?
-            .clone();
+    async fn decrypt_impl(
+        &self,
+        mut request: DecryptRequest,
+        trace_id: Option<String>,
+    ) -> Result<Vec<u8>, Status> {
+        let ciphertext = ciphertext_of(&mut request)?;
+        self.decrypt_ciphertext(request.service, request.uid, ciphertext, trace_id)
+            .await
+    }
 
+    async fn decrypt_ciphertext(
+        &self,
+        service: String,
+        user: String,
+        ciphertext: Ciphertext,
+        trace_id: Option<String>,
+    ) -> Result<Vec<u8>, Status> {
         self.encryptor
             .decrypt(
+                RequestInfo {
+                    event_id: Uuid::now_v7().to_string(),
+                    service,
+                    user,
+                    data_key: None,
+                    trace_id,
+                    key_name: None,
+                },
+                ciphertext,
+            )
+            .await
+            .debug_log()
+            .map_err(from_encryption_error)
+    }
+
+    async fn generate_data_key_impl(
+        &self,
+        request: GenerateDataKeyRequest,
+        trace_id: Option<String>,
+        with_plaintext: bool,
+    ) -> Result<DataKey, Status> {
+        self.encryptor
+            .generate_data_key(
                 RequestInfo {
                     event_id: Uuid::now_v7().to_string(),
                     service: request.service,
                     user: request.uid,
                     data_key: None,
+                    trace_id,
+                    key_name: key_name(request.key_name),
                 },
-                Ciphertext {
-                    key_id: request.kek_id,
-                    ciphertext: request.ciphertext,
-                    dek,
-                },
+                with_plaintext,
             )
             .await
             .debug_log()
-            .map_err(|e| Status::internal(format!("Internal: {e:?}")))
+            .map_err(from_encryption_error)
     }
 }
 
@@ -91,12 +272,18 @@ where
 {
     async fn get_information(
         &self,
-        _request: Request<GetInformationRequest>,
+        request: Request<GetInformationRequest>,
     ) -> Result<Response<GetInformationResponse>, Status> {
         info!("KagimoriKeyManagementService::GetInformation");
+        let (span, _) = rpc_span(
+            &request,
+            "kinorca.kagimori.v1.KagimoriKeyManagementService/GetInformation",
+        );
+        let _enter = span.enter();
+        ensure_unsealed(&self.encryptor)?;
         Ok(GetInformationResponse {
             version: "kagimori.kinorca.com/v1".to_string(),
-            kek_id: self.encryptor.get_key_id(),
+            kek_id: self.encryptor.get_key_id().unwrap_or_default(),
         }
         .into())
     }
@@ -106,16 +293,15 @@ where
         request: Request<EncryptRequest>,
     ) -> Result<Response<EncryptResponse>, Status> {
         info!("KagimoriKeyManagementService::Encrypt");
+        let (span, trace_id) = rpc_span(
+            &request,
+            "kinorca.kagimori.v1.KagimoriKeyManagementService/Encrypt",
+        );
         let req = request.into_inner();
+        let single_blob = req.single_blob;
 
-        self.encrypt_impl(req).await.map(|c| {
-            EncryptResponse {
-                ciphertext: c.ciphertext,
-                kek_id: c.key_id,
-                annotations: HashMap::from([(DEK_KEY.to_string(), c.dek)]),
-            }
-            .into()
-        })
+        let ciphertext = self.encrypt_impl(req, trace_id).instrument(span).await?;
+        encrypt_response(ciphertext, single_blob).map(Response::new)
     }
 
     async fn decrypt(
@@ -123,9 +309,14 @@ where
         request: Request<DecryptRequest>,
     ) -> Result<Response<DecryptResponse>, Status> {
         info!("KagimoriKeyManagementService::Decrypt");
+        let (span, trace_id) = rpc_span(
+            &request,
+            "kinorca.kagimori.v1.KagimoriKeyManagementService/Decrypt",
+        );
         let req = request.into_inner();
 
-        self.decrypt_impl(req)
+        self.decrypt_impl(req, trace_id)
+            .instrument(span)
             .await
             .map(|plaintext| DecryptResponse { plaintext }.into())
     }
@@ -135,27 +326,477 @@ where
         request: Request<MigrateRequest>,
     ) -> Result<Response<MigrateResponse>, Status> {
         info!("KagimoriKeyManagementService::Migrate");
-        let mut responses = Vec::new();
-        for req in request.into_inner().requests {
-            let service = req.service.clone();
-            let uid = req.uid.clone();
+        let (span, trace_id) = rpc_span(
+            &request,
+            "kinorca.kagimori.v1.KagimoriKeyManagementService/Migrate",
+        );
+
+        let request = request.into_inner();
+        let results = self
+            .batch(request.requests, |req| {
+                self.migrate_impl(req, trace_id.clone())
+            })
+            .instrument(span)
+            .await;
+        let response = if request.per_item_results {
+            MigrateResponse {
+                responses: Vec::new(),
+                results: results.into_iter().map(EncryptResult::from).collect(),
+            }
+        } else {
+            // all or nothing, as expected by clients predating per-item results
+            MigrateResponse {
+                responses: results.into_iter().collect::<Result<_, _>>()?,
+                results: Vec::new(),
+            }
+        };
+        Ok(response.into())
+    }
 
-            let plaintext = self.decrypt_impl(req).await?;
+    async fn batch_encrypt(
+        &self,
+        request: Request<BatchEncryptRequest>,
+    ) -> Result<Response<BatchEncryptResponse>, Status> {
+        info!("KagimoriKeyManagementService::BatchEncrypt");
+        let (span, trace_id) = rpc_span(
+            &request,
+            "kinorca.kagimori.v1.KagimoriKeyManagementService/BatchEncrypt",
+        );
 
-            let ciphertext = self
-                .encrypt_impl(EncryptRequest {
-                    plaintext,
-                    service,
-                    uid,
-                })
-                .await?;
-            responses.push(EncryptResponse {
-                ciphertext: ciphertext.ciphertext,
-                kek_id: ciphertext.key_id,
-                annotations: HashMap::from([(DEK_KEY.to_string(), ciphertext.dek)]),
-            });
+        let results = self
+            .batch(request.into_inner().requests, |req| async {
+                let single_blob = req.single_blob;
+                let ciphertext = self.encrypt_impl(req, trace_id.clone()).await?;
+                encrypt_response(ciphertext, single_blob)
+            })
+            .instrument(span)
+            .await;
+        Ok(BatchEncryptResponse {
+            results: results.into_iter().map(EncryptResult::from).collect(),
+        }
+        .into())
+    }
+
+    async fn batch_decrypt(
+        &self,
+        request: Request<BatchDecryptRequest>,
+    ) -> Result<Response<BatchDecryptResponse>, Status> {
+        info!("KagimoriKeyManagementService::BatchDecrypt");
+        let (span, trace_id) = rpc_span(
+            &request,
+            "kinorca.kagimori.v1.KagimoriKeyManagementService/BatchDecrypt",
+        );
+
+        let results = self
+            .batch(request.into_inner().requests, |req| async {
+                let plaintext = self.decrypt_impl(req, trace_id.clone()).await?;
+                Ok(DecryptResponse { plaintext })
+            })
+            .instrument(span)
+            .await;
+        Ok(BatchDecryptResponse {
+            results: results.into_iter().map(DecryptResult::from).collect(),
+        }
+        .into())
+    }
+
+    async fn rewrap(
+        &self,
+        request: Request<RewrapRequest>,
+    ) -> Result<Response<RewrapResponse>, Status> {
+        info!("KagimoriKeyManagementService::Rewrap");
+        let (span, trace_id) = rpc_span(
+            &request,
+            "kinorca.kagimori.v1.KagimoriKeyManagementService/Rewrap",
+        );
+        let req = request.into_inner();
+        let dek = annotated_dek(&req.annotations)?;
+
+        let rewrapped = self
+            .encryptor
+            .rewrap(
+                RequestInfo {
+                    event_id: Uuid::now_v7().to_string(),
+                    service: req.service,
+                    user: req.uid,
+                    data_key: None,
+                    trace_id,
+                    key_name: key_name(req.key_name),
+                },
+                dek,
+            )
+            .instrument(span)
+            .await
+            .debug_log()
+            .map_err(from_encryption_error)?;
+        Ok(RewrapResponse {
+            kek_id: rewrapped.key_id,
+            annotations: HashMap::from([(DEK_KEY.to_string(), rewrapped.dek)]),
+        }
+        .into())
+    }
+
+    async fn generate_data_key(
+        &self,
+        request: Request<GenerateDataKeyRequest>,
+    ) -> Result<Response<GenerateDataKeyResponse>, Status> {
+        info!("KagimoriKeyManagementService::GenerateDataKey");
+        let (span, trace_id) = rpc_span(
+            &request,
+            "kinorca.kagimori.v1.KagimoriKeyManagementService/GenerateDataKey",
+        );
+        let req = request.into_inner();
+
+        let data_key = self
+            .generate_data_key_impl(req, trace_id, true)
+            .instrument(span)
+            .await?;
+        Ok(GenerateDataKeyResponse {
+            plaintext: data_key.plaintext.unwrap_or_default(),
+            algorithm: data_key.algorithm.to_string(),
+            kek_id: data_key.key_id,
+            annotations: HashMap::from([(DEK_KEY.to_string(), data_key.dek)]),
+        }
+        .into())
+    }
+
+    async fn generate_data_key_without_plaintext(
+        &self,
+        request: Request<GenerateDataKeyRequest>,
+    ) -> Result<Response<GenerateDataKeyWithoutPlaintextResponse>, Status> {
+        info!("KagimoriKeyManagementService::GenerateDataKeyWithoutPlaintext");
+        let (span, trace_id) = rpc_span(
+            &request,
+            "kinorca.kagimori.v1.KagimoriKeyManagementService/GenerateDataKeyWithoutPlaintext",
+        );
+        let req = request.into_inner();
+
+        let data_key = self
+            .generate_data_key_impl(req, trace_id, false)
+            .instrument(span)
+            .await?;
+        Ok(GenerateDataKeyWithoutPlaintextResponse {
+            algorithm: data_key.algorithm.to_string(),
+            kek_id: data_key.key_id,
+            annotations: HashMap::from([(DEK_KEY.to_string(), data_key.dek)]),
+        }
+        .into())
+    }
+
+    async fn decrypt_data_key(
+        &self,
+        request: Request<DecryptDataKeyRequest>,
+    ) -> Result<Response<DecryptDataKeyResponse>, Status> {
+        info!("KagimoriKeyManagementService::DecryptDataKey");
+        let (span, trace_id) = rpc_span(
+            &request,
+            "kinorca.kagimori.v1.KagimoriKeyManagementService/DecryptDataKey",
+        );
+        let req = request.into_inner();
+        let dek = annotated_dek(&req.annotations)?;
+
+        let data_key = self
+            .encryptor
+            .decrypt_data_key(
+                RequestInfo {
+                    event_id: Uuid::now_v7().to_string(),
+                    service: req.service,
+                    user: req.uid,
+                    data_key: None,
+                    trace_id,
+                    key_name: None,
+                },
+                dek,
+            )
+            .instrument(span)
+            .await
+            .debug_log()
+            .map_err(from_encryption_error)?;
+        Ok(DecryptDataKeyResponse {
+            plaintext: data_key.plaintext.unwrap_or_default(),
+            algorithm: data_key.algorithm.to_string(),
+        }
+        .into())
+    }
+
+    type EncryptStreamStream = ReceiverStream<Result<EncryptStreamResponse, Status>>;
+
+    async fn encrypt_stream(
+        &self,
+        request: Request<Streaming<EncryptStreamRequest>>,
+    ) -> Result<Response<Self::EncryptStreamStream>, Status> {
+        info!("KagimoriKeyManagementService::EncryptStream");
+        let (span, trace_id) = rpc_span(
+            &request,
+            "kinorca.kagimori.v1.KagimoriKeyManagementService/EncryptStream",
+        );
+        let mut input = request.into_inner();
+        let first = input
+            .message()
+            .await?
+            .ok_or(Status::invalid_argument("stream must not be empty"))?;
+
+        let (dek, mut encryption) = self
+            .encryptor
+            .encrypt_stream(RequestInfo {
+                event_id: Uuid::now_v7().to_string(),
+                service: first.service,
+                user: first.uid,
+                data_key: None,
+                trace_id,
+                key_name: key_name(first.key_name),
+            })
+            .instrument(span.clone())
+            .await
+            .debug_log()
+            .map_err(from_encryption_error)?;
+        let response = EncryptStreamResponse {
+            kek_id: dek.key_id,
+            annotations: HashMap::from([
+                (DEK_KEY.to_string(), dek.dek),
+                (STREAM_KEY.to_string(), encryption.header()),
+            ]),
+            chunk: encryption
+                .update(&first.chunk)
+                .map_err(from_encryption_error)?,
+        };
+        Ok(Response::new(stream::forward(
+            input, response, encryption, span,
+        )))
+    }
+
+    type DecryptStreamStream = ReceiverStream<Result<DecryptStreamResponse, Status>>;
+
+    async fn decrypt_stream(
+        &self,
+        request: Request<Streaming<DecryptStreamRequest>>,
+    ) -> Result<Response<Self::DecryptStreamStream>, Status> {
+        info!("KagimoriKeyManagementService::DecryptStream");
+        let (span, trace_id) = rpc_span(
+            &request,
+            "kinorca.kagimori.v1.KagimoriKeyManagementService/DecryptStream",
+        );
+        let mut input = request.into_inner();
+        let first = input
+            .message()
+            .await?
+            .ok_or(Status::invalid_argument("stream must not be empty"))?;
+        let dek = WrappedDek {
+            dek: annotated_dek(&first.annotations)?.to_vec(),
+            key_id: first.kek_id,
+        };
+        let header = first
+            .annotations
+            .get(STREAM_KEY)
+            .ok_or(Status::invalid_argument(
+                "annotations must contain stream.v1.kagimori.kinorca.com",
+            ))?;
+
+        let mut decryption = self
+            .encryptor
+            .decrypt_stream(
+                RequestInfo {
+                    event_id: Uuid::now_v7().to_string(),
+                    service: first.service,
+                    user: first.uid,
+                    data_key: None,
+                    trace_id,
+                    key_name: None,
+                },
+                &dek,
+                header,
+            )
+            .instrument(span.clone())
+            .await
+            .debug_log()
+            .map_err(from_encryption_error)?;
+        let response = DecryptStreamResponse {
+            chunk: decryption
+                .update(&first.chunk)
+                .map_err(from_encryption_error)?,
+        };
+        Ok(Response::new(stream::forward(
+            input, response, decryption, span,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use audit_log::logger::tracing::TracingAuditLogger;
+    use ciphers::Unencrypted;
+    use ciphers::oneof::OneOfCipher;
+    use ciphers::rotatable::RotatableCipher;
+    use encryption::KeyAlgorithm;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tonic::Code;
+
+    fn create_sut(batch_concurrency: Option<usize>) -> KagimoriService<TracingAuditLogger> {
+        let id = Uuid::new_v4();
+        let kek = RotatableCipher::new(
+            id,
+            HashMap::from([(id, OneOfCipher::Unencrypted(Unencrypted))]),
+        )
+        .unwrap();
+        let encryptor = Encryptor::new(TracingAuditLogger, KeyAlgorithm::ChaCha20Poly1305, kek);
+        KagimoriService::new(encryptor, batch_concurrency)
+    }
+
+    fn encrypt_request(plaintext: &[u8], key_name: &str) -> EncryptRequest {
+        EncryptRequest {
+            plaintext: plaintext.to_vec(),
+            service: "test".to_string(),
+            uid: "uid".to_string(),
+            single_blob: false,
+            key_name: key_name.to_string(),
+        }
+    }
+
+    fn decrypt_request(response: &EncryptResponse) -> DecryptRequest {
+        DecryptRequest {
+            ciphertext: response.ciphertext.clone(),
+            service: "test".to_string(),
+            uid: "uid".to_string(),
+            kek_id: response.kek_id.clone(),
+            annotations: response.annotations.clone(),
+        }
+    }
+
+    fn encrypted(result: &EncryptResult) -> &EncryptResponse {
+        match &result.result {
+            Some(encrypt_result::Result::Response(response)) => response,
+            result => panic!("{result:?}"),
         }
+    }
+
+    fn encryption_error(result: &EncryptResult) -> Code {
+        match &result.result {
+            Some(encrypt_result::Result::Error(error)) => Code::from_i32(error.code),
+            result => panic!("{result:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batch_encrypt_and_decrypt() {
+        let sut = create_sut(None);
+        let results = sut
+            .batch_encrypt(Request::new(BatchEncryptRequest {
+                requests: vec![
+                    encrypt_request(b"first", ""),
+                    encrypt_request(b"second", "unknown"),
+                    encrypt_request(b"third", ""),
+                ],
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .results;
+        assert_eq!(results.len(), 3);
+        assert_eq!(encryption_error(&results[1]), Code::NotFound);
+
+        let mut malformed = decrypt_request(encrypted(&results[0]));
+        malformed.annotations.clear();
+        let results = sut
+            .batch_decrypt(Request::new(BatchDecryptRequest {
+                requests: vec![
+                    decrypt_request(encrypted(&results[2])),
+                    malformed,
+                    decrypt_request(encrypted(&results[0])),
+                ],
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .results;
+        let results: Vec<_> = results
+            .into_iter()
+            .map(|result| match result.result.unwrap() {
+                decrypt_result::Result::Response(response) => Ok(response.plaintext),
+                decrypt_result::Result::Error(error) => Err(Code::from_i32(error.code)),
+            })
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                Ok(b"third".to_vec()),
+                Err(Code::InvalidArgument),
+                Ok(b"first".to_vec()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_migrate() {
+        let sut = create_sut(None);
+        let mut requests = Vec::new();
+        for plaintext in [&b"first"[..], b"second"] {
+            let ciphertext = sut
+                .encrypt(Request::new(encrypt_request(plaintext, "")))
+                .await
+                .unwrap()
+                .into_inner();
+            requests.push(decrypt_request(&ciphertext));
+        }
+        let migrate = |requests, per_item_results| {
+            sut.migrate(Request::new(MigrateRequest {
+                requests,
+                per_item_results,
+            }))
+        };
+
+        let response = migrate(requests.clone(), false).await.unwrap().into_inner();
+        assert_eq!(response.responses.len(), 2);
+        assert!(response.results.is_empty());
+
+        let mut malformed = requests[0].clone();
+        malformed.annotations.clear();
+        requests.insert(1, malformed);
+        // the whole request fails unless results per item are requested
+        let status = migrate(requests.clone(), false).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let response = migrate(requests, true).await.unwrap().into_inner();
+        assert!(response.responses.is_empty());
+        assert_eq!(response.results.len(), 3);
+        assert_eq!(
+            encryption_error(&response.results[1]),
+            Code::InvalidArgument
+        );
+        for (result, plaintext) in [
+            (&response.results[0], &b"first"[..]),
+            (&response.results[2], b"second"),
+        ] {
+            let decrypted = sut
+                .decrypt(Request::new(decrypt_request(encrypted(result))))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(decrypted.plaintext, plaintext);
+        }
+    }
 
-        Ok(MigrateResponse { responses }.into())
+    #[tokio::test]
+    async fn test_batch_concurrency() {
+        let sut = create_sut(Some(2));
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+        let results = sut
+            .batch((0..8).collect(), |i| {
+                let running = &running;
+                let max_running = &max_running;
+                async move {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    // later items finish first, which must not change the order
+                    tokio::time::sleep(Duration::from_millis(10 * (8 - i))).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    i
+                }
+            })
+            .await;
+        assert_eq!(results, (0..8).collect::<Vec<u64>>());
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
     }
 }
@@ -0,0 +1,126 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use crate::proto::kinorca::kagimori::v1::{
+    DecryptStreamRequest, DecryptStreamResponse, EncryptStreamRequest, EncryptStreamResponse,
+};
+use crate::status::from_encryption_error;
+use encryption::{DecryptionStream, EncryptionStream, Error};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Status, Streaming};
+use tracing::{Instrument, Span};
+
+/// Number of responses buffered before reading more requests.
+const BUFFER: usize = 4;
+
+/// State of a stream transforming chunks of input into chunks of output.
+pub(crate) trait Transform: Send + 'static {
+    fn update(&mut self, chunk: &[u8]) -> Result<Vec<u8>, Error>;
+    fn finish(self) -> Result<Vec<u8>, Error>;
+}
+
+impl Transform for EncryptionStream {
+    fn update(&mut self, chunk: &[u8]) -> Result<Vec<u8>, Error> {
+        EncryptionStream::update(self, chunk)
+    }
+
+    fn finish(self) -> Result<Vec<u8>, Error> {
+        EncryptionStream::finish(self)
+    }
+}
+
+impl Transform for DecryptionStream {
+    fn update(&mut self, chunk: &[u8]) -> Result<Vec<u8>, Error> {
+        DecryptionStream::update(self, chunk)
+    }
+
+    fn finish(self) -> Result<Vec<u8>, Error> {
+        DecryptionStream::finish(self)
+    }
+}
+
+pub(crate) trait Chunked: Send + 'static {
+    fn into_chunk(self) -> Vec<u8>;
+}
+
+impl Chunked for EncryptStreamRequest {
+    fn into_chunk(self) -> Vec<u8> {
+        self.chunk
+    }
+}
+
+impl Chunked for DecryptStreamRequest {
+    fn into_chunk(self) -> Vec<u8> {
+        self.chunk
+    }
+}
+
+impl From<Vec<u8>> for EncryptStreamResponse {
+    fn from(chunk: Vec<u8>) -> Self {
+        EncryptStreamResponse {
+            chunk,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<Vec<u8>> for DecryptStreamResponse {
+    fn from(chunk: Vec<u8>) -> Self {
+        DecryptStreamResponse { chunk }
+    }
+}
+
+/// Sends `first` and then transforms the rest of `input` in a task, responding with
+/// each output chunk. Any error ends the responses with its status.
+pub(crate) fn forward<T, R, S>(
+    mut input: Streaming<T>,
+    first: R,
+    mut transform: S,
+    span: Span,
+) -> ReceiverStream<Result<R, Status>>
+where
+    T: Chunked,
+    R: From<Vec<u8>> + Send + 'static,
+    S: Transform,
+{
+    let (sender, receiver) = mpsc::channel(BUFFER);
+    tokio::spawn(
+        async move {
+            if sender.send(Ok(first)).await.is_err() {
+                return;
+            }
+            let result = loop {
+                let request = match input.message().await {
+                    Ok(Some(request)) => request,
+                    Ok(None) => break transform.finish().map_err(from_encryption_error),
+                    Err(status) => break Err(status),
+                };
+                match transform.update(&request.into_chunk()) {
+                    Ok(output) if output.is_empty() => {}
+                    Ok(output) => {
+                        if sender.send(Ok(output.into())).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => break Err(from_encryption_error(e)),
+                }
+            };
+            let _ = sender.send(result.map(R::from)).await;
+        }
+        .instrument(span),
+    );
+    ReceiverStream::new(receiver)
+}
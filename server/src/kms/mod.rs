@@ -18,11 +18,13 @@ use crate::proto::kubernetes::kms::v2::key_management_service_server::KeyManagem
 use crate::proto::kubernetes::kms::v2::{
     DecryptRequest, DecryptResponse, EncryptRequest, EncryptResponse, StatusRequest, StatusResponse,
 };
+use crate::status::{ensure_unsealed, from_encryption_error};
+use crate::trace::rpc_span;
 use audit_log::AuditLogger;
 use encryption::{Ciphertext, Encryptor, RequestInfo};
 use std::collections::HashMap;
 use tonic::{Request, Response, Status, async_trait};
-use tracing::info;
+use tracing::{Instrument, info};
 use uuid::Uuid;
 
 const KMS_SERVICE_NAME: &str = "kubernetes.io/kms/v2";
@@ -48,14 +50,19 @@ where
 {
     async fn status(
         &self,
-        _request: Request<StatusRequest>,
+        request: Request<StatusRequest>,
     ) -> Result<Response<StatusResponse>, Status> {
         info!("v2.KeyManagementService.Status called");
-        let kid = self.encryptor.get_key_id();
+        let (span, _) = rpc_span(&request, "v2.KeyManagementService/Status");
+        let _enter = span.enter();
+        let (healthz, key_id) = match self.encryptor.get_key_id() {
+            Some(kid) => ("ok", kid),
+            None => ("sealed", String::new()),
+        };
         Ok(Response::new(StatusResponse {
             version: "v2".to_string(),
-            healthz: "ok".to_string(),
-            key_id: kid,
+            healthz: healthz.to_string(),
+            key_id,
         }))
     }
 
@@ -64,8 +71,10 @@ where
         request: Request<DecryptRequest>,
     ) -> Result<Response<DecryptResponse>, Status> {
         info!("v2.KeyManagementService.Decrypt called");
+        let (span, trace_id) = rpc_span(&request, "v2.KeyManagementService/Decrypt");
         let req = request.into_inner();
 
+        ensure_unsealed(&self.encryptor)?;
         let key_id = Uuid::parse_str(&req.key_id)
             .map_err(|e| Status::invalid_argument(format!("Invalid key_id: {e:?}")))?;
         if !self.encryptor.contains_key(&key_id) {
@@ -88,6 +97,8 @@ where
                     service: KMS_SERVICE_NAME.to_string(),
                     user: req.uid,
                     data_key: None,
+                    trace_id,
+                    key_name: None,
                 },
                 Ciphertext {
                     key_id: req.key_id,
@@ -95,9 +106,10 @@ where
                     dek,
                 },
             )
+            .instrument(span)
             .await
             .debug_log()
-            .map_err(|e| Status::internal(format!("Internal: {e:?}")))?;
+            .map_err(from_encryption_error)?;
         Ok(Response::new(DecryptResponse { plaintext }))
     }
 
@@ -106,6 +118,7 @@ where
         request: Request<EncryptRequest>,
     ) -> Result<Response<EncryptResponse>, Status> {
         info!("v2.KeyManagementService.Encrypt called");
+        let (span, trace_id) = rpc_span(&request, "v2.KeyManagementService/Encrypt");
         let req = request.into_inner();
 
         let ciphertext = self
@@ -116,12 +129,15 @@ where
                     service: KMS_SERVICE_NAME.to_string(),
                     user: req.uid,
                     data_key: None,
+                    trace_id,
+                    key_name: None,
                 },
                 &req.plaintext,
             )
+            .instrument(span)
             .await
             .debug_log()
-            .map_err(|e| Status::internal(format!("Internal: {e:?}")))?;
+            .map_err(from_encryption_error)?;
 
         Ok(Response::new(EncryptResponse {
             ciphertext: ciphertext.ciphertext,
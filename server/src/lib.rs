@@ -13,10 +13,18 @@
 // You should have received a copy of the GNU General Public License along with Kagimori.
 // If not, see <https://www.gnu.org/licenses/>.
 
+mod admin;
+pub mod client;
 mod debug_log;
 mod kagimori;
 mod kms;
+pub mod lifecycle;
+mod peer;
 mod proto;
+mod rpc_metrics;
 mod server;
+mod status;
+mod trace;
+pub mod unseal;
 
 pub use server::*;
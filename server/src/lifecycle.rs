@@ -0,0 +1,45 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+use encryption::{KeyState, KeyStatus};
+use std::time::Duration;
+use tonic::async_trait;
+use uuid::Uuid;
+
+/// A requested change of the lifecycle state of a key.
+#[derive(Debug, Clone)]
+pub struct KeyStateChange {
+    pub key_id: Uuid,
+    pub state: KeyState,
+    /// Who makes the change
+    pub changed_by: String,
+    /// Time until a key pending destruction is destroyed, or the default if not given
+    pub grace_period: Option<Duration>,
+}
+
+#[derive(Debug)]
+pub enum KeyStateError {
+    /// The key is not found in the keyring.
+    NotFound(String),
+    /// The key cannot be in the state, such as a default key which is not enabled.
+    Rejected(String),
+    /// The keyring cannot be updated.
+    Failed(String),
+}
+
+/// Changes lifecycle states of keys of the keyring of an `Encryptor` and persists them.
+#[async_trait]
+pub trait KeyLifecycle: Send + Sync {
+    async fn set_state(&self, change: KeyStateChange) -> Result<KeyStatus, KeyStateError>;
+}
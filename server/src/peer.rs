@@ -0,0 +1,88 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use std::net::SocketAddr;
+use tonic::Request;
+use tonic::transport::server::UdsConnectInfo;
+use x509_parser::prelude::parse_x509_certificate;
+
+/// Client of a TLS connection, set as a request extension by the TLS server.
+#[derive(Debug, Clone)]
+pub(crate) struct TlsPeer {
+    pub remote_addr: SocketAddr,
+    /// Subject of the verified client certificate
+    pub subject: Option<String>,
+}
+
+impl TlsPeer {
+    pub(crate) fn new(remote_addr: SocketAddr, certificate: Option<&[u8]>) -> Self {
+        let subject = certificate
+            .and_then(|der| parse_x509_certificate(der).ok())
+            .map(|(_, certificate)| certificate.subject().to_string());
+        Self {
+            remote_addr,
+            subject,
+        }
+    }
+}
+
+/// Identifies the client of `request` as authenticated by the transport: the subject of
+/// the client certificate, or the credentials of the process on a unix socket.
+/// Clients which are not authenticated are identified by their address.
+pub(crate) fn caller<T>(request: &Request<T>) -> String {
+    if let Some(peer) = request.extensions().get::<TlsPeer>() {
+        return match &peer.subject {
+            Some(subject) => format!("tls:{subject}"),
+            None => format!("tcp:{}", peer.remote_addr),
+        };
+    }
+    if let Some(credentials) = request
+        .extensions()
+        .get::<UdsConnectInfo>()
+        .and_then(|info| info.peer_cred)
+    {
+        return match credentials.pid() {
+            Some(pid) => format!("unix:uid={},pid={pid}", credentials.uid()),
+            None => format!("unix:uid={}", credentials.uid()),
+        };
+    }
+    match request.remote_addr() {
+        Some(addr) => format!("tcp:{addr}"),
+        None => "unknown".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_caller() {
+        let mut request = Request::new(());
+        assert_eq!(caller(&request), "unknown");
+
+        let remote_addr = "192.0.2.1:50000".parse().unwrap();
+        request
+            .extensions_mut()
+            .insert(TlsPeer::new(remote_addr, None));
+        assert_eq!(caller(&request), "tcp:192.0.2.1:50000");
+
+        request.extensions_mut().insert(TlsPeer {
+            remote_addr,
+            subject: Some("CN=operator".to_string()),
+        });
+        assert_eq!(caller(&request), "tls:CN=operator");
+    }
+}
@@ -18,6 +18,12 @@ pub(crate) mod kinorca {
         pub mod v1 {
             tonic::include_proto!("kinorca.kagimori.v1");
         }
+
+        pub mod admin {
+            pub mod v1 {
+                tonic::include_proto!("kinorca.kagimori.admin.v1");
+            }
+        }
     }
 }
 
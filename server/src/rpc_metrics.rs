@@ -0,0 +1,161 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use crate::proto::kinorca::kagimori::admin::v1::kagimori_admin_service_server;
+use crate::proto::kinorca::kagimori::v1::kagimori_key_management_service_server;
+use crate::proto::kubernetes::kms::v2::key_management_service_server;
+use hyper::http;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use telemetry::metrics::metrics;
+use tonic::Code;
+use tower::{Layer, Service};
+
+/// Records request count and latency of each gRPC call.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct RpcMetricsLayer;
+
+impl<S> Layer<S> for RpcMetricsLayer {
+    type Service = RpcMetrics<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RpcMetrics { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RpcMetrics<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for RpcMetrics<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<ReqBody>) -> Self::Future {
+        let (service, method) = split_path(req.uri().path());
+        let started = Instant::now();
+        let future = self.inner.call(req);
+
+        Box::pin(async move {
+            let result = future.await;
+            // Failed calls carry `grpc-status` in the headers (Trailers-Only response).
+            // Successful unary calls send it in the trailers, which are not awaited here.
+            let code = match &result {
+                Ok(res) => res
+                    .headers()
+                    .get("grpc-status")
+                    .map(|v| Code::from_bytes(v.as_bytes()))
+                    .unwrap_or(Code::Ok),
+                Err(_) => Code::Unknown,
+            };
+            metrics().record_rpc(&service, &method, &format!("{code:?}"), started.elapsed());
+            result
+        })
+    }
+}
+
+/// Label of paths which are not methods of the services, as they come from any client
+/// before authentication and would otherwise make any number of series.
+const UNKNOWN: &str = "unknown";
+
+/// Returns the methods of a service which may be served.
+fn methods(service: &str) -> &'static [&'static str] {
+    match service {
+        key_management_service_server::SERVICE_NAME => &["Status", "Decrypt", "Encrypt"],
+        kagimori_key_management_service_server::SERVICE_NAME => &[
+            "GetInformation",
+            "Encrypt",
+            "Decrypt",
+            "Migrate",
+            "BatchEncrypt",
+            "BatchDecrypt",
+            "Rewrap",
+            "GenerateDataKey",
+            "GenerateDataKeyWithoutPlaintext",
+            "DecryptDataKey",
+            "EncryptStream",
+            "DecryptStream",
+        ],
+        kagimori_admin_service_server::SERVICE_NAME => {
+            &["GetKeyring", "GetSealStatus", "Unseal", "SetKeyState"]
+        }
+        tonic_health::pb::health_server::SERVICE_NAME => &["Check", "Watch"],
+        "grpc.reflection.v1.ServerReflection" => &["ServerReflectionInfo"],
+        _ => &[],
+    }
+}
+
+fn split_path(path: &str) -> (String, String) {
+    match path.trim_start_matches('/').split_once('/') {
+        Some((service, method)) if methods(service).contains(&method) => {
+            (service.to_string(), method.to_string())
+        }
+        _ => (UNKNOWN.to_string(), UNKNOWN.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_path() {
+        assert_eq!(
+            split_path("/v2.KeyManagementService/Encrypt"),
+            ("v2.KeyManagementService".to_string(), "Encrypt".to_string())
+        );
+        let unknown = (UNKNOWN.to_string(), UNKNOWN.to_string());
+        assert_eq!(split_path("/"), unknown);
+        assert_eq!(split_path("/v2.KeyManagementService/Unknown"), unknown);
+        assert_eq!(split_path("/random-1234/Encrypt"), unknown);
+        assert_eq!(split_path("/random-1234"), unknown);
+    }
+
+    /// The methods listed are those defined by the protos.
+    #[test]
+    fn test_methods_match_protos() {
+        for proto in [
+            include_str!("../proto/api.proto"),
+            include_str!("../proto/kagimori.proto"),
+            include_str!("../proto/admin.proto"),
+        ] {
+            let mut package = "";
+            let mut service = String::new();
+            let mut rpcs = Vec::new();
+            for line in proto.lines().map(str::trim) {
+                if let Some(name) = line.strip_prefix("package ") {
+                    package = name.trim_end_matches(';');
+                } else if let Some(name) = line.strip_prefix("service ") {
+                    service = format!("{package}.{}", name.trim_end_matches(" {"));
+                } else if let Some(rpc) = line.strip_prefix("rpc ") {
+                    rpcs.push(rpc.split_once('(').unwrap().0);
+                }
+            }
+            assert_eq!(methods(&service), rpcs, "{service}");
+        }
+    }
+}
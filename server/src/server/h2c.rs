@@ -14,6 +14,7 @@
 // If not, see <https://www.gnu.org/licenses/>.
 
 use crate::debug_log::DebugLog;
+use crate::rpc_metrics::RpcMetricsLayer;
 use crate::server::KagimoriServer;
 use audit_log::AuditLogger;
 use std::net::SocketAddr;
@@ -36,13 +37,25 @@ where
     L: 'static + AuditLogger + Clone,
 {
     pub async fn run(self) -> Result<(), tonic::transport::Error> {
-        let svc = self.inner.create_service();
+        self.run_with_shutdown(std::future::pending()).await
+    }
+
+    /// Serves until `signal` completes.
+    pub async fn run_with_shutdown<F>(self, signal: F) -> Result<(), tonic::transport::Error>
+    where
+        F: Future<Output = ()> + Send,
+    {
+        let max_concurrent_streams = self.inner.limits.max_concurrent_streams;
+        let svc = self.inner.create_service().await;
         info!("Listening on: tcp://{}", self.listen);
         Server::builder()
+            .max_concurrent_streams(max_concurrent_streams)
+            .layer(RpcMetricsLayer)
             .add_routes(svc)
-            .serve(self.listen)
+            .serve_with_shutdown(self.listen, signal)
             .await
             .debug_log()?;
+        info!("Stopped listening on: tcp://{}", self.listen);
         Ok(())
     }
 }
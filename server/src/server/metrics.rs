@@ -0,0 +1,74 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use crate::debug_log::DebugLog;
+use hyper::body::Incoming;
+use hyper::server::conn::http1::Builder;
+use hyper::service::service_fn;
+use hyper::{StatusCode, http};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use telemetry::metrics::metrics;
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+/// Serves Prometheus metrics on `/metrics` over plain HTTP/1.1.
+pub struct MetricsServer {
+    listener: TcpListener,
+}
+
+impl MetricsServer {
+    /// Binds `listen`, so that a failure is reported before serving.
+    pub async fn bind(listen: SocketAddr) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(listen).await.debug_log()?;
+        info!("Serving metrics on: http://{listen}/metrics");
+        Ok(Self { listener })
+    }
+
+    pub async fn run(self) {
+        loop {
+            let (conn, _) = match self.listener.accept().await {
+                Ok(incoming) => incoming,
+                Err(e) => {
+                    error!("Failed to accept connection: {e}");
+                    continue;
+                }
+            };
+
+            tokio::spawn(async move {
+                if let Err(e) = Builder::new()
+                    .serve_connection(TokioIo::new(conn), service_fn(handle))
+                    .await
+                {
+                    error!("Failed to serve metrics connection: {e}");
+                }
+            });
+        }
+    }
+}
+
+async fn handle(req: http::Request<Incoming>) -> Result<http::Response<String>, Infallible> {
+    let response = if req.method() == http::Method::GET && req.uri().path() == "/metrics" {
+        http::Response::builder()
+            .header(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(metrics().encode())
+    } else {
+        http::Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(String::new())
+    };
+    Ok(response.unwrap())
+}
@@ -14,50 +14,140 @@
 // If not, see <https://www.gnu.org/licenses/>.
 
 pub mod h2c;
+pub mod metrics;
 pub mod tls;
 mod uds;
 
+use crate::admin::AdminService;
 use crate::kms::KmsService;
+use crate::proto::kinorca::kagimori::admin::v1::kagimori_admin_service_server::KagimoriAdminServiceServer;
 use crate::proto::kubernetes::kms::v2::key_management_service_server::KeyManagementServiceServer;
 use crate::server::h2c::KagimoriH2cServer;
 use crate::server::tls::KagimoriTlsServer;
 use encryption::Encryptor;
 use std::net::SocketAddr;
 use std::path::Path;
-use tokio_rustls::rustls::ServerConfig;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
 use tonic::service::Routes;
+use tonic_health::server::HealthReporter;
 
 use crate::kagimori::KagimoriService;
+use crate::lifecycle::KeyLifecycle;
 use crate::proto::kinorca::kagimori::v1::kagimori_key_management_service_server::KagimoriKeyManagementServiceServer;
 use crate::server::uds::KagimoriUnixDomainSocketServer;
+use crate::unseal::Unsealer;
 use audit_log::AuditLogger;
 pub use tokio_rustls::rustls::pki_types::pem::PemObject;
 pub use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+#[cfg(not(feature = "reflection"))]
+use tracing::warn;
+
+/// Interval to check whether the keyring is unsealed for health reporting.
+const UNSEAL_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Services served on a listener.
+///
+/// Nothing is served unless enabled, so that exposing one API on a listener
+/// never exposes another.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RouteSet {
+    /// Kubernetes KMS v2
+    pub kms_v2: bool,
+    /// Kagimori v1
+    pub kagimori_v1: bool,
+    /// Kagimori admin API
+    pub admin: bool,
+    /// gRPC health checking protocol
+    pub health: bool,
+    /// gRPC server reflection (requires the `reflection` feature)
+    pub reflection: bool,
+}
+
+impl RouteSet {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Resource limits applied to each listener.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum size of a gRPC message in bytes
+    pub max_message_size: Option<usize>,
+    /// Maximum number of concurrent requests on a connection
+    pub max_concurrent_streams: Option<u32>,
+    /// Maximum number of items of a batch request processed concurrently
+    pub batch_concurrency: Option<usize>,
+}
 
 pub struct KagimoriServer<L> {
     encryptor: Encryptor<L>,
-    kms_v2_enabled: bool,
-    kagimori_v1_enabled: bool,
+    routes: RouteSet,
+    limits: Limits,
+    unsealer: Option<Arc<dyn Unsealer>>,
+    lifecycle: Option<Arc<dyn KeyLifecycle>>,
+}
+
+impl<L> Clone for KagimoriServer<L>
+where
+    L: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            encryptor: self.encryptor.clone(),
+            routes: self.routes,
+            limits: self.limits,
+            unsealer: self.unsealer.clone(),
+            lifecycle: self.lifecycle.clone(),
+        }
+    }
 }
 
 impl<L> KagimoriServer<L> {
     pub fn new(encryptor: Encryptor<L>) -> Self {
         Self {
             encryptor,
-            kms_v2_enabled: false,
-            kagimori_v1_enabled: false,
+            routes: RouteSet::default(),
+            limits: Limits::default(),
+            unsealer: None,
+            lifecycle: None,
         }
     }
 }
 
 impl<L> KagimoriServer<L> {
+    pub fn with_routes(mut self, routes: RouteSet) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Serves the `Unseal` admin RPC with `unsealer`.
+    pub fn with_unsealer(mut self, unsealer: Arc<dyn Unsealer>) -> Self {
+        self.unsealer = Some(unsealer);
+        self
+    }
+
+    /// Serves the `SetKeyState` admin RPC with `lifecycle`.
+    pub fn with_key_lifecycle(mut self, lifecycle: Arc<dyn KeyLifecycle>) -> Self {
+        self.lifecycle = Some(lifecycle);
+        self
+    }
+
     pub fn enable_kms_v2(mut self) -> Self {
-        self.kms_v2_enabled = true;
+        self.routes.kms_v2 = true;
         self
     }
 
     pub fn enable_kagimori_v1(mut self) -> Self {
-        self.kagimori_v1_enabled = true;
+        self.routes.kagimori_v1 = true;
         self
     }
 }
@@ -69,7 +159,8 @@ impl<L> KagimoriServer<L> {
         certificate: Vec<CertificateDer>,
         private_key: PrivateKeyDer,
     ) -> Result<KagimoriTlsServer<L>, tokio_rustls::rustls::Error> {
-        let config = ServerConfig::builder()
+        let config = ServerConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()?
             .with_no_client_auth()
             .with_single_cert(
                 certificate.into_iter().map(|c| c.into_owned()).collect(),
@@ -78,6 +169,34 @@ impl<L> KagimoriServer<L> {
         Ok(KagimoriTlsServer::new(self, config, listen))
     }
 
+    /// Binds a TLS listener which requires clients to present a certificate
+    /// issued by one of `client_ca_certificates`.
+    pub fn bind_mtls(
+        self,
+        listen: SocketAddr,
+        certificate: Vec<CertificateDer>,
+        private_key: PrivateKeyDer,
+        client_ca_certificates: Vec<CertificateDer>,
+    ) -> Result<KagimoriTlsServer<L>, tokio_rustls::rustls::Error> {
+        let mut roots = RootCertStore::empty();
+        for ca in client_ca_certificates {
+            roots.add(ca.into_owned())?;
+        }
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), crypto_provider())
+                .build()
+                .map_err(|e| tokio_rustls::rustls::Error::General(e.to_string()))?;
+
+        let config = ServerConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(
+                certificate.into_iter().map(|c| c.into_owned()).collect(),
+                private_key.clone_key(),
+            )?;
+        Ok(KagimoriTlsServer::new(self, config, listen))
+    }
+
     pub fn bind(self, listen: SocketAddr) -> KagimoriH2cServer<L> {
         KagimoriH2cServer::new(self, listen)
     }
@@ -87,35 +206,120 @@ impl<L> KagimoriServer<L> {
     }
 }
 
+fn crypto_provider() -> Arc<tokio_rustls::rustls::crypto::CryptoProvider> {
+    Arc::new(tokio_rustls::rustls::crypto::ring::default_provider())
+}
+
 impl<L> KagimoriServer<L>
 where
     L: 'static + AuditLogger + Clone,
 {
-    fn create_service(self) -> Routes {
+    async fn create_service(self) -> Routes {
         let mut routes = Routes::default();
-        if self.kms_v2_enabled {
-            routes = routes.add_service(KeyManagementServiceServer::new(KmsService::new(
+        let (health_reporter, health_service) = tonic_health::server::health_reporter();
+
+        if self.routes.kms_v2 {
+            let mut service =
+                KeyManagementServiceServer::new(KmsService::new(self.encryptor.clone()));
+            if let Some(size) = self.limits.max_message_size {
+                service = service
+                    .max_decoding_message_size(size)
+                    .max_encoding_message_size(size);
+            }
+            routes = routes.add_service(service);
+            health_reporter
+                .set_serving::<KeyManagementServiceServer<KmsService<L>>>()
+                .await;
+        }
+        if self.routes.kagimori_v1 {
+            let mut service = KagimoriKeyManagementServiceServer::new(KagimoriService::new(
                 self.encryptor.clone(),
+                self.limits.batch_concurrency,
+            ));
+            if let Some(size) = self.limits.max_message_size {
+                service = service
+                    .max_decoding_message_size(size)
+                    .max_encoding_message_size(size);
+            }
+            routes = routes.add_service(service);
+            health_reporter
+                .set_serving::<KagimoriKeyManagementServiceServer<KagimoriService<L>>>()
+                .await;
+        }
+        if self.encryptor.is_sealed() {
+            // Encryption services are not ready until unsealed
+            set_encryption_serving::<L>(&health_reporter, self.routes, false).await;
+            let encryptor = self.encryptor.clone();
+            let health_reporter = health_reporter.clone();
+            let route_set = self.routes;
+            tokio::spawn(async move {
+                while encryptor.is_sealed() {
+                    tokio::time::sleep(UNSEAL_POLL_INTERVAL).await;
+                }
+                set_encryption_serving::<L>(&health_reporter, route_set, true).await;
+            });
+        }
+        if self.routes.admin {
+            routes = routes.add_service(KagimoriAdminServiceServer::new(AdminService::new(
+                self.encryptor.clone(),
+                self.unsealer.clone(),
+                self.lifecycle.clone(),
             )));
+            health_reporter
+                .set_serving::<KagimoriAdminServiceServer<AdminService<L>>>()
+                .await;
         }
-        if self.kagimori_v1_enabled {
-            routes = routes.add_service(KagimoriKeyManagementServiceServer::new(
-                KagimoriService::new(self.encryptor.clone()),
-            ));
+        if self.routes.health {
+            routes = routes.add_service(health_service);
         }
 
-        #[cfg(feature = "reflection")]
-        {
-            routes = routes.add_service(
-                tonic_reflection::server::Builder::configure()
-                    .register_encoded_file_descriptor_set(tonic::include_file_descriptor_set!(
-                        "kagimori_descriptor"
-                    ))
-                    .build_v1()
-                    .unwrap(),
-            );
+        if self.routes.reflection {
+            #[cfg(feature = "reflection")]
+            {
+                routes = routes.add_service(
+                    tonic_reflection::server::Builder::configure()
+                        .register_encoded_file_descriptor_set(tonic::include_file_descriptor_set!(
+                            "kagimori_descriptor"
+                        ))
+                        .build_v1()
+                        .unwrap(),
+                );
+            }
+            #[cfg(not(feature = "reflection"))]
+            warn!("gRPC reflection is requested but not compiled in");
         }
 
         routes
     }
 }
+
+async fn set_encryption_serving<L>(
+    health_reporter: &HealthReporter,
+    routes: RouteSet,
+    serving: bool,
+) where
+    L: 'static + AuditLogger + Clone,
+{
+    if routes.kms_v2 {
+        if serving {
+            health_reporter
+                .set_serving::<KeyManagementServiceServer<KmsService<L>>>()
+                .await;
+        } else {
+            health_reporter
+                .set_not_serving::<KeyManagementServiceServer<KmsService<L>>>()
+                .await;
+        }
+    }
+    if routes.kagimori_v1 {
+        if serving {
+            health_reporter
+                .set_serving::<KagimoriKeyManagementServiceServer<KagimoriService<L>>>()
+                .await;
+        } else {
+            health_reporter
+                .set_not_serving::<KagimoriKeyManagementServiceServer<KagimoriService<L>>>()
+                .await;
+        }
+    }
+}
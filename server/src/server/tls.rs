@@ -14,6 +14,8 @@
 // If not, see <https://www.gnu.org/licenses/>.
 
 use crate::debug_log::DebugLog;
+use crate::peer::TlsPeer;
+use crate::rpc_metrics::RpcMetricsLayer;
 use crate::server::KagimoriServer;
 use audit_log::AuditLogger;
 use hyper::http;
@@ -22,6 +24,7 @@ use hyper_util::rt::{TokioExecutor, TokioIo};
 use hyper_util::service::TowerToHyperService;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use telemetry::metrics::metrics;
 use tokio::net::TcpListener;
 use tokio_rustls::TlsAcceptor;
 use tokio_rustls::rustls::ServerConfig;
@@ -50,17 +53,36 @@ where
     L: 'static + AuditLogger + Clone,
 {
     pub async fn run(self) -> std::io::Result<()> {
+        self.run_with_shutdown(std::future::pending()).await
+    }
+
+    /// Serves until `signal` completes.
+    pub async fn run_with_shutdown<F>(self, signal: F) -> std::io::Result<()>
+    where
+        F: Future<Output = ()> + Send,
+    {
         info!("Listening on: tcp://{} (using TLS)", self.listen);
         let listener = TcpListener::bind(self.listen).await.debug_log()?;
         let tls_acceptor = TlsAcceptor::from(Arc::new(self.config));
 
-        let svc = self.inner.create_service();
-        let svc = tower::ServiceBuilder::new().service(svc);
+        let max_concurrent_streams = self.inner.limits.max_concurrent_streams;
+        let svc = self.inner.create_service().await;
+        let svc = tower::ServiceBuilder::new()
+            .layer(RpcMetricsLayer)
+            .service(svc);
 
-        let http = Builder::new(TokioExecutor::new());
+        let mut http = Builder::new(TokioExecutor::new());
+        if let Some(max_concurrent_streams) = max_concurrent_streams {
+            http.max_concurrent_streams(max_concurrent_streams);
+        }
 
+        let mut signal = std::pin::pin!(signal);
         loop {
-            let (conn, addr) = match listener.accept().await {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = &mut signal => break,
+            };
+            let (conn, addr) = match accepted {
                 Ok(incoming) => incoming,
                 Err(e) => {
                     error!("Failed to accept connection: {e}");
@@ -88,12 +110,17 @@ where
                     .await
                 {
                     Ok(conn) => {
+                        let peer = TlsPeer::new(addr, certificates.first().map(AsRef::as_ref));
                         if let Err(e) = http
                             .serve_connection(
                                 TokioIo::new(conn),
-                                TowerToHyperService::new(
-                                    svc.map_request(|req: http::Request<_>| req.map(Body::new)),
-                                ),
+                                TowerToHyperService::new(svc.map_request(
+                                    move |req: http::Request<_>| {
+                                        let mut req = req.map(Body::new);
+                                        req.extensions_mut().insert(peer.clone());
+                                        req
+                                    },
+                                )),
                             )
                             .await
                         {
@@ -101,10 +128,14 @@ where
                         }
                     }
                     Err(e) => {
+                        metrics().record_tls_handshake_failure();
                         error!("Failed to accept TLS connection: {e}");
                     }
                 }
             });
         }
+
+        info!("Stopped listening on: tcp://{} (using TLS)", self.listen);
+        Ok(())
     }
 }
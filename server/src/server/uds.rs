@@ -15,6 +15,7 @@
 
 use crate::KagimoriServer;
 use crate::debug_log::DebugLog;
+use crate::rpc_metrics::RpcMetricsLayer;
 use audit_log::AuditLogger;
 use std::path::PathBuf;
 use tokio::net::UnixListener;
@@ -38,6 +39,14 @@ where
     L: 'static + AuditLogger + Clone,
 {
     pub async fn run(self) -> std::io::Result<()> {
+        self.run_with_shutdown(std::future::pending()).await
+    }
+
+    /// Serves until `signal` completes.
+    pub async fn run_with_shutdown<F>(self, signal: F) -> std::io::Result<()>
+    where
+        F: Future<Output = ()> + Send,
+    {
         if let Some(parent) = self.path.parent() {
             tokio::fs::create_dir_all(parent).await.debug_log()?;
         }
@@ -49,15 +58,23 @@ where
         let uds = UnixListener::bind(&self.path).debug_log()?;
         let uds_stream = UnixListenerStream::new(uds);
 
-        let svc = self.inner.create_service();
+        let max_concurrent_streams = self.inner.limits.max_concurrent_streams;
+        let svc = self.inner.create_service().await;
 
         Server::builder()
+            .max_concurrent_streams(max_concurrent_streams)
+            .layer(RpcMetricsLayer)
             .add_routes(svc)
-            .serve_with_incoming(uds_stream)
+            .serve_with_incoming_shutdown(uds_stream, signal)
             .await
             .debug_log()
-            .unwrap();
+            .map_err(std::io::Error::other)?;
 
+        tokio::fs::remove_file(&self.path).await.debug_log()?;
+        info!(
+            "Stopped listening on: unix://{}",
+            self.path.as_os_str().to_str().unwrap_or_default()
+        );
         Ok(())
     }
 }
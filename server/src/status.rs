@@ -0,0 +1,42 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use encryption::{Encryptor, Error};
+use tonic::Status;
+
+const SEALED: &str = "Kagimori is sealed";
+
+pub(crate) fn from_encryption_error(error: Error) -> Status {
+    match error {
+        Error::Sealed => Status::unavailable(SEALED),
+        Error::InvalidDek(reason) => Status::invalid_argument(format!("Invalid DEK: {reason}")),
+        Error::InvalidStream(reason) => {
+            Status::invalid_argument(format!("Invalid stream: {reason}"))
+        }
+        Error::KeyNameNotFound(name) => Status::not_found(format!("Key {name} is not found")),
+        Error::KeyNotUsable(id, state) => {
+            Status::failed_precondition(format!("Key {id} is {state}"))
+        }
+        e => Status::internal(format!("Internal: {e:?}")),
+    }
+}
+
+pub(crate) fn ensure_unsealed<L>(encryptor: &Encryptor<L>) -> Result<(), Status> {
+    if encryptor.is_sealed() {
+        Err(Status::unavailable(SEALED))
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,55 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use opentelemetry::propagation::Extractor;
+use telemetry::trace::{extract_context, link_span};
+use tonic::Request;
+use tonic::metadata::{KeyRef, MetadataMap};
+use tracing::{Span, info_span};
+
+struct MetadataExtractor<'a>(&'a MetadataMap);
+
+impl Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .keys()
+            .filter_map(|k| match k {
+                KeyRef::Ascii(k) => Some(k.as_str()),
+                KeyRef::Binary(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// Creates the server span of an RPC, continuing the trace propagated by the caller.
+///
+/// Returns the span and the trace ID to be recorded in audit logs.
+pub(crate) fn rpc_span<T>(request: &Request<T>, method: &'static str) -> (Span, Option<String>) {
+    let span = info_span!(
+        "rpc",
+        otel.name = method,
+        otel.kind = "server",
+        rpc.system = "grpc"
+    );
+    let trace_id = link_span(
+        &span,
+        extract_context(&MetadataExtractor(request.metadata())),
+    );
+    (span, trace_id)
+}
@@ -0,0 +1,42 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use tonic::async_trait;
+
+/// Progress of unsealing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SealStatus {
+    pub sealed: bool,
+    /// Number of shares required to unseal
+    pub threshold: u32,
+    /// Number of shares submitted so far
+    pub progress: u32,
+}
+
+#[derive(Debug)]
+pub enum UnsealError {
+    /// The share is malformed or does not belong to the keyring.
+    InvalidShare(String),
+    /// The shares do not unseal the keyring. Submitted shares are discarded.
+    Failed(String),
+}
+
+/// Collects shares of the root key and unseals the keyring of an `Encryptor`.
+#[async_trait]
+pub trait Unsealer: Send + Sync {
+    fn status(&self) -> SealStatus;
+    /// Submits a share on behalf of `caller`, as identified by the transport.
+    async fn unseal(&self, share: &str, caller: &str) -> Result<SealStatus, UnsealError>;
+}
@@ -13,49 +13,453 @@
 // You should have received a copy of the GNU General Public License along with Kagimori.
 // If not, see <https://www.gnu.org/licenses/>.
 
+use crate::config::{Config, DEFAULT_LISTEN};
+use crate::keyring::{KeygenCommand, KeyringCommand};
+use crate::keystore::KeystoreCommand;
+use crate::listener::{ListenAddress, ListenerSpec};
 use crate::master_key::MasterKeyConfig;
-use clap::{Parser, ValueEnum};
+use crate::seal::SealCommand;
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+use server::RouteSet;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Copy, Clone, ValueEnum)]
+#[derive(Debug, Copy, Clone, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub(crate) enum CipherAlgorithm {
     Chacha20Poly1305,
     AesGcmSiv,
 }
 
+// Settings given by flags take precedence over `KAGIMORI_*` environment variables,
+// which take precedence over the configuration file.
 #[derive(Debug, Parser)]
 pub(crate) struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[arg(
+        long,
+        env = "KAGIMORI_CONFIG",
+        help = "Path to configuration file (TOML, YAML or JSON)"
+    )]
+    pub config: Option<PathBuf>,
+
     // server
     #[arg(
         long,
-        help = "Listen address (tcp://HOST:PORT or unix://PATH)",
-        default_value = "tcp://0.0.0.0:8602"
+        env = "KAGIMORI_LISTEN",
+        help = "Listen address (tcp://HOST:PORT or unix://PATH), may be repeated. \
+                Options may follow as a query: ?services=kms-v2,kagimori-v1,admin,health,reflection&tls-certificate=PATH&tls-private-key=PATH&tls-client-ca=PATH \
+                [default: tcp://0.0.0.0:8602]"
     )]
-    pub listen: String,
+    pub listen: Vec<ListenerSpec>,
+    #[arg(
+        long,
+        env = "KAGIMORI_ADMIN_LISTEN",
+        help = "Listen address of admin, health and reflection services (tcp://HOST:PORT or unix://PATH). \
+                Takes the options of --listen; over TCP, TLS with a client CA is required"
+    )]
+    pub admin_listen: Option<ListenerSpec>,
+    #[arg(
+        long,
+        env = "KAGIMORI_METRICS_LISTEN",
+        help = "Listen address of Prometheus metrics endpoint (HOST:PORT)"
+    )]
+    pub metrics_listen: Option<SocketAddr>,
+    #[arg(
+        long,
+        env = "KAGIMORI_OTLP_ENDPOINT",
+        help = "OTLP/gRPC endpoint to export traces to (e.g. http://localhost:4317)"
+    )]
+    pub otlp_endpoint: Option<String>,
 
     // Service enabler
-    #[arg(long, help = "Enable Kubernetes KMS v2")]
+    #[arg(
+        long,
+        env = "KAGIMORI_KMS_V2",
+        help = "Enable Kubernetes KMS v2 on listeners without `services` option"
+    )]
     pub kms_v2: bool,
-    #[arg(long, help = "Enable Kagimori v1")]
+    #[arg(
+        long,
+        env = "KAGIMORI_KAGIMORI_V1",
+        help = "Enable Kagimori v1 on listeners without `services` option"
+    )]
     pub kagimori_v1: bool,
 
     // TLS
-    #[arg(long, help = "Path to TLS certificate PEM file")]
+    #[arg(
+        long,
+        env = "KAGIMORI_TLS_CERTIFICATE",
+        help = "Path to TLS certificate PEM file"
+    )]
     pub tls_certificate: Option<String>,
-    #[arg(long, help = "Path to TLS private key PEM file")]
+    #[arg(
+        long,
+        env = "KAGIMORI_TLS_PRIVATE_KEY",
+        help = "Path to TLS private key PEM file"
+    )]
     pub tls_private_key: Option<String>,
+    #[arg(
+        long,
+        env = "KAGIMORI_TLS_CLIENT_CA",
+        help = "Path to CA certificate PEM file to verify TLS client certificates"
+    )]
+    pub tls_client_ca: Option<String>,
 
     // Master key
-    #[arg(long, help = "Path to master key configuration file")]
-    pub master_key: String,
+    #[arg(
+        long,
+        env = "KAGIMORI_MASTER_KEY",
+        help = "Path to master key configuration file"
+    )]
+    pub master_key: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "KAGIMORI_ALLOW_MASTER_KEY_REMOVAL",
+        help = "Allow keyring reloads to remove master keys"
+    )]
+    pub allow_master_key_removal: bool,
+    #[arg(
+        long,
+        env = "KAGIMORI_AUTO_ROTATE_MASTER_KEYS",
+        help = "Rotate master keys by their rotation policies; enable on one replica only"
+    )]
+    pub auto_rotate_master_keys: bool,
+    #[arg(
+        long,
+        env = "KAGIMORI_MASTER_KEY_PASSPHRASE_FILE",
+        help = "Path to file containing the passphrase of an encrypted master key file \
+                (or set KAGIMORI_MASTER_KEY_PASSPHRASE)"
+    )]
+    pub master_key_passphrase_file: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "KAGIMORI_MASTER_KEY_PASSPHRASE_FD",
+        help = "File descriptor to read the passphrase of an encrypted master key file from"
+    )]
+    pub master_key_passphrase_fd: Option<i32>,
 
     // DEK
-    #[arg(long, help = "DEK algorithm", default_value = "chacha20-poly1305")]
-    pub dek_algorithm: CipherAlgorithm,
+    #[arg(
+        long,
+        env = "KAGIMORI_DEK_ALGORITHM",
+        help = "DEK algorithm [default: chacha20-poly1305]"
+    )]
+    pub dek_algorithm: Option<CipherAlgorithm>,
+    #[arg(
+        long,
+        env = "KAGIMORI_DEK_REUSE_MAX_ENCRYPTIONS",
+        help = "Reuse a DEK for up to this many encryptions, at most 2^32 [default: 1048576 if reuse is enabled]"
+    )]
+    pub dek_reuse_max_encryptions: Option<u64>,
+    #[arg(
+        long,
+        env = "KAGIMORI_DEK_REUSE_MAX_AGE_SECONDS",
+        help = "Reuse a DEK for up to this many seconds [default: 3600 if reuse is enabled]"
+    )]
+    pub dek_reuse_max_age_seconds: Option<u64>,
+    #[arg(
+        long,
+        env = "KAGIMORI_DEK_CACHE_CAPACITY",
+        help = "Maximum number of cached unwrapped DEKs [default: 4096 if the cache is enabled]"
+    )]
+    pub dek_cache_capacity: Option<usize>,
+    #[arg(
+        long,
+        env = "KAGIMORI_DEK_CACHE_TTL_SECONDS",
+        help = "Seconds to cache an unwrapped DEK for [default: 300 if the cache is enabled]"
+    )]
+    pub dek_cache_ttl_seconds: Option<u64>,
+
+    // Audit log
+    #[arg(
+        long,
+        env = "KAGIMORI_AUDIT_QUEUE_CAPACITY",
+        help = "Maximum number of audit logs waiting to be written [default: 1024]"
+    )]
+    pub audit_queue_capacity: Option<usize>,
+
+    // Limits
+    #[arg(
+        long,
+        env = "KAGIMORI_MAX_MESSAGE_SIZE",
+        help = "Maximum size of a gRPC message in bytes"
+    )]
+    pub max_message_size: Option<usize>,
+    #[arg(
+        long,
+        env = "KAGIMORI_MAX_CONCURRENT_STREAMS",
+        help = "Maximum number of concurrent requests on a connection"
+    )]
+    pub max_concurrent_streams: Option<u32>,
+    #[arg(
+        long,
+        env = "KAGIMORI_BATCH_CONCURRENCY",
+        help = "Maximum number of items of a batch request processed concurrently [default: 16]"
+    )]
+    pub batch_concurrency: Option<usize>,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum Command {
+    /// Manage configuration files
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Generate a new master key file
+    Keygen(KeygenCommand),
+    /// Manage keys in a master key file
+    #[command(subcommand)]
+    Keyring(KeyringCommand),
+    /// Seal a master key file with Shamir shares, and unseal a running server
+    #[command(subcommand)]
+    Seal(SealCommand),
+    /// Keep the master keys in an encrypted embedded database, and back it up
+    #[command(subcommand)]
+    Keystore(KeystoreCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum ConfigCommand {
+    /// Validate a configuration file without starting the server
+    Check {
+        /// Path to configuration file
+        file: PathBuf,
+    },
 }
 
 impl Args {
-    pub(crate) fn create_master_key(&self) -> MasterKeyConfig {
-        let content = std::fs::read_to_string(&self.master_key).unwrap();
-        toml::from_str(&content).unwrap()
+    /// Loads the configuration file, if any, and overrides it with flags and environment variables.
+    pub(crate) fn resolve_config(&self) -> Result<Config, String> {
+        self.resolve_config_file(self.config.as_deref())
+    }
+
+    fn resolve_config_file(&self, path: Option<&Path>) -> Result<Config, String> {
+        let mut config = match path {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+
+        if !self.listen.is_empty() {
+            config.listeners = self.listeners(&self.listen);
+        } else if config.listeners.is_empty() {
+            config.listeners = self.listeners(&[DEFAULT_LISTEN.parse()?]);
+        } else {
+            config.listeners = self.listeners(&config.listeners);
+        }
+        if let Some(spec) = &self.admin_listen {
+            let mut spec = spec.clone();
+            spec.services.get_or_insert(RouteSet {
+                admin: true,
+                health: true,
+                reflection: true,
+                ..Default::default()
+            });
+            config.listeners.extend(self.listeners(&[spec]));
+        }
+
+        if self.metrics_listen.is_some() {
+            config.metrics_listen = self.metrics_listen;
+        }
+        if self.otlp_endpoint.is_some() {
+            config.otlp_endpoint = self.otlp_endpoint.clone();
+        }
+        if self.master_key.is_some() {
+            config.master_key.path = self.master_key.clone();
+        }
+        if self.allow_master_key_removal {
+            config.master_key.allow_key_removal = true;
+        }
+        if self.auto_rotate_master_keys {
+            config.master_key.auto_rotate = true;
+        }
+        if self.master_key_passphrase_file.is_some() {
+            config.master_key.passphrase_file = self.master_key_passphrase_file.clone();
+        }
+        if self.master_key_passphrase_fd.is_some() {
+            config.master_key.passphrase_fd = self.master_key_passphrase_fd;
+        }
+        if self.dek_algorithm.is_some() {
+            config.dek_algorithm = self.dek_algorithm;
+        }
+        if self.dek_reuse_max_encryptions.is_some() {
+            config.dek_reuse.max_encryptions = self.dek_reuse_max_encryptions;
+        }
+        if self.dek_reuse_max_age_seconds.is_some() {
+            config.dek_reuse.max_age_seconds = self.dek_reuse_max_age_seconds;
+        }
+        if self.dek_cache_capacity.is_some() {
+            config.dek_cache.capacity = self.dek_cache_capacity;
+        }
+        if self.dek_cache_ttl_seconds.is_some() {
+            config.dek_cache.ttl_seconds = self.dek_cache_ttl_seconds;
+        }
+        if self.audit_queue_capacity.is_some() {
+            config.audit.queue_capacity = self.audit_queue_capacity;
+        }
+        if self.max_message_size.is_some() {
+            config.limits.max_message_size = self.max_message_size;
+        }
+        if self.max_concurrent_streams.is_some() {
+            config.limits.max_concurrent_streams = self.max_concurrent_streams;
+        }
+        if self.batch_concurrency.is_some() {
+            config.limits.batch_concurrency = self.batch_concurrency;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Returns listeners with the global service and TLS flags applied
+    /// to those which do not override them.
+    fn listeners(&self, listen: &[ListenerSpec]) -> Vec<ListenerSpec> {
+        listen
+            .iter()
+            .cloned()
+            .map(|mut spec| {
+                spec.services.get_or_insert(RouteSet {
+                    kms_v2: self.kms_v2,
+                    kagimori_v1: self.kagimori_v1,
+                    ..Default::default()
+                });
+                if matches!(spec.address, ListenAddress::Tcp(_)) {
+                    let tls = &mut spec.tls;
+                    tls.certificate = tls
+                        .certificate
+                        .take()
+                        .or(self.tls_certificate.as_ref().map(Into::into));
+                    tls.private_key = tls
+                        .private_key
+                        .take()
+                        .or(self.tls_private_key.as_ref().map(Into::into));
+                    tls.client_ca = tls
+                        .client_ca
+                        .take()
+                        .or(self.tls_client_ca.as_ref().map(Into::into));
+                }
+                spec
+            })
+            .collect()
+    }
+}
+
+impl ConfigCommand {
+    /// Runs the command, resolving configuration files as the server does with `args`.
+    pub(crate) fn run(self, args: &Args) -> Result<(), String> {
+        match self {
+            ConfigCommand::Check { file } => {
+                let config = args.resolve_config_file(Some(&file))?;
+                if let Some(master_key) = &config.master_key.path {
+                    MasterKeyConfig::load(master_key, &mut config.master_key.passphrase())?
+                        .into_cipher()?;
+                }
+                println!("{}: OK", file.display());
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_config_applies_flags_to_file_listeners() {
+        let dir = tempfile::tempdir().unwrap();
+        let master_key = dir.path().join("master-key.yaml");
+        std::fs::write(&master_key, "").unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            format!(
+                r#"
+                [[listeners]]
+                address = "tcp://127.0.0.1:8602"
+
+                [[listeners]]
+                address = "tcp://127.0.0.1:8603"
+                services = ["admin"]
+                tls = {{ certificate = "own.crt", private-key = "own.key", client-ca = "own-ca.crt" }}
+
+                [master-key]
+                path = "{}"
+                "#,
+                master_key.display()
+            ),
+        )
+        .unwrap();
+
+        let args = Args::parse_from(["kagimori", "--kms-v2"]);
+        let config = args.resolve_config_file(Some(&path)).unwrap();
+        assert!(config.listeners[0].services.unwrap().kms_v2);
+        assert!(!config.listeners[1].services.unwrap().kms_v2);
+
+        let args = Args::parse_from([
+            "kagimori",
+            "--kagimori-v1",
+            "--tls-certificate=flag.crt",
+            "--tls-private-key=flag.key",
+        ]);
+        let config = args.resolve_config_file(Some(&path)).unwrap();
+        assert!(config.listeners[0].services.unwrap().kagimori_v1);
+        assert_eq!(
+            config.listeners[0].tls.certificate.as_deref(),
+            Some(Path::new("flag.crt"))
+        );
+        assert_eq!(
+            config.listeners[1].tls.certificate.as_deref(),
+            Some(Path::new("own.crt"))
+        );
+
+        // without listeners in the file, the default one is validated
+        std::fs::write(
+            &path,
+            format!("[master-key]\npath = \"{}\"\n", master_key.display()),
+        )
+        .unwrap();
+        let args = Args::parse_from(["kagimori"]);
+        assert!(args.resolve_config_file(Some(&path)).is_err());
+        let args = Args::parse_from(["kagimori", "--kms-v2"]);
+        let config = args.resolve_config_file(Some(&path)).unwrap();
+        assert_eq!(config.listeners[0].address, DEFAULT_LISTEN.parse().unwrap());
+    }
+
+    #[test]
+    fn test_resolve_config_requires_mtls_for_admin_over_tcp() {
+        let master_key = tempfile::NamedTempFile::new().unwrap();
+        let master_key = format!("--master-key={}", master_key.path().display());
+        let resolve = |admin_listen: &str| {
+            Args::parse_from([
+                "kagimori",
+                "--kms-v2",
+                &master_key,
+                "--admin-listen",
+                admin_listen,
+            ])
+            .resolve_config_file(None)
+        };
+        let refused = "admin service over TCP requires TLS with a client CA on listener \
+                       Tcp(127.0.0.1:8603)";
+
+        assert_eq!(resolve("tcp://127.0.0.1:8603").unwrap_err(), refused);
+        assert_eq!(
+            resolve("tcp://127.0.0.1:8603?tls-certificate=tls.crt&tls-private-key=tls.key")
+                .unwrap_err(),
+            refused
+        );
+        let config = resolve(
+            "tcp://127.0.0.1:8603?tls-certificate=tls.crt&tls-private-key=tls.key&tls-client-ca=ca.crt",
+        )
+        .unwrap();
+        let admin = &config.listeners[1];
+        assert!(admin.services.unwrap().admin);
+        assert_eq!(admin.tls.client_ca.as_deref(), Some(Path::new("ca.crt")));
+
+        let config = resolve("unix:///run/kagimori/admin.sock").unwrap();
+        assert!(config.listeners[1].services.unwrap().admin);
     }
 }
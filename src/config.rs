@@ -0,0 +1,380 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+//! Server configuration file given by `--config`.
+//!
+//! Each setting is resolved in the following order, later ones taking precedence:
+//!
+//! 1. Built-in defaults
+//! 2. Configuration file
+//! 3. `KAGIMORI_*` environment variables
+//! 4. Command line flags
+//!
+//! Listeners given by `--listen` (or `KAGIMORI_LISTEN`) replace those of the file.
+
+use crate::args::CipherAlgorithm;
+use crate::format::read_file;
+use crate::listener::ListenerSpec;
+use crate::passphrase::{PASSPHRASE_ENV, Passphrase};
+use encryption::{DekCache, DekReuse, MAX_DEK_REUSE_ENCRYPTIONS};
+use serde::Deserialize;
+use server::Limits;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub(crate) const DEFAULT_LISTEN: &str = "tcp://0.0.0.0:8602";
+pub(crate) const DEFAULT_AUDIT_QUEUE_CAPACITY: usize = 1024;
+pub(crate) const DEFAULT_DEK_REUSE_MAX_ENCRYPTIONS: u64 = 1 << 20;
+pub(crate) const DEFAULT_DEK_REUSE_MAX_AGE_SECONDS: u64 = 3600;
+pub(crate) const DEFAULT_DEK_CACHE_CAPACITY: usize = 4096;
+pub(crate) const DEFAULT_DEK_CACHE_TTL_SECONDS: u64 = 300;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Config {
+    #[serde(default)]
+    pub listeners: Vec<ListenerSpec>,
+    pub metrics_listen: Option<SocketAddr>,
+    pub otlp_endpoint: Option<String>,
+    pub dek_algorithm: Option<CipherAlgorithm>,
+    #[serde(default)]
+    pub dek_reuse: DekReuseConfig,
+    #[serde(default)]
+    pub dek_cache: DekCacheConfig,
+    #[serde(default)]
+    pub master_key: MasterKeySource,
+    #[serde(default)]
+    pub audit: AuditConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct MasterKeySource {
+    /// Path to master key configuration file
+    pub path: Option<PathBuf>,
+    /// Allow reloads to remove keys which may still be referenced by data
+    #[serde(default)]
+    pub allow_key_removal: bool,
+    /// Rotate keys by their rotation policies, which only one replica may do
+    #[serde(default)]
+    pub auto_rotate: bool,
+    /// Shortest grace period of pending destruction accepted from the admin API
+    pub min_destruction_grace_period_seconds: Option<u64>,
+    /// File to read the passphrase of an encrypted master key file from
+    pub passphrase_file: Option<PathBuf>,
+    /// File descriptor to read the passphrase of an encrypted master key file from
+    pub passphrase_fd: Option<i32>,
+}
+
+impl MasterKeySource {
+    pub(crate) fn passphrase(&self) -> Passphrase {
+        Passphrase::new(
+            self.passphrase_file.clone(),
+            self.passphrase_fd,
+            PASSPHRASE_ENV,
+        )
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct AuditConfig {
+    pub queue_capacity: Option<usize>,
+    #[serde(default = "default_audit_sinks")]
+    pub sinks: Vec<AuditSink>,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            queue_capacity: None,
+            sinks: default_audit_sinks(),
+        }
+    }
+}
+
+fn default_audit_sinks() -> Vec<AuditSink> {
+    vec![AuditSink::Log]
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) enum AuditSink {
+    /// Write audit logs to the application log
+    Log,
+    /// Append audit logs to a file as JSON lines
+    File { path: PathBuf },
+}
+
+/// Reuse of a DEK for many encryptions, which is disabled unless either limit is set.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct DekReuseConfig {
+    pub max_encryptions: Option<u64>,
+    pub max_age_seconds: Option<u64>,
+}
+
+impl DekReuseConfig {
+    pub(crate) fn limits(&self) -> Option<DekReuse> {
+        if self.max_encryptions.is_none() && self.max_age_seconds.is_none() {
+            return None;
+        }
+        Some(DekReuse {
+            max_encryptions: self
+                .max_encryptions
+                .unwrap_or(DEFAULT_DEK_REUSE_MAX_ENCRYPTIONS),
+            max_age: Duration::from_secs(
+                self.max_age_seconds
+                    .unwrap_or(DEFAULT_DEK_REUSE_MAX_AGE_SECONDS),
+            ),
+        })
+    }
+}
+
+/// Cache of unwrapped DEKs for decryption, which is disabled unless either limit is set.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct DekCacheConfig {
+    pub capacity: Option<usize>,
+    pub ttl_seconds: Option<u64>,
+}
+
+impl DekCacheConfig {
+    pub(crate) fn limits(&self) -> Result<Option<DekCache>, String> {
+        if self.capacity.is_none() && self.ttl_seconds.is_none() {
+            return Ok(None);
+        }
+        let capacity = NonZeroUsize::new(self.capacity.unwrap_or(DEFAULT_DEK_CACHE_CAPACITY))
+            .ok_or("DEK cache capacity must be positive")?;
+        Ok(Some(DekCache {
+            capacity,
+            ttl: Duration::from_secs(self.ttl_seconds.unwrap_or(DEFAULT_DEK_CACHE_TTL_SECONDS)),
+        }))
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct LimitsConfig {
+    pub max_message_size: Option<usize>,
+    pub max_concurrent_streams: Option<u32>,
+    pub batch_concurrency: Option<usize>,
+}
+
+impl From<&LimitsConfig> for Limits {
+    fn from(value: &LimitsConfig) -> Self {
+        Limits {
+            max_message_size: value.max_message_size,
+            max_concurrent_streams: value.max_concurrent_streams,
+            batch_concurrency: value.batch_concurrency,
+        }
+    }
+}
+
+impl Config {
+    pub(crate) fn load(path: &Path) -> Result<Self, String> {
+        read_file(path)
+    }
+
+    /// Checks consistency of settings which cannot be expressed in the schema.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.listeners.is_empty() {
+            return Err("no listeners are configured".to_string());
+        }
+        for listener in &self.listeners {
+            if listener.services.is_none_or(|s| s.is_empty()) {
+                return Err(format!(
+                    "no services are enabled on listener {:?}",
+                    listener.address
+                ));
+            }
+            listener
+                .check_tls()
+                .map_err(|e| format!("{e} on listener {:?}", listener.address))?;
+        }
+        match self.dek_reuse.max_encryptions {
+            Some(0) => return Err("DEK reuse max encryptions must be positive".to_string()),
+            Some(n) if n > MAX_DEK_REUSE_ENCRYPTIONS => {
+                return Err(format!(
+                    "DEK reuse max encryptions must not exceed {MAX_DEK_REUSE_ENCRYPTIONS}"
+                ));
+            }
+            _ => {}
+        }
+        self.dek_cache.limits()?;
+        if self.limits.batch_concurrency == Some(0) {
+            return Err("batch concurrency must be positive".to_string());
+        }
+
+        if let Some(endpoint) = &self.otlp_endpoint {
+            telemetry::trace::check_endpoint(endpoint)?;
+        }
+        let Some(master_key) = &self.master_key.path else {
+            return Err("master key is not configured".to_string());
+        };
+        if !master_key.is_file() {
+            return Err(format!(
+                "master key file does not exist: {}",
+                master_key.display()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::FileFormat;
+    use crate::listener::ListenAddress;
+
+    #[test]
+    fn test_parse_toml() {
+        let config: Config = FileFormat::Toml
+            .parse(
+                r#"
+                metrics-listen = "127.0.0.1:9602"
+                dek-algorithm = "aes-gcm-siv"
+
+                [dek-reuse]
+                max-encryptions = 1000
+
+                [dek-cache]
+                ttl-seconds = 60
+
+                [master-key]
+                path = "/etc/kagimori/keys/master-key.yaml"
+
+                [[listeners]]
+                address = "unix:///var/run/kagimori/kagimori.sock"
+                services = ["kms-v2"]
+
+                [[listeners]]
+                address = "tcp://0.0.0.0:8602"
+                services = ["kagimori-v1"]
+                tls = { certificate = "/tls/tls.crt", private-key = "/tls/tls.key" }
+
+                [audit]
+                sinks = [{ type = "log" }, { type = "file", path = "/var/log/kagimori/audit.log" }]
+
+                [limits]
+                max-message-size = 1048576
+                batch-concurrency = 32
+                "#,
+            )
+            .unwrap();
+
+        assert_eq!(config.listeners.len(), 2);
+        assert_eq!(
+            config.listeners[0].address,
+            ListenAddress::Unix("/var/run/kagimori/kagimori.sock".into())
+        );
+        assert!(config.listeners[1].services.unwrap().kagimori_v1);
+        assert_eq!(
+            config.listeners[1].tls.private_key,
+            Some("/tls/tls.key".into())
+        );
+        assert!(matches!(
+            config.dek_algorithm,
+            Some(CipherAlgorithm::AesGcmSiv)
+        ));
+        assert_eq!(
+            config.dek_reuse.limits(),
+            Some(DekReuse {
+                max_encryptions: 1000,
+                max_age: Duration::from_secs(DEFAULT_DEK_REUSE_MAX_AGE_SECONDS),
+            })
+        );
+        assert_eq!(
+            config.dek_cache.limits().unwrap(),
+            Some(DekCache {
+                capacity: NonZeroUsize::new(DEFAULT_DEK_CACHE_CAPACITY).unwrap(),
+                ttl: Duration::from_secs(60),
+            })
+        );
+        assert_eq!(config.audit.sinks.len(), 2);
+        assert_eq!(config.limits.max_message_size, Some(1048576));
+        assert_eq!(Limits::from(&config.limits).batch_concurrency, Some(32));
+    }
+
+    #[test]
+    fn test_parse_yaml() {
+        let config: Config = FileFormat::Yaml
+            .parse(
+                r#"
+                listeners:
+                  - address: unix:///var/run/kagimori/kagimori.sock
+                    services: [kms-v2, health]
+                master-key:
+                  path: /etc/kagimori/keys/master-key.yaml
+                "#,
+            )
+            .unwrap();
+
+        let services = config.listeners[0].services.unwrap();
+        assert!(services.kms_v2 && services.health);
+        assert!(matches!(config.audit.sinks[..], [AuditSink::Log]));
+        assert_eq!(config.dek_reuse.limits(), None);
+        assert_eq!(config.dek_cache.limits().unwrap(), None);
+    }
+
+    #[test]
+    fn test_reject_unknown_fields() {
+        assert!(
+            FileFormat::Toml
+                .parse::<Config>("metric-listen = \"127.0.0.1:9602\"")
+                .is_err()
+        );
+        assert!(
+            FileFormat::Toml
+                .parse::<Config>("[limits]\nmax-request-size = 1")
+                .is_err()
+        );
+        assert!(
+            FileFormat::Yaml
+                .parse::<Config>(
+                    "listeners:\n  - address: tcp://0.0.0.0:8602\n    services: [kms-v2]\n    tls: { cert: /tls/tls.crt }\n"
+                )
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_validate_bounds_dek_reuse() {
+        let mut config: Config = FileFormat::Toml
+            .parse("[[listeners]]\naddress = \"tcp://0.0.0.0:8602\"\nservices = [\"kms-v2\"]\n")
+            .unwrap();
+        config.dek_reuse.max_encryptions = Some(MAX_DEK_REUSE_ENCRYPTIONS + 1);
+        assert_eq!(
+            config.validate().unwrap_err(),
+            "DEK reuse max encryptions must not exceed 4294967296"
+        );
+        config.dek_reuse.max_encryptions = Some(0);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_requires_services() {
+        let config: Config = FileFormat::Toml
+            .parse("[[listeners]]\naddress = \"tcp://0.0.0.0:8602\"\nservices = []\n")
+            .unwrap();
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,196 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use clap::ValueEnum;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// Format of a configuration file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub(crate) enum FileFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl FileFormat {
+    /// Detects the format from the extension of `path`, or from `content`
+    /// if the extension is unknown.
+    pub(crate) fn detect(path: &Path, content: &str) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => FileFormat::Toml,
+            Some("yaml" | "yml") => FileFormat::Yaml,
+            Some("json") => FileFormat::Json,
+            _ => Self::sniff(content),
+        }
+    }
+
+    fn sniff(content: &str) -> Self {
+        let first_line = content
+            .lines()
+            .map(str::trim)
+            .find(|l| !l.is_empty() && !l.starts_with('#'))
+            .unwrap_or_default();
+
+        if first_line.starts_with('{') {
+            FileFormat::Json
+        } else if first_line.starts_with('[')
+            || first_line
+                .find('=')
+                .is_some_and(|eq| first_line.find(':').is_none_or(|colon| eq < colon))
+        {
+            FileFormat::Toml
+        } else {
+            FileFormat::Yaml
+        }
+    }
+
+    pub(crate) fn parse<T>(self, content: &str) -> Result<T, String>
+    where
+        T: DeserializeOwned,
+    {
+        match self {
+            FileFormat::Toml => toml::from_str(content).map_err(|e| e.to_string()),
+            FileFormat::Yaml => serde_yaml_ng::from_str(content).map_err(|e| e.to_string()),
+            FileFormat::Json => serde_json::from_str(content).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub(crate) fn serialize<T>(self, value: &T) -> Result<String, String>
+    where
+        T: Serialize,
+    {
+        match self {
+            FileFormat::Toml => toml::to_string(value).map_err(|e| e.to_string()),
+            FileFormat::Yaml => serde_yaml_ng::to_string(value).map_err(|e| e.to_string()),
+            FileFormat::Json => serde_json::to_string_pretty(value)
+                .map(|s| s + "\n")
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Reads and parses a TOML, YAML or JSON file.
+pub(crate) fn read_file<T>(path: &Path) -> Result<T, String>
+where
+    T: DeserializeOwned,
+{
+    let content = std::fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    parse_file(path, &content)
+}
+
+/// Parses `content` read from `path` as TOML, YAML or JSON.
+pub(crate) fn parse_file<T>(path: &Path, content: &str) -> Result<T, String>
+where
+    T: DeserializeOwned,
+{
+    FileFormat::detect(path, content)
+        .parse(content)
+        .map_err(|e| format!("{}: {e}", path.display()))
+}
+
+/// Writes `content` to `path` with 0600 permissions.
+///
+/// The content is written to a temporary file in the same directory which then
+/// replaces `path`, so readers never observe a partially written file.
+pub(crate) fn write_file_atomically(path: &Path, content: &str) -> Result<(), String> {
+    let error = |e: std::io::Error| format!("{}: {e}", path.display());
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| format!("{}: not a file path", path.display()))?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = dir.join(temp_name);
+
+    let result = (|| {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, path)?;
+        std::fs::File::open(dir)?.sync_all()
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result.map_err(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_by_extension() {
+        assert_eq!(
+            FileFormat::detect(Path::new("key.yaml"), "default = 1"),
+            FileFormat::Yaml
+        );
+        assert_eq!(
+            FileFormat::detect(Path::new("key.json"), ""),
+            FileFormat::Json
+        );
+    }
+
+    #[test]
+    fn test_detect_by_content() {
+        let path = Path::new("/etc/kagimori/keys/master-key");
+        assert_eq!(
+            FileFormat::detect(path, "# keys\ndefault = \"id\"\n"),
+            FileFormat::Toml
+        );
+        assert_eq!(
+            FileFormat::detect(path, "[[keys]]\nid = \"id\"\n"),
+            FileFormat::Toml
+        );
+        assert_eq!(
+            FileFormat::detect(path, "default: id\nkeys: []\n"),
+            FileFormat::Yaml
+        );
+        assert_eq!(
+            FileFormat::detect(path, "  {\"default\": \"id\"}"),
+            FileFormat::Json
+        );
+    }
+
+    #[test]
+    fn test_write_file_atomically() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("master-key.yaml");
+
+        write_file_atomically(&path, "first").unwrap();
+        write_file_atomically(&path, "second").unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,518 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use crate::args::CipherAlgorithm;
+use crate::format::{FileFormat, write_file_atomically};
+use crate::keyring_file::{EncryptedKeyring, KdfParams, KeyringFile, SealedKeyring};
+use crate::keystore::{KeyProtection, Keystore, Secret, UnlockedKeystore};
+use crate::master_key::{DEFAULT_DESTRUCTION_GRACE_PERIOD, MasterKey, MasterKeyConfig};
+use crate::passphrase::{NEW_PASSPHRASE_ENV, PASSPHRASE_ENV, Passphrase, PassphraseArgs};
+use crate::seal::{RootKey, read_shares};
+use chrono::Utc;
+use ciphers::rotatable::{DEFAULT_KEY_NAME, KeyState};
+use clap::{Args, Subcommand, ValueEnum};
+use server::lifecycle::KeyStateError;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use uuid::Uuid;
+
+#[derive(Debug, Args)]
+pub(crate) struct KeygenCommand {
+    /// Algorithm of the generated key
+    #[arg(long, value_enum, default_value = "chacha20-poly1305")]
+    algorithm: CipherAlgorithm,
+    /// Format of the keyring [default: by extension of --output, or yaml]
+    #[arg(long, value_enum)]
+    format: Option<FileFormat>,
+    /// Write the keyring to this file instead of standard output
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+    /// Encrypt the keyring with a passphrase
+    #[arg(long)]
+    encrypt: bool,
+    #[command(flatten)]
+    passphrase: PassphraseArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum KeyringCommand {
+    /// Generate a new key and add it to a keyring file
+    Add {
+        /// Path to keyring file
+        file: PathBuf,
+        /// Algorithm of the generated key
+        #[arg(long, value_enum, default_value = "chacha20-poly1305")]
+        algorithm: CipherAlgorithm,
+        /// Use the new key to encrypt new data
+        #[arg(long)]
+        default: bool,
+        /// Add the key as the primary version of this named key (e.g. payments/pii),
+        /// which is created if missing
+        #[arg(long, conflicts_with = "default")]
+        name: Option<String>,
+        #[command(flatten)]
+        secret: SecretArgs,
+    },
+    /// List keys in a keyring file without revealing them
+    List {
+        /// Path to keyring file
+        file: PathBuf,
+        #[command(flatten)]
+        secret: SecretArgs,
+    },
+    /// Remove a key which is no longer used from a keyring file, recording it as destroyed
+    Retire {
+        /// Path to keyring file
+        file: PathBuf,
+        /// ID of the key to remove
+        id: Uuid,
+        /// Who retires the key, recorded in the keyring
+        #[arg(long, env = "USER", default_value = "unknown")]
+        actor: String,
+        #[command(flatten)]
+        secret: SecretArgs,
+    },
+    /// Change the lifecycle state of a key
+    SetState {
+        /// Path to keyring file
+        file: PathBuf,
+        /// ID of the key
+        id: Uuid,
+        #[arg(value_enum)]
+        state: KeyStateArg,
+        /// Days until a key pending destruction is destroyed by the server
+        #[arg(long, default_value_t = DEFAULT_DESTRUCTION_GRACE_PERIOD.as_secs() / 86400)]
+        grace_period_days: u64,
+        /// Who changes the state, recorded in the keyring
+        #[arg(long, env = "USER", default_value = "unknown")]
+        actor: String,
+        #[command(flatten)]
+        secret: SecretArgs,
+    },
+    /// Rotate the primary version of a key automatically on reaching either limit,
+    /// or stop rotating it if neither is given
+    SetRotationPolicy {
+        /// Path to keyring file
+        file: PathBuf,
+        /// Name of the key
+        #[arg(long, default_value = DEFAULT_KEY_NAME)]
+        name: String,
+        /// Days after which the primary version is rotated
+        #[arg(long)]
+        max_age_days: Option<u64>,
+        /// Number of DEKs wrapped with the primary version after which it is rotated
+        #[arg(long)]
+        max_encryptions: Option<u64>,
+        #[command(flatten)]
+        secret: SecretArgs,
+    },
+    /// Change the passphrase of a keyring file, or encrypt a plain or sealed one with a passphrase
+    Rekey {
+        /// Path to keyring file
+        file: PathBuf,
+        #[command(flatten)]
+        secret: SecretArgs,
+        /// Read the new passphrase from this file
+        #[arg(long)]
+        new_passphrase_file: Option<PathBuf>,
+        /// Read the new passphrase from this file descriptor
+        #[arg(long)]
+        new_passphrase_fd: Option<i32>,
+        /// Store the keyring without encryption instead
+        #[arg(long, conflicts_with_all = ["new_passphrase_file", "new_passphrase_fd"])]
+        remove_passphrase: bool,
+    },
+}
+
+/// Lifecycle states which can be set by hand.
+#[derive(Debug, Copy, Clone, ValueEnum)]
+pub(crate) enum KeyStateArg {
+    Enabled,
+    DecryptOnly,
+    Disabled,
+    PendingDestruction,
+}
+
+impl From<KeyStateArg> for KeyState {
+    fn from(value: KeyStateArg) -> Self {
+        match value {
+            KeyStateArg::Enabled => KeyState::Enabled,
+            KeyStateArg::DecryptOnly => KeyState::DecryptOnly,
+            KeyStateArg::Disabled => KeyState::Disabled,
+            KeyStateArg::PendingDestruction => KeyState::PendingDestruction,
+        }
+    }
+}
+
+const KEYSTORE_UNENCRYPTED: &str = "records of a keystore are always encrypted";
+
+/// Secrets to open an encrypted or sealed keyring file.
+#[derive(Debug, Args)]
+pub(crate) struct SecretArgs {
+    #[command(flatten)]
+    passphrase: PassphraseArgs,
+    /// Read shares of the root key of a sealed keyring from this file, one per line
+    #[arg(long)]
+    share_file: Option<PathBuf>,
+}
+
+impl PassphraseArgs {
+    fn passphrase(&self) -> Passphrase {
+        Passphrase::new(
+            self.passphrase_file.clone(),
+            self.passphrase_fd,
+            PASSPHRASE_ENV,
+        )
+    }
+}
+
+/// A keyring read from a file, with what is needed to write it back the same way.
+pub(crate) struct OpenedKeyring {
+    pub keyring: MasterKeyConfig,
+    pub format: FileFormat,
+    pub protection: Protection,
+    /// Set if the keyring is kept in a keystore instead of a file
+    pub keystore: Option<UnlockedKeystore>,
+}
+
+pub(crate) enum Protection {
+    None,
+    /// Encrypted with a key derived from the passphrase with the KDF parameters
+    Passphrase(Passphrase, KdfParams),
+    Sealed {
+        root_key: RootKey,
+        threshold: u8,
+        shares: u8,
+    },
+}
+
+impl SecretArgs {
+    /// Returns the protection of a keystore, reading shares if it is sealed.
+    pub(crate) fn protection_of(&self, protection: &KeyProtection) -> Result<Protection, String> {
+        match protection {
+            KeyProtection::Passphrase { kdf_params, .. } => Ok(Protection::Passphrase(
+                self.passphrase.passphrase(),
+                *kdf_params,
+            )),
+            KeyProtection::RootKey {
+                share_set,
+                threshold,
+                shares,
+            } => {
+                let root_key =
+                    RootKey::combine(&read_shares(self.share_file.as_deref(), *threshold)?)?;
+                if root_key.share_set() != *share_set {
+                    return Err("shares belong to another share set".to_string());
+                }
+                Ok(Protection::Sealed {
+                    root_key,
+                    threshold: *threshold,
+                    shares: *shares,
+                })
+            }
+        }
+    }
+}
+
+impl Protection {
+    /// Returns the secret which unlocks a keystore protected in this way.
+    pub(crate) fn secret(&mut self) -> Result<Secret<'_>, String> {
+        match self {
+            Protection::None => Err(KEYSTORE_UNENCRYPTED.to_string()),
+            Protection::Passphrase(passphrase, _) => Ok(Secret::Passphrase(passphrase.get(false)?)),
+            Protection::Sealed { root_key, .. } => Ok(Secret::RootKey(root_key)),
+        }
+    }
+
+    /// Returns how the store key of a keystore is protected in this way, with its secret.
+    pub(crate) fn keystore_key(&mut self) -> Result<(KeyProtection, Secret<'_>), String> {
+        let protection = match self {
+            Protection::None => return Err(KEYSTORE_UNENCRYPTED.to_string()),
+            Protection::Passphrase(_, kdf_params) => KeyProtection::passphrase(*kdf_params),
+            Protection::Sealed {
+                root_key,
+                threshold,
+                shares,
+            } => KeyProtection::root_key(root_key, *threshold, *shares),
+        };
+        Ok((protection, self.secret()?))
+    }
+}
+
+impl KeygenCommand {
+    pub(crate) fn run(self) -> Result<(), String> {
+        let format = self.format.unwrap_or_else(|| {
+            self.output
+                .as_deref()
+                .map(|path| FileFormat::detect(path, ""))
+                .unwrap_or(FileFormat::Yaml)
+        });
+        let keyring = MasterKeyConfig::new(MasterKey::generate(self.algorithm));
+        let id = keyring.default_key_id();
+        let content = if self.encrypt {
+            let passphrase = self.passphrase.passphrase().get(true)?.to_string();
+            KeyringFile::Encrypted(EncryptedKeyring::seal(
+                &keyring,
+                &passphrase,
+                KdfParams::default(),
+            )?)
+            .serialize(format)?
+        } else {
+            format.serialize(&keyring)?
+        };
+
+        match &self.output {
+            Some(path) => {
+                if path.exists() {
+                    return Err(format!(
+                        "{}: already exists, use `kagimori keyring add` to add a key",
+                        path.display()
+                    ));
+                }
+                write_file_atomically(path, &content)?;
+                eprintln!("Generated key {id} in {}", path.display());
+            }
+            None => print!("{content}"),
+        }
+        Ok(())
+    }
+}
+
+impl KeyringCommand {
+    pub(crate) fn run(self) -> Result<(), String> {
+        match self {
+            KeyringCommand::Add {
+                file,
+                algorithm,
+                default,
+                name,
+                secret,
+            } => {
+                let mut opened = OpenedKeyring::load(&file, &secret)?;
+                let key = MasterKey::generate(algorithm);
+                let id = key.id();
+                match name {
+                    Some(name) => opened.keyring.add_version(&name, key)?,
+                    None => opened.keyring.add(key, default),
+                }
+                opened.save(&file)?;
+                println!("{id}");
+                Ok(())
+            }
+            KeyringCommand::List { file, secret } => {
+                let opened = OpenedKeyring::load(&file, &secret)?;
+                let keyring = &opened.keyring;
+                for key in keyring.keys() {
+                    let id = key.id();
+                    let state = keyring.key_state(id).unwrap_or_default();
+                    let version = match keyring.version_of(id) {
+                        Some((name, version)) => format!("{name}@v{version}"),
+                        None => DEFAULT_KEY_NAME.to_string(),
+                    };
+                    println!(
+                        "{}\t{}\t{}\t{}\t{}\t{}",
+                        id,
+                        key.algorithm(),
+                        key.fingerprint().as_deref().unwrap_or("-"),
+                        if id == keyring.default_key_id() {
+                            "default"
+                        } else if keyring.is_primary(id) {
+                            "primary"
+                        } else {
+                            "-"
+                        },
+                        version,
+                        state,
+                    );
+                }
+                Ok(())
+            }
+            KeyringCommand::Retire {
+                file,
+                id,
+                actor,
+                secret,
+            } => {
+                let mut opened = OpenedKeyring::load(&file, &secret)?;
+                opened
+                    .keyring
+                    .remove(id, &actor, Utc::now())
+                    .map_err(|e| format!("{}: {e}", file.display()))?;
+                opened.save(&file)?;
+                eprintln!("Retired key {id}; data encrypted with it can no longer be decrypted");
+                Ok(())
+            }
+            KeyringCommand::SetState {
+                file,
+                id,
+                state,
+                grace_period_days,
+                actor,
+                secret,
+            } => {
+                let mut opened = OpenedKeyring::load(&file, &secret)?;
+                let grace_period = Duration::from_secs(grace_period_days.saturating_mul(86400));
+                let old = opened
+                    .keyring
+                    .set_key_state(id, state.into(), &actor, grace_period, Utc::now())
+                    .map_err(|e| match e {
+                        KeyStateError::NotFound(e)
+                        | KeyStateError::Rejected(e)
+                        | KeyStateError::Failed(e) => format!("{}: {e}", file.display()),
+                    })?;
+                let record = opened.keyring.key_state_record(id).cloned();
+                opened.save(&file)?;
+                match record.and_then(|r| r.destroy_at) {
+                    Some(at) => eprintln!("Key {id} is {old} -> pending-destruction until {at}"),
+                    None => eprintln!("Key {id} is {old} -> {}", KeyState::from(state)),
+                }
+                Ok(())
+            }
+            KeyringCommand::SetRotationPolicy {
+                file,
+                name,
+                max_age_days,
+                max_encryptions,
+                secret,
+            } => {
+                let mut opened = OpenedKeyring::load(&file, &secret)?;
+                let max_age =
+                    max_age_days.map(|days| Duration::from_secs(days.saturating_mul(86400)));
+                opened
+                    .keyring
+                    .set_rotation_policy(&name, max_age, max_encryptions, Utc::now())
+                    .map_err(|e| format!("{}: {e}", file.display()))?;
+                opened.save(&file)?;
+                if max_age.is_none() && max_encryptions.is_none() {
+                    eprintln!("Key {name} is no longer rotated automatically");
+                } else {
+                    eprintln!("Key {name} is rotated automatically by the server");
+                }
+                Ok(())
+            }
+            KeyringCommand::Rekey {
+                file,
+                secret,
+                new_passphrase_file,
+                new_passphrase_fd,
+                remove_passphrase,
+            } => {
+                let mut opened = OpenedKeyring::load(&file, &secret)?;
+                let kdf_params = match &opened.protection {
+                    Protection::Passphrase(_, kdf_params) => *kdf_params,
+                    _ => KdfParams::default(),
+                };
+                opened.protection = if remove_passphrase {
+                    Protection::None
+                } else {
+                    let mut new_passphrase =
+                        Passphrase::new(new_passphrase_file, new_passphrase_fd, NEW_PASSPHRASE_ENV);
+                    new_passphrase.get(true)?;
+                    Protection::Passphrase(new_passphrase, kdf_params)
+                };
+                opened.save(&file)?;
+                eprintln!("Updated passphrase of {}", file.display());
+                Ok(())
+            }
+        }
+    }
+}
+
+impl OpenedKeyring {
+    pub(crate) fn load(path: &Path, secret: &SecretArgs) -> Result<Self, String> {
+        let error = |e| format!("{}: {e}", path.display());
+        if Keystore::is_keystore(path) {
+            let keystore = Keystore::open(path)?;
+            let mut protection = secret.protection_of(keystore.protection())?;
+            let keystore = keystore.unlock(protection.secret()?)?;
+            return Ok(Self {
+                keyring: keystore.load_keyring()?,
+                format: FileFormat::Yaml,
+                protection,
+                keystore: Some(keystore),
+            });
+        }
+        let content = std::fs::read_to_string(path).map_err(|e| error(e.to_string()))?;
+        let format = FileFormat::detect(path, &content);
+        let (keyring, protection) = match KeyringFile::parse(path, &content)? {
+            KeyringFile::Plain(keyring) => (keyring, Protection::None),
+            KeyringFile::Encrypted(encrypted) => {
+                let mut passphrase = secret.passphrase.passphrase();
+                let keyring = encrypted.open(passphrase.get(false)?).map_err(error)?;
+                (
+                    keyring,
+                    Protection::Passphrase(passphrase, encrypted.kdf_params()),
+                )
+            }
+            KeyringFile::Sealed(sealed) => {
+                let shares = read_shares(secret.share_file.as_deref(), sealed.threshold())?;
+                let root_key = RootKey::combine(&shares).map_err(error)?;
+                let keyring = sealed.open(&root_key).map_err(error)?;
+                let protection = Protection::Sealed {
+                    root_key,
+                    threshold: sealed.threshold(),
+                    shares: sealed.shares(),
+                };
+                (keyring, protection)
+            }
+        };
+        Ok(Self {
+            keyring,
+            format,
+            protection,
+            keystore: None,
+        })
+    }
+
+    /// Protects a plain keyring by a new passphrase, as a keystore is always encrypted.
+    pub(crate) fn protect_plain(&mut self, secret: &SecretArgs) -> Result<(), String> {
+        if let Protection::None = self.protection {
+            let mut passphrase = secret.passphrase.passphrase();
+            passphrase.get(true)?;
+            self.protection = Protection::Passphrase(passphrase, KdfParams::default());
+        }
+        Ok(())
+    }
+
+    /// Validates the keyring and writes it back, protected in the same way.
+    pub(crate) fn save(mut self, path: &Path) -> Result<(), String> {
+        self.keyring
+            .clone()
+            .into_cipher()
+            .map_err(|e| format!("{}: {e}", path.display()))?;
+        if let Some(mut keystore) = self.keystore {
+            let (protection, secret) = self.protection.keystore_key()?;
+            keystore.protect(protection, secret)?;
+            return keystore.save_keyring(&self.keyring);
+        }
+        let file = match &mut self.protection {
+            Protection::None => KeyringFile::Plain(self.keyring),
+            Protection::Passphrase(passphrase, kdf_params) => KeyringFile::Encrypted(
+                EncryptedKeyring::seal(&self.keyring, passphrase.get(false)?, *kdf_params)?,
+            ),
+            Protection::Sealed {
+                root_key,
+                threshold,
+                shares,
+            } => KeyringFile::Sealed(SealedKeyring::seal(
+                &self.keyring,
+                root_key,
+                *threshold,
+                *shares,
+            )?),
+        };
+        write_file_atomically(path, &file.serialize(self.format)?)
+    }
+}
@@ -0,0 +1,469 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use crate::format::{FileFormat, parse_file};
+use crate::master_key::MasterKeyConfig;
+use crate::passphrase::Passphrase;
+use crate::seal::RootKey;
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, OsRng, Payload};
+use chacha20poly1305::{AeadCore, KeyInit, XChaCha20Poly1305, XNonce};
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+pub(crate) const SALT_SIZE: usize = 16;
+/// Upper bound of the Argon2 memory cost accepted from a file, in KiB (4 GiB).
+const MAX_MEMORY_COST: u32 = 4 * 1024 * 1024;
+/// Upper bound of the Argon2 iterations accepted from a file.
+const MAX_TIME_COST: u32 = 16;
+/// Upper bound of the Argon2 lanes accepted from a file.
+const MAX_PARALLELISM: u32 = 64;
+
+/// A master key file, holding a plain keyring, one encrypted with a passphrase,
+/// or one sealed with a root key split into shares.
+#[derive(Debug)]
+pub(crate) enum KeyringFile {
+    Plain(MasterKeyConfig),
+    Encrypted(EncryptedKeyring),
+    Sealed(SealedKeyring),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EncryptedKeyringFile {
+    encrypted: EncryptedKeyring,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SealedKeyringFile {
+    sealed: SealedKeyring,
+}
+
+/// Keyring encrypted by XChaCha20-Poly1305 with a key derived from a passphrase by Argon2id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct EncryptedKeyring {
+    version: u32,
+    kdf: Kdf,
+    kdf_params: KdfParams,
+    salt: String,
+    cipher: SealCipher,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Keyring encrypted by XChaCha20-Poly1305 with a random root key,
+/// which is split into Shamir shares held by operators.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct SealedKeyring {
+    version: u32,
+    share_set: String,
+    threshold: u8,
+    shares: u8,
+    cipher: SealCipher,
+    nonce: String,
+    ciphertext: String,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Kdf {
+    Argon2id,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum SealCipher {
+    Xchacha20Poly1305,
+}
+
+/// Argon2id cost parameters.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct KdfParams {
+    /// Memory size in KiB
+    pub memory_cost: u32,
+    /// Number of iterations
+    pub time_cost: u32,
+    /// Degree of parallelism
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    /// The second recommended option of RFC 9106.
+    fn default() -> Self {
+        Self {
+            memory_cost: 64 * 1024,
+            time_cost: 3,
+            parallelism: 4,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Rejects parameters from a file which would take too long or too much memory to derive.
+    pub(crate) fn check_bounds(&self) -> Result<(), String> {
+        if self.memory_cost > MAX_MEMORY_COST {
+            return Err(format!("memory-cost must be at most {MAX_MEMORY_COST} KiB"));
+        }
+        if self.time_cost > MAX_TIME_COST {
+            return Err(format!("time-cost must be at most {MAX_TIME_COST}"));
+        }
+        if self.parallelism > MAX_PARALLELISM {
+            return Err(format!("parallelism must be at most {MAX_PARALLELISM}"));
+        }
+        Ok(())
+    }
+}
+
+impl KeyringFile {
+    /// Loads a TOML, YAML or JSON master key file.
+    pub(crate) fn load(path: &Path) -> Result<Self, String> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        Self::parse(path, &content)
+    }
+
+    /// Parses `content` read from `path`.
+    pub(crate) fn parse(path: &Path, content: &str) -> Result<Self, String> {
+        #[derive(Deserialize)]
+        struct Probe {
+            encrypted: Option<IgnoredAny>,
+            sealed: Option<IgnoredAny>,
+        }
+
+        let probe: Probe = parse_file(path, content)?;
+        if probe.encrypted.is_some() {
+            let file: EncryptedKeyringFile = parse_file(path, content)?;
+            Ok(KeyringFile::Encrypted(file.encrypted))
+        } else if probe.sealed.is_some() {
+            let file: SealedKeyringFile = parse_file(path, content)?;
+            Ok(KeyringFile::Sealed(file.sealed))
+        } else {
+            parse_file(path, content).map(KeyringFile::Plain)
+        }
+    }
+
+    /// Returns the keyring, decrypting it with `passphrase` if needed.
+    pub(crate) fn open(self, passphrase: &mut Passphrase) -> Result<MasterKeyConfig, String> {
+        match self {
+            KeyringFile::Plain(keyring) => Ok(keyring),
+            KeyringFile::Encrypted(encrypted) => encrypted.open(passphrase.get(false)?),
+            KeyringFile::Sealed(_) => {
+                Err("keyring is sealed, shares of the root key are required".to_string())
+            }
+        }
+    }
+
+    pub(crate) fn serialize(&self, format: FileFormat) -> Result<String, String> {
+        match self {
+            KeyringFile::Plain(keyring) => format.serialize(keyring),
+            KeyringFile::Encrypted(encrypted) => format.serialize(&EncryptedKeyringFile {
+                encrypted: encrypted.clone(),
+            }),
+            KeyringFile::Sealed(sealed) => format.serialize(&SealedKeyringFile {
+                sealed: sealed.clone(),
+            }),
+        }
+    }
+}
+
+impl EncryptedKeyring {
+    const VERSION: u32 = 1;
+
+    /// Encrypts `keyring` with a key derived from `passphrase` and a random salt.
+    pub(crate) fn seal(
+        keyring: &MasterKeyConfig,
+        passphrase: &str,
+        params: KdfParams,
+    ) -> Result<Self, String> {
+        let mut salt = [0u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+
+        let mut sealed = Self {
+            version: Self::VERSION,
+            kdf: Kdf::Argon2id,
+            kdf_params: params,
+            salt: BASE64_STANDARD.encode(salt),
+            cipher: SealCipher::Xchacha20Poly1305,
+            nonce: String::new(),
+            ciphertext: String::new(),
+        };
+        let key = derive_key(passphrase, &salt, params)?;
+        (sealed.nonce, sealed.ciphertext) =
+            encrypt_keyring(&key, &sealed.associated_data(), keyring)?;
+        Ok(sealed)
+    }
+
+    pub(crate) fn kdf_params(&self) -> KdfParams {
+        self.kdf_params
+    }
+
+    pub(crate) fn open(&self, passphrase: &str) -> Result<MasterKeyConfig, String> {
+        if self.version != Self::VERSION {
+            return Err(format!(
+                "unsupported encrypted keyring version {}",
+                self.version
+            ));
+        }
+        self.kdf_params.check_bounds()?;
+        let salt = decode("salt", &self.salt)?;
+        let key = derive_key(passphrase, &salt, self.kdf_params)?;
+        decrypt_keyring(&key, &self.associated_data(), &self.nonce, &self.ciphertext)
+            .map_err(|e| format!("{e}: wrong passphrase or corrupted file"))
+    }
+
+    /// Binds the header to the ciphertext, so that it cannot be altered.
+    fn associated_data(&self) -> String {
+        format!(
+            "kagimori-keyring:v{}:{:?}:{}:m={},t={},p={}:{:?}",
+            self.version,
+            self.kdf,
+            self.salt,
+            self.kdf_params.memory_cost,
+            self.kdf_params.time_cost,
+            self.kdf_params.parallelism,
+            self.cipher,
+        )
+    }
+}
+
+impl SealedKeyring {
+    const VERSION: u32 = 1;
+
+    /// Encrypts `keyring` with `root_key`, whose shares are distributed separately.
+    pub(crate) fn seal(
+        keyring: &MasterKeyConfig,
+        root_key: &RootKey,
+        threshold: u8,
+        shares: u8,
+    ) -> Result<Self, String> {
+        let mut sealed = Self {
+            version: Self::VERSION,
+            share_set: root_key.share_set(),
+            threshold,
+            shares,
+            cipher: SealCipher::Xchacha20Poly1305,
+            nonce: String::new(),
+            ciphertext: String::new(),
+        };
+        (sealed.nonce, sealed.ciphertext) =
+            encrypt_keyring(root_key.key(), &sealed.associated_data(), keyring)?;
+        Ok(sealed)
+    }
+
+    pub(crate) fn open(&self, root_key: &RootKey) -> Result<MasterKeyConfig, String> {
+        if self.version != Self::VERSION {
+            return Err(format!(
+                "unsupported sealed keyring version {}",
+                self.version
+            ));
+        }
+        if root_key.share_set() != self.share_set {
+            return Err("root key belongs to another share set".to_string());
+        }
+        decrypt_keyring(
+            root_key.key(),
+            &self.associated_data(),
+            &self.nonce,
+            &self.ciphertext,
+        )
+        .map_err(|e| format!("{e}: wrong shares or corrupted file"))
+    }
+
+    /// Identifies the root key, so that shares of another one are rejected.
+    pub(crate) fn share_set(&self) -> &str {
+        &self.share_set
+    }
+
+    pub(crate) fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    pub(crate) fn shares(&self) -> u8 {
+        self.shares
+    }
+
+    fn associated_data(&self) -> String {
+        format!(
+            "kagimori-sealed-keyring:v{}:{}:{}/{}:{:?}",
+            self.version, self.share_set, self.threshold, self.shares, self.cipher,
+        )
+    }
+}
+
+/// Encrypts the keyring serialized as JSON, returning the nonce and the ciphertext in base64.
+fn encrypt_keyring(
+    key: &[u8; 32],
+    associated_data: &str,
+    keyring: &MasterKeyConfig,
+) -> Result<(String, String), String> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let plaintext = Zeroizing::new(serde_json::to_vec(keyring).map_err(|e| e.to_string())?);
+    let ciphertext = XChaCha20Poly1305::new(key.into())
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: &plaintext,
+                aad: associated_data.as_bytes(),
+            },
+        )
+        .map_err(|_| "cannot encrypt master key file".to_string())?;
+    Ok((
+        BASE64_STANDARD.encode(nonce),
+        BASE64_STANDARD.encode(ciphertext),
+    ))
+}
+
+fn decrypt_keyring(
+    key: &[u8; 32],
+    associated_data: &str,
+    nonce: &str,
+    ciphertext: &str,
+) -> Result<MasterKeyConfig, String> {
+    let nonce = decode("nonce", nonce)?;
+    if nonce.len() != XNonce::default().len() {
+        return Err(format!("nonce must be {} bytes", XNonce::default().len()));
+    }
+    let ciphertext = decode("ciphertext", ciphertext)?;
+
+    let plaintext = Zeroizing::new(
+        XChaCha20Poly1305::new(key.into())
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: associated_data.as_bytes(),
+                },
+            )
+            .map_err(|_| "cannot decrypt master key file".to_string())?,
+    );
+    serde_json::from_slice(&plaintext).map_err(|e| e.to_string())
+}
+
+pub(crate) fn derive_key(
+    passphrase: &str,
+    salt: &[u8],
+    params: KdfParams,
+) -> Result<Zeroizing<[u8; 32]>, String> {
+    let params = Params::new(
+        params.memory_cost,
+        params.time_cost,
+        params.parallelism,
+        Some(32),
+    )
+    .map_err(|e| format!("invalid Argon2 parameters: {e}"))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+        .map_err(|e| format!("cannot derive key from passphrase: {e}"))?;
+    Ok(key)
+}
+
+pub(crate) fn decode(field: &str, value: &str) -> Result<Vec<u8>, String> {
+    BASE64_STANDARD
+        .decode(value)
+        .map_err(|e| format!("{field} is not valid base64: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::CipherAlgorithm;
+    use crate::master_key::MasterKey;
+
+    const PARAMS: KdfParams = KdfParams {
+        memory_cost: 64,
+        time_cost: 1,
+        parallelism: 1,
+    };
+
+    #[test]
+    fn test_seal_and_open() {
+        let keyring = MasterKeyConfig::new(MasterKey::generate(CipherAlgorithm::Chacha20Poly1305));
+        let id = keyring.default_key_id();
+        let sealed = EncryptedKeyring::seal(&keyring, "passphrase", PARAMS).unwrap();
+
+        for format in [FileFormat::Toml, FileFormat::Yaml, FileFormat::Json] {
+            let content = KeyringFile::Encrypted(sealed.clone())
+                .serialize(format)
+                .unwrap();
+            let file = KeyringFile::parse(Path::new("master-key"), &content).unwrap();
+            let KeyringFile::Encrypted(encrypted) = file else {
+                panic!("{content}");
+            };
+            assert_eq!(encrypted.open("passphrase").unwrap().default_key_id(), id);
+            assert!(encrypted.open("wrong").is_err());
+        }
+    }
+
+    #[test]
+    fn test_reject_tampered_header() {
+        let keyring = MasterKeyConfig::new(MasterKey::generate(CipherAlgorithm::AesGcmSiv));
+        let mut sealed = EncryptedKeyring::seal(&keyring, "passphrase", PARAMS).unwrap();
+        sealed.kdf_params.time_cost = 2;
+        assert!(sealed.open("passphrase").is_err());
+    }
+
+    #[test]
+    fn test_reject_excessive_kdf_params() {
+        let keyring = MasterKeyConfig::new(MasterKey::generate(CipherAlgorithm::AesGcmSiv));
+        let sealed = EncryptedKeyring::seal(&keyring, "passphrase", PARAMS).unwrap();
+        for (params, error) in [
+            (
+                KdfParams {
+                    memory_cost: MAX_MEMORY_COST + 1,
+                    ..PARAMS
+                },
+                "memory-cost must be at most 4194304 KiB",
+            ),
+            (
+                KdfParams {
+                    time_cost: MAX_TIME_COST + 1,
+                    ..PARAMS
+                },
+                "time-cost must be at most 16",
+            ),
+            (
+                KdfParams {
+                    parallelism: MAX_PARALLELISM + 1,
+                    ..PARAMS
+                },
+                "parallelism must be at most 64",
+            ),
+        ] {
+            let mut sealed = sealed.clone();
+            sealed.kdf_params = params;
+            assert_eq!(sealed.open("passphrase").unwrap_err(), error);
+        }
+    }
+
+    #[test]
+    fn test_parse_plain() {
+        let file = KeyringFile::parse(
+            Path::new("master-key.yaml"),
+            "default: be555a3d-11fe-4b26-b28f-3ddc349f9c6d\nkeys: []\n",
+        )
+        .unwrap();
+        assert!(matches!(file, KeyringFile::Plain(_)));
+    }
+}
@@ -0,0 +1,1044 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use crate::format::{FileFormat, parse_file, write_file_atomically};
+use crate::keyring::{OpenedKeyring, SecretArgs};
+use crate::keyring_file::{KdfParams, SALT_SIZE, decode, derive_key};
+use crate::master_key::MasterKeyConfig;
+use crate::seal::RootKey;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, OsRng, Payload};
+use chacha20poly1305::{AeadCore, KeyInit, XChaCha20Poly1305, XNonce};
+use chrono::{DateTime, Utc};
+use clap::Subcommand;
+use redb::{
+    Builder, Database, ReadOnlyDatabase, ReadableDatabase, ReadableTable, Table, TableDefinition,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+/// Version of the layout of records, which is bumped when it changes incompatibly.
+pub(crate) const SCHEMA_VERSION: u32 = 1;
+/// Start of redb database files, which tells a keystore from a keyring file.
+const MAGIC: &[u8] = b"redb\x1a\n";
+const META: TableDefinition<&str, &[u8]> = TableDefinition::new("meta");
+const RECORDS: TableDefinition<&str, &[u8]> = TableDefinition::new("records");
+const HEADER: &str = "header";
+const MANIFEST: &str = "manifest";
+/// Prefix of records holding the keyring, split by [`MasterKeyConfig::to_records`].
+const KEYRING_PREFIX: &str = "keyring/";
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum KeystoreCommand {
+    /// Create a keystore from a keyring file, protected by the passphrase or the root key
+    /// of the file, or by a new passphrase if the file is plain
+    Create {
+        /// Path to the keystore to create
+        store: PathBuf,
+        /// Path to keyring file
+        #[arg(long)]
+        from: PathBuf,
+        #[command(flatten)]
+        secret: SecretArgs,
+    },
+    /// Write an encrypted backup of a keystore, which is imported with the same passphrase or shares
+    Export {
+        /// Path to the keystore
+        store: PathBuf,
+        /// Path to the backup (TOML, YAML or JSON by extension)
+        #[arg(long, short)]
+        output: PathBuf,
+        #[command(flatten)]
+        secret: SecretArgs,
+    },
+    /// Restore a keystore from a backup
+    Import {
+        /// Path to the backup
+        backup: PathBuf,
+        /// Path to the keystore to create
+        store: PathBuf,
+        #[command(flatten)]
+        secret: SecretArgs,
+    },
+}
+
+impl KeystoreCommand {
+    pub(crate) fn run(self) -> Result<(), String> {
+        match self {
+            KeystoreCommand::Create {
+                store,
+                from,
+                secret,
+            } => {
+                let mut opened = OpenedKeyring::load(&from, &secret)?;
+                if opened.keystore.is_some() {
+                    return Err(format!("{}: already a keystore", from.display()));
+                }
+                opened.protect_plain(&secret)?;
+                let (protection, key) = opened.protection.keystore_key()?;
+                let mut keystore = UnlockedKeystore::create(&store, protection, key)?;
+                opened.keyring.clone().into_cipher()?;
+                keystore.save_keyring(&opened.keyring)?;
+                eprintln!("Created {} from {}", store.display(), from.display());
+                Ok(())
+            }
+            KeystoreCommand::Export {
+                store,
+                output,
+                secret,
+            } => {
+                let keystore = Keystore::open(&store)?;
+                let mut protection = secret.protection_of(keystore.protection())?;
+                let backup = keystore.unlock(protection.secret()?)?.export()?;
+                let format = FileFormat::detect(&output, "");
+                write_file_atomically(&output, &format.serialize(&backup)?)?;
+                eprintln!("Exported {} to {}", store.display(), output.display());
+                Ok(())
+            }
+            KeystoreCommand::Import {
+                backup: path,
+                store,
+                secret,
+            } => {
+                let content = std::fs::read_to_string(&path)
+                    .map_err(|e| format!("{}: {e}", path.display()))?;
+                let backup: Backup = parse_file(&path, &content)?;
+                let mut protection = secret.protection_of(&backup.header.protection)?;
+                backup.restore(&store, protection.secret()?)?;
+                eprintln!("Imported {} into {}", path.display(), store.display());
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Secret which the store key of a keystore is encrypted under.
+pub(crate) enum Secret<'a> {
+    Passphrase(&'a str),
+    RootKey(&'a RootKey),
+}
+
+/// How the store key, which encrypts the records, is protected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) enum KeyProtection {
+    /// By a key derived from a passphrase by Argon2id
+    #[serde(rename_all = "kebab-case")]
+    Passphrase { kdf_params: KdfParams, salt: String },
+    /// By the root key of sealed mode, which is split into shares
+    #[serde(rename_all = "kebab-case")]
+    RootKey {
+        share_set: String,
+        threshold: u8,
+        shares: u8,
+    },
+}
+
+impl KeyProtection {
+    /// Protection by a passphrase with a random salt.
+    pub(crate) fn passphrase(kdf_params: KdfParams) -> Self {
+        let mut salt = [0u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        KeyProtection::Passphrase {
+            kdf_params,
+            salt: BASE64_STANDARD.encode(salt),
+        }
+    }
+
+    pub(crate) fn root_key(root_key: &RootKey, threshold: u8, shares: u8) -> Self {
+        KeyProtection::RootKey {
+            share_set: root_key.share_set(),
+            threshold,
+            shares,
+        }
+    }
+
+    /// Returns the key which the store key is encrypted under.
+    fn key(&self, secret: Secret<'_>) -> Result<Zeroizing<[u8; 32]>, String> {
+        match (self, secret) {
+            (KeyProtection::Passphrase { kdf_params, salt }, Secret::Passphrase(passphrase)) => {
+                kdf_params.check_bounds()?;
+                derive_key(passphrase, &decode("salt", salt)?, *kdf_params)
+            }
+            (KeyProtection::RootKey { share_set, .. }, Secret::RootKey(root_key)) => {
+                if root_key.share_set() != *share_set {
+                    return Err("root key belongs to another share set".to_string());
+                }
+                Ok(Zeroizing::new(*root_key.key()))
+            }
+            (KeyProtection::Passphrase { .. }, Secret::RootKey(_)) => {
+                Err("keystore is protected by a passphrase".to_string())
+            }
+            (KeyProtection::RootKey { .. }, Secret::Passphrase(_)) => {
+                Err("keystore is sealed, shares of the root key are required".to_string())
+            }
+        }
+    }
+}
+
+/// Kept in the `meta` table, telling how to decrypt the records.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Header {
+    schema_version: u32,
+    protection: KeyProtection,
+    /// Store key encrypted by XChaCha20-Poly1305 under the key of `protection`,
+    /// in base64 with the nonce first
+    wrapped_key: String,
+}
+
+impl Header {
+    fn seal(
+        protection: KeyProtection,
+        secret: Secret<'_>,
+        store_key: &[u8; 32],
+    ) -> Result<Self, String> {
+        let mut header = Self {
+            schema_version: SCHEMA_VERSION,
+            protection,
+            wrapped_key: String::new(),
+        };
+        let key = header.protection.key(secret)?;
+        let wrapped_key = encrypt(&key, &header.associated_data(), store_key)?;
+        header.wrapped_key = BASE64_STANDARD.encode(wrapped_key);
+        Ok(header)
+    }
+
+    fn open(&self, secret: Secret<'_>) -> Result<Zeroizing<[u8; 32]>, String> {
+        self.check_schema_version()?;
+        let key = self.protection.key(secret)?;
+        let store_key = decrypt(
+            &key,
+            &self.associated_data(),
+            &decode("wrapped-key", &self.wrapped_key)?,
+        )
+        .map_err(|e| format!("{e}: wrong secret or corrupted keystore"))?;
+        let store_key: [u8; 32] = store_key
+            .as_slice()
+            .try_into()
+            .map_err(|_| "store key must be 32 bytes".to_string())?;
+        Ok(Zeroizing::new(store_key))
+    }
+
+    fn check_schema_version(&self) -> Result<(), String> {
+        // No migration is needed until the layout changes.
+        if self.schema_version != SCHEMA_VERSION {
+            return Err(format!(
+                "unsupported keystore schema version {}",
+                self.schema_version
+            ));
+        }
+        Ok(())
+    }
+
+    /// Binds the protection to the store key, so that it cannot be altered.
+    fn associated_data(&self) -> String {
+        let protection = serde_json::to_string(&self.protection).unwrap();
+        format!("kagimori-keystore:v{}:{protection}", self.schema_version)
+    }
+}
+
+/// Kept in the `meta` table encrypted under the store key, listing every record with the hash
+/// of its encrypted value, so that no record can be deleted, added or rolled back alone.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Manifest {
+    /// Incremented by every transaction
+    generation: u64,
+    /// SHA-256 hashes of the encrypted records in base64
+    records: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    fn read(
+        meta: &impl ReadableTable<&'static str, &'static [u8]>,
+        key: &[u8; 32],
+    ) -> Result<Self, String> {
+        let manifest = meta
+            .get(MANIFEST)
+            .map_err(|e| e.to_string())?
+            .ok_or("manifest is not found")?;
+        let manifest = decrypt(key, &Self::associated_data(), manifest.value())
+            .map_err(|e| format!("manifest: {e}"))?;
+        serde_json::from_slice(&manifest).map_err(|e| format!("manifest: {e}"))
+    }
+
+    fn seal(&self, key: &[u8; 32]) -> Result<Vec<u8>, String> {
+        let manifest = serde_json::to_vec(self).map_err(|e| e.to_string())?;
+        encrypt(key, &Self::associated_data(), &manifest)
+    }
+
+    fn associated_data() -> String {
+        format!("kagimori-keystore-manifest:v{SCHEMA_VERSION}")
+    }
+
+    /// Checks that `value` is the encrypted value of `name` recorded in the manifest.
+    fn check(&self, name: &str, value: &[u8]) -> Result<(), String> {
+        match self.records.get(name) {
+            Some(hash) if *hash == record_hash(value) => Ok(()),
+            Some(_) => Err(format!("{name}: does not match the manifest")),
+            None => Err(format!("{name}: is not in the manifest")),
+        }
+    }
+
+    fn names<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a String> {
+        self.records
+            .range(prefix.to_string()..)
+            .map(|(name, _)| name)
+            .take_while(move |name| name.starts_with(prefix))
+    }
+}
+
+/// A keystore in an embedded redb database, whose records are not decrypted yet.
+///
+/// Every record is encrypted under a random store key, which is kept in the header
+/// encrypted under a passphrase or the root key. The database is opened only while
+/// used, so the CLI can change a keystore used by a running server.
+pub(crate) struct Keystore {
+    path: PathBuf,
+    header: Header,
+}
+
+/// A keystore whose store key has been recovered.
+pub(crate) struct UnlockedKeystore {
+    path: PathBuf,
+    header: Header,
+    key: Zeroizing<[u8; 32]>,
+    /// Generation of the manifest last read or written, below which the keystore is rolled back
+    generation: u64,
+}
+
+/// A write transaction over records of a keystore, which is committed atomically.
+pub(crate) struct Transaction<'a> {
+    records: Table<'a, &'static str, &'static [u8]>,
+    manifest: Manifest,
+    key: &'a [u8; 32],
+}
+
+/// An encrypted backup of all records of a keystore.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Backup {
+    created_at: DateTime<Utc>,
+    /// Generation of the exported keystore, from which the restored one continues
+    generation: u64,
+    header: Header,
+    /// Records encrypted under the store key, in base64 with the nonce first
+    records: String,
+}
+
+impl Keystore {
+    /// Returns whether `content` read from a master key file is a keystore.
+    pub(crate) fn detect(content: &[u8]) -> bool {
+        content.starts_with(MAGIC)
+    }
+
+    /// Returns whether the file at `path` is a keystore, or `false` if it cannot be read.
+    pub(crate) fn is_keystore(path: &Path) -> bool {
+        let mut magic = [0u8; MAGIC.len()];
+        std::fs::File::open(path)
+            .and_then(|mut file| file.read_exact(&mut magic))
+            .is_ok_and(|()| Self::detect(&magic))
+    }
+
+    pub(crate) fn open(path: &Path) -> Result<Self, String> {
+        let error = |e: String| format!("{}: {e}", path.display());
+        let db = ReadOnlyDatabase::open(path).map_err(|e| error(e.to_string()))?;
+        let header = (|| {
+            let transaction = db.begin_read().map_err(|e| e.to_string())?;
+            let meta = transaction.open_table(META).map_err(|e| e.to_string())?;
+            let header = meta
+                .get(HEADER)
+                .map_err(|e| e.to_string())?
+                .ok_or("header is not found")?;
+            serde_json::from_slice::<Header>(header.value()).map_err(|e| e.to_string())
+        })()
+        .map_err(error)?;
+        header.check_schema_version().map_err(error)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            header,
+        })
+    }
+
+    pub(crate) fn protection(&self) -> &KeyProtection {
+        &self.header.protection
+    }
+
+    /// Recovers the store key with `secret`, and reads the generation of the manifest.
+    pub(crate) fn unlock(self, secret: Secret<'_>) -> Result<UnlockedKeystore, String> {
+        let error = |e: String| format!("{}: {e}", self.path.display());
+        let key = self.header.open(secret).map_err(error)?;
+        let generation = (|| {
+            let db = ReadOnlyDatabase::open(&self.path).map_err(|e| e.to_string())?;
+            let transaction = db.begin_read().map_err(|e| e.to_string())?;
+            let meta = transaction.open_table(META).map_err(|e| e.to_string())?;
+            Manifest::read(&meta, &key).map(|manifest| manifest.generation)
+        })()
+        .map_err(error)?;
+        Ok(UnlockedKeystore {
+            path: self.path,
+            header: self.header,
+            key,
+            generation,
+        })
+    }
+}
+
+impl UnlockedKeystore {
+    /// Prepares a keystore with a new store key, which is written to `path`
+    /// by the first transaction.
+    pub(crate) fn create(
+        path: &Path,
+        protection: KeyProtection,
+        secret: Secret<'_>,
+    ) -> Result<Self, String> {
+        if path.exists() {
+            return Err(format!("{}: already exists", path.display()));
+        }
+        let mut key = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(key.as_mut());
+        Ok(Self {
+            path: path.to_path_buf(),
+            header: Header::seal(protection, secret, &key)?,
+            key,
+            generation: 0,
+        })
+    }
+
+    /// Returns the generation of the manifest, which every transaction increments.
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Protects the store key in another way from the next transaction,
+    /// without encrypting the records again.
+    pub(crate) fn protect(
+        &mut self,
+        protection: KeyProtection,
+        secret: Secret<'_>,
+    ) -> Result<(), String> {
+        self.header = Header::seal(protection, secret, &self.key)?;
+        Ok(())
+    }
+
+    /// Runs `f` in a write transaction, which is committed durably if it succeeds
+    /// and leaves the keystore as before otherwise, even if the process crashes.
+    pub(crate) fn transaction<T>(
+        &mut self,
+        f: impl FnOnce(&mut Transaction<'_>) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let created = !self.path.exists();
+        let result = self.transaction_impl(created, f);
+        if result.is_err() && created {
+            let _ = std::fs::remove_file(&self.path);
+        }
+        result.map_err(|e| format!("{}: {e}", self.path.display()))
+    }
+
+    fn transaction_impl<T>(
+        &mut self,
+        created: bool,
+        f: impl FnOnce(&mut Transaction<'_>) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let db = self.database()?;
+        let transaction = db.begin_write().map_err(|e| e.to_string())?;
+        let (value, generation) = {
+            let mut meta = transaction.open_table(META).map_err(|e| e.to_string())?;
+            let manifest = if created {
+                Manifest {
+                    generation: self.generation,
+                    records: BTreeMap::new(),
+                }
+            } else {
+                Manifest::read(&meta, &self.key)?
+            };
+            if manifest.generation < self.generation {
+                return Err(format!(
+                    "keystore is rolled back to generation {} from {}",
+                    manifest.generation, self.generation
+                ));
+            }
+            let header = serde_json::to_vec(&self.header).map_err(|e| e.to_string())?;
+            meta.insert(HEADER, header.as_slice())
+                .map_err(|e| e.to_string())?;
+
+            let mut records = Transaction {
+                records: transaction.open_table(RECORDS).map_err(|e| e.to_string())?,
+                manifest,
+                key: &self.key,
+            };
+            let value = f(&mut records)?;
+            let mut manifest = records.manifest;
+            manifest.generation += 1;
+            meta.insert(MANIFEST, manifest.seal(&self.key)?.as_slice())
+                .map_err(|e| e.to_string())?;
+            (value, manifest.generation)
+        };
+        transaction.commit().map_err(|e| e.to_string())?;
+        self.generation = generation;
+        Ok(value)
+    }
+
+    fn database(&self) -> Result<Database, String> {
+        if self.path.exists() {
+            return Database::open(&self.path).map_err(|e| e.to_string());
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&self.path)
+            .map_err(|e| e.to_string())?;
+        Builder::new().create_file(file).map_err(|e| e.to_string())
+    }
+
+    /// Returns all records whose names start with `prefix`, decrypted and checked against
+    /// the manifest.
+    pub(crate) fn records(
+        &self,
+        prefix: &str,
+    ) -> Result<BTreeMap<String, Zeroizing<Vec<u8>>>, String> {
+        (|| {
+            let db = ReadOnlyDatabase::open(&self.path).map_err(|e| e.to_string())?;
+            let transaction = db.begin_read().map_err(|e| e.to_string())?;
+            let meta = transaction.open_table(META).map_err(|e| e.to_string())?;
+            let manifest = Manifest::read(&meta, &self.key)?;
+            let table = transaction.open_table(RECORDS).map_err(|e| e.to_string())?;
+            let mut records = BTreeMap::new();
+            for entry in table.range(prefix..).map_err(|e| e.to_string())? {
+                let (name, value) = entry.map_err(|e| e.to_string())?;
+                let name = name.value();
+                if !name.starts_with(prefix) {
+                    break;
+                }
+                manifest.check(name, value.value())?;
+                records.insert(
+                    name.to_string(),
+                    open_record(&self.key, name, value.value())?,
+                );
+            }
+            if let Some(name) = manifest.names(prefix).find(|n| !records.contains_key(*n)) {
+                return Err(format!("{name}: is missing"));
+            }
+            Ok(records)
+        })()
+        .map_err(|e: String| format!("{}: {e}", self.path.display()))
+    }
+
+    pub(crate) fn load_keyring(&self) -> Result<MasterKeyConfig, String> {
+        let records = self
+            .records(KEYRING_PREFIX)?
+            .into_iter()
+            .map(|(name, value)| (name[KEYRING_PREFIX.len()..].to_string(), value));
+        MasterKeyConfig::from_records(records).map_err(|e| format!("{}: {e}", self.path.display()))
+    }
+
+    /// Replaces the keyring in one transaction.
+    pub(crate) fn save_keyring(&mut self, keyring: &MasterKeyConfig) -> Result<(), String> {
+        let records = keyring.to_records()?;
+        self.transaction(|transaction| {
+            for name in transaction.names(KEYRING_PREFIX) {
+                if !records.contains_key(&name[KEYRING_PREFIX.len()..]) {
+                    transaction.delete(&name)?;
+                }
+            }
+            for (name, record) in &records {
+                transaction.put(&format!("{KEYRING_PREFIX}{name}"), record)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Returns a backup of all records, encrypted under the store key.
+    pub(crate) fn export(&self) -> Result<Backup, String> {
+        let created_at = Utc::now();
+        let records = encode_records(&self.records("")?);
+        let associated_data = Backup::associated_data(created_at, self.generation);
+        let records = encrypt(&self.key, &associated_data, &records)?;
+        Ok(Backup {
+            created_at,
+            generation: self.generation,
+            header: self.header.clone(),
+            records: BASE64_STANDARD.encode(records),
+        })
+    }
+}
+
+impl Transaction<'_> {
+    /// Encrypts `plaintext` as the record `name`.
+    pub(crate) fn put(&mut self, name: &str, plaintext: &[u8]) -> Result<(), String> {
+        let value = encrypt(self.key, &record_associated_data(name), plaintext)?;
+        self.records
+            .insert(name, value.as_slice())
+            .map_err(|e| e.to_string())?;
+        self.manifest
+            .records
+            .insert(name.to_string(), record_hash(&value));
+        Ok(())
+    }
+
+    pub(crate) fn delete(&mut self, name: &str) -> Result<(), String> {
+        self.records.remove(name).map_err(|e| e.to_string())?;
+        self.manifest.records.remove(name);
+        Ok(())
+    }
+
+    /// Returns the names of records starting with `prefix`.
+    pub(crate) fn names(&self, prefix: &str) -> Vec<String> {
+        self.manifest.names(prefix).cloned().collect()
+    }
+}
+
+impl Backup {
+    /// Writes the records to a new keystore at `path` in one transaction, after checking
+    /// that they can be decrypted with `secret` and hold a valid keyring.
+    pub(crate) fn restore(self, path: &Path, secret: Secret<'_>) -> Result<(), String> {
+        let key = self.header.open(secret)?;
+        let records = decrypt(
+            &key,
+            &Self::associated_data(self.created_at, self.generation),
+            &decode("records", &self.records)?,
+        )
+        .map_err(|e| format!("{e}: corrupted backup"))?;
+        let records = decode_records(&records)?;
+        let keyring = records.iter().filter_map(|(name, value)| {
+            let name = name.strip_prefix(KEYRING_PREFIX)?;
+            Some((name.to_string(), value.clone()))
+        });
+        MasterKeyConfig::from_records(keyring)?.into_cipher()?;
+
+        if path.exists() {
+            return Err(format!("{}: already exists", path.display()));
+        }
+        let mut keystore = UnlockedKeystore {
+            path: path.to_path_buf(),
+            header: self.header,
+            key,
+            generation: self.generation,
+        };
+        keystore.transaction(|transaction| {
+            for (name, record) in &records {
+                transaction.put(name, record)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Binds the creation time and the generation to the records.
+    fn associated_data(created_at: DateTime<Utc>, generation: u64) -> String {
+        format!(
+            "kagimori-keystore-backup:v{SCHEMA_VERSION}:{}:{generation}",
+            created_at.to_rfc3339()
+        )
+    }
+}
+
+/// Binds a record to its name, so that records cannot be swapped.
+fn record_associated_data(name: &str) -> String {
+    format!("kagimori-keystore-record:v{SCHEMA_VERSION}:{name}")
+}
+
+fn record_hash(value: &[u8]) -> String {
+    BASE64_STANDARD.encode(Sha256::digest(value))
+}
+
+fn open_record(key: &[u8; 32], name: &str, value: &[u8]) -> Result<Zeroizing<Vec<u8>>, String> {
+    decrypt(key, &record_associated_data(name), value).map_err(|e| format!("{name}: {e}"))
+}
+
+/// Encodes records as their names and values, each prefixed by its length in 4 bytes.
+fn encode_records(records: &BTreeMap<String, Zeroizing<Vec<u8>>>) -> Zeroizing<Vec<u8>> {
+    let size = records
+        .iter()
+        .map(|(name, value)| 8 + name.len() + value.len())
+        .sum();
+    // allocated at once, so that no copy is left behind by growing
+    let mut encoded = Zeroizing::new(Vec::with_capacity(size));
+    for (name, value) in records {
+        for field in [name.as_bytes(), value.as_slice()] {
+            encoded.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            encoded.extend_from_slice(field);
+        }
+    }
+    encoded
+}
+
+fn decode_records(mut data: &[u8]) -> Result<BTreeMap<String, Zeroizing<Vec<u8>>>, String> {
+    fn field<'a>(data: &mut &'a [u8]) -> Result<&'a [u8], String> {
+        let truncated = || "records are truncated".to_string();
+        let (size, rest) = data.split_first_chunk::<4>().ok_or_else(truncated)?;
+        let size = u32::from_be_bytes(*size) as usize;
+        if rest.len() < size {
+            return Err(truncated());
+        }
+        let (field, rest) = rest.split_at(size);
+        *data = rest;
+        Ok(field)
+    }
+
+    let mut records = BTreeMap::new();
+    while !data.is_empty() {
+        let name = std::str::from_utf8(field(&mut data)?).map_err(|e| e.to_string())?;
+        let value = Zeroizing::new(field(&mut data)?.to_vec());
+        records.insert(name.to_string(), value);
+    }
+    Ok(records)
+}
+
+/// Encrypts by XChaCha20-Poly1305, returning the nonce followed by the ciphertext.
+fn encrypt(key: &[u8; 32], associated_data: &str, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = XChaCha20Poly1305::new(key.into())
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad: associated_data.as_bytes(),
+            },
+        )
+        .map_err(|_| "cannot encrypt".to_string())?;
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+fn decrypt(
+    key: &[u8; 32],
+    associated_data: &str,
+    data: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, String> {
+    let nonce_size = XNonce::default().len();
+    if data.len() < nonce_size {
+        return Err("ciphertext is too short".to_string());
+    }
+    let (nonce, ciphertext) = data.split_at(nonce_size);
+    XChaCha20Poly1305::new(key.into())
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: associated_data.as_bytes(),
+            },
+        )
+        .map(Zeroizing::new)
+        .map_err(|_| "cannot decrypt".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::CipherAlgorithm;
+    use crate::master_key::MasterKey;
+    use ciphers::rotatable::{DEFAULT_KEY_NAME, KeyState};
+    use std::time::Duration;
+
+    const PARAMS: KdfParams = KdfParams {
+        memory_cost: 64,
+        time_cost: 1,
+        parallelism: 1,
+    };
+
+    fn keyring() -> MasterKeyConfig {
+        let mut keyring =
+            MasterKeyConfig::new(MasterKey::generate(CipherAlgorithm::Chacha20Poly1305));
+        keyring
+            .add_version("payments", MasterKey::generate(CipherAlgorithm::AesGcmSiv))
+            .unwrap();
+        keyring
+            .set_rotation_policy("payments", None, Some(1000), Utc::now())
+            .unwrap();
+        keyring
+    }
+
+    fn create(path: &Path) -> UnlockedKeystore {
+        UnlockedKeystore::create(
+            path,
+            KeyProtection::passphrase(PARAMS),
+            Secret::Passphrase("passphrase"),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_save_and_load_keyring() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("master-key.db");
+        let mut keyring = keyring();
+        let removed = MasterKey::generate(CipherAlgorithm::AesGcmSiv);
+        let removed_id = removed.id();
+        keyring.add(removed, false);
+        create(&path).save_keyring(&keyring).unwrap();
+        assert!(Keystore::is_keystore(&path));
+        assert!(!Keystore::detect(b"default: 00000000"));
+        assert!(
+            UnlockedKeystore::create(
+                &path,
+                KeyProtection::passphrase(PARAMS),
+                Secret::Passphrase("passphrase"),
+            )
+            .is_err()
+        );
+
+        assert!(
+            Keystore::open(&path)
+                .unwrap()
+                .unlock(Secret::Passphrase("wrong"))
+                .is_err()
+        );
+        let mut keystore = Keystore::open(&path)
+            .unwrap()
+            .unlock(Secret::Passphrase("passphrase"))
+            .unwrap();
+        assert_eq!(
+            keystore.load_keyring().unwrap().to_records().unwrap(),
+            keyring.to_records().unwrap()
+        );
+
+        // records no longer in the keyring are deleted
+        keyring.remove(removed_id, "admin", Utc::now()).unwrap();
+        keystore.save_keyring(&keyring).unwrap();
+        let records = keystore.records(KEYRING_PREFIX).unwrap();
+        assert!(!records.contains_key(&format!("{KEYRING_PREFIX}keys/{removed_id}")));
+        assert_eq!(
+            keystore.load_keyring().unwrap().to_records().unwrap(),
+            keyring.to_records().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_transaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("master-key.db");
+        let mut keystore = create(&path);
+
+        // the file of a keystore whose first transaction fails is not left behind
+        let result: Result<(), _> = keystore.transaction(|_| Err("failed".to_string()));
+        assert!(result.is_err());
+        assert!(!path.exists());
+
+        keystore
+            .transaction(|transaction| {
+                transaction.put("a", b"1")?;
+                transaction.put("b", b"2")
+            })
+            .unwrap();
+        assert_eq!(keystore.generation(), 1);
+        let result: Result<(), _> = keystore.transaction(|transaction| {
+            transaction.delete("a")?;
+            transaction.put("c", b"3")?;
+            Err("failed".to_string())
+        });
+        assert!(result.is_err());
+        assert_eq!(keystore.generation(), 1);
+        let records = keystore.records("").unwrap();
+        assert_eq!(records.keys().collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(records["b"].as_slice(), b"2");
+    }
+
+    /// Changes records behind the manifest, as an attacker with write access to the file could.
+    fn tamper(path: &Path, f: impl FnOnce(&mut Table<&'static str, &'static [u8]>)) {
+        let db = Database::open(path).unwrap();
+        let transaction = db.begin_write().unwrap();
+        f(&mut transaction.open_table(RECORDS).unwrap());
+        transaction.commit().unwrap();
+    }
+
+    #[test]
+    fn test_reject_tampered_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("master-key.db");
+        let mut keystore = create(&path);
+        let mut keyring = keyring();
+        let old = MasterKey::generate(CipherAlgorithm::AesGcmSiv);
+        let old_id = old.id();
+        keyring.add(old, false);
+        keyring
+            .set_key_state(
+                old_id,
+                KeyState::Disabled,
+                "admin",
+                Duration::ZERO,
+                Utc::now(),
+            )
+            .unwrap();
+        keystore.save_keyring(&keyring).unwrap();
+        let old_copy = std::fs::read(&path).unwrap();
+        let state = format!("{KEYRING_PREFIX}key-states/{old_id}");
+        let default = format!("{KEYRING_PREFIX}default");
+        let value = |name: &str| {
+            let db = Database::open(&path).unwrap();
+            let transaction = db.begin_read().unwrap();
+            let table = transaction.open_table(RECORDS).unwrap();
+            table.get(name).unwrap().unwrap().value().to_vec()
+        };
+
+        // a record moved to another name
+        let original = value(&default);
+        let moved = value(&state);
+        tamper(&path, |table| {
+            table.insert(default.as_str(), moved.as_slice()).unwrap();
+        });
+        assert!(keystore.load_keyring().is_err());
+        tamper(&path, |table| {
+            table.insert(default.as_str(), original.as_slice()).unwrap();
+        });
+        assert!(keystore.load_keyring().is_ok());
+
+        // a deleted record would enable the key again
+        let original = value(&state);
+        tamper(&path, |table| {
+            table.remove(state.as_str()).unwrap();
+        });
+        assert!(keystore.load_keyring().unwrap_err().contains("is missing"));
+        tamper(&path, |table| {
+            table.insert(state.as_str(), original.as_slice()).unwrap();
+        });
+
+        // a record replayed from an older generation
+        keyring.rotate(DEFAULT_KEY_NAME, Utc::now()).unwrap();
+        keystore.save_keyring(&keyring).unwrap();
+        let replayed = {
+            std::fs::write(dir.path().join("old.db"), &old_copy).unwrap();
+            let db = Database::open(dir.path().join("old.db")).unwrap();
+            let transaction = db.begin_read().unwrap();
+            let table = transaction.open_table(RECORDS).unwrap();
+            table
+                .get(default.as_str())
+                .unwrap()
+                .unwrap()
+                .value()
+                .to_vec()
+        };
+        tamper(&path, |table| {
+            table.insert(default.as_str(), replayed.as_slice()).unwrap();
+        });
+        assert!(
+            keystore
+                .load_keyring()
+                .unwrap_err()
+                .contains("does not match the manifest")
+        );
+
+        // the whole file rolled back to an older copy
+        std::fs::write(&path, &old_copy).unwrap();
+        let result = keystore.transaction(|transaction| transaction.put("other", b"1"));
+        assert!(result.unwrap_err().contains("rolled back"));
+        let reopened = Keystore::open(&path)
+            .unwrap()
+            .unlock(Secret::Passphrase("passphrase"))
+            .unwrap();
+        assert!(reopened.generation() < keystore.generation());
+    }
+
+    #[test]
+    fn test_protect_with_root_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("master-key.db");
+        let keyring = keyring();
+        let root_key = RootKey::generate();
+        let mut keystore = create(&path);
+        keystore
+            .protect(
+                KeyProtection::root_key(&root_key, 2, 3),
+                Secret::RootKey(&root_key),
+            )
+            .unwrap();
+        keystore.save_keyring(&keyring).unwrap();
+
+        let open = || Keystore::open(&path).unwrap();
+        assert!(matches!(
+            open().protection(),
+            KeyProtection::RootKey {
+                threshold: 2,
+                shares: 3,
+                ..
+            }
+        ));
+        assert!(open().unlock(Secret::Passphrase("passphrase")).is_err());
+        assert!(
+            open()
+                .unlock(Secret::RootKey(&RootKey::generate()))
+                .is_err()
+        );
+        let keystore = open().unlock(Secret::RootKey(&root_key)).unwrap();
+        assert_eq!(
+            keystore.load_keyring().unwrap().default_key_id(),
+            keyring.default_key_id()
+        );
+    }
+
+    #[test]
+    fn test_export_and_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("master-key.db");
+        let keyring = keyring();
+        let mut keystore = create(&path);
+        keystore.save_keyring(&keyring).unwrap();
+        keystore
+            .transaction(|transaction| transaction.put("other", b"value"))
+            .unwrap();
+
+        let backup = FileFormat::Yaml
+            .serialize(&keystore.export().unwrap())
+            .unwrap();
+        let parse = || -> Backup { FileFormat::Yaml.parse(&backup).unwrap() };
+        let restored = dir.path().join("restored.db");
+        assert!(
+            parse()
+                .restore(&restored, Secret::Passphrase("wrong"))
+                .is_err()
+        );
+        assert!(!restored.exists());
+        assert!(
+            parse()
+                .restore(&path, Secret::Passphrase("passphrase"))
+                .is_err()
+        );
+
+        let mut tampered = parse();
+        tampered.created_at = Utc::now();
+        assert!(
+            tampered
+                .restore(&restored, Secret::Passphrase("passphrase"))
+                .is_err()
+        );
+
+        parse()
+            .restore(&restored, Secret::Passphrase("passphrase"))
+            .unwrap();
+        let keystore = Keystore::open(&restored)
+            .unwrap()
+            .unlock(Secret::Passphrase("passphrase"))
+            .unwrap();
+        assert_eq!(
+            keystore.load_keyring().unwrap().to_records().unwrap(),
+            keyring.to_records().unwrap()
+        );
+        assert_eq!(
+            keystore.records("other").unwrap()["other"].as_slice(),
+            b"value"
+        );
+        // the restored keystore continues from the generation of the backup
+        assert_eq!(keystore.generation(), 3);
+    }
+}
@@ -0,0 +1,270 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use serde::Deserialize;
+use server::RouteSet;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// A listener given by `--listen`.
+///
+/// The address may be followed by a query string which overrides the global
+/// service and TLS flags for this listener only, e.g.
+/// `tcp://0.0.0.0:8602?services=kagimori-v1&tls-certificate=/tls/tls.crt&tls-private-key=/tls/tls.key`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "ListenerConfig")]
+pub(crate) struct ListenerSpec {
+    pub address: ListenAddress,
+    pub services: Option<RouteSet>,
+    pub tls: TlsSpec,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct TlsSpec {
+    pub certificate: Option<PathBuf>,
+    pub private_key: Option<PathBuf>,
+    pub client_ca: Option<PathBuf>,
+}
+
+/// Files of a listener with TLS enabled.
+#[derive(Debug, PartialEq)]
+pub(crate) struct TlsFiles<'a> {
+    pub certificate: &'a Path,
+    pub private_key: &'a Path,
+    pub client_ca: Option<&'a Path>,
+}
+
+impl TlsSpec {
+    /// Returns the files to serve TLS with, or `None` for plaintext.
+    pub(crate) fn files(&self) -> Result<Option<TlsFiles<'_>>, String> {
+        match (&self.certificate, &self.private_key, &self.client_ca) {
+            (Some(certificate), Some(private_key), client_ca) => Ok(Some(TlsFiles {
+                certificate,
+                private_key,
+                client_ca: client_ca.as_deref(),
+            })),
+            (None, None, None) => Ok(None),
+            (None, None, Some(_)) => Err("TLS client CA requires a TLS certificate".to_string()),
+            _ => Err("TLS certificate and private key must be given together".to_string()),
+        }
+    }
+}
+
+/// A listener in the configuration file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct ListenerConfig {
+    address: ListenAddress,
+    services: Option<Vec<String>>,
+    #[serde(default)]
+    tls: TlsSpec,
+}
+
+impl TryFrom<ListenerConfig> for ListenerSpec {
+    type Error = String;
+
+    fn try_from(value: ListenerConfig) -> Result<Self, Self::Error> {
+        if matches!(value.address, ListenAddress::Unix(_)) && value.tls != TlsSpec::default() {
+            return Err(format!(
+                "TLS is not supported on unix sockets: {:?}",
+                value.address
+            ));
+        }
+        Ok(ListenerSpec {
+            address: value.address,
+            services: value
+                .services
+                .map(|services| parse_services(&services.join(",")))
+                .transpose()?,
+            tls: value.tls,
+        })
+    }
+}
+
+impl TryFrom<String> for ListenAddress {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl FromStr for ListenAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(addr) = s.strip_prefix("tcp://") {
+            addr.parse()
+                .map(ListenAddress::Tcp)
+                .map_err(|e| format!("invalid TCP address '{addr}': {e}"))
+        } else if let Some(path) = s.strip_prefix("unix://") {
+            Ok(ListenAddress::Unix(PathBuf::from(path)))
+        } else {
+            Err(format!(
+                "listen address must start with tcp:// or unix://: {s}"
+            ))
+        }
+    }
+}
+
+/// Parses a comma separated list of service names.
+pub(crate) fn parse_services(s: &str) -> Result<RouteSet, String> {
+    let mut services = RouteSet::default();
+    for name in s.split(',').filter(|n| !n.is_empty()) {
+        match name {
+            "kms-v2" => services.kms_v2 = true,
+            "kagimori-v1" => services.kagimori_v1 = true,
+            "admin" => services.admin = true,
+            "health" => services.health = true,
+            "reflection" => services.reflection = true,
+            _ => return Err(format!("unknown service: {name}")),
+        }
+    }
+    Ok(services)
+}
+
+impl ListenerSpec {
+    /// Checks the TLS settings. The admin service, which receives unseal shares and key
+    /// state changes, is only served on unix sockets or over TLS authenticating clients.
+    pub(crate) fn check_tls(&self) -> Result<(), String> {
+        let files = self.tls.files()?;
+        let admin = self.services.is_some_and(|services| services.admin);
+        if admin
+            && matches!(self.address, ListenAddress::Tcp(_))
+            && files.is_none_or(|files| files.client_ca.is_none())
+        {
+            return Err("admin service over TCP requires TLS with a client CA".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ListenerSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, query) = s.split_once('?').unwrap_or((s, ""));
+        let mut spec = ListenerSpec {
+            address: address.parse()?,
+            services: None,
+            tls: TlsSpec::default(),
+        };
+
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("listener option must be KEY=VALUE: {pair}"))?;
+            match key {
+                "services" => spec.services = Some(parse_services(value)?),
+                "tls-certificate" => spec.tls.certificate = Some(value.into()),
+                "tls-private-key" => spec.tls.private_key = Some(value.into()),
+                "tls-client-ca" => spec.tls.client_ca = Some(value.into()),
+                _ => return Err(format!("unknown listener option: {key}")),
+            }
+        }
+
+        if matches!(spec.address, ListenAddress::Unix(_)) && spec.tls != TlsSpec::default() {
+            return Err(format!("TLS is not supported on unix sockets: {s}"));
+        }
+
+        Ok(spec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_plain_address() {
+        let spec: ListenerSpec = "unix:///var/run/kagimori/kagimori.sock".parse().unwrap();
+        assert_eq!(
+            spec,
+            ListenerSpec {
+                address: ListenAddress::Unix("/var/run/kagimori/kagimori.sock".into()),
+                services: None,
+                tls: TlsSpec::default(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let spec: ListenerSpec = "tcp://0.0.0.0:8602?services=kagimori-v1,health&tls-certificate=/tls/tls.crt&tls-private-key=/tls/tls.key&tls-client-ca=/tls/ca.crt"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            spec,
+            ListenerSpec {
+                address: ListenAddress::Tcp("0.0.0.0:8602".parse().unwrap()),
+                services: Some(RouteSet {
+                    kagimori_v1: true,
+                    health: true,
+                    ..Default::default()
+                }),
+                tls: TlsSpec {
+                    certificate: Some("/tls/tls.crt".into()),
+                    private_key: Some("/tls/tls.key".into()),
+                    client_ca: Some("/tls/ca.crt".into()),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_unknown_option() {
+        assert!(
+            "tcp://0.0.0.0:8602?service=kms-v2"
+                .parse::<ListenerSpec>()
+                .is_err()
+        );
+        assert!(
+            "unix:///tmp/k.sock?tls-certificate=/tls/tls.crt"
+                .parse::<ListenerSpec>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_tls_files_rejects_partial_settings() {
+        let tls = |query: &str| {
+            format!("tcp://0.0.0.0:8602?{query}")
+                .parse::<ListenerSpec>()
+                .unwrap()
+                .tls
+        };
+        assert_eq!(tls("").files(), Ok(None));
+        assert_eq!(
+            tls("tls-certificate=/tls/tls.crt&tls-private-key=/tls/tls.key").files(),
+            Ok(Some(TlsFiles {
+                certificate: Path::new("/tls/tls.crt"),
+                private_key: Path::new("/tls/tls.key"),
+                client_ca: None,
+            }))
+        );
+        assert!(tls("tls-certificate=/tls/tls.crt").files().is_err());
+        assert!(tls("tls-private-key=/tls/tls.key").files().is_err());
+        assert!(tls("tls-client-ca=/tls/ca.crt").files().is_err());
+    }
+}
@@ -14,80 +14,398 @@
 // If not, see <https://www.gnu.org/licenses/>.
 
 mod args;
+mod config;
+mod format;
+mod keyring;
+mod keyring_file;
+mod keystore;
+mod listener;
 mod master_key;
+mod passphrase;
+mod reload;
+mod seal;
+mod unseal;
 
-use crate::args::{Args, CipherAlgorithm};
+use crate::args::{Args, CipherAlgorithm, Command};
+use crate::config::{AuditConfig, AuditSink, Config, DEFAULT_AUDIT_QUEUE_CAPACITY};
+use crate::keyring_file::KeyringFile;
+use crate::keystore::{KeyProtection, Keystore, Secret};
+use crate::listener::{ListenAddress, ListenerSpec};
+use crate::master_key::MasterKeyConfig;
+use crate::passphrase::Passphrase;
+use crate::reload::{KeyringEditor, KeyringReloader};
+use crate::unseal::KeyringUnsealer;
 use audit_log::AuditLogger;
+use audit_log::logger::fanout::FanOutAuditLogger;
+use audit_log::logger::file::FileAuditLogger;
+use audit_log::logger::queued::{AuditLogWriter, QueuedAuditLogger};
 use audit_log::logger::tracing::TracingAuditLogger;
 use ciphers::rotatable::RotatableCipher;
 use clap::Parser;
 use encryption::{Encryptor, KeyAlgorithm};
-use server::{CertificateDer, KagimoriServer, PemObject, PrivateKeyDer};
+use server::lifecycle::KeyLifecycle;
+use server::metrics::MetricsServer;
+use server::unseal::Unsealer;
+use server::{CertificateDer, KagimoriServer, Limits, PemObject, PrivateKeyDer};
 use std::path::Path;
-use tracing::{debug, info};
-use tracing_subscriber::EnvFilter;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tracing::level_filters::LevelFilter;
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_env("LOG_LEVEL"))
-        .json()
+    let mut args = Args::parse();
+
+    if let Some(command) = args.command.take() {
+        let result = match command {
+            Command::Config(command) => command.run(&args),
+            Command::Keygen(command) => command.run(),
+            Command::Keyring(command) => command.run(),
+            Command::Seal(command) => command.run().await,
+            Command::Keystore(command) => command.run(),
+        };
+        if let Err(e) = result {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let config = match args.resolve_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            std::process::exit(2);
+        }
+    };
+
+    let (otlp_layer, tracer_provider) = match config
+        .otlp_endpoint
+        .as_deref()
+        .map(telemetry::trace::otlp_layer)
+        .transpose()
+    {
+        Ok(exporter) => exporter.unzip(),
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            std::process::exit(2);
+        }
+    };
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_filter(EnvFilter::from_env("LOG_LEVEL")),
+        )
+        .with(otlp_layer.with_filter(LevelFilter::INFO))
         .init();
 
     info!("Kagimori {VERSION} (Licensed under the GNU General Public License v3)");
-
-    let args = Args::parse();
     debug!("Command line arguments: {args:?}");
+    debug!("Configuration: {config:?}");
+
+    let (logger, audit_writer) = create_audit_logger(&config.audit).await;
 
-    let logger = TracingAuditLogger;
+    if let Some(listen) = config.metrics_listen {
+        match MetricsServer::bind(listen).await {
+            Ok(server) => {
+                tokio::spawn(server.run());
+            }
+            Err(e) => {
+                error!("Cannot serve metrics on {listen}: {e}");
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let master_key_path = config.master_key.path.as_deref().unwrap();
+    let mut passphrase = config.master_key.passphrase();
+    let keyring = if Keystore::is_keystore(master_key_path) {
+        load_keystore(master_key_path, &mut passphrase)
+    } else {
+        match KeyringFile::load(master_key_path) {
+            Ok(KeyringFile::Sealed(sealed)) => Ok(InitialKeyring::sealed(
+                sealed.share_set().to_string(),
+                sealed.threshold(),
+                sealed.shares(),
+            )),
+            Ok(file) => file
+                .open(&mut passphrase)
+                .map_err(|e| format!("{}: {e}", master_key_path.display()))
+                .and_then(MasterKeyConfig::into_cipher)
+                .map(|cipher| InitialKeyring::Unsealed(Box::new(cipher))),
+            Err(e) => Err(e),
+        }
+    };
+    let keyring = match keyring {
+        Ok(keyring) => keyring,
+        Err(e) => {
+            error!("Cannot load master key: {e}");
+            std::process::exit(2);
+        }
+    };
 
-    let cipher = args.create_master_key().into_cipher();
+    let succeeded = run_server(keyring, passphrase, logger, config).await;
+    // audit logs still queued are written before exiting
+    audit_writer.close().await;
+    if let Some(provider) = tracer_provider {
+        // flushes pending spans, blocking until they are exported
+        match tokio::task::spawn_blocking(move || provider.shutdown()).await {
+            Ok(Ok(())) => {}
+            result => error!("Cannot flush traces: {result:?}"),
+        }
+    }
+    if !succeeded {
+        std::process::exit(1);
+    }
+}
+
+async fn create_audit_logger(config: &AuditConfig) -> (QueuedAuditLogger, AuditLogWriter) {
+    let mut sinks: Vec<Box<dyn AuditLogger>> = Vec::new();
+    for sink in &config.sinks {
+        match sink {
+            AuditSink::Log => sinks.push(Box::new(TracingAuditLogger)),
+            AuditSink::File { path } => match FileAuditLogger::open(path).await {
+                Ok(logger) => sinks.push(Box::new(logger)),
+                Err(e) => {
+                    error!("Cannot open audit log file {}: {e}", path.display());
+                    std::process::exit(2);
+                }
+            },
+        }
+    }
+    QueuedAuditLogger::new(
+        FanOutAuditLogger::new(sinks),
+        config
+            .queue_capacity
+            .unwrap_or(DEFAULT_AUDIT_QUEUE_CAPACITY),
+    )
+}
+
+fn load_keystore(path: &Path, passphrase: &mut Passphrase) -> Result<InitialKeyring, String> {
+    let keystore = Keystore::open(path)?;
+    match keystore.protection().clone() {
+        KeyProtection::Passphrase { .. } => keystore
+            .unlock(Secret::Passphrase(passphrase.get(false)?))?
+            .load_keyring()
+            .and_then(MasterKeyConfig::into_cipher)
+            .map(|cipher| InitialKeyring::Unsealed(Box::new(cipher))),
+        KeyProtection::RootKey {
+            share_set,
+            threshold,
+            shares,
+        } => Ok(InitialKeyring::sealed(share_set, threshold, shares)),
+    }
+}
+
+enum InitialKeyring {
+    Unsealed(Box<RotatableCipher>),
+    Sealed { share_set: String, threshold: u8 },
+}
 
-    run_server(cipher, logger, args).await;
+impl InitialKeyring {
+    fn sealed(share_set: String, threshold: u8, shares: u8) -> Self {
+        info!("Master keyring is sealed, submit {threshold} of {shares} shares to unseal it");
+        InitialKeyring::Sealed {
+            share_set,
+            threshold,
+        }
+    }
 }
 
-async fn run_server<L>(cipher: RotatableCipher, audit_logger: L, args: Args)
+async fn run_server<L>(
+    keyring: InitialKeyring,
+    passphrase: Passphrase,
+    audit_logger: L,
+    config: Config,
+) -> bool
 where
     L: 'static + AuditLogger + Clone,
 {
-    let encryptor = Encryptor::new(
+    let algorithm = match config.dek_algorithm {
+        None | Some(CipherAlgorithm::Chacha20Poly1305) => KeyAlgorithm::ChaCha20Poly1305,
+        Some(CipherAlgorithm::AesGcmSiv) => KeyAlgorithm::AesGcmSiv,
+    };
+    let master_key_path = config.master_key.path.unwrap();
+    let dek_reuse = config.dek_reuse.limits();
+    // validated with the configuration
+    let dek_cache = config.dek_cache.limits().unwrap();
+    let with_dek_options = |mut encryptor: Encryptor<L>| {
+        if let Some(limits) = dek_reuse {
+            encryptor = encryptor.with_dek_reuse(limits);
+        }
+        if let Some(limits) = dek_cache {
+            encryptor = encryptor.with_dek_cache(limits);
+        }
+        encryptor
+    };
+    let (encryptor, unsealer) = match keyring {
+        InitialKeyring::Unsealed(cipher) => (
+            with_dek_options(Encryptor::new(audit_logger.clone(), algorithm, *cipher)),
+            None,
+        ),
+        InitialKeyring::Sealed {
+            share_set,
+            threshold,
+        } => {
+            let encryptor = with_dek_options(Encryptor::sealed(audit_logger.clone(), algorithm));
+            let unsealer = Arc::new(KeyringUnsealer::new(
+                master_key_path.clone(),
+                share_set,
+                threshold,
+                encryptor.clone(),
+                audit_logger.clone(),
+            ));
+            (encryptor, Some(unsealer))
+        }
+    };
+
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+    let (editor, edits) = KeyringEditor::new();
+    let lifecycle: Arc<dyn KeyLifecycle> = Arc::new(editor);
+    let mut reloader = KeyringReloader::new(
+        master_key_path,
+        passphrase,
+        unsealer.clone(),
+        encryptor.clone(),
         audit_logger,
-        match args.dek_algorithm {
-            CipherAlgorithm::Chacha20Poly1305 => KeyAlgorithm::ChaCha20Poly1305,
-            CipherAlgorithm::AesGcmSiv => KeyAlgorithm::AesGcmSiv,
-        },
-        cipher.default_key_id(),
-        cipher,
+        config.master_key.allow_key_removal,
     );
+    if let Some(seconds) = config.master_key.min_destruction_grace_period_seconds {
+        reloader = reloader.with_min_grace_period(Duration::from_secs(seconds));
+    }
+    if config.master_key.auto_rotate {
+        reloader = reloader.with_auto_rotation();
+    } else if !encryptor.rotation_policies().is_empty() {
+        warn!("Keys have rotation policies, which are ignored unless `auto-rotate` is set");
+    }
+    let mut reloader_shutdown = shutdown_receiver.clone();
+    let reloader = tokio::spawn(reloader.run(edits, async move {
+        let _ = reloader_shutdown.wait_for(|stop| *stop).await;
+    }));
 
-    let mut server = KagimoriServer::new(encryptor);
-    if args.kms_v2 {
-        server = server.enable_kms_v2();
+    let mut listeners = JoinSet::new();
+    let limits = Limits::from(&config.limits);
+    for spec in config.listeners {
+        let mut shutdown_receiver = shutdown_receiver.clone();
+        let signal = async move {
+            let _ = shutdown_receiver.wait_for(|stop| *stop).await;
+        };
+        listeners.spawn(run_listener(
+            encryptor.clone(),
+            unsealer.clone().map(|u| u as Arc<dyn Unsealer>),
+            lifecycle.clone(),
+            spec,
+            limits,
+            signal,
+        ));
     }
-    if args.kagimori_v1 {
-        server = server.enable_kagimori_v1();
+
+    let mut failed = false;
+    tokio::select! {
+        _ = shutdown_signal() => info!("Shutting down"),
+        Some(result) = listeners.join_next() => {
+            error!("Listener stopped unexpectedly, shutting down: {result:?}");
+            failed = true;
+        }
+    }
+    let _ = shutdown_sender.send(true);
+    while let Some(result) = listeners.join_next().await {
+        if !matches!(result, Ok(Ok(()))) {
+            error!("Listener failed: {result:?}");
+            failed = true;
+        }
+    }
+    if let Err(e) = reloader.await {
+        error!("Keyring reloader failed: {e}");
+        failed = true;
     }
-    if let Some(sock_addr) = args.listen.strip_prefix("tcp://") {
-        if let Some(cert) = args.tls_certificate
-            && let Some(private_key) = args.tls_private_key
-        {
-            let cert = CertificateDer::pem_file_iter(cert)
-                .unwrap()
-                .map(Result::unwrap);
-            let private_key = PrivateKeyDer::from_pem_file(private_key).unwrap();
+
+    !failed
+}
+
+async fn run_listener<L, F>(
+    encryptor: Encryptor<L>,
+    unsealer: Option<Arc<dyn Unsealer>>,
+    lifecycle: Arc<dyn KeyLifecycle>,
+    spec: ListenerSpec,
+    limits: Limits,
+    signal: F,
+) -> Result<(), String>
+where
+    L: 'static + AuditLogger + Clone,
+    F: Future<Output = ()> + Send,
+{
+    spec.check_tls()
+        .map_err(|e| format!("{e} on {:?}", spec.address))?;
+    let services = spec.services.unwrap_or_default();
+    if services.is_empty() {
+        return Err(format!("No services are enabled on {:?}", spec.address));
+    }
+    let mut server = KagimoriServer::new(encryptor)
+        .with_routes(services)
+        .with_limits(limits)
+        .with_key_lifecycle(lifecycle);
+    if let Some(unsealer) = unsealer {
+        server = server.with_unsealer(unsealer);
+    }
+
+    let tls = spec
+        .tls
+        .files()
+        .map_err(|e| format!("{e} on {:?}", spec.address))?;
+    match (spec.address, tls) {
+        (ListenAddress::Tcp(listen), Some(files)) => {
+            let cert = CertificateDer::pem_file_iter(files.certificate)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| format!("Cannot load TLS certificate: {e}"))?;
+            let private_key = PrivateKeyDer::from_pem_file(files.private_key)
+                .map_err(|e| format!("Cannot load TLS private key: {e}"))?;
+
+            let server = if let Some(client_ca) = files.client_ca {
+                let client_ca = CertificateDer::pem_file_iter(client_ca)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .map_err(|e| format!("Cannot load TLS client CA: {e}"))?;
+                server.bind_mtls(listen, cert, private_key, client_ca)
+            } else {
+                server.bind_tls(listen, cert, private_key)
+            }
+            .map_err(|e| format!("Invalid TLS configuration: {e}"))?;
 
             server
-                .bind_tls(sock_addr.parse().unwrap(), cert.collect(), private_key)
-                .unwrap()
-                .run()
+                .run_with_shutdown(signal)
                 .await
-                .unwrap();
-        } else {
-            server.bind(sock_addr.parse().unwrap()).run().await.unwrap();
+                .map_err(|e| e.to_string())
+        }
+        (ListenAddress::Tcp(listen), None) => server
+            .bind(listen)
+            .run_with_shutdown(signal)
+            .await
+            .map_err(|e| e.to_string()),
+        (ListenAddress::Unix(path), None) => server
+            .bind_uds(path)
+            .run_with_shutdown(signal)
+            .await
+            .map_err(|e| e.to_string()),
+        (address @ ListenAddress::Unix(_), Some(_)) => {
+            Err(format!("TLS is not supported on unix sockets: {address:?}"))
         }
-    } else if let Some(path) = args.listen.strip_prefix("unix://") {
-        server.bind_uds(Path::new(path)).run().await.unwrap();
+    }
+}
+
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
     }
 }
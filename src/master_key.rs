@@ -13,57 +13,1426 @@
 // You should have received a copy of the GNU General Public License along with Kagimori.
 // If not, see <https://www.gnu.org/licenses/>.
 
+use crate::args::CipherAlgorithm;
+use crate::keyring_file::KeyringFile;
+use crate::keystore::{KeyProtection, Keystore, Secret};
+use crate::passphrase::Passphrase;
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
-use ciphers::Unencrypted;
+use chrono::{DateTime, Utc};
 use ciphers::aesgcmsiv::AesGcmSivCipher;
+use ciphers::aws_kms::{AwsCredentials, AwsKmsCipher, AwsKmsConfig};
 use ciphers::chacha20poly1305::ChaCha20Poly1305Cipher;
 use ciphers::oneof::OneOfCipher;
-use ciphers::rotatable::RotatableCipher;
-use serde::Deserialize;
+use ciphers::pkcs11::{Pkcs11Cipher, Pkcs11Key};
+use ciphers::rotatable::{
+    DEFAULT_KEY_NAME, KeyState, KeyStatus, NamedKey, RotatableCipher, RotationPolicy,
+};
+use ciphers::vault::{VaultAuth, VaultTransitCipher, VaultTransitConfig};
+use ciphers::{Cipher, Unencrypted};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use server::lifecycle::KeyStateError;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tracing::debug;
 use uuid::Uuid;
+use zeroize::Zeroizing;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub(crate) struct MasterKeyConfig {
     default: Uuid,
     keys: Vec<MasterKey>,
+    /// Logical keys chosen by name, whose versions are keys of `keys`.
+    /// Keys not belonging to any of them are versions of the default key.
+    #[serde(
+        default,
+        rename = "named-keys",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    named_keys: BTreeMap<String, NamedKeyConfig>,
+    /// Lifecycle states of keys with their last change. Keys not given are enabled,
+    /// and destroyed keys are kept here after being removed from `keys`.
+    #[serde(
+        default,
+        rename = "key-states",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    key_states: BTreeMap<Uuid, KeyStateConfig>,
+    /// Rotation policies by name of the key, which is `default` for the default key.
+    #[serde(
+        default,
+        rename = "rotation-policies",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    rotation_policies: BTreeMap<String, RotationPolicyConfig>,
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(tag = "algorithm")]
-enum MasterKey {
-    Unencrypted { id: Uuid },
-    ChaCha20Poly1305 { id: Uuid, key: String },
-    AesGcmSiv { id: Uuid, key: String },
+/// When the primary version of a key is replaced by a newly generated one,
+/// on reaching either limit.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct RotationPolicyConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age_seconds: Option<u64>,
+    /// Number of DEKs wrapped with the primary version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_encryptions: Option<u64>,
+    /// When the primary version became primary, which the server records if missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotated_at: Option<DateTime<Utc>>,
+    /// Number of DEKs wrapped with the primary version so far, which the server saves
+    /// from time to time so that `max_encryptions` counts across restarts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryptions: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct KeyStateConfig {
+    pub state: KeyState,
+    pub changed_at: DateTime<Utc>,
+    pub changed_by: String,
+    /// When the key is destroyed, if it is pending destruction
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destroy_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct NamedKeyConfig {
+    /// ID of the version used for new encryptions
+    primary: Uuid,
+    /// All versions, oldest first
+    #[serde(deserialize_with = "deserialize_versions")]
+    versions: Vec<KeyVersion>,
+}
+
+/// A version of a named key. Its number is assigned when it is added and never changes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct KeyVersion {
+    id: Uuid,
+    version: u32,
+}
+
+/// Reads versions, which older keyrings list as bare IDs numbered from 1.
+fn deserialize_versions<'de, D>(deserializer: D) -> Result<Vec<KeyVersion>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Entry {
+        Id(Uuid),
+        Version(KeyVersion),
+    }
+
+    let entries = Vec::<Entry>::deserialize(deserializer)?;
+    Ok(entries
+        .into_iter()
+        .zip(1..)
+        .map(|(entry, number)| match entry {
+            Entry::Id(id) => KeyVersion {
+                id,
+                version: number,
+            },
+            Entry::Version(version) => version,
+        })
+        .collect())
+}
+
+impl NamedKeyConfig {
+    fn ids(&self) -> impl Iterator<Item = Uuid> + '_ {
+        self.versions.iter().map(|v| v.id)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "algorithm", deny_unknown_fields)]
+pub(crate) enum MasterKey {
+    Unencrypted {
+        id: Uuid,
+    },
+    ChaCha20Poly1305 {
+        id: Uuid,
+        key: String,
+    },
+    AesGcmSiv {
+        id: Uuid,
+        key: String,
+    },
+    /// An AES key on a PKCS#11 token, used with `CKM_AES_GCM`.
+    #[serde(rename_all = "kebab-case")]
+    Pkcs11 {
+        id: Uuid,
+        /// Path to the PKCS#11 module
+        module: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        slot: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token_label: Option<String>,
+        key_label: String,
+        /// File containing the user PIN, which is otherwise read from `KAGIMORI_PKCS11_PIN`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pin_file: Option<PathBuf>,
+    },
+    /// A key of the Transit secrets engine of HashiCorp Vault.
+    #[serde(rename_all = "kebab-case")]
+    VaultTransit {
+        id: Uuid,
+        address: String,
+        #[serde(default = "default_transit_mount")]
+        mount: String,
+        key_name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key_version: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        /// PEM file of the CA certificate of Vault
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ca_certificate: Option<PathBuf>,
+        auth: VaultAuthConfig,
+        #[serde(default = "default_retries")]
+        retries: u32,
+    },
+    /// A key of AWS KMS, or a service compatible with its API.
+    #[serde(rename_all = "kebab-case")]
+    AwsKms {
+        id: Uuid,
+        region: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        endpoint: Option<String>,
+        key_id: String,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        encryption_context: BTreeMap<String, String>,
+        /// Access key ID, which is otherwise read from `AWS_ACCESS_KEY_ID`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        access_key_id: Option<String>,
+        /// File containing the secret access key, which is otherwise read from `AWS_SECRET_ACCESS_KEY`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        secret_access_key_file: Option<PathBuf>,
+        /// File containing the session token, which is otherwise read from `AWS_SESSION_TOKEN`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session_token_file: Option<PathBuf>,
+        /// PEM file of the CA certificate of the endpoint
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ca_certificate: Option<PathBuf>,
+        #[serde(default = "default_retries")]
+        retries: u32,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) enum VaultAuthConfig {
+    /// Token read from `token-file`, or `VAULT_TOKEN`
+    #[serde(rename_all = "kebab-case")]
+    Token {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token_file: Option<PathBuf>,
+    },
+    /// AppRole with secret ID read from `secret-id-file`, or `KAGIMORI_VAULT_SECRET_ID`
+    #[serde(rename_all = "kebab-case")]
+    AppRole {
+        #[serde(default = "default_app_role_mount")]
+        mount: String,
+        role_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        secret_id_file: Option<PathBuf>,
+    },
+}
+
+/// Who makes changes done by Kagimori itself.
+pub(crate) const SYSTEM_ACTOR: &str = "system";
+/// Time from pending destruction to destruction unless given.
+pub(crate) const DEFAULT_DESTRUCTION_GRACE_PERIOD: Duration = Duration::from_secs(30 * 24 * 3600);
+/// Shortest grace period of pending destruction accepted from the admin API unless configured.
+pub(crate) const DEFAULT_MIN_DESTRUCTION_GRACE_PERIOD: Duration = Duration::from_secs(24 * 3600);
+
+const PKCS11_PIN_ENV: &str = "KAGIMORI_PKCS11_PIN";
+const VAULT_TOKEN_ENV: &str = "VAULT_TOKEN";
+const VAULT_SECRET_ID_ENV: &str = "KAGIMORI_VAULT_SECRET_ID";
+const AWS_ACCESS_KEY_ID_ENV: &str = "AWS_ACCESS_KEY_ID";
+const AWS_SECRET_ACCESS_KEY_ENV: &str = "AWS_SECRET_ACCESS_KEY";
+const AWS_SESSION_TOKEN_ENV: &str = "AWS_SESSION_TOKEN";
+
+fn default_transit_mount() -> String {
+    "transit".to_string()
+}
+
+fn default_app_role_mount() -> String {
+    "approle".to_string()
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+/// Upper bound of `retries`; with backoff capped at 10 seconds, a request gives up within a few minutes.
+const MAX_RETRIES: u32 = 10;
+
+fn check_retries(retries: u32) -> Result<(), String> {
+    if retries > MAX_RETRIES {
+        return Err(format!("retries must not exceed {MAX_RETRIES}"));
+    }
+    Ok(())
+}
+
+impl From<&KeyStateConfig> for KeyStatus {
+    fn from(record: &KeyStateConfig) -> Self {
+        KeyStatus {
+            state: record.state,
+            changed_at: Some(record.changed_at),
+            changed_by: Some(record.changed_by.clone()),
+            destroy_at: record.destroy_at,
+        }
+    }
+}
+
+impl From<&RotationPolicyConfig> for RotationPolicy {
+    fn from(policy: &RotationPolicyConfig) -> Self {
+        RotationPolicy {
+            max_age: policy.max_age_seconds.map(Duration::from_secs),
+            max_encryptions: policy.max_encryptions,
+            rotated_at: policy.rotated_at,
+            saved_encryptions: policy.encryptions.unwrap_or_default(),
+        }
+    }
 }
 
 impl MasterKeyConfig {
-    pub(crate) fn into_cipher(self) -> RotatableCipher {
+    /// Loads a TOML, YAML or JSON master key configuration file or a keystore,
+    /// decrypting it with `passphrase` if it is encrypted.
+    pub(crate) fn load(path: &Path, passphrase: &mut Passphrase) -> Result<Self, String> {
+        if Keystore::is_keystore(path) {
+            let keystore = Keystore::open(path)?;
+            if let KeyProtection::RootKey { .. } = keystore.protection() {
+                return Err(format!(
+                    "{}: keystore is sealed, shares of the root key are required",
+                    path.display()
+                ));
+            }
+            return keystore
+                .unlock(Secret::Passphrase(passphrase.get(false)?))?
+                .load_keyring();
+        }
+        KeyringFile::load(path)?
+            .open(passphrase)
+            .map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    pub(crate) fn new(key: MasterKey) -> Self {
+        Self {
+            default: key.id(),
+            keys: vec![key],
+            named_keys: BTreeMap::new(),
+            key_states: BTreeMap::new(),
+            rotation_policies: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn default_key_id(&self) -> Uuid {
+        self.default
+    }
+
+    pub(crate) fn keys(&self) -> &[MasterKey] {
+        &self.keys
+    }
+
+    /// Adds `key`, making it the default key if `make_default` is set.
+    pub(crate) fn add(&mut self, key: MasterKey, make_default: bool) {
+        if make_default {
+            self.default = key.id();
+            self.restart_rotation(DEFAULT_KEY_NAME, Utc::now());
+        }
+        self.keys.push(key);
+    }
+
+    /// Adds `key` as a new version of the named key `name`, creating it if missing,
+    /// and makes it the primary version.
+    pub(crate) fn add_version(&mut self, name: &str, key: MasterKey) -> Result<(), String> {
+        validate_key_name(name)?;
+        let id = key.id();
+        let named_key = self
+            .named_keys
+            .entry(name.to_string())
+            .or_insert_with(|| NamedKeyConfig {
+                primary: id,
+                versions: Vec::new(),
+            });
+        let version = named_key
+            .versions
+            .iter()
+            .map(|v| v.version)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        named_key.primary = id;
+        named_key.versions.push(KeyVersion { id, version });
+        self.keys.push(key);
+        self.restart_rotation(name, Utc::now());
+        Ok(())
+    }
+
+    /// Sets the rotation policy of the key `name`, or removes it if neither limit is given.
+    /// The age of the primary version counts from `now` unless recorded before.
+    pub(crate) fn set_rotation_policy(
+        &mut self,
+        name: &str,
+        max_age: Option<Duration>,
+        max_encryptions: Option<u64>,
+        now: DateTime<Utc>,
+    ) -> Result<(), String> {
+        if self.primary_of(name).is_none() {
+            return Err(format!("key {name} is not found"));
+        }
+        if max_age.is_none() && max_encryptions.is_none() {
+            self.rotation_policies.remove(name);
+            return Ok(());
+        }
+        let previous = self.rotation_policies.get(name);
+        let rotated_at = previous.and_then(|policy| policy.rotated_at).unwrap_or(now);
+        let encryptions = previous.and_then(|policy| policy.encryptions);
+        self.rotation_policies.insert(
+            name.to_string(),
+            RotationPolicyConfig {
+                max_age_seconds: max_age.map(|age| age.as_secs()),
+                max_encryptions,
+                rotated_at: Some(rotated_at),
+                encryptions,
+            },
+        );
+        Ok(())
+    }
+
+    /// Records `now` as when the primary versions became primary for the rotation
+    /// policies lacking it, returning the names of their keys.
+    pub(crate) fn start_rotation_clocks(&mut self, now: DateTime<Utc>) -> Vec<String> {
+        self.rotation_policies
+            .iter_mut()
+            .filter(|(_, policy)| policy.rotated_at.is_none())
+            .map(|(name, policy)| {
+                policy.rotated_at = Some(now);
+                name.clone()
+            })
+            .collect()
+    }
+
+    /// Replaces the primary version of the key `name` by a newly generated key of the same
+    /// algorithm, keeping the old one for decryption. Returns the old and new IDs.
+    pub(crate) fn rotate(
+        &mut self,
+        name: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(Uuid, Uuid), String> {
+        let old = self
+            .primary_of(name)
+            .ok_or_else(|| format!("key {name} is not found"))?;
+        let algorithm = self
+            .keys
+            .iter()
+            .find(|k| k.id() == old)
+            .ok_or_else(|| format!("{old} is not found in keys"))
+            .and_then(MasterKey::generated_algorithm)?;
+        let key = MasterKey::generate(algorithm);
+        let new = key.id();
+        if name == DEFAULT_KEY_NAME {
+            self.add(key, true);
+        } else {
+            self.add_version(name, key)?;
+        }
+        self.restart_rotation(name, now);
+        Ok((old, new))
+    }
+
+    fn primary_of(&self, name: &str) -> Option<Uuid> {
+        if name == DEFAULT_KEY_NAME {
+            return Some(self.default);
+        }
+        self.named_keys.get(name).map(|k| k.primary)
+    }
+
+    fn restart_rotation(&mut self, name: &str, now: DateTime<Utc>) {
+        if let Some(policy) = self.rotation_policies.get_mut(name) {
+            policy.rotated_at = Some(now);
+            policy.encryptions = None;
+        }
+    }
+
+    /// Saves the number of encryptions with `primary` in the rotation policy of the key
+    /// `name`, unless `primary` is no longer its primary version. Returns whether it is saved.
+    pub(crate) fn save_encryptions(&mut self, name: &str, primary: Uuid, encryptions: u64) -> bool {
+        if self.primary_of(name) != Some(primary) {
+            return false;
+        }
+        match self.rotation_policies.get_mut(name) {
+            Some(policy) => {
+                policy.encryptions = Some(encryptions);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the name of the named key which `id` is a version of with its version
+    /// number, or `None` for a version of the default key.
+    pub(crate) fn version_of(&self, id: Uuid) -> Option<(&str, u32)> {
+        self.named_keys.iter().find_map(|(name, named_key)| {
+            let version = named_key.versions.iter().find(|v| v.id == id)?;
+            Some((name.as_str(), version.version))
+        })
+    }
+
+    /// Returns whether `id` is the primary version of a named key.
+    pub(crate) fn is_primary(&self, id: Uuid) -> bool {
+        self.named_keys.values().any(|k| k.primary == id)
+    }
+
+    /// Returns the lifecycle state of the key `id`, or `None` if it is not found.
+    pub(crate) fn key_state(&self, id: Uuid) -> Option<KeyState> {
+        match self.key_states.get(&id) {
+            Some(record) => Some(record.state),
+            None => self
+                .keys
+                .iter()
+                .any(|k| k.id() == id)
+                .then_some(KeyState::Enabled),
+        }
+    }
+
+    pub(crate) fn key_state_record(&self, id: Uuid) -> Option<&KeyStateConfig> {
+        self.key_states.get(&id)
+    }
+
+    /// Changes the lifecycle state of the key `id`, returning the previous one.
+    ///
+    /// The default key and primary versions must stay enabled, and a key is destroyed only
+    /// through [`MasterKeyConfig::destroy_due`] after `grace_period` of pending destruction.
+    pub(crate) fn set_key_state(
+        &mut self,
+        id: Uuid,
+        state: KeyState,
+        changed_by: &str,
+        grace_period: Duration,
+        now: DateTime<Utc>,
+    ) -> Result<KeyState, KeyStateError> {
+        let old = self
+            .key_state(id)
+            .ok_or_else(|| KeyStateError::NotFound(format!("{id} is not found in keys")))?;
+        if old == KeyState::Destroyed {
+            return Err(KeyStateError::Rejected(format!("{id} is destroyed")));
+        }
+        if state == KeyState::Destroyed {
+            return Err(KeyStateError::Rejected(
+                "keys are destroyed only when the grace period of pending destruction ends"
+                    .to_string(),
+            ));
+        }
+        if state != KeyState::Enabled {
+            self.ensure_not_in_use(id)
+                .map_err(KeyStateError::Rejected)?;
+        }
+
+        let destroy_at = (state == KeyState::PendingDestruction)
+            .then(|| chrono::Duration::from_std(grace_period).map(|period| now + period))
+            .transpose()
+            .map_err(|_| KeyStateError::Rejected("grace period is too long".to_string()))?;
+        self.key_states.insert(
+            id,
+            KeyStateConfig {
+                state,
+                changed_at: now,
+                changed_by: changed_by.to_string(),
+                destroy_at,
+            },
+        );
+        Ok(old)
+    }
+
+    /// Destroys keys whose grace period of pending destruction has ended by `now`,
+    /// removing them from `keys` and returning their IDs.
+    pub(crate) fn destroy_due(&mut self, now: DateTime<Utc>) -> Vec<Uuid> {
+        let due: Vec<Uuid> = self
+            .key_states
+            .iter()
+            .filter(|(_, record)| {
+                record.state == KeyState::PendingDestruction
+                    && record.destroy_at.is_some_and(|at| at <= now)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &due {
+            self.destroy(*id, SYSTEM_ACTOR, now);
+        }
+        due
+    }
+
+    /// Removes the key `id`, keeping a record of its destruction.
+    /// The default key and primary versions cannot be removed.
+    pub(crate) fn remove(
+        &mut self,
+        id: Uuid,
+        changed_by: &str,
+        now: DateTime<Utc>,
+    ) -> Result<MasterKey, String> {
+        self.ensure_not_in_use(id)?;
+        if !self.keys.iter().any(|k| k.id() == id) {
+            return Err(format!("{id} is not found in keys"));
+        }
+        Ok(self.destroy(id, changed_by, now).unwrap())
+    }
+
+    fn ensure_not_in_use(&self, id: Uuid) -> Result<(), String> {
+        if id == self.default {
+            return Err(format!("{id} is the default key"));
+        }
+        if let Some((name, _)) = self.named_keys.iter().find(|(_, k)| k.primary == id) {
+            return Err(format!("{id} is the primary version of {name}"));
+        }
+        Ok(())
+    }
+
+    fn destroy(&mut self, id: Uuid, changed_by: &str, now: DateTime<Utc>) -> Option<MasterKey> {
+        for named_key in self.named_keys.values_mut() {
+            named_key.versions.retain(|v| v.id != id);
+        }
+        self.key_states.insert(
+            id,
+            KeyStateConfig {
+                state: KeyState::Destroyed,
+                changed_at: now,
+                changed_by: changed_by.to_string(),
+                destroy_at: None,
+            },
+        );
+        let index = self.keys.iter().position(|k| k.id() == id)?;
+        Some(self.keys.remove(index))
+    }
+
+    /// Splits the keyring into records named by section and ID or name
+    /// (e.g. `keys/{id}`), which [`MasterKeyConfig::from_records`] joins again.
+    /// Records are serialized to JSON in buffers zeroized when dropped.
+    pub(crate) fn to_records(&self) -> Result<BTreeMap<String, Zeroizing<Vec<u8>>>, String> {
+        fn value<T: Serialize>(value: &T) -> Result<Zeroizing<Vec<u8>>, String> {
+            serde_json::to_vec(value)
+                .map(Zeroizing::new)
+                .map_err(|e| e.to_string())
+        }
+
+        let mut records = BTreeMap::from([("default".to_string(), value(&self.default)?)]);
+        for key in &self.keys {
+            records.insert(format!("keys/{}", key.id()), value(key)?);
+        }
+        for (name, named_key) in &self.named_keys {
+            records.insert(format!("named-keys/{name}"), value(named_key)?);
+        }
+        for (id, record) in &self.key_states {
+            records.insert(format!("key-states/{id}"), value(record)?);
+        }
+        for (name, policy) in &self.rotation_policies {
+            records.insert(format!("rotation-policies/{name}"), value(policy)?);
+        }
+        Ok(records)
+    }
+
+    pub(crate) fn from_records(
+        records: impl IntoIterator<Item = (String, Zeroizing<Vec<u8>>)>,
+    ) -> Result<Self, String> {
+        fn value<T: DeserializeOwned>(name: &str, value: Zeroizing<Vec<u8>>) -> Result<T, String> {
+            serde_json::from_slice(&value).map_err(|e| format!("{name}: {e}"))
+        }
+
+        let mut default = None;
+        let mut keyring = Self {
+            default: Uuid::nil(),
+            keys: Vec::new(),
+            named_keys: BTreeMap::new(),
+            key_states: BTreeMap::new(),
+            rotation_policies: BTreeMap::new(),
+        };
+        for (name, record) in records {
+            match name.split_once('/') {
+                None if name == "default" => default = Some(value(&name, record)?),
+                Some(("keys", _)) => keyring.keys.push(value(&name, record)?),
+                Some(("named-keys", key_name)) => {
+                    let named_key = value(&name, record)?;
+                    keyring.named_keys.insert(key_name.to_string(), named_key);
+                }
+                Some(("key-states", id)) => {
+                    let id = id.parse().map_err(|e| format!("{name}: {e}"))?;
+                    keyring.key_states.insert(id, value(&name, record)?);
+                }
+                Some(("rotation-policies", key_name)) => {
+                    let policy = value(&name, record)?;
+                    keyring
+                        .rotation_policies
+                        .insert(key_name.to_string(), policy);
+                }
+                _ => return Err(format!("{name}: unknown record")),
+            }
+        }
+        keyring.default = default.ok_or("default key is not recorded")?;
+        Ok(keyring)
+    }
+
+    pub(crate) fn into_cipher(self) -> Result<RotatableCipher, String> {
         debug!("default master key ID: {}", self.default);
-        RotatableCipher::new(
-            self.default,
-            self.keys.into_iter().map(MasterKey::into_cipher).collect(),
-        )
+
+        let mut rotation_policies = HashMap::with_capacity(self.rotation_policies.len());
+        for (name, policy) in &self.rotation_policies {
+            let error = |e: String| format!("rotation-policies.{name}: {e}");
+            let primary = self
+                .primary_of(name)
+                .ok_or_else(|| error("key is not found".to_string()))?;
+            if policy.max_age_seconds.is_none() && policy.max_encryptions.is_none() {
+                return Err(error(
+                    "either max-age-seconds or max-encryptions must be set".to_string(),
+                ));
+            }
+            if policy.max_age_seconds == Some(0) || policy.max_encryptions == Some(0) {
+                return Err(error("limits must be positive".to_string()));
+            }
+            if let Some(key) = self.keys.iter().find(|k| k.id() == primary) {
+                key.generated_algorithm().map_err(error)?;
+            }
+            rotation_policies.insert(name.clone(), RotationPolicy::from(policy));
+        }
+
+        let mut ciphers = HashMap::with_capacity(self.keys.len());
+        for (index, key) in self.keys.into_iter().enumerate() {
+            let id = key.id();
+            let cipher = key
+                .into_cipher()
+                .map_err(|e| format!("keys[{index}] (id {id}): {e}"))?;
+            if ciphers.insert(id, cipher).is_some() {
+                return Err(format!("keys[{index}] (id {id}): duplicate key ID"));
+            }
+        }
+
+        if !ciphers.contains_key(&self.default) {
+            return Err(format!("default key {} is not found in keys", self.default));
+        }
+
+        let mut owners = HashMap::from([(self.default, DEFAULT_KEY_NAME)]);
+        let mut named_keys = HashMap::with_capacity(self.named_keys.len());
+        for (name, named_key) in &self.named_keys {
+            let error = |e: String| format!("named-keys.{name}: {e}");
+            validate_key_name(name).map_err(error)?;
+            if !named_key.ids().any(|id| id == named_key.primary) {
+                return Err(error(format!(
+                    "primary {} is not found in versions",
+                    named_key.primary
+                )));
+            }
+            if !named_key
+                .versions
+                .is_sorted_by(|a, b| a.version < b.version)
+            {
+                return Err(error(
+                    "versions must be numbered in increasing order".to_string(),
+                ));
+            }
+            for id in named_key.ids() {
+                if !ciphers.contains_key(&id) {
+                    return Err(error(format!("version {id} is not found in keys")));
+                }
+                if let Some(owner) = owners.insert(id, name) {
+                    return Err(error(format!("version {id} is also a version of {owner}")));
+                }
+            }
+            named_keys.insert(
+                name.clone(),
+                NamedKey {
+                    primary: named_key.primary,
+                    versions: named_key.ids().collect(),
+                },
+            );
+        }
+
+        let mut statuses = HashMap::with_capacity(self.key_states.len());
+        for (id, record) in &self.key_states {
+            let error = |e: &str| format!("key-states.{id}: {e}");
+            let destroyed = record.state == KeyState::Destroyed;
+            if destroyed && ciphers.contains_key(id) {
+                return Err(error("destroyed key is still in keys"));
+            }
+            if !destroyed && !ciphers.contains_key(id) {
+                return Err(error("key is not found in keys"));
+            }
+            let in_use =
+                *id == self.default || named_keys.values().any(|k: &NamedKey| k.primary == *id);
+            if record.state != KeyState::Enabled && in_use {
+                return Err(error(
+                    "the default key and primary versions of named keys must be enabled",
+                ));
+            }
+            statuses.insert(*id, KeyStatus::from(record));
+        }
+
+        RotatableCipher::new(self.default, ciphers)
+            .and_then(|cipher| cipher.with_named_keys(named_keys))
+            .and_then(|cipher| cipher.with_key_statuses(statuses))
+            .and_then(|cipher| cipher.with_rotation_policies(rotation_policies))
+            .map_err(|e| format!("{e:?}"))
     }
 }
 
 impl MasterKey {
-    fn into_cipher(self) -> (Uuid, OneOfCipher) {
+    pub(crate) fn id(&self) -> Uuid {
         match self {
-            MasterKey::Unencrypted { id } => (id, OneOfCipher::Unencrypted(Unencrypted)),
-            MasterKey::ChaCha20Poly1305 { id, key } => (
+            MasterKey::Unencrypted { id }
+            | MasterKey::ChaCha20Poly1305 { id, .. }
+            | MasterKey::AesGcmSiv { id, .. }
+            | MasterKey::Pkcs11 { id, .. }
+            | MasterKey::VaultTransit { id, .. }
+            | MasterKey::AwsKms { id, .. } => *id,
+        }
+    }
+
+    /// Generates a new random key.
+    pub(crate) fn generate(algorithm: CipherAlgorithm) -> Self {
+        let id = Uuid::new_v4();
+        match algorithm {
+            CipherAlgorithm::Chacha20Poly1305 => MasterKey::ChaCha20Poly1305 {
                 id,
-                OneOfCipher::ChaCha20Poly1305(
-                    ChaCha20Poly1305Cipher::try_from(BASE64_STANDARD.decode(key).unwrap()).unwrap(),
-                ),
-            ),
-            MasterKey::AesGcmSiv { id, key } => (
+                key: BASE64_STANDARD.encode(ChaCha20Poly1305Cipher::default().key()),
+            },
+            CipherAlgorithm::AesGcmSiv => MasterKey::AesGcmSiv {
                 id,
-                OneOfCipher::AesGcmSiv(
-                    AesGcmSivCipher::try_from(BASE64_STANDARD.decode(key).unwrap()).unwrap(),
-                ),
+                key: BASE64_STANDARD.encode(AesGcmSivCipher::default().key()),
+            },
+        }
+    }
+
+    /// Returns the algorithm for generating a key like this one,
+    /// which fails for keys kept outside the keyring.
+    fn generated_algorithm(&self) -> Result<CipherAlgorithm, String> {
+        match self {
+            MasterKey::ChaCha20Poly1305 { .. } => Ok(CipherAlgorithm::Chacha20Poly1305),
+            MasterKey::AesGcmSiv { .. } => Ok(CipherAlgorithm::AesGcmSiv),
+            key => Err(format!(
+                "primary {} is a {} key, which cannot be generated",
+                key.id(),
+                key.algorithm()
+            )),
+        }
+    }
+
+    pub(crate) fn algorithm(&self) -> &'static str {
+        match self {
+            MasterKey::Unencrypted { .. } => "Unencrypted",
+            MasterKey::ChaCha20Poly1305 { .. } => "ChaCha20Poly1305",
+            MasterKey::AesGcmSiv { .. } => "AesGcmSiv",
+            MasterKey::Pkcs11 { .. } => "Pkcs11",
+            MasterKey::VaultTransit { .. } => "VaultTransit",
+            MasterKey::AwsKms { .. } => "AwsKms",
+        }
+    }
+
+    /// Returns the first 8 bytes of SHA-256 of the key, which identifies the key
+    /// without revealing it.
+    pub(crate) fn fingerprint(&self) -> Option<String> {
+        let key = match self {
+            MasterKey::Unencrypted { .. }
+            | MasterKey::Pkcs11 { .. }
+            | MasterKey::VaultTransit { .. }
+            | MasterKey::AwsKms { .. } => return None,
+            MasterKey::ChaCha20Poly1305 { key, .. } | MasterKey::AesGcmSiv { key, .. } => key,
+        };
+        let key = BASE64_STANDARD.decode(key).ok()?;
+        let digest = Sha256::digest(key);
+        Some(digest[..8].iter().map(|b| format!("{b:02x}")).collect())
+    }
+
+    fn into_cipher(self) -> Result<OneOfCipher, String> {
+        match self {
+            MasterKey::Unencrypted { .. } => Ok(OneOfCipher::Unencrypted(Unencrypted)),
+            MasterKey::ChaCha20Poly1305 { key, .. } => {
+                let key = decode_key(&key, ChaCha20Poly1305Cipher::KEY_SIZE)?;
+                ChaCha20Poly1305Cipher::try_from(key)
+                    .map(OneOfCipher::ChaCha20Poly1305)
+                    .map_err(|e| format!("{e:?}"))
+            }
+            MasterKey::AesGcmSiv { key, .. } => {
+                let key = decode_key(&key, AesGcmSivCipher::KEY_SIZE)?;
+                AesGcmSivCipher::try_from(key)
+                    .map(OneOfCipher::AesGcmSiv)
+                    .map_err(|e| format!("{e:?}"))
+            }
+            MasterKey::Pkcs11 {
+                module,
+                slot,
+                token_label,
+                key_label,
+                pin_file,
+                ..
+            } => {
+                let pin = read_secret(pin_file.as_deref(), PKCS11_PIN_ENV)?;
+                let key = Pkcs11Key {
+                    module,
+                    slot,
+                    token_label,
+                    key_label,
+                };
+                Pkcs11Cipher::open(&key, pin.as_deref())
+                    .map(OneOfCipher::Pkcs11)
+                    .map_err(|e| format!("{e:?}"))
+            }
+            MasterKey::VaultTransit {
+                address,
+                mount,
+                key_name,
+                key_version,
+                namespace,
+                ca_certificate,
+                auth,
+                retries,
+                ..
+            } => {
+                check_retries(retries)?;
+                let ca_certificate = ca_certificate
+                    .map(|path| {
+                        std::fs::read(&path).map_err(|e| format!("{}: {e}", path.display()))
+                    })
+                    .transpose()?;
+                let auth = match auth {
+                    VaultAuthConfig::Token { token_file } => VaultAuth::Token(
+                        read_secret(token_file.as_deref(), VAULT_TOKEN_ENV)?
+                            .ok_or("Vault token is not given")?,
+                    ),
+                    VaultAuthConfig::AppRole {
+                        mount,
+                        role_id,
+                        secret_id_file,
+                    } => VaultAuth::AppRole {
+                        mount,
+                        role_id,
+                        secret_id: read_secret(secret_id_file.as_deref(), VAULT_SECRET_ID_ENV)?
+                            .ok_or("Vault secret ID is not given")?,
+                    },
+                };
+                VaultTransitCipher::new(VaultTransitConfig {
+                    address,
+                    mount,
+                    key_name,
+                    key_version,
+                    namespace,
+                    ca_certificate,
+                    auth,
+                    retries,
+                })
+                .map(OneOfCipher::VaultTransit)
+                .map_err(|e| format!("{e:?}"))
+            }
+            MasterKey::AwsKms {
+                region,
+                endpoint,
+                key_id,
+                encryption_context,
+                access_key_id,
+                secret_access_key_file,
+                session_token_file,
+                ca_certificate,
+                retries,
+                ..
+            } => {
+                check_retries(retries)?;
+                let credentials = AwsCredentials {
+                    access_key_id: match access_key_id {
+                        Some(id) => id,
+                        None => std::env::var(AWS_ACCESS_KEY_ID_ENV)
+                            .map_err(|_| "AWS access key ID is not given")?,
+                    },
+                    secret_access_key: read_secret(
+                        secret_access_key_file.as_deref(),
+                        AWS_SECRET_ACCESS_KEY_ENV,
+                    )?
+                    .ok_or("AWS secret access key is not given")?,
+                    session_token: read_secret(
+                        session_token_file.as_deref(),
+                        AWS_SESSION_TOKEN_ENV,
+                    )?,
+                };
+                AwsKmsCipher::new(AwsKmsConfig {
+                    region,
+                    endpoint,
+                    key_id,
+                    encryption_context,
+                    credentials,
+                    ca_certificate: read_ca_certificate(ca_certificate)?,
+                    retries,
+                })
+                .map(OneOfCipher::AwsKms)
+                .map_err(|e| format!("{e:?}"))
+            }
+        }
+    }
+}
+
+/// Accepts names of ASCII letters, digits and `-_./`, such as `payments/pii`,
+/// other than the name of the default key.
+fn validate_key_name(name: &str) -> Result<(), String> {
+    if name == DEFAULT_KEY_NAME {
+        return Err(format!("{name} is reserved for the default key"));
+    }
+    let valid = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-_./".contains(c));
+    if name.is_empty() || !valid {
+        return Err(format!(
+            "invalid key name {name:?}, which must consist of ASCII letters, digits and -_./"
+        ));
+    }
+    Ok(())
+}
+
+fn read_ca_certificate(path: Option<PathBuf>) -> Result<Option<Vec<u8>>, String> {
+    path.map(|path| std::fs::read(&path).map_err(|e| format!("{}: {e}", path.display())))
+        .transpose()
+}
+
+/// Reads a secret from `file`, or from the environment variable `env` if no file is given.
+fn read_secret(file: Option<&Path>, env: &str) -> Result<Option<String>, String> {
+    match file {
+        Some(path) => std::fs::read_to_string(path)
+            .map(|secret| Some(secret.trim_end_matches(['\r', '\n']).to_string()))
+            .map_err(|e| format!("{}: {e}", path.display())),
+        None => Ok(std::env::var(env).ok()),
+    }
+}
+
+fn decode_key(key: &str, size: usize) -> Result<Vec<u8>, String> {
+    let key = BASE64_STANDARD
+        .decode(key)
+        .map_err(|e| format!("key is not valid base64: {e}"))?;
+    if key.len() != size {
+        return Err(format!(
+            "key must be {size} bytes, but it is {} bytes",
+            key.len()
+        ));
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::FileFormat;
+
+    const ID1: &str = "be555a3d-11fe-4b26-b28f-3ddc349f9c6d";
+    const ID2: &str = "0a7e2cf4-4f4a-4b7b-9e0e-5b8b0c6b7f21";
+    const KEY: &str = "6cvV8L83a5MzKHaQFmjh8e8OTM+iX6j3qlhnmA5MFMA=";
+
+    fn parse(format: FileFormat, content: &str) -> Result<RotatableCipher, String> {
+        format.parse::<MasterKeyConfig>(content)?.into_cipher()
+    }
+
+    #[test]
+    fn test_parse_all_formats() {
+        let toml = format!(
+            "default = \"{ID1}\"\n[[keys]]\nalgorithm = \"ChaCha20Poly1305\"\nid = \"{ID1}\"\nkey = \"{KEY}\"\n"
+        );
+        let yaml = format!(
+            "default: {ID1}\nkeys:\n  - algorithm: ChaCha20Poly1305\n    id: {ID1}\n    key: '{KEY}'\n"
+        );
+        let json = format!(
+            r#"{{"default": "{ID1}", "keys": [{{"algorithm": "ChaCha20Poly1305", "id": "{ID1}", "key": "{KEY}"}}]}}"#
+        );
+
+        for (format, content) in [
+            (FileFormat::Toml, toml),
+            (FileFormat::Yaml, yaml),
+            (FileFormat::Json, json),
+        ] {
+            let cipher = parse(format, &content).unwrap();
+            assert_eq!(cipher.default_key_id(), ID1);
+        }
+    }
+
+    #[test]
+    fn test_reject_missing_default() {
+        let yaml = format!("default: {ID2}\nkeys:\n  - algorithm: Unencrypted\n    id: {ID1}\n");
+        let error = parse(FileFormat::Yaml, &yaml).err().unwrap();
+        assert!(error.contains(ID2), "{error}");
+    }
+
+    #[test]
+    fn test_reject_duplicate_id() {
+        let yaml = format!(
+            "default: {ID1}\nkeys:\n  - algorithm: Unencrypted\n    id: {ID1}\n  - algorithm: ChaCha20Poly1305\n    id: {ID1}\n    key: '{KEY}'\n"
+        );
+        let error = parse(FileFormat::Yaml, &yaml).err().unwrap();
+        assert!(error.starts_with(&format!("keys[1] (id {ID1})")), "{error}");
+    }
+
+    #[test]
+    fn test_reject_invalid_key() {
+        let yaml = format!(
+            "default: {ID1}\nkeys:\n  - algorithm: Unencrypted\n    id: {ID1}\n  - algorithm: AesGcmSiv\n    id: {ID2}\n    key: '{KEY}'\n"
+        );
+        let error = parse(FileFormat::Yaml, &yaml).err().unwrap();
+        assert_eq!(
+            error,
+            format!("keys[1] (id {ID2}): key must be 64 bytes, but it is 32 bytes")
+        );
+
+        let yaml = format!(
+            "default: {ID1}\nkeys:\n  - algorithm: ChaCha20Poly1305\n    id: {ID1}\n    key: 'not base64'\n"
+        );
+        let error = parse(FileFormat::Yaml, &yaml).err().unwrap();
+        assert!(error.starts_with(&format!("keys[0] (id {ID1}): key is not valid base64")));
+    }
+
+    #[test]
+    fn test_parse_pkcs11() {
+        let yaml = format!(
+            "default: {ID1}\nkeys:\n- algorithm: Pkcs11\n  id: {ID1}\n  module: /nonexistent/libsofthsm2.so\n  token-label: kagimori\n  key-label: kek\n"
+        );
+        let keyring = FileFormat::Yaml.parse::<MasterKeyConfig>(&yaml).unwrap();
+        assert!(matches!(
+            &keyring.keys()[0],
+            MasterKey::Pkcs11 { slot: None, token_label: Some(token), key_label, .. }
+                if token == "kagimori" && key_label == "kek"
+        ));
+        assert_eq!(keyring.keys()[0].fingerprint(), None);
+        assert_eq!(FileFormat::Yaml.serialize(&keyring).unwrap(), yaml);
+
+        let error = keyring.into_cipher().err().unwrap();
+        assert!(error.starts_with(&format!(
+            "keys[0] (id {ID1}): Pkcs11(\"/nonexistent/libsofthsm2.so: "
+        )));
+    }
+
+    #[test]
+    fn test_parse_vault_transit() {
+        let dir = tempfile::tempdir().unwrap();
+        let token_file = dir.path().join("token");
+        std::fs::write(&token_file, "s.token\n").unwrap();
+
+        let yaml = format!(
+            "default: {ID1}\nkeys:\n- algorithm: VaultTransit\n  id: {ID1}\n  address: https://vault:8200\n  key-name: kek\n  auth:\n    method: token\n    token-file: {}\n",
+            token_file.display()
+        );
+        let keyring = FileFormat::Yaml.parse::<MasterKeyConfig>(&yaml).unwrap();
+        assert!(matches!(
+            &keyring.keys()[0],
+            MasterKey::VaultTransit { mount, retries: 3, auth: VaultAuthConfig::Token { .. }, .. }
+                if mount == "transit"
+        ));
+        let cipher = keyring.into_cipher().unwrap();
+        assert_eq!(cipher.default_key_id(), ID1);
+
+        let yaml = format!(
+            "default: {ID1}\nkeys:\n- algorithm: VaultTransit\n  id: {ID1}\n  address: https://vault:8200\n  key-name: kek\n  auth:\n    method: app-role\n    role-id: role\n    secret-id-file: {}\n",
+            dir.path().join("missing").display()
+        );
+        let error = parse(FileFormat::Yaml, &yaml).err().unwrap();
+        assert!(error.starts_with(&format!(
+            "keys[0] (id {ID1}): {}",
+            dir.path().join("missing").display()
+        )));
+    }
+
+    #[test]
+    fn test_parse_aws_kms() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret_file = dir.path().join("secret");
+        std::fs::write(&secret_file, "secret\n").unwrap();
+
+        let json = format!(
+            r#"{{"default": "{ID1}", "keys": [{{"algorithm": "AwsKms", "id": "{ID1}", "region": "us-east-1", "endpoint": "http://localhost:8080", "key-id": "alias/kagimori", "encryption-context": {{"cluster": "prod"}}, "access-key-id": "AKIDEXAMPLE", "secret-access-key-file": "{}", "session-token-file": "{}"}}]}}"#,
+            secret_file.display(),
+            secret_file.display(),
+        );
+        let keyring = FileFormat::Json.parse::<MasterKeyConfig>(&json).unwrap();
+        assert!(matches!(
+            &keyring.keys()[0],
+            MasterKey::AwsKms { encryption_context, retries: 3, .. }
+                if encryption_context["cluster"] == "prod"
+        ));
+        assert_eq!(keyring.into_cipher().unwrap().default_key_id(), ID1);
+
+        let error = parse(
+            FileFormat::Json,
+            &json.replace("}]}", ", \"retries\": 11}]}"),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            error,
+            format!("keys[0] (id {ID1}): retries must not exceed 10")
+        );
+
+        let json = json.replace("http://localhost:8080", "not a URL");
+        let error = parse(FileFormat::Json, &json).err().unwrap();
+        assert!(error.starts_with(&format!("keys[0] (id {ID1}): AwsKms(\"not a URL: ")));
+    }
+
+    #[test]
+    fn test_generate_add_and_remove() {
+        let first = MasterKey::generate(CipherAlgorithm::AesGcmSiv);
+        let first_id = first.id();
+        let mut keyring = MasterKeyConfig::new(first);
+
+        let second = MasterKey::generate(CipherAlgorithm::Chacha20Poly1305);
+        let second_id = second.id();
+        keyring.add(second, true);
+        assert_eq!(keyring.default_key_id(), second_id);
+        assert!(keyring.remove(second_id, "admin", Utc::now()).is_err());
+
+        for format in [FileFormat::Toml, FileFormat::Yaml, FileFormat::Json] {
+            let content = format.serialize(&keyring).unwrap();
+            let cipher = parse(format, &content).unwrap();
+            assert_eq!(cipher.key_count(), 2);
+        }
+
+        keyring.remove(first_id, "admin", Utc::now()).unwrap();
+        assert_eq!(keyring.keys().len(), 1);
+        assert_eq!(keyring.key_state(first_id), Some(KeyState::Destroyed));
+        assert!(keyring.remove(first_id, "admin", Utc::now()).is_err());
+    }
+
+    #[test]
+    fn test_named_keys() {
+        const ID3: &str = "5f0c9a43-8a52-4c1e-9d3b-2b7d5a6e4f10";
+        let yaml = format!(
+            "default: {ID1}\nkeys:\n- algorithm: Unencrypted\n  id: {ID1}\n- algorithm: Unencrypted\n  id: {ID2}\n- algorithm: ChaCha20Poly1305\n  id: {ID3}\n  key: {KEY}\nnamed-keys:\n  payments/pii:\n    primary: {ID3}\n    versions:\n    - id: {ID2}\n      version: 1\n    - id: {ID3}\n      version: 2\n"
+        );
+        let keyring = FileFormat::Yaml.parse::<MasterKeyConfig>(&yaml).unwrap();
+        assert_eq!(FileFormat::Yaml.serialize(&keyring).unwrap(), yaml);
+        let legacy = yaml
+            .replace("- id: ", "- ")
+            .replace("      version: 1\n", "")
+            .replace("      version: 2\n", "");
+        let legacy = FileFormat::Yaml.parse::<MasterKeyConfig>(&legacy).unwrap();
+        assert_eq!(FileFormat::Yaml.serialize(&legacy).unwrap(), yaml);
+        assert_eq!(
+            keyring.version_of(ID2.parse().unwrap()),
+            Some(("payments/pii", 1))
+        );
+        assert_eq!(keyring.version_of(ID1.parse().unwrap()), None);
+        let cipher = keyring.clone().into_cipher().unwrap();
+        assert_eq!(
+            cipher.primary_key("payments/pii").unwrap().0.to_string(),
+            ID3
+        );
+        assert_eq!(cipher.key_name_of(&ID2.parse().unwrap()), "payments/pii");
+
+        for (from, to, error) in [
+            (
+                "payments/pii:",
+                "default:",
+                "named-keys.default: default is reserved for the default key",
+            ),
+            (
+                "payments/pii:",
+                "payments pii:",
+                "named-keys.payments pii: invalid key name",
+            ),
+            (
+                "primary: {ID3}",
+                "primary: {ID1}",
+                "named-keys.payments/pii: primary {ID1} is not found in versions",
+            ),
+            (
+                "    - id: {ID2}\n",
+                "    - id: {ID1}\n",
+                "named-keys.payments/pii: version {ID1} is also a version of default",
             ),
+            (
+                "      version: 1\n",
+                "      version: 3\n",
+                "named-keys.payments/pii: versions must be numbered in increasing order",
+            ),
+        ] {
+            let [from, to, error] = [from, to, error].map(|s| {
+                s.replace("{ID1}", ID1)
+                    .replace("{ID2}", ID2)
+                    .replace("{ID3}", ID3)
+            });
+            let error_yaml = yaml.replace(&from, &to);
+            let actual = parse(FileFormat::Yaml, &error_yaml).err().unwrap();
+            assert!(actual.starts_with(&error), "{actual}");
+        }
+
+        let mut keyring = keyring;
+        assert!(
+            keyring
+                .remove(ID3.parse().unwrap(), "admin", Utc::now())
+                .is_err()
+        );
+        let key = MasterKey::generate(CipherAlgorithm::AesGcmSiv);
+        let id = key.id();
+        keyring.add_version("payments/pii", key).unwrap();
+        assert_eq!(keyring.version_of(id), Some(("payments/pii", 3)));
+        keyring
+            .remove(ID2.parse().unwrap(), "admin", Utc::now())
+            .unwrap();
+        assert_eq!(keyring.version_of(id), Some(("payments/pii", 3)));
+        assert_eq!(
+            keyring.version_of(ID3.parse().unwrap()),
+            Some(("payments/pii", 2))
+        );
+        let key = MasterKey::generate(CipherAlgorithm::AesGcmSiv);
+        let newest = key.id();
+        keyring.add_version("payments/pii", key).unwrap();
+        assert_eq!(keyring.version_of(newest), Some(("payments/pii", 4)));
+        let cipher = keyring.into_cipher().unwrap();
+        assert_eq!(cipher.primary_key("payments/pii").unwrap().0, newest);
+    }
+
+    #[test]
+    fn test_key_states() {
+        let now = Utc::now();
+        let mut keyring = MasterKeyConfig::new(MasterKey::generate(CipherAlgorithm::AesGcmSiv));
+        let default_id = keyring.default_key_id();
+        let key = MasterKey::generate(CipherAlgorithm::AesGcmSiv);
+        let id = key.id();
+        keyring.add(key, false);
+        let set = |keyring: &mut MasterKeyConfig, id, state| {
+            keyring.set_key_state(id, state, "admin", Duration::from_secs(60), now)
+        };
+
+        assert!(matches!(
+            set(&mut keyring, default_id, KeyState::Disabled),
+            Err(KeyStateError::Rejected(_))
+        ));
+        assert!(matches!(
+            set(&mut keyring, Uuid::nil(), KeyState::Disabled),
+            Err(KeyStateError::NotFound(_))
+        ));
+        assert!(matches!(
+            set(&mut keyring, id, KeyState::Destroyed),
+            Err(KeyStateError::Rejected(_))
+        ));
+        assert_eq!(
+            set(&mut keyring, id, KeyState::PendingDestruction).unwrap(),
+            KeyState::Enabled
+        );
+        let record = keyring.key_state_record(id).unwrap();
+        assert_eq!(record.changed_by, "admin");
+        assert_eq!(record.destroy_at, Some(now + chrono::Duration::seconds(60)));
+
+        for format in [FileFormat::Toml, FileFormat::Yaml, FileFormat::Json] {
+            let content = format.serialize(&keyring).unwrap();
+            let cipher = parse(format, &content).unwrap();
+            assert_eq!(cipher.status(&id).state, KeyState::PendingDestruction);
         }
+
+        assert!(keyring.destroy_due(now).is_empty());
+        assert_eq!(
+            keyring.destroy_due(now + chrono::Duration::seconds(60)),
+            vec![id]
+        );
+        assert_eq!(keyring.key_state(id), Some(KeyState::Destroyed));
+        assert_eq!(keyring.keys().len(), 1);
+        assert!(matches!(
+            set(&mut keyring, id, KeyState::Enabled),
+            Err(KeyStateError::Rejected(_))
+        ));
+        assert_eq!(keyring.into_cipher().unwrap().key_count(), 1);
+
+        let yaml = format!(
+            "default: {ID1}\nkeys:\n- algorithm: Unencrypted\n  id: {ID1}\nkey-states:\n  {ID1}:\n    state: disabled\n    changed-at: 2024-01-01T00:00:00Z\n    changed-by: admin\n"
+        );
+        let error = parse(FileFormat::Yaml, &yaml).err().unwrap();
+        assert!(error.starts_with(&format!("key-states.{ID1}: ")), "{error}");
+    }
+
+    #[test]
+    fn test_rotation_policies() {
+        let now = Utc::now();
+        let mut keyring = MasterKeyConfig::new(MasterKey::generate(CipherAlgorithm::AesGcmSiv));
+        let first_id = keyring.default_key_id();
+        let day = Duration::from_secs(86400);
+        assert!(
+            keyring
+                .set_rotation_policy("payments/pii", Some(day), None, now)
+                .is_err()
+        );
+        keyring
+            .set_rotation_policy(DEFAULT_KEY_NAME, Some(day), Some(1000), now)
+            .unwrap();
+        let cipher = keyring.clone().into_cipher().unwrap();
+        let policy = cipher.rotation_policies()[DEFAULT_KEY_NAME];
+        assert_eq!(policy.max_age, Some(day));
+        assert_eq!(policy.max_encryptions, Some(1000));
+        assert_eq!(policy.rotated_at, Some(now));
+
+        let later = now + chrono::Duration::days(1);
+        let (old, new) = keyring.rotate(DEFAULT_KEY_NAME, later).unwrap();
+        assert_eq!(old, first_id);
+        assert_eq!(keyring.default_key_id(), new);
+        assert_eq!(keyring.keys()[1].algorithm(), "AesGcmSiv");
+        let cipher = keyring.clone().into_cipher().unwrap();
+        assert_eq!(
+            cipher.rotation_policies()[DEFAULT_KEY_NAME].rotated_at,
+            Some(later)
+        );
+
+        keyring
+            .add_version(
+                "payments/pii",
+                MasterKey::generate(CipherAlgorithm::Chacha20Poly1305),
+            )
+            .unwrap();
+        keyring
+            .set_rotation_policy("payments/pii", None, Some(10), now)
+            .unwrap();
+        let (_, new) = keyring.rotate("payments/pii", later).unwrap();
+        assert_eq!(keyring.version_of(new), Some(("payments/pii", 2)));
+        assert!(keyring.is_primary(new));
+
+        keyring
+            .set_rotation_policy("payments/pii", None, None, now)
+            .unwrap();
+        let cipher = keyring.into_cipher().unwrap();
+        assert!(!cipher.rotation_policies().contains_key("payments/pii"));
+
+        let yaml = |policy: &str| {
+            format!(
+                "default: {ID1}\nkeys:\n- algorithm: Unencrypted\n  id: {ID1}\nrotation-policies:\n  {policy}\n"
+            )
+        };
+        for (policy, expected) in [
+            ("default: {}", "either max-age-seconds"),
+            ("default: {max-encryptions: 0}", "limits must be positive"),
+            ("other: {max-encryptions: 1}", "key is not found"),
+            ("default: {max-encryptions: 1}", "cannot be generated"),
+        ] {
+            let error = parse(FileFormat::Yaml, &yaml(policy)).err().unwrap();
+            assert!(error.starts_with("rotation-policies."), "{error}");
+            assert!(error.contains(expected), "{error}");
+        }
+
+        let mut keyring: MasterKeyConfig = FileFormat::Yaml
+            .parse(&format!(
+                "default: {ID1}\nkeys:\n- algorithm: AesGcmSiv\n  id: {ID1}\n  key: {}\nrotation-policies:\n  default:\n    max-age-seconds: 60\n",
+                BASE64_STANDARD.encode([0; 32])
+            ))
+            .unwrap();
+        assert_eq!(keyring.start_rotation_clocks(now), vec![DEFAULT_KEY_NAME]);
+        assert!(keyring.start_rotation_clocks(now).is_empty());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let key: MasterKey = FileFormat::Yaml
+            .parse(&format!(
+                "algorithm: ChaCha20Poly1305\nid: {ID1}\nkey: '{KEY}'\n"
+            ))
+            .unwrap();
+        let fingerprint = key.fingerprint().unwrap();
+        assert_eq!(fingerprint.len(), 16);
+        assert!(!fingerprint.contains(KEY));
+
+        let key: MasterKey = FileFormat::Yaml
+            .parse(&format!("algorithm: Unencrypted\nid: {ID1}\n"))
+            .unwrap();
+        assert_eq!(key.fingerprint(), None);
     }
 }
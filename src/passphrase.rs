@@ -0,0 +1,138 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use clap::Args;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use zeroize::Zeroizing;
+
+/// Environment variable holding the passphrase of an encrypted master key file.
+pub(crate) const PASSPHRASE_ENV: &str = "KAGIMORI_MASTER_KEY_PASSPHRASE";
+/// Environment variable holding the new passphrase for `kagimori keyring rekey`.
+pub(crate) const NEW_PASSPHRASE_ENV: &str = "KAGIMORI_NEW_MASTER_KEY_PASSPHRASE";
+
+#[derive(Debug, Clone, Default, Args)]
+pub(crate) struct PassphraseArgs {
+    /// Read the passphrase of the master key file from this file
+    #[arg(long, env = "KAGIMORI_MASTER_KEY_PASSPHRASE_FILE")]
+    pub passphrase_file: Option<PathBuf>,
+    /// Read the passphrase of the master key file from this file descriptor
+    #[arg(long, env = "KAGIMORI_MASTER_KEY_PASSPHRASE_FD")]
+    pub passphrase_fd: Option<i32>,
+}
+
+/// Passphrase of an encrypted master key file, read on first use.
+///
+/// It is looked up in the given file or file descriptor, then in an environment variable,
+/// and finally prompted for if standard input is a terminal.
+pub(crate) struct Passphrase {
+    file: Option<PathBuf>,
+    fd: Option<i32>,
+    env: &'static str,
+    value: Option<Zeroizing<String>>,
+}
+
+impl Passphrase {
+    pub(crate) fn new(file: Option<PathBuf>, fd: Option<i32>, env: &'static str) -> Self {
+        Self {
+            file,
+            fd,
+            env,
+            value: None,
+        }
+    }
+
+    /// Returns the passphrase. A prompted passphrase is asked twice if `confirm` is set.
+    pub(crate) fn get(&mut self, confirm: bool) -> Result<&str, String> {
+        if self.value.is_none() {
+            self.value = Some(self.read(confirm)?);
+        }
+        Ok(self.value.as_deref().unwrap())
+    }
+
+    fn read(&self, confirm: bool) -> Result<Zeroizing<String>, String> {
+        let passphrase = if let Some(path) = &self.file {
+            read_path(path.clone())?
+        } else if let Some(fd) = self.fd {
+            // A descriptor can be read only once, which is fine as the value is kept.
+            read_path(PathBuf::from(format!("/dev/fd/{fd}")))?
+        } else if let Ok(value) = std::env::var(self.env) {
+            Zeroizing::new(value)
+        } else if std::io::stdin().is_terminal() {
+            prompt(confirm)?
+        } else {
+            return Err(format!(
+                "master key passphrase is required, but none of a passphrase file, a file descriptor or {} is given",
+                self.env
+            ));
+        };
+
+        if passphrase.is_empty() {
+            return Err("master key passphrase is empty".to_string());
+        }
+        Ok(passphrase)
+    }
+}
+
+fn read_path(path: PathBuf) -> Result<Zeroizing<String>, String> {
+    let mut content = Zeroizing::new(
+        std::fs::read_to_string(&path).map_err(|e| format!("{}: {e}", path.display()))?,
+    );
+    let len = content.trim_end_matches(['\r', '\n']).len();
+    content.truncate(len);
+    Ok(content)
+}
+
+fn prompt(confirm: bool) -> Result<Zeroizing<String>, String> {
+    let passphrase = Zeroizing::new(
+        rpassword::prompt_password("Master key passphrase: ").map_err(|e| e.to_string())?,
+    );
+    if confirm {
+        let again = Zeroizing::new(
+            rpassword::prompt_password("Confirm passphrase: ").map_err(|e| e.to_string())?,
+        );
+        if passphrase != again {
+            return Err("passphrases do not match".to_string());
+        }
+    }
+    Ok(passphrase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_from_file() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "correct horse\n").unwrap();
+
+        let mut sut = Passphrase::new(Some(file.path().to_path_buf()), None, PASSPHRASE_ENV);
+        assert_eq!(sut.get(false).unwrap(), "correct horse");
+
+        // the value is kept after the file is gone
+        file.close().unwrap();
+        assert_eq!(sut.get(false).unwrap(), "correct horse");
+    }
+
+    #[test]
+    fn test_reject_empty() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "\n").unwrap();
+
+        let mut sut = Passphrase::new(Some(file.path().to_path_buf()), None, PASSPHRASE_ENV);
+        assert!(sut.get(false).is_err());
+    }
+}
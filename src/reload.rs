@@ -0,0 +1,1125 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use crate::format::{FileFormat, write_file_atomically};
+use crate::keyring_file::{EncryptedKeyring, KeyringFile};
+use crate::keystore::{KeyProtection, Keystore, Secret, UnlockedKeystore};
+use crate::master_key::{
+    DEFAULT_DESTRUCTION_GRACE_PERIOD, DEFAULT_MIN_DESTRUCTION_GRACE_PERIOD, MasterKeyConfig,
+    SYSTEM_ACTOR,
+};
+use crate::passphrase::Passphrase;
+use crate::unseal::KeyringUnsealer;
+use async_trait::async_trait;
+use audit_log::{
+    Action, AuditLog, AuditLogger, KeyRotationAction, KeyStateChangeAction, KeyringReloadAction,
+};
+use chrono::Utc;
+use ciphers::rotatable::RotatableCipher;
+use encryption::{Encryptor, Error, KeyState, KeyStatus, KeyringChange, RotationReason};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use server::lifecycle::{KeyLifecycle, KeyStateChange, KeyStateError};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use telemetry::metrics::metrics;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+/// Time to wait for a burst of file system events to settle before reloading.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+/// Interval of looking for keys whose grace period of pending destruction has ended,
+/// and for keys due for rotation.
+const KEY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Time to wait after a failed rotation before trying again.
+const ROTATION_RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+/// Interval of saving the numbers of encryptions with primary versions into the keyring.
+const USAGE_SAVE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// A change of the keyring file requested through the admin API.
+pub(crate) struct KeyringEdit {
+    change: KeyStateChange,
+    reply: oneshot::Sender<Result<KeyStatus, KeyStateError>>,
+}
+
+/// Changes the keyring file through a [`KeyringReloader`], which serializes the changes
+/// with reloads.
+pub(crate) struct KeyringEditor {
+    sender: mpsc::Sender<KeyringEdit>,
+}
+
+impl KeyringEditor {
+    /// Creates an editor and the receiver of its changes for [`KeyringReloader::run`].
+    pub(crate) fn new() -> (Self, mpsc::Receiver<KeyringEdit>) {
+        let (sender, edits) = mpsc::channel(16);
+        (Self { sender }, edits)
+    }
+}
+
+#[async_trait]
+impl KeyLifecycle for KeyringEditor {
+    async fn set_state(&self, change: KeyStateChange) -> Result<KeyStatus, KeyStateError> {
+        let stopped = || KeyStateError::Failed("keyring reloader is stopped".to_string());
+        let (reply, result) = oneshot::channel();
+        self.sender
+            .send(KeyringEdit { change, reply })
+            .await
+            .map_err(|_| stopped())?;
+        result.await.map_err(|_| stopped())?
+    }
+}
+
+/// Reloads the master keyring into a running [`Encryptor`] on SIGHUP or when the file changes.
+pub(crate) struct KeyringReloader<L> {
+    path: PathBuf,
+    /// Read and written on blocking threads, as deriving a key from the passphrase or
+    /// committing to a keystore can take seconds.
+    source: Arc<Mutex<KeyringSource<L>>>,
+    encryptor: Encryptor<L>,
+    audit_logger: L,
+    allow_key_removal: bool,
+    digest: Option<[u8; 32]>,
+    auto_rotate: bool,
+    min_grace_period: Duration,
+    rotation_retry_at: Option<Instant>,
+    usage_saved_at: Option<Instant>,
+}
+
+/// The keyring file or keystore, with what opens it.
+struct KeyringSource<L> {
+    path: PathBuf,
+    passphrase: Passphrase,
+    unsealer: Option<Arc<KeyringUnsealer<L>>>,
+    /// Highest generation of a keystore loaded, to refuse one rolled back to an older copy
+    keystore_generation: u64,
+}
+
+impl<L> KeyringReloader<L>
+where
+    L: 'static + AuditLogger,
+{
+    pub(crate) fn new(
+        path: PathBuf,
+        passphrase: Passphrase,
+        unsealer: Option<Arc<KeyringUnsealer<L>>>,
+        encryptor: Encryptor<L>,
+        audit_logger: L,
+        allow_key_removal: bool,
+    ) -> Self {
+        let digest = std::fs::read(&path).ok().map(|c| Sha256::digest(c).into());
+        Self {
+            path: path.clone(),
+            source: Arc::new(Mutex::new(KeyringSource {
+                path,
+                passphrase,
+                unsealer,
+                keystore_generation: 0,
+            })),
+            encryptor,
+            audit_logger,
+            allow_key_removal,
+            digest,
+            auto_rotate: false,
+            min_grace_period: DEFAULT_MIN_DESTRUCTION_GRACE_PERIOD,
+            rotation_retry_at: None,
+            usage_saved_at: None,
+        }
+    }
+
+    /// Rotates keys by their rotation policies, and saves the numbers of encryptions
+    /// counted for them. Replicas sharing a keyring must not all do this, as each would
+    /// rotate the keys on its own.
+    pub(crate) fn with_auto_rotation(mut self) -> Self {
+        self.auto_rotate = true;
+        self
+    }
+
+    /// Refuses key state changes asking for a shorter grace period of pending destruction.
+    pub(crate) fn with_min_grace_period(mut self, min_grace_period: Duration) -> Self {
+        self.min_grace_period = min_grace_period;
+        self
+    }
+
+    pub(crate) async fn run<F>(mut self, mut edits: mpsc::Receiver<KeyringEdit>, shutdown: F)
+    where
+        F: Future<Output = ()>,
+    {
+        let mut key_check = tokio::time::interval(KEY_CHECK_INTERVAL);
+        let mut hangup = signal(SignalKind::hangup()).unwrap();
+        let (sender, mut events) = mpsc::unbounded_channel();
+        // Kubernetes updates Secret volumes by swapping a symlink in the mounted
+        // directory, so watch the directory rather than the file itself.
+        let _watcher = match watch(&self.path, sender) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                warn!(
+                    "Cannot watch {}, reload on SIGHUP only: {e}",
+                    self.path.display()
+                );
+                None
+            }
+        };
+
+        // Reloads once a change has settled, without delaying the other events.
+        let debounce = tokio::time::sleep(DEBOUNCE);
+        let mut changed = false;
+        tokio::pin!(shutdown, debounce);
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => {
+                    if self.auto_rotate {
+                        self.save_usage().await;
+                    }
+                    break;
+                }
+                _ = hangup.recv() => self.reload("SIGHUP", true).await,
+                Some(()) = events.recv() => {
+                    if !changed {
+                        changed = true;
+                        debounce.as_mut().reset(tokio::time::Instant::now() + DEBOUNCE);
+                    }
+                }
+                _ = &mut debounce, if changed => {
+                    changed = false;
+                    self.reload("file-watch", false).await;
+                }
+                Some(edit) = edits.recv() => {
+                    let result = self.set_key_state(edit.change).await;
+                    let _ = edit.reply.send(result);
+                }
+                _ = key_check.tick() => {
+                    self.destroy_due().await;
+                    if self.auto_rotate {
+                        self.rotate_due().await;
+                        if self
+                            .usage_saved_at
+                            .is_none_or(|at| at.elapsed() >= USAGE_SAVE_INTERVAL)
+                        {
+                            self.save_usage().await;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Runs `f` with the keyring source on a blocking thread.
+    async fn blocking<T>(
+        &self,
+        f: impl FnOnce(&mut KeyringSource<L>) -> T + Send + 'static,
+    ) -> Result<T, String>
+    where
+        T: Send + 'static,
+    {
+        let source = self.source.clone();
+        tokio::task::spawn_blocking(move || {
+            f(&mut source.lock().unwrap_or_else(PoisonError::into_inner))
+        })
+        .await
+        .map_err(|e| e.to_string())
+    }
+
+    /// Reloads the keyring. Unless `always` is set, an unchanged file is ignored.
+    async fn reload(&mut self, trigger: &str, always: bool) {
+        if self.encryptor.is_sealed() {
+            // The file is read when unsealed
+            return;
+        }
+        let unchanged = if always { None } else { self.digest };
+        let loaded = self
+            .blocking(move |source| source.load(unchanged))
+            .await
+            .and_then(|loaded| loaded);
+        let result = match loaded {
+            Ok(None) => return,
+            Ok(Some((digest, kek))) => self
+                .encryptor
+                .replace_kek(kek, self.allow_key_removal)
+                .map(|change| (digest, change))
+                .map_err(|e| match e {
+                    Error::KeysRemoved(ids) => format!(
+                        "keys {} would be removed, set `allow-key-removal` to force",
+                        join(&ids)
+                    ),
+                    e => format!("{e:?}"),
+                }),
+            Err(e) => Err(e),
+        };
+        // A failed file is read again on the next change, e.g. when its passphrase is fixed.
+        let result = result.map(|(digest, change)| {
+            self.digest = Some(digest);
+            change
+        });
+        self.report(trigger, result).await;
+    }
+
+    /// Applies `edit` to the keyring file, writes it back protected in the same way
+    /// and loads it, returning what `edit` returned.
+    async fn update<T>(
+        &mut self,
+        edit: impl FnOnce(&mut MasterKeyConfig) -> Result<T, KeyStateError> + Send + 'static,
+    ) -> Result<T, KeyStateError>
+    where
+        T: Send + 'static,
+    {
+        if self.encryptor.is_sealed() {
+            return Err(KeyStateError::Failed("keyring is sealed".to_string()));
+        }
+        let (value, kek, digest) = self
+            .blocking(move |source| source.update(edit))
+            .await
+            .map_err(KeyStateError::Failed)??;
+        // the watcher is notified of this write, which need not be reloaded
+        self.digest = Some(digest);
+        self.encryptor
+            .replace_kek(kek, self.allow_key_removal)
+            .map_err(|e| KeyStateError::Failed(format!("{e:?}")))?;
+        Ok(value)
+    }
+
+    async fn set_key_state(&mut self, change: KeyStateChange) -> Result<KeyStatus, KeyStateError> {
+        let now = Utc::now();
+        let grace_period = change
+            .grace_period
+            .unwrap_or(DEFAULT_DESTRUCTION_GRACE_PERIOD.max(self.min_grace_period));
+        if change.state == KeyState::PendingDestruction && grace_period < self.min_grace_period {
+            return Err(KeyStateError::Rejected(format!(
+                "grace period must be at least {} seconds",
+                self.min_grace_period.as_secs()
+            )));
+        }
+        let (key_id, state, changed_by) = (change.key_id, change.state, change.changed_by.clone());
+        let result = self
+            .update(move |keyring| {
+                let old = keyring.set_key_state(key_id, state, &changed_by, grace_period, now)?;
+                let status = keyring
+                    .key_state_record(key_id)
+                    .map(KeyStatus::from)
+                    .unwrap_or_default();
+                Ok((old, status))
+            })
+            .await;
+
+        let (old_state, status) = match &result {
+            Ok((old, status)) => {
+                info!(
+                    "Changed state of key {} from {old} to {} by {}",
+                    change.key_id, status.state, change.changed_by
+                );
+                (Some(old.to_string()), Some(status.clone()))
+            }
+            Err(e) => {
+                warn!("Cannot change state of key {}: {e:?}", change.key_id);
+                (None, None)
+            }
+        };
+        self.log_key_state_change(
+            &change.changed_by,
+            KeyStateChangeAction {
+                key_id: change.key_id.to_string(),
+                old_state,
+                new_state: change.state.to_string(),
+                destroy_at: status.and_then(|s| s.destroy_at),
+                succeeded: result.is_ok(),
+                error: result.as_ref().err().map(|e| format!("{e:?}")),
+            },
+        )
+        .await;
+        result.map(|(_, status)| status)
+    }
+
+    /// Destroys keys whose grace period of pending destruction has ended.
+    async fn destroy_due(&mut self) {
+        let now = Utc::now();
+        let due = self.encryptor.key_statuses().values().any(|status| {
+            status.state == KeyState::PendingDestruction
+                && status.destroy_at.is_some_and(|at| at <= now)
+        });
+        if !due {
+            return;
+        }
+
+        match self
+            .update(move |keyring| Ok(keyring.destroy_due(now)))
+            .await
+        {
+            Ok(ids) => {
+                for id in ids {
+                    info!("Destroyed key {id} at the end of its grace period");
+                    self.log_key_state_change(
+                        SYSTEM_ACTOR,
+                        KeyStateChangeAction {
+                            key_id: id.to_string(),
+                            old_state: Some(KeyState::PendingDestruction.to_string()),
+                            new_state: KeyState::Destroyed.to_string(),
+                            destroy_at: None,
+                            succeeded: true,
+                            error: None,
+                        },
+                    )
+                    .await;
+                }
+            }
+            Err(e) => error!("Cannot destroy keys pending destruction: {e:?}"),
+        }
+    }
+
+    /// Rotates keys whose primary version is due by their rotation policies,
+    /// and records when the primary versions became primary if missing.
+    async fn rotate_due(&mut self) {
+        if self.rotation_retry_at.is_some_and(|at| Instant::now() < at) {
+            return;
+        }
+        let now = Utc::now();
+        let due = self.encryptor.rotations_due(now);
+        let unrecorded = self
+            .encryptor
+            .rotation_policies()
+            .values()
+            .any(|policy| policy.rotated_at.is_none());
+        if due.is_empty() && !unrecorded {
+            return;
+        }
+
+        let primaries = self.encryptor.named_keys();
+        let names: Vec<String> = due.iter().map(|(name, _)| name.clone()).collect();
+        let results = self
+            .update(move |keyring| {
+                keyring.start_rotation_clocks(now);
+                Ok(names
+                    .iter()
+                    .map(|name| keyring.rotate(name, now))
+                    .collect::<Vec<_>>())
+            })
+            .await
+            .unwrap_or_else(|e| vec![Err(format!("{e:?}")); due.len()]);
+        self.rotation_retry_at = results
+            .iter()
+            .any(Result::is_err)
+            .then(|| Instant::now() + ROTATION_RETRY_INTERVAL);
+
+        for ((name, reason), result) in due.into_iter().zip(results) {
+            self.report_rotation(
+                &name,
+                reason,
+                result,
+                primaries.get(&name).map(|k| k.primary),
+            )
+            .await;
+        }
+    }
+
+    /// Saves the numbers of encryptions with the primary versions of keys rotated by
+    /// `max-encryptions` into the keyring, so that they count across restarts.
+    async fn save_usage(&mut self) {
+        self.usage_saved_at = Some(Instant::now());
+        let named_keys = self.encryptor.named_keys();
+        let counts: Vec<_> = self
+            .encryptor
+            .rotation_policies()
+            .into_iter()
+            .filter(|(_, policy)| policy.max_encryptions.is_some())
+            .filter_map(|(name, policy)| {
+                let primary = named_keys.get(&name)?.primary;
+                let count = self.encryptor.encryption_count(&primary);
+                (count > policy.saved_encryptions).then_some((name, primary, count))
+            })
+            .collect();
+        if counts.is_empty() {
+            return;
+        }
+        let result = self
+            .update(move |keyring| {
+                for (name, primary, count) in &counts {
+                    keyring.save_encryptions(name, *primary, *count);
+                }
+                Ok(())
+            })
+            .await;
+        match result {
+            Ok(()) => debug!("Saved numbers of encryptions with primary versions"),
+            Err(e) => warn!("Cannot save numbers of encryptions with primary versions: {e:?}"),
+        }
+    }
+
+    async fn report_rotation(
+        &self,
+        name: &str,
+        reason: RotationReason,
+        result: Result<(Uuid, Uuid), String>,
+        primary: Option<Uuid>,
+    ) {
+        metrics().record_key_rotation(name, result.is_ok());
+        let action = match result {
+            Ok((old, new)) => {
+                info!("Rotated key {name} ({reason}): {old} -> {new}");
+                KeyRotationAction {
+                    key_name: name.to_string(),
+                    reason: reason.to_string(),
+                    old_key_id: old.to_string(),
+                    new_key_id: Some(new.to_string()),
+                    succeeded: true,
+                    error: None,
+                }
+            }
+            Err(e) => {
+                error!("Cannot rotate key {name} ({reason}), retrying later: {e}");
+                KeyRotationAction {
+                    key_name: name.to_string(),
+                    reason: reason.to_string(),
+                    old_key_id: primary.map(|id| id.to_string()).unwrap_or_default(),
+                    new_key_id: None,
+                    succeeded: false,
+                    error: Some(e),
+                }
+            }
+        };
+        self.audit_logger
+            .log(AuditLog {
+                timestamp: Utc::now(),
+                event_id: Uuid::new_v4().to_string(),
+                service: "kagimori".to_string(),
+                user: SYSTEM_ACTOR.to_string(),
+                trace_id: None,
+                action: Action::KeyRotation(action),
+            })
+            .await;
+    }
+
+    async fn log_key_state_change(&self, user: &str, action: KeyStateChangeAction) {
+        self.audit_logger
+            .log(AuditLog {
+                timestamp: Utc::now(),
+                event_id: Uuid::new_v4().to_string(),
+                service: "kagimori".to_string(),
+                user: user.to_string(),
+                trace_id: None,
+                action: Action::KeyStateChange(action),
+            })
+            .await;
+    }
+
+    async fn report(&self, trigger: &str, result: Result<KeyringChange, String>) {
+        metrics().record_keyring_reload(result.is_ok());
+        let action = match result {
+            Ok(change) => {
+                info!(
+                    "Reloaded master keyring ({trigger}): default key {}, added [{}], removed [{}]",
+                    change.default_key_id,
+                    join(&change.added),
+                    join(&change.removed),
+                );
+                KeyringReloadAction {
+                    trigger: trigger.to_string(),
+                    succeeded: true,
+                    default_key_id: Some(change.default_key_id),
+                    added_key_ids: change.added.iter().map(Uuid::to_string).collect(),
+                    removed_key_ids: change.removed.iter().map(Uuid::to_string).collect(),
+                    error: None,
+                }
+            }
+            Err(e) => {
+                error!("Cannot reload master keyring ({trigger}), keeping current keys: {e}");
+                KeyringReloadAction {
+                    trigger: trigger.to_string(),
+                    succeeded: false,
+                    default_key_id: None,
+                    added_key_ids: Vec::new(),
+                    removed_key_ids: Vec::new(),
+                    error: Some(e),
+                }
+            }
+        };
+
+        self.audit_logger
+            .log(AuditLog {
+                timestamp: Utc::now(),
+                event_id: Uuid::new_v4().to_string(),
+                service: "kagimori".to_string(),
+                user: "system".to_string(),
+                trace_id: None,
+                action: Action::KeyringReload(action),
+            })
+            .await;
+    }
+}
+
+impl<L> KeyringSource<L>
+where
+    L: 'static + AuditLogger,
+{
+    fn open(&mut self, content: &[u8]) -> Result<MasterKeyConfig, String> {
+        if Keystore::detect(content) {
+            return self.unlock_keystore()?.load_keyring();
+        }
+        let content = std::str::from_utf8(content).map_err(|e| e.to_string());
+        content
+            .and_then(|content| KeyringFile::parse(&self.path, content))
+            .and_then(|file| match file {
+                KeyringFile::Sealed(sealed) => match &self.unsealer {
+                    Some(unsealer) => unsealer.open(&sealed),
+                    None => Err("keyring is sealed, restart Kagimori to unseal it".to_string()),
+                },
+                file => file.open(&mut self.passphrase),
+            })
+            .map_err(|e| format!("{}: {e}", self.path.display()))
+    }
+
+    fn unlock_keystore(&mut self) -> Result<UnlockedKeystore, String> {
+        let keystore = Keystore::open(&self.path)?;
+        let keystore = match (keystore.protection(), &self.unsealer) {
+            (KeyProtection::Passphrase { .. }, _) => {
+                keystore.unlock(Secret::Passphrase(self.passphrase.get(false)?))
+            }
+            (KeyProtection::RootKey { .. }, Some(unsealer)) => unsealer
+                .unlock(keystore)
+                .map_err(|e| format!("{}: {e}", self.path.display())),
+            (KeyProtection::RootKey { .. }, None) => Err(format!(
+                "{}: keyring is sealed, restart Kagimori to unseal it",
+                self.path.display()
+            )),
+        }?;
+        if keystore.generation() < self.keystore_generation {
+            return Err(format!(
+                "{}: keystore is rolled back to generation {} from {}",
+                self.path.display(),
+                keystore.generation(),
+                self.keystore_generation
+            ));
+        }
+        self.keystore_generation = keystore.generation();
+        Ok(keystore)
+    }
+
+    /// Reads the keyring, returning its digest and keys unless its digest is `unchanged`.
+    fn load(
+        &mut self,
+        unchanged: Option<[u8; 32]>,
+    ) -> Result<Option<([u8; 32], RotatableCipher)>, String> {
+        let content =
+            std::fs::read(&self.path).map_err(|e| format!("{}: {e}", self.path.display()))?;
+        let digest: [u8; 32] = Sha256::digest(&content).into();
+        if unchanged == Some(digest) {
+            return Ok(None);
+        }
+        let kek = self.open(&content)?.into_cipher()?;
+        Ok(Some((digest, kek)))
+    }
+
+    /// Applies `edit` to the keyring and writes it back protected in the same way,
+    /// returning what `edit` returned, the keys and the digest of the written file.
+    fn update<T>(
+        &mut self,
+        edit: impl FnOnce(&mut MasterKeyConfig) -> Result<T, KeyStateError>,
+    ) -> Result<(T, RotatableCipher, [u8; 32]), KeyStateError> {
+        let path = self.path.clone();
+        let failed = |e: String| KeyStateError::Failed(format!("{}: {e}", path.display()));
+        let content = std::fs::read(&self.path).map_err(|e| failed(e.to_string()))?;
+        let keystore = if Keystore::detect(&content) {
+            Some(self.unlock_keystore().map_err(KeyStateError::Failed)?)
+        } else {
+            None
+        };
+        let mut keyring = match &keystore {
+            Some(keystore) => keystore.load_keyring(),
+            None => self.open(&content),
+        }
+        .map_err(KeyStateError::Failed)?;
+        let value = edit(&mut keyring)?;
+        let kek = keyring
+            .clone()
+            .into_cipher()
+            .map_err(KeyStateError::Rejected)?;
+
+        let content = match keystore {
+            Some(mut keystore) => {
+                keystore
+                    .save_keyring(&keyring)
+                    .map_err(KeyStateError::Failed)?;
+                self.keystore_generation = keystore.generation();
+                std::fs::read(&self.path).map_err(|e| failed(e.to_string()))?
+            }
+            None => {
+                let content = String::from_utf8(content).map_err(|e| failed(e.to_string()))?;
+                let content = self.protect(&content, &keyring).map_err(failed)?;
+                write_file_atomically(&self.path, &content).map_err(KeyStateError::Failed)?;
+                content.into_bytes()
+            }
+        };
+        Ok((value, kek, Sha256::digest(&content).into()))
+    }
+
+    /// Serializes `keyring` protected in the same way as the file `content`.
+    fn protect(&mut self, content: &str, keyring: &MasterKeyConfig) -> Result<String, String> {
+        let format = FileFormat::detect(&self.path, content);
+        match KeyringFile::parse(&self.path, content)? {
+            KeyringFile::Plain(_) => format.serialize(keyring),
+            KeyringFile::Encrypted(encrypted) => KeyringFile::Encrypted(EncryptedKeyring::seal(
+                keyring,
+                self.passphrase.get(false)?,
+                encrypted.kdf_params(),
+            )?)
+            .serialize(format),
+            KeyringFile::Sealed(sealed) => match &self.unsealer {
+                Some(unsealer) => {
+                    KeyringFile::Sealed(unsealer.seal(keyring, &sealed)?).serialize(format)
+                }
+                None => Err("keyring is sealed, restart Kagimori to unseal it".to_string()),
+            },
+        }
+    }
+}
+
+fn watch(path: &Path, sender: mpsc::UnboundedSender<()>) -> notify::Result<RecommendedWatcher> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<_>| {
+        if event.is_ok() {
+            let _ = sender.send(());
+        }
+    })?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
+
+fn join(ids: &[Uuid]) -> String {
+    ids.iter()
+        .map(Uuid::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::CipherAlgorithm;
+    use crate::format::{FileFormat, write_file_atomically};
+    use crate::keyring_file::KdfParams;
+    use crate::master_key::MasterKey;
+    use crate::passphrase::PASSPHRASE_ENV;
+    use async_trait::async_trait;
+    use ciphers::rotatable::DEFAULT_KEY_NAME;
+    use encryption::{KeyAlgorithm, RequestInfo};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct CollectingAuditLogger(
+        Arc<Mutex<Vec<KeyringReloadAction>>>,
+        Arc<Mutex<Vec<KeyRotationAction>>>,
+    );
+
+    #[async_trait]
+    impl AuditLogger for CollectingAuditLogger {
+        async fn log(&self, log: AuditLog) {
+            match log.action {
+                Action::KeyringReload(action) => self.0.lock().unwrap().push(action),
+                Action::KeyRotation(action) => self.1.lock().unwrap().push(action),
+                _ => {}
+            }
+        }
+    }
+
+    fn passphrase() -> Passphrase {
+        Passphrase::new(None, None, PASSPHRASE_ENV)
+    }
+
+    fn write(path: &Path, keyring: &MasterKeyConfig) {
+        write_file_atomically(path, &FileFormat::Yaml.serialize(keyring).unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("master-key.yaml");
+
+        let first = MasterKey::generate(CipherAlgorithm::Chacha20Poly1305);
+        let first_id = first.id();
+        let mut keyring = MasterKeyConfig::new(first);
+        write(&path, &keyring);
+
+        let logger = CollectingAuditLogger::default();
+        let encryptor = Encryptor::new(
+            logger.clone(),
+            KeyAlgorithm::ChaCha20Poly1305,
+            MasterKeyConfig::load(&path, &mut passphrase())
+                .unwrap()
+                .into_cipher()
+                .unwrap(),
+        );
+        let mut sut = KeyringReloader::new(
+            path.clone(),
+            passphrase(),
+            None,
+            encryptor.clone(),
+            logger.clone(),
+            false,
+        );
+
+        // unchanged file is ignored unless forced by SIGHUP
+        sut.reload("file-watch", false).await;
+        assert!(logger.0.lock().unwrap().is_empty());
+
+        let second = MasterKey::generate(CipherAlgorithm::AesGcmSiv);
+        let second_id = second.id();
+        keyring.add(second, true);
+        write(&path, &keyring);
+        sut.reload("file-watch", false).await;
+        assert_eq!(encryptor.get_key_id(), Some(second_id.to_string()));
+
+        // a key removed without a record of its destruction is kept
+        write(
+            &path,
+            &MasterKeyConfig::new(MasterKey::generate(CipherAlgorithm::AesGcmSiv)),
+        );
+        sut.reload("SIGHUP", true).await;
+        assert_eq!(encryptor.key_ids().len(), 2);
+        // a file which failed to load is not taken as loaded
+        sut.reload("file-watch", false).await;
+        assert_eq!(logger.0.lock().unwrap().len(), 3);
+
+        keyring.remove(first_id, "admin", Utc::now()).unwrap();
+        write(&path, &keyring);
+        sut.reload("SIGHUP", true).await;
+        assert_eq!(encryptor.key_ids(), vec![second_id]);
+        assert_eq!(
+            encryptor.key_statuses()[&first_id].state,
+            KeyState::Destroyed
+        );
+
+        let actions = logger.0.lock().unwrap();
+        assert_eq!(actions.len(), 4);
+        assert!(actions[0].succeeded);
+        assert_eq!(actions[0].added_key_ids, vec![second_id.to_string()]);
+        assert!(!actions[1].succeeded);
+        assert!(
+            actions[1]
+                .error
+                .as_ref()
+                .unwrap()
+                .contains(&first_id.to_string())
+        );
+        assert!(!actions[2].succeeded);
+        assert!(actions[3].succeeded);
+    }
+
+    #[tokio::test]
+    async fn test_set_key_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("master-key.yaml");
+
+        let mut keyring = MasterKeyConfig::new(MasterKey::generate(CipherAlgorithm::AesGcmSiv));
+        let default_id = keyring.default_key_id();
+        let old = MasterKey::generate(CipherAlgorithm::AesGcmSiv);
+        let old_id = old.id();
+        keyring.add(old, false);
+        write(&path, &keyring);
+
+        let logger = CollectingAuditLogger::default();
+        let encryptor = Encryptor::new(
+            logger.clone(),
+            KeyAlgorithm::ChaCha20Poly1305,
+            keyring.into_cipher().unwrap(),
+        );
+        let mut sut = KeyringReloader::new(
+            path.clone(),
+            passphrase(),
+            None,
+            encryptor.clone(),
+            logger.clone(),
+            false,
+        );
+        let change = |key_id, state, grace_period| KeyStateChange {
+            key_id,
+            state,
+            changed_by: "admin".to_string(),
+            grace_period,
+        };
+
+        let status = sut
+            .set_key_state(change(old_id, KeyState::DecryptOnly, None))
+            .await
+            .unwrap();
+        assert_eq!(status.state, KeyState::DecryptOnly);
+        assert_eq!(status.changed_by.as_deref(), Some("admin"));
+        assert_eq!(
+            encryptor.key_statuses()[&old_id].state,
+            KeyState::DecryptOnly
+        );
+        // persisted, and not reloaded again
+        let saved = MasterKeyConfig::load(&path, &mut passphrase()).unwrap();
+        assert_eq!(saved.key_state(old_id), Some(KeyState::DecryptOnly));
+        sut.reload("file-watch", false).await;
+        assert!(logger.0.lock().unwrap().is_empty());
+
+        let result = sut
+            .set_key_state(change(default_id, KeyState::Disabled, None))
+            .await;
+        assert!(matches!(result, Err(KeyStateError::Rejected(_))));
+
+        // grace periods shorter than the configured minimum are refused
+        let result = sut
+            .set_key_state(change(
+                old_id,
+                KeyState::PendingDestruction,
+                Some(Duration::from_secs(1)),
+            ))
+            .await;
+        assert!(matches!(
+            result,
+            Err(KeyStateError::Rejected(e)) if e == "grace period must be at least 86400 seconds"
+        ));
+        assert_eq!(
+            encryptor.key_statuses()[&old_id].state,
+            KeyState::DecryptOnly
+        );
+
+        let mut sut = sut.with_min_grace_period(Duration::ZERO);
+        let status = sut
+            .set_key_state(change(
+                old_id,
+                KeyState::PendingDestruction,
+                Some(Duration::ZERO),
+            ))
+            .await
+            .unwrap();
+        assert!(status.destroy_at.is_some());
+        sut.destroy_due().await;
+        assert_eq!(encryptor.key_ids(), vec![default_id]);
+        let saved = MasterKeyConfig::load(&path, &mut passphrase()).unwrap();
+        assert_eq!(saved.key_state(old_id), Some(KeyState::Destroyed));
+        assert_eq!(saved.keys().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_set_key_state_keeps_kdf_params() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("master-key.yaml");
+        let passphrase_path = dir.path().join("passphrase");
+        std::fs::write(&passphrase_path, "passphrase").unwrap();
+        let passphrase = || Passphrase::new(Some(passphrase_path.clone()), None, PASSPHRASE_ENV);
+
+        let mut keyring = MasterKeyConfig::new(MasterKey::generate(CipherAlgorithm::AesGcmSiv));
+        let old = MasterKey::generate(CipherAlgorithm::AesGcmSiv);
+        let old_id = old.id();
+        keyring.add(old, false);
+        let params = KdfParams {
+            memory_cost: 64,
+            time_cost: 1,
+            parallelism: 1,
+        };
+        let encrypted = EncryptedKeyring::seal(&keyring, "passphrase", params).unwrap();
+        let content = KeyringFile::Encrypted(encrypted)
+            .serialize(FileFormat::Yaml)
+            .unwrap();
+        write_file_atomically(&path, &content).unwrap();
+
+        let logger = CollectingAuditLogger::default();
+        let encryptor = Encryptor::new(
+            logger.clone(),
+            KeyAlgorithm::ChaCha20Poly1305,
+            keyring.into_cipher().unwrap(),
+        );
+        let mut sut =
+            KeyringReloader::new(path.clone(), passphrase(), None, encryptor, logger, false);
+        sut.set_key_state(KeyStateChange {
+            key_id: old_id,
+            state: KeyState::Disabled,
+            changed_by: "admin".to_string(),
+            grace_period: None,
+        })
+        .await
+        .unwrap();
+
+        let KeyringFile::Encrypted(saved) = KeyringFile::load(&path).unwrap() else {
+            panic!("keyring is no longer encrypted");
+        };
+        let saved_params = saved.kdf_params();
+        assert_eq!(
+            (
+                saved_params.memory_cost,
+                saved_params.time_cost,
+                saved_params.parallelism
+            ),
+            (64, 1, 1)
+        );
+        let saved = saved.open("passphrase").unwrap();
+        assert_eq!(saved.key_state(old_id), Some(KeyState::Disabled));
+    }
+
+    fn keyring_with_limit(path: &Path, max_encryptions: u64) {
+        let mut keyring = MasterKeyConfig::load(path, &mut passphrase()).unwrap();
+        keyring
+            .set_rotation_policy(DEFAULT_KEY_NAME, None, Some(max_encryptions), Utc::now())
+            .unwrap();
+        write(path, &keyring);
+    }
+
+    #[tokio::test]
+    async fn test_rotate_due() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("master-key.yaml");
+
+        let mut keyring =
+            MasterKeyConfig::new(MasterKey::generate(CipherAlgorithm::Chacha20Poly1305));
+        let old_id = keyring.default_key_id();
+        keyring
+            .set_rotation_policy(DEFAULT_KEY_NAME, None, Some(1), Utc::now())
+            .unwrap();
+        write(&path, &keyring);
+
+        let logger = CollectingAuditLogger::default();
+        let encryptor = Encryptor::new(
+            logger.clone(),
+            KeyAlgorithm::ChaCha20Poly1305,
+            keyring.into_cipher().unwrap(),
+        );
+        let mut sut = KeyringReloader::new(
+            path.clone(),
+            passphrase(),
+            None,
+            encryptor.clone(),
+            logger.clone(),
+            false,
+        );
+
+        sut.rotate_due().await;
+        assert_eq!(encryptor.get_key_id(), Some(old_id.to_string()));
+
+        let request = || RequestInfo {
+            event_id: "event".to_string(),
+            service: "test".to_string(),
+            user: "user".to_string(),
+            data_key: None,
+            trace_id: None,
+            key_name: None,
+        };
+        let ciphertext = encryptor.encrypt(request(), b"data").await.unwrap();
+        sut.rotate_due().await;
+        let new_id = encryptor.get_key_id().unwrap();
+        assert_ne!(new_id, old_id.to_string());
+        assert_eq!(ciphertext.key_id, old_id.to_string());
+
+        // persisted, and not reloaded again
+        let saved = MasterKeyConfig::load(&path, &mut passphrase()).unwrap();
+        assert_eq!(saved.default_key_id().to_string(), new_id);
+        assert_eq!(saved.keys().len(), 2);
+        sut.reload("file-watch", false).await;
+        assert!(logger.0.lock().unwrap().is_empty());
+
+        {
+            let rotations = logger.1.lock().unwrap();
+            assert_eq!(rotations.len(), 1);
+            assert!(rotations[0].succeeded);
+            assert_eq!(rotations[0].key_name, DEFAULT_KEY_NAME);
+            assert_eq!(rotations[0].reason, "max-encryptions");
+            assert_eq!(rotations[0].old_key_id, old_id.to_string());
+            assert_eq!(rotations[0].new_key_id, Some(new_id.clone()));
+        }
+
+        // the count of the new primary version survives a restart
+        keyring_with_limit(&path, 2);
+        sut.reload("SIGHUP", true).await;
+        encryptor.encrypt(request(), b"data").await.unwrap();
+        sut.save_usage().await;
+        let saved = MasterKeyConfig::load(&path, &mut passphrase()).unwrap();
+        let restarted = Encryptor::new(
+            logger.clone(),
+            KeyAlgorithm::ChaCha20Poly1305,
+            saved.into_cipher().unwrap(),
+        );
+        assert_eq!(restarted.encryption_count(&new_id.parse().unwrap()), 1);
+    }
+
+    #[tokio::test]
+    async fn test_keystore() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("master-key.db");
+        let passphrase_path = dir.path().join("passphrase");
+        std::fs::write(&passphrase_path, "passphrase").unwrap();
+        let passphrase = || Passphrase::new(Some(passphrase_path.clone()), None, PASSPHRASE_ENV);
+
+        let mut keyring = MasterKeyConfig::new(MasterKey::generate(CipherAlgorithm::AesGcmSiv));
+        let old = MasterKey::generate(CipherAlgorithm::AesGcmSiv);
+        let old_id = old.id();
+        keyring.add(old, false);
+        let params = KdfParams {
+            memory_cost: 64,
+            time_cost: 1,
+            parallelism: 1,
+        };
+        let mut keystore = UnlockedKeystore::create(
+            &path,
+            KeyProtection::passphrase(params),
+            Secret::Passphrase("passphrase"),
+        )
+        .unwrap();
+        keystore.save_keyring(&keyring).unwrap();
+
+        let logger = CollectingAuditLogger::default();
+        let encryptor = Encryptor::new(
+            logger.clone(),
+            KeyAlgorithm::ChaCha20Poly1305,
+            MasterKeyConfig::load(&path, &mut passphrase())
+                .unwrap()
+                .into_cipher()
+                .unwrap(),
+        );
+        let mut sut = KeyringReloader::new(
+            path.clone(),
+            passphrase(),
+            None,
+            encryptor.clone(),
+            logger.clone(),
+            false,
+        );
+
+        sut.set_key_state(KeyStateChange {
+            key_id: old_id,
+            state: KeyState::Disabled,
+            changed_by: "admin".to_string(),
+            grace_period: None,
+        })
+        .await
+        .unwrap();
+        let saved = MasterKeyConfig::load(&path, &mut passphrase()).unwrap();
+        assert_eq!(saved.key_state(old_id), Some(KeyState::Disabled));
+        sut.reload("file-watch", false).await;
+        assert!(logger.0.lock().unwrap().is_empty());
+
+        let old_copy = std::fs::read(&path).unwrap();
+
+        // changed by the CLI
+        let new = MasterKey::generate(CipherAlgorithm::Chacha20Poly1305);
+        let new_id = new.id();
+        let mut keyring = saved;
+        keyring.add(new, true);
+        keystore.save_keyring(&keyring).unwrap();
+        sut.reload("file-watch", false).await;
+        assert_eq!(encryptor.get_key_id(), Some(new_id.to_string()));
+        assert!(logger.0.lock().unwrap()[0].succeeded);
+
+        // an older copy of the keystore is not loaded
+        std::fs::write(&path, &old_copy).unwrap();
+        sut.reload("SIGHUP", true).await;
+        assert_eq!(encryptor.get_key_id(), Some(new_id.to_string()));
+        let actions = logger.0.lock().unwrap();
+        assert!(actions[1].error.as_ref().unwrap().contains("rolled back"));
+    }
+}
@@ -0,0 +1,381 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use crate::keyring::{OpenedKeyring, Protection, SecretArgs};
+use crate::listener::ListenAddress;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::rand_core::RngCore;
+use ciphers::shamir;
+use clap::{Args, Subcommand};
+use server::client::{AdminClient, ClientTls};
+use std::fmt::{Display, Formatter};
+use std::io::{BufRead, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use zeroize::Zeroizing;
+
+const ROOT_KEY_SIZE: usize = 32;
+const SHARE_SET_SIZE: usize = 8;
+const SHARE_VERSION: u8 = 1;
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum SealCommand {
+    /// Seal a keyring file with a new root key and print its shares.
+    /// Shares of the previous root key of a sealed file become invalid.
+    #[command(alias = "resplit")]
+    Split {
+        /// Path to keyring file
+        file: PathBuf,
+        /// Number of shares to generate
+        #[arg(long)]
+        shares: u8,
+        /// Number of shares required to unseal
+        #[arg(long)]
+        threshold: u8,
+        #[command(flatten)]
+        secret: SecretArgs,
+    },
+    /// Show whether a running server is sealed
+    Status {
+        /// Admin listener of the server (tcp://HOST:PORT or unix://PATH)
+        #[arg(long, env = "KAGIMORI_ADDRESS")]
+        address: ListenAddress,
+        #[command(flatten)]
+        tls: ClientTlsArgs,
+    },
+    /// Submit shares to a running server, through a unix socket or a TLS connection
+    Unseal {
+        /// Admin listener of the server (tcp://HOST:PORT or unix://PATH)
+        #[arg(long, env = "KAGIMORI_ADDRESS")]
+        address: ListenAddress,
+        #[command(flatten)]
+        tls: ClientTlsArgs,
+        /// Read shares from this file, one per line, instead of prompting for one
+        #[arg(long)]
+        share_file: Option<PathBuf>,
+    },
+}
+
+/// TLS settings to connect to a TCP admin listener.
+#[derive(Debug, Args)]
+pub(crate) struct ClientTlsArgs {
+    /// Connect with TLS, verifying the server with this CA certificate PEM file
+    #[arg(long, env = "KAGIMORI_CA_CERTIFICATE")]
+    ca_certificate: Option<PathBuf>,
+    /// Client certificate PEM file, for listeners verifying clients
+    #[arg(long, env = "KAGIMORI_CLIENT_CERTIFICATE", requires_all = ["ca_certificate", "client_private_key"])]
+    client_certificate: Option<PathBuf>,
+    /// Private key PEM file of the client certificate
+    #[arg(
+        long,
+        env = "KAGIMORI_CLIENT_PRIVATE_KEY",
+        requires = "client_certificate"
+    )]
+    client_private_key: Option<PathBuf>,
+    /// Name to verify the server certificate against instead of the host
+    #[arg(long, requires = "ca_certificate")]
+    server_name: Option<String>,
+}
+
+impl ClientTlsArgs {
+    fn load(&self) -> Result<Option<ClientTls>, String> {
+        let Some(ca_certificate) = &self.ca_certificate else {
+            return Ok(None);
+        };
+        let read = |path: &Path| {
+            std::fs::read(path).map_err(|e| format!("cannot read {}: {e}", path.display()))
+        };
+        let identity = match (&self.client_certificate, &self.client_private_key) {
+            (Some(certificate), Some(private_key)) => {
+                Some((read(certificate)?, read(private_key)?))
+            }
+            _ => None,
+        };
+        Ok(Some(ClientTls {
+            ca_certificate: read(ca_certificate)?,
+            identity,
+            server_name: self.server_name.clone(),
+        }))
+    }
+}
+
+impl SealCommand {
+    pub(crate) async fn run(self) -> Result<(), String> {
+        match self {
+            SealCommand::Split {
+                file,
+                shares,
+                threshold,
+                secret,
+            } => {
+                let mut opened = OpenedKeyring::load(&file, &secret)?;
+                let root_key = RootKey::generate();
+                let split = root_key.split(threshold, shares)?;
+                opened.protection = Protection::Sealed {
+                    root_key,
+                    threshold,
+                    shares,
+                };
+                opened.save(&file)?;
+                for share in split {
+                    println!("{share}");
+                }
+                eprintln!(
+                    "Sealed {} with {threshold} of {shares} shares; give each share to a different operator",
+                    file.display()
+                );
+                Ok(())
+            }
+            SealCommand::Status { address, tls } => {
+                let status = connect(&address, tls.load()?)
+                    .await?
+                    .seal_status()
+                    .await
+                    .map_err(|e| e.message().to_string())?;
+                print_status(status);
+                Ok(())
+            }
+            SealCommand::Unseal {
+                address,
+                tls,
+                share_file,
+            } => {
+                let tls = tls.load()?;
+                if matches!(address, ListenAddress::Tcp(_)) && tls.is_none() {
+                    return Err(
+                        "refusing to send shares over plaintext TCP; give --ca-certificate or use a unix socket"
+                            .to_string(),
+                    );
+                }
+                let mut client = connect(&address, tls).await?;
+                for share in read_shares(share_file.as_deref(), 1)? {
+                    let status = client
+                        .unseal(share.to_string())
+                        .await
+                        .map_err(|e| e.message().to_string())?;
+                    print_status(status);
+                    if !status.sealed {
+                        break;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+async fn connect(address: &ListenAddress, tls: Option<ClientTls>) -> Result<AdminClient, String> {
+    match address {
+        ListenAddress::Tcp(addr) => {
+            let scheme = if tls.is_some() { "https" } else { "http" };
+            AdminClient::connect_tcp(format!("{scheme}://{addr}"), tls).await
+        }
+        ListenAddress::Unix(path) => AdminClient::connect_unix(path.clone()).await,
+    }
+    .map_err(|e| format!("cannot connect to {address:?}: {e}"))
+}
+
+fn print_status(status: server::unseal::SealStatus) {
+    if status.sealed {
+        println!("Sealed ({}/{} shares)", status.progress, status.threshold);
+    } else {
+        println!("Unsealed");
+    }
+}
+
+/// Key sealing a keyring. Only its shares are ever stored.
+pub(crate) struct RootKey {
+    share_set: [u8; SHARE_SET_SIZE],
+    key: Zeroizing<[u8; ROOT_KEY_SIZE]>,
+}
+
+/// A share of a root key, encoded as base64 of the version, the share set,
+/// the index and the value.
+pub(crate) struct Share {
+    share_set: [u8; SHARE_SET_SIZE],
+    inner: shamir::Share,
+}
+
+impl RootKey {
+    pub(crate) fn generate() -> Self {
+        let mut share_set = [0u8; SHARE_SET_SIZE];
+        OsRng.fill_bytes(&mut share_set);
+        let mut key = Zeroizing::new([0u8; ROOT_KEY_SIZE]);
+        OsRng.fill_bytes(key.as_mut());
+        Self { share_set, key }
+    }
+
+    /// Identifies this key and its shares.
+    pub(crate) fn share_set(&self) -> String {
+        BASE64_STANDARD.encode(self.share_set)
+    }
+
+    pub(crate) fn key(&self) -> &[u8; ROOT_KEY_SIZE] {
+        &self.key
+    }
+
+    pub(crate) fn split(&self, threshold: u8, shares: u8) -> Result<Vec<Share>, String> {
+        let shares = shamir::split(self.key.as_ref(), threshold, shares)
+            .map_err(|e| format!("cannot split root key: {e:?}"))?;
+        Ok(shares
+            .into_iter()
+            .map(|inner| Share {
+                share_set: self.share_set,
+                inner,
+            })
+            .collect())
+    }
+
+    /// Recovers the key from shares of the same share set. A wrong key recovered
+    /// from too few shares is detected only when opening the keyring.
+    pub(crate) fn combine(shares: &[Share]) -> Result<Self, String> {
+        let Some(first) = shares.first() else {
+            return Err("no shares are given".to_string());
+        };
+        if shares.iter().any(|s| s.share_set != first.share_set) {
+            return Err("shares belong to different share sets".to_string());
+        }
+        let inner: Vec<_> = shares.iter().map(|s| s.inner.clone()).collect();
+        let secret = Zeroizing::new(
+            shamir::combine(&inner).map_err(|e| format!("cannot combine shares: {e:?}"))?,
+        );
+        let key = Zeroizing::new(
+            <[u8; ROOT_KEY_SIZE]>::try_from(secret.as_slice())
+                .map_err(|_| "shares have an invalid length".to_string())?,
+        );
+        Ok(Self {
+            share_set: first.share_set,
+            key,
+        })
+    }
+}
+
+impl Share {
+    pub(crate) fn share_set(&self) -> String {
+        BASE64_STANDARD.encode(self.share_set)
+    }
+
+    pub(crate) fn index(&self) -> u8 {
+        self.inner.x
+    }
+}
+
+impl Display for Share {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut bytes = Zeroizing::new(Vec::with_capacity(2 + SHARE_SET_SIZE + ROOT_KEY_SIZE));
+        bytes.push(SHARE_VERSION);
+        bytes.extend_from_slice(&self.share_set);
+        bytes.push(self.inner.x);
+        bytes.extend_from_slice(&self.inner.y);
+        f.write_str(&BASE64_STANDARD.encode(bytes.as_slice()))
+    }
+}
+
+impl FromStr for Share {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = Zeroizing::new(
+            BASE64_STANDARD
+                .decode(s.trim())
+                .map_err(|e| format!("share is not valid base64: {e}"))?,
+        );
+        if bytes.len() != 2 + SHARE_SET_SIZE + ROOT_KEY_SIZE {
+            return Err("share has an invalid length".to_string());
+        }
+        if bytes[0] != SHARE_VERSION {
+            return Err(format!("unsupported share version {}", bytes[0]));
+        }
+        let x = bytes[1 + SHARE_SET_SIZE];
+        if x == 0 {
+            return Err("share index must not be zero".to_string());
+        }
+        Ok(Self {
+            share_set: bytes[1..1 + SHARE_SET_SIZE].try_into().unwrap(),
+            inner: shamir::Share {
+                x,
+                y: bytes[2 + SHARE_SET_SIZE..].to_vec(),
+            },
+        })
+    }
+}
+
+/// Reads shares from `file`, one per line, or prompts for `count` shares.
+pub(crate) fn read_shares(file: Option<&Path>, count: u8) -> Result<Vec<Share>, String> {
+    let lines: Vec<Zeroizing<String>> = match file {
+        Some(path) => std::fs::read_to_string(path)
+            .map_err(|e| format!("{}: {e}", path.display()))?
+            .lines()
+            .map(|l| Zeroizing::new(l.to_string()))
+            .collect(),
+        None if std::io::stdin().is_terminal() => (1..=count)
+            .map(|i| {
+                rpassword::prompt_password(format!("Share {i}/{count}: "))
+                    .map(Zeroizing::new)
+                    .map_err(|e| e.to_string())
+            })
+            .collect::<Result<_, _>>()?,
+        None => std::io::stdin()
+            .lock()
+            .lines()
+            .map(|l| l.map(Zeroizing::new).map_err(|e| e.to_string()))
+            .collect::<Result<_, _>>()?,
+    };
+    lines
+        .iter()
+        .filter(|l| !l.trim().is_empty())
+        .enumerate()
+        .map(|(i, l)| l.parse().map_err(|e| format!("share {}: {e}", i + 1)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_and_combine() {
+        let root_key = RootKey::generate();
+        let shares = root_key.split(2, 3).unwrap();
+
+        let encoded: Vec<String> = shares.iter().map(ToString::to_string).collect();
+        let decoded: Vec<Share> = encoded[1..].iter().map(|s| s.parse().unwrap()).collect();
+        assert_eq!(decoded[0].index(), 2);
+        assert_eq!(decoded[0].share_set(), root_key.share_set());
+
+        let combined = RootKey::combine(&decoded).unwrap();
+        assert_eq!(combined.key(), root_key.key());
+        assert_eq!(combined.share_set(), root_key.share_set());
+    }
+
+    #[test]
+    fn test_reject_mixed_share_sets() {
+        let first = RootKey::generate().split(2, 2).unwrap();
+        let second = RootKey::generate().split(2, 2).unwrap();
+        let shares = vec![
+            first[0].to_string().parse().unwrap(),
+            second[1].to_string().parse().unwrap(),
+        ];
+        assert!(RootKey::combine(&shares).is_err());
+    }
+
+    #[test]
+    fn test_reject_malformed_share() {
+        assert!("not a share".parse::<Share>().is_err());
+        assert!(BASE64_STANDARD.encode([1u8; 10]).parse::<Share>().is_err());
+    }
+}
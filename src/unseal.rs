@@ -0,0 +1,409 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use crate::keyring_file::{KeyringFile, SealedKeyring};
+use crate::keystore::{KeyProtection, Keystore, Secret, UnlockedKeystore};
+use crate::master_key::MasterKeyConfig;
+use crate::seal::{RootKey, Share};
+use async_trait::async_trait;
+use audit_log::{Action, AuditLog, AuditLogger, UnsealAction};
+use chrono::Utc;
+use encryption::Encryptor;
+use server::unseal::{SealStatus, UnsealError, Unsealer};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Collects shares submitted through the admin API and unseals the keyring of an [`Encryptor`].
+pub(crate) struct KeyringUnsealer<L> {
+    path: PathBuf,
+    encryptor: Encryptor<L>,
+    audit_logger: L,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    share_set: String,
+    threshold: u8,
+    shares: Vec<Share>,
+    root_key: Option<RootKey>,
+}
+
+/// A keyring sealed with the root key, in a file or in a keystore.
+enum Sealed {
+    File(SealedKeyring),
+    Keystore {
+        keystore: Keystore,
+        share_set: String,
+        threshold: u8,
+    },
+}
+
+impl Sealed {
+    fn load(path: &Path) -> Result<Self, String> {
+        let not_sealed = || {
+            format!(
+                "{} is no longer sealed, restart Kagimori to load it",
+                path.display()
+            )
+        };
+        if Keystore::is_keystore(path) {
+            let keystore = Keystore::open(path)?;
+            return match keystore.protection().clone() {
+                KeyProtection::RootKey {
+                    share_set,
+                    threshold,
+                    ..
+                } => Ok(Sealed::Keystore {
+                    keystore,
+                    share_set,
+                    threshold,
+                }),
+                KeyProtection::Passphrase { .. } => Err(not_sealed()),
+            };
+        }
+        match KeyringFile::load(path)? {
+            KeyringFile::Sealed(sealed) => Ok(Sealed::File(sealed)),
+            _ => Err(not_sealed()),
+        }
+    }
+
+    fn share_set(&self) -> &str {
+        match self {
+            Sealed::File(sealed) => sealed.share_set(),
+            Sealed::Keystore { share_set, .. } => share_set,
+        }
+    }
+
+    fn threshold(&self) -> u8 {
+        match self {
+            Sealed::File(sealed) => sealed.threshold(),
+            Sealed::Keystore { threshold, .. } => *threshold,
+        }
+    }
+
+    fn open(self, root_key: &RootKey) -> Result<MasterKeyConfig, String> {
+        match self {
+            Sealed::File(sealed) => sealed.open(root_key),
+            Sealed::Keystore { keystore, .. } => {
+                keystore.unlock(Secret::RootKey(root_key))?.load_keyring()
+            }
+        }
+    }
+}
+
+impl State {
+    fn unsealed_status(&self) -> Option<SealStatus> {
+        self.root_key.is_some().then(|| self.status())
+    }
+
+    fn status(&self) -> SealStatus {
+        SealStatus {
+            sealed: self.root_key.is_none(),
+            threshold: self.threshold.into(),
+            progress: self.shares.len() as u32,
+        }
+    }
+}
+
+impl<L> KeyringUnsealer<L>
+where
+    L: AuditLogger,
+{
+    pub(crate) fn new(
+        path: PathBuf,
+        share_set: String,
+        threshold: u8,
+        encryptor: Encryptor<L>,
+        audit_logger: L,
+    ) -> Self {
+        Self {
+            path,
+            encryptor,
+            audit_logger,
+            state: Arc::new(Mutex::new(State {
+                share_set,
+                threshold,
+                shares: Vec::new(),
+                root_key: None,
+            })),
+        }
+    }
+
+    /// Opens a sealed keyring with the root key recovered by unsealing, so that
+    /// the keyring can be reloaded as long as it is sealed with the same root key.
+    pub(crate) fn open(&self, sealed: &SealedKeyring) -> Result<MasterKeyConfig, String> {
+        let state = self.state.lock().unwrap();
+        let root_key = state
+            .root_key
+            .as_ref()
+            .ok_or_else(|| "keyring is not unsealed yet".to_string())?;
+        sealed
+            .open(root_key)
+            .map_err(|e| format!("{e}; restart Kagimori and unseal it with the new shares"))
+    }
+
+    /// Unlocks a keystore protected by the root key recovered by unsealing.
+    pub(crate) fn unlock(&self, keystore: Keystore) -> Result<UnlockedKeystore, String> {
+        let state = self.state.lock().unwrap();
+        let root_key = state
+            .root_key
+            .as_ref()
+            .ok_or_else(|| "keyring is not unsealed yet".to_string())?;
+        keystore
+            .unlock(Secret::RootKey(root_key))
+            .map_err(|e| format!("{e}; restart Kagimori and unseal it with the new shares"))
+    }
+
+    /// Seals `keyring` with the root key recovered by unsealing, in the same way as `sealed`.
+    pub(crate) fn seal(
+        &self,
+        keyring: &MasterKeyConfig,
+        sealed: &SealedKeyring,
+    ) -> Result<SealedKeyring, String> {
+        let state = self.state.lock().unwrap();
+        let root_key = state
+            .root_key
+            .as_ref()
+            .ok_or_else(|| "keyring is not unsealed yet".to_string())?;
+        if root_key.share_set() != sealed.share_set() {
+            return Err(
+                "keyring is sealed with another root key; restart Kagimori and unseal it"
+                    .to_string(),
+            );
+        }
+        SealedKeyring::seal(keyring, root_key, sealed.threshold(), sealed.shares())
+    }
+}
+
+/// Adds a share, and unseals the keyring once enough shares are submitted. This reads
+/// the file and derives keys, so it runs on a blocking thread without holding `state`
+/// meanwhile.
+fn submit<L>(
+    path: &Path,
+    encryptor: &Encryptor<L>,
+    state: &Mutex<State>,
+    share: Share,
+) -> Result<SealStatus, UnsealError>
+where
+    L: 'static + AuditLogger,
+{
+    if let Some(status) = state.lock().unwrap().unsealed_status() {
+        return Ok(status);
+    }
+    // The file may have been sealed again with another root key since startup.
+    let sealed = Sealed::load(path).map_err(UnsealError::Failed)?;
+
+    let shares = {
+        let mut state = state.lock().unwrap();
+        if let Some(status) = state.unsealed_status() {
+            return Ok(status);
+        }
+        if sealed.share_set() != state.share_set {
+            warn!("Master key file is sealed with another root key, discarding submitted shares");
+            state.share_set = sealed.share_set().to_string();
+            state.threshold = sealed.threshold();
+            state.shares.clear();
+        }
+
+        if share.share_set() != state.share_set {
+            return Err(UnsealError::InvalidShare(
+                "share belongs to another root key".to_string(),
+            ));
+        }
+        if state.shares.iter().any(|s| s.index() == share.index()) {
+            return Err(UnsealError::InvalidShare(format!(
+                "share {} is already submitted",
+                share.index()
+            )));
+        }
+        state.shares.push(share);
+        if state.shares.len() < state.threshold as usize {
+            return Ok(state.status());
+        }
+        std::mem::take(&mut state.shares)
+    };
+
+    let root_key = RootKey::combine(&shares).map_err(UnsealError::Failed)?;
+    let cipher = sealed
+        .open(&root_key)
+        .and_then(MasterKeyConfig::into_cipher)
+        .map_err(|e| UnsealError::Failed(format!("{e}; submit shares again")))?;
+    let mut state = state.lock().unwrap();
+    if let Some(status) = state.unsealed_status() {
+        return Ok(status);
+    }
+    encryptor
+        .replace_kek(cipher, false)
+        .map_err(|e| UnsealError::Failed(format!("{e:?}")))?;
+    state.root_key = Some(root_key);
+    Ok(state.status())
+}
+
+#[async_trait]
+impl<L> Unsealer for KeyringUnsealer<L>
+where
+    L: 'static + AuditLogger + Clone,
+{
+    fn status(&self) -> SealStatus {
+        self.state.lock().unwrap().status()
+    }
+
+    async fn unseal(&self, share: &str, caller: &str) -> Result<SealStatus, UnsealError> {
+        let share: Result<Share, _> = share.parse().map_err(UnsealError::InvalidShare);
+        let share_index = share.as_ref().ok().map(Share::index);
+        let result = match share {
+            Ok(share) => {
+                let path = self.path.clone();
+                let encryptor = self.encryptor.clone();
+                let state = self.state.clone();
+                tokio::task::spawn_blocking(move || submit(&path, &encryptor, &state, share))
+                    .await
+                    .unwrap_or_else(|e| Err(UnsealError::Failed(e.to_string())))
+            }
+            Err(e) => Err(e),
+        };
+
+        let status = self.status();
+        let error = match &result {
+            Ok(status) if !status.sealed => {
+                info!("Unsealed master keyring");
+                None
+            }
+            Ok(status) => {
+                info!(
+                    "Accepted unseal share ({}/{})",
+                    status.progress, status.threshold
+                );
+                None
+            }
+            Err(UnsealError::InvalidShare(e) | UnsealError::Failed(e)) => Some(e.clone()),
+        };
+        self.audit_logger
+            .log(AuditLog {
+                timestamp: Utc::now(),
+                event_id: Uuid::new_v4().to_string(),
+                service: "kagimori".to_string(),
+                user: caller.to_string(),
+                trace_id: None,
+                action: Action::Unseal(UnsealAction {
+                    share_index,
+                    accepted: result.is_ok(),
+                    progress: status.progress,
+                    threshold: status.threshold,
+                    unsealed: !status.sealed,
+                    error,
+                }),
+            })
+            .await;
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::CipherAlgorithm;
+    use crate::format::{FileFormat, write_file_atomically};
+    use crate::master_key::MasterKey;
+    use audit_log::logger::tracing::TracingAuditLogger;
+    use encryption::KeyAlgorithm;
+
+    #[tokio::test]
+    async fn test_unseal() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("master-key.yaml");
+
+        let keyring = MasterKeyConfig::new(MasterKey::generate(CipherAlgorithm::Chacha20Poly1305));
+        let root_key = RootKey::generate();
+        let shares = root_key.split(2, 3).unwrap();
+        let sealed = SealedKeyring::seal(&keyring, &root_key, 2, 3).unwrap();
+        let content = KeyringFile::Sealed(sealed.clone())
+            .serialize(FileFormat::Yaml)
+            .unwrap();
+        write_file_atomically(&path, &content).unwrap();
+
+        let encryptor = Encryptor::sealed(TracingAuditLogger, KeyAlgorithm::ChaCha20Poly1305);
+        let sut = KeyringUnsealer::new(
+            path,
+            sealed.share_set().to_string(),
+            sealed.threshold(),
+            encryptor.clone(),
+            TracingAuditLogger,
+        );
+        assert!(sut.open(&sealed).is_err());
+
+        let status = sut.unseal(&shares[2].to_string(), "test").await.unwrap();
+        assert!(status.sealed);
+        assert_eq!(status.progress, 1);
+        assert!(matches!(
+            sut.unseal(&shares[2].to_string(), "test").await,
+            Err(UnsealError::InvalidShare(_))
+        ));
+        let foreign = RootKey::generate().split(2, 3).unwrap();
+        assert!(matches!(
+            sut.unseal(&foreign[0].to_string(), "test").await,
+            Err(UnsealError::InvalidShare(_))
+        ));
+        assert!(encryptor.is_sealed());
+
+        let status = sut.unseal(&shares[0].to_string(), "test").await.unwrap();
+        assert!(!status.sealed);
+        assert!(!encryptor.is_sealed());
+        assert_eq!(
+            encryptor.get_key_id(),
+            Some(keyring.default_key_id().to_string())
+        );
+        assert!(sut.open(&sealed).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_unseal_keystore() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("master-key.db");
+
+        let keyring = MasterKeyConfig::new(MasterKey::generate(CipherAlgorithm::AesGcmSiv));
+        let root_key = RootKey::generate();
+        let shares = root_key.split(2, 3).unwrap();
+        let mut keystore = UnlockedKeystore::create(
+            &path,
+            KeyProtection::root_key(&root_key, 2, 3),
+            Secret::RootKey(&root_key),
+        )
+        .unwrap();
+        keystore.save_keyring(&keyring).unwrap();
+
+        let encryptor = Encryptor::sealed(TracingAuditLogger, KeyAlgorithm::ChaCha20Poly1305);
+        let sut = KeyringUnsealer::new(
+            path.clone(),
+            root_key.share_set(),
+            2,
+            encryptor.clone(),
+            TracingAuditLogger,
+        );
+        assert!(sut.unlock(Keystore::open(&path).unwrap()).is_err());
+
+        sut.unseal(&shares[1].to_string(), "test").await.unwrap();
+        let status = sut.unseal(&shares[2].to_string(), "test").await.unwrap();
+        assert!(!status.sealed);
+        assert_eq!(
+            encryptor.get_key_id(),
+            Some(keyring.default_key_id().to_string())
+        );
+        assert!(sut.unlock(Keystore::open(&path).unwrap()).is_ok());
+    }
+}
@@ -0,0 +1,307 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+const NAMESPACE: &str = "kagimori";
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Returns the process wide metrics registry.
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+pub struct Metrics {
+    registry: Registry,
+    rpc_requests: IntCounterVec,
+    rpc_duration: HistogramVec,
+    encryptions: IntCounterVec,
+    decryptions: IntCounterVec,
+    dek_generations: IntCounterVec,
+    dek_cache_lookups: IntCounterVec,
+    dek_cache_evictions: IntCounterVec,
+    dek_cache_entries: IntGauge,
+    audit_sink_failures: IntCounter,
+    audit_queue_depth: IntGauge,
+    tls_handshake_failures: IntCounter,
+    loaded_keys: IntGauge,
+    keyring_reloads: IntCounterVec,
+    key_rotations: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let rpc_requests = IntCounterVec::new(
+            Opts::new("rpc_requests_total", "Number of handled gRPC requests").namespace(NAMESPACE),
+            &["service", "method", "code"],
+        )
+        .unwrap();
+        let rpc_duration = HistogramVec::new(
+            HistogramOpts::new("rpc_duration_seconds", "Latency of handled gRPC requests")
+                .namespace(NAMESPACE),
+            &["service", "method", "code"],
+        )
+        .unwrap();
+        let encryptions = IntCounterVec::new(
+            Opts::new("encryptions_total", "Number of encryptions").namespace(NAMESPACE),
+            &["algorithm", "kek_id"],
+        )
+        .unwrap();
+        let decryptions = IntCounterVec::new(
+            Opts::new("decryptions_total", "Number of decryptions").namespace(NAMESPACE),
+            &["algorithm", "kek_id"],
+        )
+        .unwrap();
+        let dek_generations = IntCounterVec::new(
+            Opts::new(
+                "dek_generations_total",
+                "Number of DEKs generated and wrapped with a master key",
+            )
+            .namespace(NAMESPACE),
+            &["kek_id"],
+        )
+        .unwrap();
+        let dek_cache_lookups = IntCounterVec::new(
+            Opts::new(
+                "dek_cache_lookups_total",
+                "Number of lookups of unwrapped DEKs in the cache",
+            )
+            .namespace(NAMESPACE),
+            &["result"],
+        )
+        .unwrap();
+        let dek_cache_evictions = IntCounterVec::new(
+            Opts::new(
+                "dek_cache_evictions_total",
+                "Number of unwrapped DEKs removed from the cache",
+            )
+            .namespace(NAMESPACE),
+            &["reason"],
+        )
+        .unwrap();
+        let dek_cache_entries = IntGauge::with_opts(
+            Opts::new("dek_cache_entries", "Number of cached unwrapped DEKs").namespace(NAMESPACE),
+        )
+        .unwrap();
+        let audit_sink_failures = IntCounter::with_opts(
+            Opts::new(
+                "audit_sink_failures_total",
+                "Number of audit logs which could not be written",
+            )
+            .namespace(NAMESPACE),
+        )
+        .unwrap();
+        let audit_queue_depth = IntGauge::with_opts(
+            Opts::new(
+                "audit_queue_depth",
+                "Number of audit logs waiting to be written",
+            )
+            .namespace(NAMESPACE),
+        )
+        .unwrap();
+        let tls_handshake_failures = IntCounter::with_opts(
+            Opts::new(
+                "tls_handshake_failures_total",
+                "Number of failed TLS handshakes",
+            )
+            .namespace(NAMESPACE),
+        )
+        .unwrap();
+        let loaded_keys = IntGauge::with_opts(
+            Opts::new("loaded_keys", "Number of loaded master keys").namespace(NAMESPACE),
+        )
+        .unwrap();
+
+        registry.register(Box::new(rpc_requests.clone())).unwrap();
+        registry.register(Box::new(rpc_duration.clone())).unwrap();
+        registry.register(Box::new(encryptions.clone())).unwrap();
+        registry.register(Box::new(decryptions.clone())).unwrap();
+        registry
+            .register(Box::new(dek_generations.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(dek_cache_lookups.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(dek_cache_evictions.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(dek_cache_entries.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(audit_sink_failures.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(audit_queue_depth.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(tls_handshake_failures.clone()))
+            .unwrap();
+        let keyring_reloads = IntCounterVec::new(
+            Opts::new("keyring_reloads_total", "Number of master keyring reloads")
+                .namespace(NAMESPACE),
+            &["result"],
+        )
+        .unwrap();
+        let key_rotations = IntCounterVec::new(
+            Opts::new(
+                "key_rotations_total",
+                "Number of master key rotations by rotation policies",
+            )
+            .namespace(NAMESPACE),
+            &["key_name", "result"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(loaded_keys.clone())).unwrap();
+        registry
+            .register(Box::new(keyring_reloads.clone()))
+            .unwrap();
+        registry.register(Box::new(key_rotations.clone())).unwrap();
+
+        Self {
+            registry,
+            rpc_requests,
+            rpc_duration,
+            encryptions,
+            decryptions,
+            dek_generations,
+            dek_cache_lookups,
+            dek_cache_evictions,
+            dek_cache_entries,
+            audit_sink_failures,
+            audit_queue_depth,
+            tls_handshake_failures,
+            loaded_keys,
+            keyring_reloads,
+            key_rotations,
+        }
+    }
+
+    pub fn record_rpc(&self, service: &str, method: &str, code: &str, elapsed: Duration) {
+        let labels = [service, method, code];
+        self.rpc_requests.with_label_values(&labels).inc();
+        self.rpc_duration
+            .with_label_values(&labels)
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn record_encryption(&self, algorithm: &str, kek_id: &str) {
+        self.encryptions
+            .with_label_values(&[algorithm, kek_id])
+            .inc();
+    }
+
+    pub fn record_decryption(&self, algorithm: &str, kek_id: &str) {
+        self.decryptions
+            .with_label_values(&[algorithm, kek_id])
+            .inc();
+    }
+
+    pub fn record_dek_generation(&self, kek_id: &str) {
+        self.dek_generations.with_label_values(&[kek_id]).inc();
+    }
+
+    pub fn record_dek_cache_lookup(&self, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        self.dek_cache_lookups.with_label_values(&[result]).inc();
+    }
+
+    pub fn record_dek_cache_eviction(&self, reason: &str, count: u64) {
+        self.dek_cache_evictions
+            .with_label_values(&[reason])
+            .inc_by(count);
+    }
+
+    pub fn set_dek_cache_entries(&self, count: usize) {
+        self.dek_cache_entries.set(count as i64);
+    }
+
+    pub fn record_audit_sink_failure(&self) {
+        self.audit_sink_failures.inc();
+    }
+
+    pub fn audit_log_enqueued(&self) {
+        self.audit_queue_depth.inc();
+    }
+
+    pub fn audit_log_dequeued(&self) {
+        self.audit_queue_depth.dec();
+    }
+
+    pub fn record_tls_handshake_failure(&self) {
+        self.tls_handshake_failures.inc();
+    }
+
+    pub fn set_loaded_keys(&self, count: usize) {
+        self.loaded_keys.set(count as i64);
+    }
+
+    pub fn record_keyring_reload(&self, succeeded: bool) {
+        let result = if succeeded { "success" } else { "failure" };
+        self.keyring_reloads.with_label_values(&[result]).inc();
+    }
+
+    pub fn record_key_rotation(&self, key_name: &str, succeeded: bool) {
+        let result = if succeeded { "success" } else { "failure" };
+        self.key_rotations
+            .with_label_values(&[key_name, result])
+            .inc();
+    }
+
+    /// Encodes all metrics in the Prometheus text exposition format.
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_contains_recorded_values() {
+        let sut = Metrics::new();
+        sut.record_rpc(
+            "v2.KeyManagementService",
+            "Encrypt",
+            "0",
+            Duration::from_millis(5),
+        );
+        sut.record_encryption("ChaCha20-Poly1305", "kek");
+        sut.set_loaded_keys(3);
+
+        let encoded = sut.encode();
+        assert!(encoded.contains(
+            r#"kagimori_rpc_requests_total{code="0",method="Encrypt",service="v2.KeyManagementService"} 1"#
+        ));
+        assert!(encoded.contains(
+            r#"kagimori_encryptions_total{algorithm="ChaCha20-Poly1305",kek_id="kek"} 1"#
+        ));
+        assert!(encoded.contains("kagimori_loaded_keys 3"));
+    }
+}
@@ -0,0 +1,185 @@
+// Copyright 2025 SiLeader.
+//
+// This file is part of Kagimori.
+//
+// Kagimori is free software: you can redistribute it and/or modify it under the terms of
+// the GNU General Public License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any later version.
+//
+// Kagimori is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Kagimori.
+// If not, see <https://www.gnu.org/licenses/>.
+
+use opentelemetry::Context;
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::trace::{TraceContextExt, TracerProvider};
+use opentelemetry_otlp::{ExporterBuildError, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::Layer;
+use tracing_subscriber::registry::LookupSpan;
+
+const SERVICE_NAME: &str = "kagimori";
+
+/// Checks that spans can be exported to `endpoint` without connecting to it.
+///
+/// The exporter is built without TLS, so only `http://` endpoints are accepted.
+pub fn check_endpoint(endpoint: &str) -> Result<(), String> {
+    let uri = tonic::transport::Endpoint::from_shared(endpoint.to_string())
+        .map_err(|e| format!("invalid OTLP endpoint '{endpoint}': {e}"))?;
+    match uri.uri().scheme_str() {
+        Some("http") => Ok(()),
+        _ => Err(format!("OTLP endpoint must start with http://: {endpoint}")),
+    }
+}
+
+/// Creates a `tracing` layer exporting spans to an OTLP/gRPC collector at `endpoint`.
+///
+/// The returned provider must be kept alive and shut down to flush pending spans.
+pub fn otlp_layer<S>(
+    endpoint: &str,
+) -> Result<(impl Layer<S> + use<S>, SdkTracerProvider), ExporterBuildError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let exporter = SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+        .build();
+
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME));
+    Ok((layer, provider))
+}
+
+/// Extracts the W3C trace context (`traceparent`/`tracestate`) from incoming headers.
+///
+/// The propagator is used whether or not spans are exported, so that audit logs
+/// refer to the caller's trace in either case.
+pub fn extract_context(headers: &impl Extractor) -> Context {
+    TraceContextPropagator::new().extract(headers)
+}
+
+/// Makes `span` a child of `parent` and returns the trace ID it belongs to.
+///
+/// The trace ID is taken from `parent` when the OpenTelemetry layer is not installed,
+/// so that audit logs still refer to the caller's trace.
+pub fn link_span(span: &Span, parent: Context) -> Option<String> {
+    let parent_trace_id = trace_id(&parent);
+    let _ = span.set_parent(parent);
+    trace_id(&span.context()).or(parent_trace_id)
+}
+
+fn trace_id(cx: &Context) -> Option<String> {
+    let span = cx.span();
+    let span_context = span.span_context();
+    span_context
+        .is_valid()
+        .then(|| span_context.trace_id().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::{
+        TraceService, TraceServiceServer,
+    };
+    use opentelemetry_proto::tonic::collector::trace::v1::{
+        ExportTraceServiceRequest, ExportTraceServiceResponse,
+    };
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+    use tonic::codegen::tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::Server;
+    use tonic::{Request, Response, Status};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+    #[test]
+    fn test_check_endpoint() {
+        assert!(check_endpoint("http://localhost:4317").is_ok());
+        assert!(check_endpoint("https://collector.example.com").is_err());
+        assert!(check_endpoint("localhost:4317").is_err());
+        assert!(check_endpoint("http://local host:4317").is_err());
+    }
+
+    #[derive(Clone, Default)]
+    struct Collector {
+        spans: Arc<Mutex<Vec<(String, String)>>>,
+    }
+
+    #[tonic::async_trait]
+    impl TraceService for Collector {
+        async fn export(
+            &self,
+            request: Request<ExportTraceServiceRequest>,
+        ) -> Result<Response<ExportTraceServiceResponse>, Status> {
+            let mut spans = self.spans.lock().unwrap();
+            for resource in request.into_inner().resource_spans {
+                for scope in resource.scope_spans {
+                    for span in scope.spans {
+                        let trace_id = span.trace_id.iter().map(|b| format!("{b:02x}")).collect();
+                        spans.push((span.name, trace_id));
+                    }
+                }
+            }
+            Ok(Response::new(ExportTraceServiceResponse::default()))
+        }
+    }
+
+    fn traceparent() -> HashMap<String, String> {
+        HashMap::from([(
+            "traceparent".to_string(),
+            format!("00-{TRACE_ID}-00f067aa0ba902b7-01"),
+        )])
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_export_span_with_remote_parent() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let collector = Collector::default();
+        tokio::spawn(
+            Server::builder()
+                .add_service(TraceServiceServer::new(collector.clone()))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let (layer, provider) = otlp_layer(&endpoint).unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        let trace_id = tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("test.rpc");
+            let trace_id = link_span(&span, extract_context(&traceparent()));
+            span.in_scope(|| tracing::info_span!("test.child").in_scope(|| {}));
+            trace_id
+        });
+        tokio::task::spawn_blocking(move || provider.shutdown())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(trace_id.as_deref(), Some(TRACE_ID));
+        let spans = collector.spans.lock().unwrap();
+        assert_eq!(spans.len(), 2);
+        assert!(spans.iter().all(|(_, id)| id == TRACE_ID));
+        assert!(spans.iter().any(|(name, _)| name == "test.child"));
+    }
+
+    #[test]
+    fn test_link_span_without_layer_uses_remote_trace_id() {
+        let span = tracing::info_span!("test.rpc");
+        let trace_id = link_span(&span, extract_context(&traceparent()));
+        assert_eq!(trace_id.as_deref(), Some(TRACE_ID));
+    }
+}